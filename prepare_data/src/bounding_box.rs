@@ -1,5 +1,5 @@
+use crate::bvh::Bounded;
 use crate::vector::Vector;
-use crate::triangle::Triangle;
 
 #[derive(Clone, Copy)]
 pub struct BoundingBox {
@@ -31,14 +31,15 @@ impl BoundingBox {
         self.center = (self.min + self.max) / 2.0;
     }
 
-    pub fn grow_to_include(&mut self, triangle: Triangle) {
-        self.min.x = if triangle.min.x < self.min.x { triangle.min.x } else { self.min.x };
-        self.min.y = if triangle.min.y < self.min.y { triangle.min.y } else { self.min.y };
-        self.min.z = if triangle.min.z < self.min.z { triangle.min.z } else { self.min.z };
+    pub fn grow_to_include<T: Bounded>(&mut self, item: &T) {
+        let (min, max) = (item.min(), item.max());
+        self.min.x = if min.x < self.min.x { min.x } else { self.min.x };
+        self.min.y = if min.y < self.min.y { min.y } else { self.min.y };
+        self.min.z = if min.z < self.min.z { min.z } else { self.min.z };
 
-        self.max.x = if triangle.max.x > self.max.x { triangle.max.x } else { self.max.x };
-        self.max.y = if triangle.max.y > self.max.y { triangle.max.y } else { self.max.y };
-        self.max.z = if triangle.max.z > self.max.z { triangle.max.z } else { self.max.z };
+        self.max.x = if max.x > self.max.x { max.x } else { self.max.x };
+        self.max.y = if max.y > self.max.y { max.y } else { self.max.y };
+        self.max.z = if max.z > self.max.z { max.z } else { self.max.z };
 
         self.center = (self.min + self.max) / 2.0;
     }
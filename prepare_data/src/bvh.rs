@@ -0,0 +1,157 @@
+use crate::bounding_box::BoundingBox;
+use crate::node::Node;
+use crate::vector::Vector;
+
+// Anything the BVH can sort: triangles of a mesh, or the spheres and other primitives of the
+// renderer's scene. Items are split by their center and the nodes grow to their box.
+pub trait Bounded {
+    fn center(&self) -> Vector;
+    fn min(&self) -> Vector;
+    fn max(&self) -> Vector;
+}
+
+// Floats per node in the data written by node_data(): min, max, item_index, item_count, child_index
+pub const FLOATS_PER_NODE: usize = 9;
+
+// Build the BVH over the items and reorder them so every leaf's items are next to each other. The
+// root is the first node, and the two children of a node are always next to each other.
+pub fn build_bvh<T: Bounded>(all_items: &mut [T], max_depth: i32) -> Vec<Node> {
+    let mut all_nodes: Vec<Node> = Vec::new();
+
+    // Create bounding box
+    let mut bounds: BoundingBox = BoundingBox::new();
+
+    for item in all_items.iter() {
+        bounds.grow_to_include(item);
+    }
+
+    // Create root noode (represents all items, un-split), and split it
+    let root: Node = Node::new(bounds, 0, all_items.len() as i32);
+    all_nodes.push(root);
+    split(root, 0, 0, &mut all_nodes, all_items, max_depth);
+    all_nodes
+}
+
+// Format: FLOATS_PER_NODE floats per node
+pub fn node_data(all_nodes: &[Node]) -> Vec<f32> {
+    let mut data: Vec<f32> = Vec::new();
+    for node in all_nodes {
+        data.push(node.bounds.min.x);
+        data.push(node.bounds.min.y);
+        data.push(node.bounds.min.z);
+        data.push(node.bounds.max.x);
+        data.push(node.bounds.max.y);
+        data.push(node.bounds.max.z);
+        data.push(node.item_index as f32);
+        data.push(node.item_count as f32);
+        data.push(node.child_index as f32);
+    }
+    data
+}
+
+fn node_cost(size: Vector, num_items: f32) -> f32 {
+    let half_area: f32 = size.x * (size.y + size.z) + size.y * size.z;
+    half_area * num_items
+}
+
+fn choose_split<T: Bounded>(node: Node, all_items: &[T]) -> (i32, f32, f32) {
+    const NUM_TESTS_PER_AXIS: i32 = 10;
+    let mut best_cost: f32 = f32::INFINITY;
+    let mut best_pos: f32 = 0.0;
+    let mut best_axis: i32 = 0;
+
+    for axis in 0..3 {
+        let bounds_start: f32 = node.bounds.min[axis];
+        let bounds_end: f32 = node.bounds.max[axis];
+
+        for i in 0..NUM_TESTS_PER_AXIS {
+            let split_t: f32 = (i + 1) as f32 / (NUM_TESTS_PER_AXIS + 1) as f32;
+            let pos: f32 = bounds_start + (bounds_end - bounds_start) * split_t;
+            let cost: f32 = evaluate_split(node, axis, pos, all_items);
+
+            if cost < best_cost {
+                best_cost = cost;
+                best_pos = pos;
+                best_axis = axis as i32;
+            }
+        }
+    }
+
+    (best_axis, best_pos, best_cost)
+}
+
+fn evaluate_split<T: Bounded>(node: Node, axis: usize, pos: f32, all_items: &[T]) -> f32 {
+    let mut bounds_a: BoundingBox = BoundingBox::new();
+    let mut bounds_b: BoundingBox = BoundingBox::new();
+    let mut num_in_a: i32 = 0;
+    let mut num_in_b: i32 = 0;
+
+    for item in &all_items[node.item_index as usize..(node.item_index + node.item_count) as usize] {
+        if item.center()[axis] < pos {
+            bounds_a.grow_to_include(item);
+            num_in_a += 1;
+        } else {
+            bounds_b.grow_to_include(item);
+            num_in_b += 1;
+        }
+    }
+
+    node_cost(bounds_a.max - bounds_a.min, num_in_a as f32)
+        + node_cost(bounds_b.max - bounds_b.min, num_in_b as f32)
+}
+
+fn split<T: Bounded>(
+    parent: Node,
+    parent_index: usize,
+    depth: i32,
+    all_nodes: &mut Vec<Node>,
+    all_items: &mut [T],
+    max_depth: i32,
+) {
+    if depth == max_depth {
+        return;
+    }
+
+    // Choose split axis and position
+    let (split_axis, split_pos, cost) = choose_split(parent, all_items);
+
+    // Stop splitting if it doesn't improve the cost
+    if cost >= node_cost(parent.bounds.max - parent.bounds.min, parent.item_count as f32) {
+        return;
+    }
+
+    let mut child_a: Node = Node::new(BoundingBox::new(), parent.item_index, 0);
+    let mut child_b: Node = Node::new(BoundingBox::new(), parent.item_index, 0);
+
+    for i in parent.item_index..parent.item_index + parent.item_count {
+        let is_side_a: bool = all_items[i as usize].center()[split_axis as usize] < split_pos;
+
+        if is_side_a {
+            child_a.bounds.grow_to_include(&all_items[i as usize]);
+            child_a.item_count += 1;
+
+            // Ensure that the items of each child node are grouped together.
+            // This allows the node to 'store' the items with an index and count.
+            let swap: i32 = child_a.item_index + child_a.item_count - 1;
+            all_items.swap(i as usize, swap as usize);
+
+            child_b.item_index += 1;
+        } else {
+            child_b.bounds.grow_to_include(&all_items[i as usize]);
+            child_b.item_count += 1;
+        }
+    }
+
+    if child_a.item_count > 0 && child_b.item_count > 0 {
+        all_nodes.push(child_a);
+        all_nodes.push(child_b);
+
+        let child_a_index: usize = all_nodes.len() - 2;
+        let child_b_index: usize = all_nodes.len() - 1;
+
+        all_nodes[parent_index].child_index = child_a_index as i32;
+
+        split(child_a, child_a_index, depth + 1, all_nodes, all_items, max_depth);
+        split(child_b, child_b_index, depth + 1, all_nodes, all_items, max_depth);
+    }
+}
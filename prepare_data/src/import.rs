@@ -0,0 +1,164 @@
+use crate::triangle::Triangle;
+use crate::vector::Vector;
+
+pub struct ImportedMesh {
+    pub triangles: Vec<Triangle>,
+    pub has_normals: bool, // Whether the file gave every corner a vertex normal
+}
+
+// Load an ASCII or binary STL, or an OBJ file. STL only stores the normals of the facets, so
+// vertex normals and texture coordinates can only come from an OBJ file.
+pub fn load_mesh(path: &str) -> ImportedMesh {
+    let data: Vec<u8> = std::fs::read(path).expect("Failed to read input file");
+    if path.to_lowercase().ends_with(".obj") {
+        load_obj(&String::from_utf8_lossy(&data))
+    } else if is_ascii_stl(&data) {
+        ImportedMesh {
+            triangles: load_ascii_stl(&String::from_utf8_lossy(&data)),
+            has_normals: false,
+        }
+    } else {
+        ImportedMesh {
+            triangles: load_binary_stl(&data),
+            has_normals: false,
+        }
+    }
+}
+
+// Binary STL files may start with "solid" as well, so also look for a facet
+fn is_ascii_stl(data: &[u8]) -> bool {
+    let start: &[u8] = &data[..data.len().min(1024)];
+    start.starts_with(b"solid") && start.windows(5).any(|word| word == b"facet")
+}
+
+fn load_ascii_stl(text: &str) -> Vec<Triangle> {
+    let vertices: Vec<Vector> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("vertex"))
+        .map(|line| parse_vector(&line["vertex".len()..]))
+        .collect();
+    vertices
+        .chunks_exact(3)
+        .map(|v| Triangle::new(v[0], v[1], v[2]))
+        .collect()
+}
+
+// An 80 byte header, the number of triangles and 50 bytes per triangle: the facet normal, the
+// three vertices and two bytes of attributes
+fn load_binary_stl(data: &[u8]) -> Vec<Triangle> {
+    if data.len() < 84 {
+        panic!("The input file is too short to be a binary STL file");
+    }
+    let count: usize = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+    if data.len() < 84 + count * 50 {
+        panic!("The binary STL file is truncated");
+    }
+
+    let read_float = |offset: usize| {
+        f32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+    };
+    let read_vector = |offset: usize| {
+        Vector::new(read_float(offset), read_float(offset + 4), read_float(offset + 8))
+    };
+    (0..count)
+        .map(|i| {
+            let vertices_start: usize = 84 + i * 50 + 12;
+            Triangle::new(
+                read_vector(vertices_start),
+                read_vector(vertices_start + 12),
+                read_vector(vertices_start + 24),
+            )
+        })
+        .collect()
+}
+
+// Faces with more than three corners are split into a fan of triangles
+fn load_obj(text: &str) -> ImportedMesh {
+    let mut positions: Vec<Vector> = Vec::new();
+    let mut normals: Vec<Vector> = Vec::new();
+    let mut uvs: Vec<[f32; 2]> = Vec::new();
+    let mut triangles: Vec<Triangle> = Vec::new();
+    let mut has_normals: bool = true;
+
+    for line in text.lines() {
+        let line: &str = line.trim();
+        if let Some(rest) = line.strip_prefix("v ") {
+            positions.push(parse_vector(rest));
+        } else if let Some(rest) = line.strip_prefix("vn ") {
+            normals.push(parse_vector(rest).normalize());
+        } else if let Some(rest) = line.strip_prefix("vt ") {
+            uvs.push(parse_uv(rest));
+        } else if let Some(rest) = line.strip_prefix("f ") {
+            // Corners are position/uv/normal, where the uv and normal may be missing
+            let corners: Vec<(Vector, [f32; 2], Option<Vector>)> = rest
+                .split_whitespace()
+                .map(|corner| {
+                    let mut indices = corner.split('/');
+                    let position: Vector = positions[obj_index(indices.next().unwrap(), positions.len())];
+                    let uv: [f32; 2] = indices
+                        .next()
+                        .filter(|index| !index.is_empty())
+                        .map_or([0.0; 2], |index| uvs[obj_index(index, uvs.len())]);
+                    let normal: Option<Vector> = indices
+                        .next()
+                        .filter(|index| !index.is_empty())
+                        .map(|index| normals[obj_index(index, normals.len())]);
+                    (position, uv, normal)
+                })
+                .collect();
+
+            for i in 1..corners.len().saturating_sub(1) {
+                let fan = [corners[0], corners[i], corners[i + 1]];
+                let mut triangle: Triangle = Triangle::new(fan[0].0, fan[1].0, fan[2].0);
+                triangle.uvs = [fan[0].1, fan[1].1, fan[2].1];
+                match (fan[0].2, fan[1].2, fan[2].2) {
+                    (Some(a), Some(b), Some(c)) => triangle.normals = [a, b, c],
+                    _ => has_normals = false,
+                }
+                triangles.push(triangle);
+            }
+        }
+    }
+
+    ImportedMesh {
+        has_normals: has_normals && !triangles.is_empty(),
+        triangles,
+    }
+}
+
+// OBJ indices start at 1, negative ones count back from the last element read so far
+fn obj_index(index: &str, count: usize) -> usize {
+    let index: i64 = index.parse().expect("Invalid index in OBJ file");
+    if index < 0 {
+        (count as i64 + index) as usize
+    } else {
+        index as usize - 1
+    }
+}
+
+// The optional third coordinate of 3D texture coordinates is ignored
+fn parse_uv(text: &str) -> [f32; 2] {
+    let values: Vec<f32> = text
+        .split_whitespace()
+        .take(2)
+        .map(|value| value.parse().expect("Invalid number in input file"))
+        .collect();
+    match values[..] {
+        [u] => [u, 0.0],
+        [u, v] => [u, v],
+        _ => panic!("Expected texture coordinates in '{}'", text.trim()),
+    }
+}
+
+fn parse_vector(text: &str) -> Vector {
+    let values: Vec<f32> = text
+        .split_whitespace()
+        .take(3)
+        .map(|value| value.parse().expect("Invalid number in input file"))
+        .collect();
+    if values.len() != 3 {
+        panic!("Expected three numbers in '{}'", text.trim());
+    }
+    Vector::new(values[0], values[1], values[2])
+}
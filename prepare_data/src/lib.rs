@@ -0,0 +1,12 @@
+// Importing meshes and building bounding volume hierarchies. The binary in main.rs prepares the
+// mesh files, and the renderer builds the BVH over the spheres and primitives of its scene with
+// build_bvh.
+pub mod bounding_box;
+pub mod bvh;
+pub mod import;
+pub mod node;
+pub mod normals;
+pub mod repair;
+pub mod triangle;
+pub mod vector;
+pub mod weld;
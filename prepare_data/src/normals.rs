@@ -0,0 +1,65 @@
+use crate::triangle::Triangle;
+use crate::vector::Vector;
+use std::collections::HashMap;
+
+// Give every corner the average normal of the triangles around its vertex, weighted by their
+// angle at the vertex. Only triangles whose normal is within the crease angle (in degrees) of
+// the corner's own triangle are averaged, so hard edges stay sharp.
+pub fn compute_vertex_normals(triangles: &mut [Triangle], crease_angle: f32) {
+    let face_normals: Vec<Vector> = triangles
+        .iter()
+        .map(|triangle| {
+            let normal: Vector = triangle.area_normal();
+            if normal.magnitude() > 0.0 {
+                normal.normalize()
+            } else {
+                normal
+            }
+        })
+        .collect();
+    let corner_angles: Vec<[f32; 3]> = triangles.iter().map(corner_angles).collect();
+
+    // Corners at the same position share a vertex
+    let mut vertices: HashMap<[u32; 3], Vec<(usize, usize)>> = HashMap::new();
+    for (triangle_index, triangle) in triangles.iter().enumerate() {
+        for (corner, position) in triangle.vertices().iter().enumerate() {
+            vertices
+                .entry(position.key())
+                .or_default()
+                .push((triangle_index, corner));
+        }
+    }
+
+    let min_cos: f32 = crease_angle.to_radians().cos();
+    for corners in vertices.values() {
+        for &(triangle_index, corner) in corners {
+            let own_normal: Vector = face_normals[triangle_index];
+            let mut sum: Vector = Vector::new(0.0, 0.0, 0.0);
+            for &(other_index, other_corner) in corners {
+                if face_normals[other_index].dot(&own_normal) >= min_cos {
+                    sum = sum + face_normals[other_index] * corner_angles[other_index][other_corner];
+                }
+            }
+            triangles[triangle_index].normals[corner] = if sum.magnitude() > 0.0 {
+                sum.normalize()
+            } else {
+                own_normal
+            };
+        }
+    }
+}
+
+// Angle of the triangle at each of its corners, 0 at the corners of degenerate triangles
+fn corner_angles(triangle: &Triangle) -> [f32; 3] {
+    let vertices: [Vector; 3] = triangle.vertices();
+    [0, 1, 2].map(|corner| {
+        let edge_a: Vector = vertices[(corner + 1) % 3] - vertices[corner];
+        let edge_b: Vector = vertices[(corner + 2) % 3] - vertices[corner];
+        let lengths: f32 = edge_a.magnitude() * edge_b.magnitude();
+        if lengths > 0.0 {
+            (edge_a.dot(&edge_b) / lengths).clamp(-1.0, 1.0).acos()
+        } else {
+            0.0
+        }
+    })
+}
@@ -0,0 +1,484 @@
+use crate::triangle::Triangle;
+use crate::vector::Vector;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Triangles whose height is below this fraction of their longest edge are slivers that rays
+// can't hit reliably
+const DEGENERATE_RATIO: f32 = 1e-6;
+
+// Defects that show up as holes in the render, where back faces are rejected
+pub struct MeshReport {
+    pub triangles: usize,
+    pub nan_triangles: usize,           // With a NaN or infinite coordinate
+    pub degenerate_triangles: usize,    // Lower than DEGENERATE_RATIO of their longest edge
+    pub duplicate_triangles: usize,     // Same corners as an earlier triangle, in any order
+    pub near_duplicate_corners: usize,  // Within the weld distance of a corner at another position
+    pub open_edges: usize,              // Only used by one triangle
+    pub non_manifold_edges: usize,      // Used by more than two triangles
+    pub flipped_edges: usize,           // Between two triangles that go along it in the same direction
+    pub components: usize,              // Groups of triangles connected by their edges
+}
+
+impl MeshReport {
+    // Open edges alone are fine, a mesh doesn't have to be closed. Open edges along cracks between
+    // near-duplicate corners are counted by those.
+    pub fn is_clean(&self) -> bool {
+        self.nan_triangles == 0
+            && self.degenerate_triangles == 0
+            && self.duplicate_triangles == 0
+            && self.near_duplicate_corners == 0
+            && self.non_manifold_edges == 0
+            && self.flipped_edges == 0
+    }
+
+    pub fn print(&self) {
+        println!("Triangles: {} in {} connected components", self.triangles, self.components);
+        println!("NaN triangles: {}", self.nan_triangles);
+        println!("Degenerate triangles: {}", self.degenerate_triangles);
+        println!("Duplicate triangles: {}", self.duplicate_triangles);
+        println!("Near-duplicate corners: {}", self.near_duplicate_corners);
+        println!("Open edges: {}", self.open_edges);
+        println!("Non-manifold edges: {}", self.non_manifold_edges);
+        println!("Edges with inconsistent winding: {}", self.flipped_edges);
+    }
+}
+
+// Edges are found between corners at exactly the same position. Corners that weld_positions would
+// move with the weld distance are counted as near-duplicates, the cracks between them also show
+// up as open edges.
+pub fn check_mesh(triangles: &[Triangle], weld_distance: f32) -> MeshReport {
+    let mut report: MeshReport = MeshReport {
+        triangles: triangles.len(),
+        nan_triangles: 0,
+        degenerate_triangles: 0,
+        duplicate_triangles: 0,
+        near_duplicate_corners: 0,
+        open_edges: 0,
+        non_manifold_edges: 0,
+        flipped_edges: 0,
+        components: 0,
+    };
+
+    let mut faces: HashSet<[[u32; 3]; 3]> = HashSet::new();
+    for triangle in triangles {
+        if has_nan(triangle) {
+            report.nan_triangles += 1;
+        } else if is_degenerate(triangle) {
+            report.degenerate_triangles += 1;
+        } else if !faces.insert(face_key(triangle)) {
+            report.duplicate_triangles += 1;
+        }
+    }
+
+    for (triangle, welded) in triangles.iter().zip(welded_positions(triangles, weld_distance)) {
+        for (vertex, welded) in triangle.vertices().iter().zip(welded) {
+            if welded.key() != vertex.key() {
+                report.near_duplicate_corners += 1;
+            }
+        }
+    }
+
+    let edges: HashMap<[[u32; 3]; 2], Vec<(usize, bool)>> = edge_map(triangles);
+    for users in edges.values() {
+        match users.len() {
+            1 => report.open_edges += 1,
+            2 => {
+                if users[0].1 == users[1].1 {
+                    report.flipped_edges += 1;
+                }
+            }
+            _ => report.non_manifold_edges += 1,
+        }
+    }
+
+    report.components = components(triangles, &edges).len();
+    report
+}
+
+// Move corners onto an earlier corner within the distance, so cracks between triangles that
+// should share a vertex close. Returns the number of corners that moved.
+pub fn weld_positions(triangles: &mut [Triangle], distance: f32) -> usize {
+    let welded_positions: Vec<[Vector; 3]> = welded_positions(triangles, distance);
+    let mut moved: usize = 0;
+    for (triangle, welded) in triangles.iter_mut().zip(welded_positions) {
+        for (vertex, welded) in triangle.vertices().iter().zip(welded) {
+            if welded.key() != vertex.key() {
+                moved += 1;
+            }
+        }
+        triangle.set(welded[0], welded[1], welded[2]);
+    }
+    moved
+}
+
+// Where the corners of every triangle end up after welding: on the closest earlier corner within
+// the distance, or where they are if there's none
+fn welded_positions(triangles: &[Triangle], distance: f32) -> Vec<[Vector; 3]> {
+    // Every position kept so far, in a grid of cells the size of the distance
+    let mut grid: HashMap<[i32; 3], Vec<Vector>> = HashMap::new();
+    let cell = |position: Vector| {
+        [position.x, position.y, position.z].map(|value| (value / distance).floor() as i32)
+    };
+
+    triangles
+        .iter()
+        .map(|triangle| {
+            triangle.vertices().map(|vertex| {
+                if has_nan_vector(vertex) {
+                    return vertex;
+                }
+
+                // The closest kept position in the neighbouring cells
+                let [x, y, z] = cell(vertex);
+                let mut closest: Option<(Vector, f32)> = None;
+                for neighbour in [x - 1, x, x + 1]
+                    .iter()
+                    .flat_map(|&x| [y - 1, y, y + 1].map(|y| (x, y)))
+                    .flat_map(|(x, y)| [z - 1, z, z + 1].map(|z| [x, y, z]))
+                {
+                    for &kept in grid.get(&neighbour).into_iter().flatten() {
+                        let kept_distance: f32 = (kept - vertex).magnitude();
+                        if kept_distance <= distance && closest.map_or(true, |(_, d)| kept_distance < d) {
+                            closest = Some((kept, kept_distance));
+                        }
+                    }
+                }
+
+                match closest {
+                    Some((kept, _)) => kept,
+                    None => {
+                        grid.entry(cell(vertex)).or_default().push(vertex);
+                        vertex
+                    }
+                }
+            })
+        })
+        .collect()
+}
+
+// Remove triangles with NaN coordinates, without area or with the same corners as an earlier
+// triangle. Returns the number removed.
+pub fn remove_invalid_triangles(triangles: &mut Vec<Triangle>) -> usize {
+    let count: usize = triangles.len();
+    let mut faces: HashSet<[[u32; 3]; 3]> = HashSet::new();
+    triangles.retain(|triangle| {
+        !has_nan(triangle) && !is_degenerate(triangle) && faces.insert(face_key(triangle))
+    });
+    count - triangles.len()
+}
+
+// Turn the triangles of every connected component the same way, by flipping the neighbours that
+// go along a shared edge in the same direction. Closed components are then turned so their
+// normals point outward, open ones keep the winding most of their triangles had. Only edges with
+// two triangles connect them, a non-manifold edge has no consistent orientation. Returns the
+// number of flipped triangles.
+pub fn fix_winding(triangles: &mut [Triangle]) -> usize {
+    let edges: HashMap<[[u32; 3]; 2], Vec<(usize, bool)>> = edge_map(triangles);
+    let mut flipped: usize = 0;
+
+    for component in components(triangles, &edges) {
+        // Whether each triangle has to flip to match the first of the component
+        let mut flip: HashMap<usize, bool> = HashMap::new();
+        let mut queue: VecDeque<usize> = VecDeque::new();
+        flip.insert(component[0], false);
+        queue.push_back(component[0]);
+        while let Some(index) = queue.pop_front() {
+            for (edge, forward) in triangle_edges(&triangles[index]) {
+                let users: &Vec<(usize, bool)> = &edges[&edge];
+                if users.len() != 2 {
+                    continue;
+                }
+                for &(other, other_forward) in users {
+                    if other != index && !flip.contains_key(&other) {
+                        // Consistent neighbours go along the shared edge in opposite directions
+                        flip.insert(other, flip[&index] ^ (forward == other_forward));
+                        queue.push_back(other);
+                    }
+                }
+            }
+        }
+
+        // A closed component encloses a positive volume when its normals point outward
+        let closed: bool = component.iter().all(|&index| {
+            triangle_edges(&triangles[index])
+                .iter()
+                .all(|(edge, _)| edges[edge].len() == 2)
+        });
+        let flip_all: bool = if closed {
+            let volume: f32 = component
+                .iter()
+                .map(|&index| {
+                    let [a, b, c] = triangles[index].vertices();
+                    let volume: f32 = a.dot(&b.cross(&c));
+                    if flip[&index] {
+                        -volume
+                    } else {
+                        volume
+                    }
+                })
+                .sum();
+            volume < 0.0
+        } else {
+            flip.values().filter(|&&f| f).count() * 2 > component.len()
+        };
+
+        for &index in &component {
+            if flip[&index] != flip_all {
+                flip_triangle(&mut triangles[index]);
+                flipped += 1;
+            }
+        }
+    }
+
+    flipped
+}
+
+// Swap two corners, with their normals and texture coordinates. The shading normals came from the
+// file or are computed afterwards, so they're kept as they are.
+fn flip_triangle(triangle: &mut Triangle) {
+    let [a, b, c] = triangle.vertices();
+    triangle.set(a, c, b);
+    triangle.normals.swap(1, 2);
+    triangle.uvs.swap(1, 2);
+}
+
+// Groups of triangles connected by edges with exactly two triangles
+fn components(triangles: &[Triangle], edges: &HashMap<[[u32; 3]; 2], Vec<(usize, bool)>>) -> Vec<Vec<usize>> {
+    let mut visited: Vec<bool> = vec![false; triangles.len()];
+    let mut components: Vec<Vec<usize>> = Vec::new();
+
+    for start in 0..triangles.len() {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let mut component: Vec<usize> = vec![start];
+        let mut next: usize = 0;
+        while next < component.len() {
+            let index: usize = component[next];
+            next += 1;
+            for (edge, _) in triangle_edges(&triangles[index]) {
+                let users: &Vec<(usize, bool)> = &edges[&edge];
+                if users.len() != 2 {
+                    continue;
+                }
+                for &(other, _) in users {
+                    if !visited[other] {
+                        visited[other] = true;
+                        component.push(other);
+                    }
+                }
+            }
+        }
+        components.push(component);
+    }
+
+    components
+}
+
+// The triangles using every edge, and whether they go along it from its lower to its higher corner
+fn edge_map(triangles: &[Triangle]) -> HashMap<[[u32; 3]; 2], Vec<(usize, bool)>> {
+    let mut edges: HashMap<[[u32; 3]; 2], Vec<(usize, bool)>> = HashMap::new();
+    for (index, triangle) in triangles.iter().enumerate() {
+        for (edge, forward) in triangle_edges(triangle) {
+            edges.entry(edge).or_default().push((index, forward));
+        }
+    }
+    edges
+}
+
+// The three edges with their corners sorted, and whether the triangle goes along them in that
+// order. Degenerate triangles can have an edge with the same corner twice.
+fn triangle_edges(triangle: &Triangle) -> Vec<([[u32; 3]; 2], bool)> {
+    let corners: [[u32; 3]; 3] = triangle.vertices().map(|vertex| vertex.key());
+    (0..3)
+        .map(|i| {
+            let from: [u32; 3] = corners[i];
+            let to: [u32; 3] = corners[(i + 1) % 3];
+            if from <= to {
+                ([from, to], true)
+            } else {
+                ([to, from], false)
+            }
+        })
+        .collect()
+}
+
+fn face_key(triangle: &Triangle) -> [[u32; 3]; 3] {
+    let mut corners: [[u32; 3]; 3] = triangle.vertices().map(|vertex| vertex.key());
+    corners.sort();
+    corners
+}
+
+fn has_nan(triangle: &Triangle) -> bool {
+    triangle.vertices().iter().any(|vertex| has_nan_vector(*vertex))
+}
+
+fn has_nan_vector(vector: Vector) -> bool {
+    !(vector.x.is_finite() && vector.y.is_finite() && vector.z.is_finite())
+}
+
+fn is_degenerate(triangle: &Triangle) -> bool {
+    let [a, b, c] = triangle.vertices();
+    let longest: f32 = (b - a).magnitude().max((c - b).magnitude()).max((a - c).magnitude());
+    // The length of the area normal is the longest edge times the height over it
+    let height: f32 = if longest > 0.0 {
+        triangle.area_normal().magnitude() / longest
+    } else {
+        0.0
+    };
+    height <= DEGENERATE_RATIO * longest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WELD_DISTANCE: f32 = 1e-4;
+
+    fn triangle(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> Triangle {
+        let vector = |v: [f32; 3]| Vector::new(v[0], v[1], v[2]);
+        Triangle::new(vector(a), vector(b), vector(c))
+    }
+
+    // Unit square in the xy plane facing +z
+    fn quad() -> Vec<Triangle> {
+        vec![
+            triangle([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]),
+            triangle([0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]),
+        ]
+    }
+
+    // Closed, with the normals pointing outward
+    fn tetrahedron() -> Vec<Triangle> {
+        let [a, b, c, d] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        vec![triangle(a, c, b), triangle(a, b, d), triangle(a, d, c), triangle(b, c, d)]
+    }
+
+    fn faces_outward(triangles: &[Triangle], center: Vector) -> bool {
+        triangles
+            .iter()
+            .all(|triangle| triangle.area_normal().dot(&(triangle.center - center)) > 0.0)
+    }
+
+    #[test]
+    fn clean_mesh() {
+        let report: MeshReport = check_mesh(&tetrahedron(), WELD_DISTANCE);
+        assert!(report.is_clean());
+        assert_eq!(report.open_edges, 0);
+        assert_eq!(report.components, 1);
+
+        let report: MeshReport = check_mesh(&quad(), WELD_DISTANCE);
+        assert!(report.is_clean());
+        assert_eq!(report.open_edges, 4);
+    }
+
+    #[test]
+    fn flipped_neighbour() {
+        let mut triangles: Vec<Triangle> = quad();
+        flip_triangle(&mut triangles[1]);
+        let report: MeshReport = check_mesh(&triangles, WELD_DISTANCE);
+        assert_eq!(report.flipped_edges, 1);
+        assert!(!report.is_clean());
+
+        // Neither winding has the majority, so the first triangle's wins
+        assert_eq!(fix_winding(&mut triangles), 1);
+        assert!(check_mesh(&triangles, WELD_DISTANCE).is_clean());
+        assert!(triangles.iter().all(|triangle| triangle.area_normal().z > 0.0));
+    }
+
+    #[test]
+    fn inverted_closed_mesh() {
+        let mut triangles: Vec<Triangle> = tetrahedron();
+        triangles.iter_mut().for_each(flip_triangle);
+        assert!(check_mesh(&triangles, WELD_DISTANCE).is_clean());
+
+        assert_eq!(fix_winding(&mut triangles), 4);
+        assert!(faces_outward(&triangles, Vector::new(0.25, 0.25, 0.25)));
+    }
+
+    #[test]
+    fn inconsistent_closed_mesh() {
+        let mut triangles: Vec<Triangle> = tetrahedron();
+        flip_triangle(&mut triangles[0]);
+        flip_triangle(&mut triangles[2]);
+        assert_eq!(check_mesh(&triangles, WELD_DISTANCE).flipped_edges, 4);
+
+        assert_eq!(fix_winding(&mut triangles), 2);
+        assert!(check_mesh(&triangles, WELD_DISTANCE).is_clean());
+        assert!(faces_outward(&triangles, Vector::new(0.25, 0.25, 0.25)));
+    }
+
+    #[test]
+    fn duplicate_face() {
+        let mut triangles: Vec<Triangle> = quad();
+        let mut duplicate: Triangle = triangles[0];
+        flip_triangle(&mut duplicate);
+        triangles.push(duplicate);
+        let report: MeshReport = check_mesh(&triangles, WELD_DISTANCE);
+        assert_eq!(report.duplicate_triangles, 1);
+        assert_eq!(report.non_manifold_edges, 1); // The diagonal
+        assert!(!report.is_clean());
+
+        assert_eq!(remove_invalid_triangles(&mut triangles), 1);
+        assert_eq!(triangles.len(), 2);
+        assert!(check_mesh(&triangles, WELD_DISTANCE).is_clean());
+    }
+
+    #[test]
+    fn nan_corner() {
+        let mut triangles: Vec<Triangle> = quad();
+        triangles.push(triangle([f32::NAN, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]));
+        let report: MeshReport = check_mesh(&triangles, WELD_DISTANCE);
+        assert_eq!(report.nan_triangles, 1);
+        assert!(!report.is_clean());
+
+        // Welding leaves the NaN alone
+        assert_eq!(weld_positions(&mut triangles, WELD_DISTANCE), 0);
+        assert_eq!(remove_invalid_triangles(&mut triangles), 1);
+        assert!(check_mesh(&triangles, WELD_DISTANCE).is_clean());
+    }
+
+    #[test]
+    fn degenerate_triangles() {
+        let mut triangles: Vec<Triangle> = quad();
+        triangles.push(triangle([0.0, 0.0, 0.0], [1.0, 1.0, 1.0], [2.0, 2.0, 2.0]));
+        triangles.push(triangle([5.0, 5.0, 5.0], [5.0, 5.0, 5.0], [5.0, 5.0, 5.0]));
+        assert_eq!(check_mesh(&triangles, WELD_DISTANCE).degenerate_triangles, 2);
+
+        assert_eq!(remove_invalid_triangles(&mut triangles), 2);
+        assert_eq!(triangles.len(), 2);
+    }
+
+    #[test]
+    fn cracked_quad() {
+        // The second triangle's copies of the shared corners are a little off
+        let mut triangles: Vec<Triangle> = vec![
+            triangle([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]),
+            triangle([0.00002, 0.0, 0.0], [1.0, 0.99998, 0.00001], [0.0, 1.0, 0.0]),
+        ];
+        let report: MeshReport = check_mesh(&triangles, WELD_DISTANCE);
+        assert_eq!(report.near_duplicate_corners, 2);
+        assert_eq!(report.open_edges, 6);
+        assert_eq!(report.components, 2);
+        assert!(!report.is_clean());
+
+        assert_eq!(weld_positions(&mut triangles, WELD_DISTANCE), 2);
+        assert_eq!(triangles[1].get_a(), triangles[0].get_a());
+        assert_eq!(triangles[1].get_b(), triangles[0].get_c());
+        let report: MeshReport = check_mesh(&triangles, WELD_DISTANCE);
+        assert!(report.is_clean());
+        assert_eq!(report.open_edges, 4);
+        assert_eq!(report.components, 1);
+    }
+
+    #[test]
+    fn corners_further_apart_stay() {
+        let mut triangles: Vec<Triangle> = vec![
+            triangle([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]),
+            triangle([0.001, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]),
+        ];
+        assert_eq!(check_mesh(&triangles, WELD_DISTANCE).near_duplicate_corners, 0);
+        assert_eq!(weld_positions(&mut triangles, WELD_DISTANCE), 0);
+    }
+}
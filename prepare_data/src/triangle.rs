@@ -1,3 +1,4 @@
+use crate::bvh::Bounded;
 use crate::vector::Vector;
 
 #[derive(Clone, Copy)]
@@ -8,10 +9,18 @@ pub struct Triangle {
     pub center: Vector,
     pub min: Vector,
     pub max: Vector,
+    pub normals: [Vector; 3], // Shading normals of a, b and c
+    pub uvs: [[f32; 2]; 3],   // Texture coordinates of a, b and c
 }
 
 impl Triangle {
+    // The shading normals start out as the normal of the triangle itself, or zero if it has no
+    // area. The texture coordinates start out as zero.
     pub fn new(a: Vector, b: Vector, c: Vector) -> Triangle {
+        let mut face_normal: Vector = (b - a).cross(&(c - a));
+        if face_normal.magnitude() > 0.0 {
+            face_normal = face_normal.normalize();
+        }
         Triangle {
             a: a,
             b: b,
@@ -27,9 +36,20 @@ impl Triangle {
                 a.y.max(b.y).max(c.y),
                 a.z.max(b.z).max(c.z),
             ),
+            normals: [face_normal; 3],
+            uvs: [[0.0; 2]; 3],
         }
     }
 
+    // Not normalized, its length is twice the area of the triangle
+    pub fn area_normal(&self) -> Vector {
+        (self.b - self.a).cross(&(self.c - self.a))
+    }
+
+    pub fn vertices(&self) -> [Vector; 3] {
+        [self.a, self.b, self.c]
+    }
+
     pub fn get_a(&self) -> Vector {
         self.a
     }
@@ -103,4 +123,18 @@ impl Triangle {
             self.a.z.max(self.b.z).max(self.c.z),
         );
     }
-}
\ No newline at end of file
+}
+
+impl Bounded for Triangle {
+    fn center(&self) -> Vector {
+        self.center
+    }
+
+    fn min(&self) -> Vector {
+        self.min
+    }
+
+    fn max(&self) -> Vector {
+        self.max
+    }
+}
@@ -235,4 +235,14 @@ impl Vector {
     pub fn get_vector(&self) -> Vector {
         *self
     }
+
+    // Exact position as a hash map key, see float_key
+    pub fn key(&self) -> [u32; 3] {
+        [self.x, self.y, self.z].map(float_key)
+    }
+}
+
+// Bits of the value as a hash map key. Adding 0 turns -0 into 0, so both end up at the same key.
+pub fn float_key(value: f32) -> u32 {
+    (value + 0.0).to_bits()
 }
\ No newline at end of file
@@ -0,0 +1,44 @@
+use crate::triangle::Triangle;
+use crate::vector::{float_key, Vector};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy)]
+pub struct Vertex {
+    pub position: Vector,
+    pub normal: Vector,
+    pub uv: [f32; 2],
+}
+
+// Share the vertices of neighbouring triangles. Corners only become one vertex if their position,
+// normal and texture coordinates are the same, so hard edges and UV seams keep a vertex for every
+// side. Returns the vertices and three indices into them per triangle, in the order of the
+// triangles.
+pub fn weld_vertices(triangles: &[Triangle]) -> (Vec<Vertex>, Vec<u32>) {
+    let mut vertices: Vec<Vertex> = Vec::new();
+    let mut indices: Vec<u32> = Vec::with_capacity(triangles.len() * 3);
+    let mut vertex_indices: HashMap<[u32; 8], u32> = HashMap::new();
+
+    for triangle in triangles {
+        for corner in 0..3 {
+            let vertex: Vertex = Vertex {
+                position: triangle.vertices()[corner],
+                normal: triangle.normals[corner],
+                uv: triangle.uvs[corner],
+            };
+            let index: u32 = *vertex_indices
+                .entry(vertex_key(vertex))
+                .or_insert_with(|| {
+                    vertices.push(vertex);
+                    vertices.len() as u32 - 1
+                });
+            indices.push(index);
+        }
+    }
+
+    (vertices, indices)
+}
+
+fn vertex_key(vertex: Vertex) -> [u32; 8] {
+    let Vertex { position, normal, uv } = vertex;
+    [position.x, position.y, position.z, normal.x, normal.y, normal.z, uv[0], uv[1]].map(float_key)
+}
@@ -0,0 +1,65 @@
+// Arbitrary output variables for compositing: the radiance split up by the way the light reached
+// the camera, and information about the first hit. Headless renders save them as the layers of
+// one EXR file or as one PFM file per layer.
+use crate::cpu_renderer::FramePixel;
+
+// Light AOVs in the order of aov_data in shader.wgsl, together they add up to the radiance
+pub const AOV_COUNT: usize = 5;
+pub const LIGHT_AOVS: [&str; AOV_COUNT] = [
+    "direct_diffuse",
+    "indirect_diffuse",
+    "specular",
+    "emission",
+    "environment",
+];
+
+// Compositing tools expect the sky far away, the frame data stores -1
+const SKY_DEPTH: f32 = 1.0e10;
+
+const RGB: &[&str] = &["R", "G", "B"];
+const XYZ: &[&str] = &["X", "Y", "Z"];
+
+pub struct Layer {
+    pub name: &'static str,
+    pub channels: &'static [&'static str],
+    pub data: Vec<f32>, // One value per channel and pixel, row by row from the top
+}
+
+// Every AOV but the radiance itself, from the frame data and the light AOVs of a render
+pub fn layers(frame_pixels: &[FramePixel], light: &[[[f32; 3]; AOV_COUNT]]) -> Vec<Layer> {
+    let mut layers: Vec<Layer> = LIGHT_AOVS
+        .iter()
+        .enumerate()
+        .map(|(i, &name)| Layer {
+            name,
+            channels: RGB,
+            data: light.iter().flat_map(|pixel| pixel[i]).collect(),
+        })
+        .collect();
+
+    layers.push(Layer {
+        name: "albedo",
+        channels: RGB,
+        data: frame_pixels.iter().flat_map(|p| [p.albedo.x, p.albedo.y, p.albedo.z]).collect(),
+    });
+    layers.push(Layer {
+        name: "normal",
+        channels: XYZ,
+        data: frame_pixels.iter().flat_map(|p| [p.geometry.x, p.geometry.y, p.geometry.z]).collect(),
+    });
+    layers.push(Layer {
+        name: "depth",
+        channels: &["Z"],
+        data: frame_pixels
+            .iter()
+            .map(|p| if p.geometry.w < 0.0 { SKY_DEPTH } else { p.geometry.w })
+            .collect(),
+    });
+    // 0 for the mesh, 1 + index in the primitive data for the spheres and primitives and -1 for the sky
+    layers.push(Layer {
+        name: "object_id",
+        channels: &["id"],
+        data: frame_pixels.iter().map(|p| p.albedo.w).collect(),
+    });
+    layers
+}
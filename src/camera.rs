@@ -0,0 +1,328 @@
+// Camera orientation and the controllers that move it: a first person fly camera and an
+// orbit camera that turns around a pivot
+use cgmath::{Deg, InnerSpace, Quaternion, Rad, Rotation, Rotation3, Vector3};
+use winit::keyboard::KeyCode;
+
+const MOVE_SPEED: f32 = 100.0; // Units per second
+const MIN_MOVE_SPEED: f32 = 1.0;
+const MAX_MOVE_SPEED: f32 = 10000.0;
+const SPEED_STEP: f32 = 1.2; // Speed factor per scroll wheel line
+const ROTATION_SPEED: f32 = 100.0; // Degrees per second for the rotation keys
+const MOUSE_SENSITIVITY: f32 = 0.1; // Degrees per pixel
+const MAX_PITCH: f32 = 89.0; // Degrees, stops the camera from flipping over in world up mode
+const ORBIT_SENSITIVITY: f32 = 0.3; // Degrees per pixel
+const PAN_SENSITIVITY: f32 = 0.0015; // Fraction of the orbit distance per pixel
+const DOLLY_STEP: f32 = 0.9; // Distance factor per scroll wheel line
+const MIN_ORBIT_DISTANCE: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    pub position: Vector3<f32>,
+    pub orientation: Quaternion<f32>, // Rotates camera space (looking along -z) into world space
+}
+
+impl Camera {
+    // Rotation in degrees around x, then y, then z, like the scene files use
+    pub fn new(position: [f32; 3], rotation: [f32; 3]) -> Self {
+        let orientation = Quaternion::from_angle_z(Deg(rotation[2]))
+            * Quaternion::from_angle_y(Deg(rotation[1]))
+            * Quaternion::from_angle_x(Deg(rotation[0]));
+        Camera {
+            position: Vector3::from(position),
+            orientation,
+        }
+    }
+
+    pub fn forward(&self) -> Vector3<f32> {
+        self.orientation.rotate_vector(-Vector3::unit_z())
+    }
+
+    // Angle above the horizon in degrees
+    pub fn pitch(&self) -> f32 {
+        Deg::from(Rad(self.forward().y.clamp(-1.0, 1.0).asin())).0
+    }
+
+    pub fn right(&self) -> Vector3<f32> {
+        self.orientation.rotate_vector(Vector3::unit_x())
+    }
+
+    pub fn up(&self) -> Vector3<f32> {
+        self.orientation.rotate_vector(Vector3::unit_y())
+    }
+
+    // Angle around the world y axis in degrees, 0 looks along -z
+    pub fn yaw(&self) -> f32 {
+        let forward = self.forward();
+        Deg::from(Rad((-forward.x).atan2(-forward.z))).0
+    }
+
+    // Remove any roll while keeping the view direction
+    pub fn level(&mut self) {
+        self.orientation = yaw_pitch_orientation(self.yaw(), self.pitch());
+    }
+
+    pub fn position_data(&self) -> [f32; 3] {
+        self.position.into()
+    }
+
+    // Format: [x, y, z, w]
+    pub fn orientation_data(&self) -> [f32; 4] {
+        let q = self.orientation;
+        [q.v.x, q.v.y, q.v.z, q.s]
+    }
+
+    // Data in the layout of the Camera struct in shader.wgsl, with the pose of the previous
+    // frame to reproject the accumulated samples from
+    pub fn to_gpu(self, previous: Camera) -> CameraData {
+        CameraData {
+            position: self.position_data(),
+            moved: (self != previous) as u32,
+            orientation: self.orientation_data(),
+            previous_position: previous.position_data(),
+            _padding: 0,
+            previous_orientation: previous.orientation_data(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CameraData {
+    position: [f32; 3],
+    moved: u32,
+    orientation: [f32; 4],
+    previous_position: [f32; 3],
+    _padding: u32,
+    previous_orientation: [f32; 4],
+}
+
+fn yaw_pitch_orientation(yaw: f32, pitch: f32) -> Quaternion<f32> {
+    Quaternion::from_angle_y(Deg(yaw)) * Quaternion::from_angle_x(Deg(pitch))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CameraMode {
+    Fly,
+    Orbit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpMode {
+    World, // Yaw around the world y axis and no roll, like walking around
+    Free,  // Yaw, pitch and roll around the camera's own axes, like flying
+}
+
+pub struct FlyController {
+    keys_pressed: [bool; 12], // [W, S, D, A, Space, Shift, I, K, L, J, O, U]
+    mouse_delta: (f32, f32),
+    pub speed: f32,
+    pub up_mode: UpMode,
+    pub cursor_grabbed: bool,
+}
+
+impl FlyController {
+    pub fn new() -> Self {
+        FlyController {
+            keys_pressed: [false; 12],
+            mouse_delta: (0.0, 0.0),
+            speed: MOVE_SPEED,
+            up_mode: UpMode::World,
+            cursor_grabbed: false,
+        }
+    }
+
+    // Returns whether the key controls the camera
+    pub fn process_key(&mut self, code: KeyCode, pressed: bool) -> bool {
+        let index = match code {
+            KeyCode::KeyW => 0,
+            KeyCode::KeyS => 1,
+            KeyCode::KeyD => 2,
+            KeyCode::KeyA => 3,
+            KeyCode::Space => 4,
+            KeyCode::ShiftLeft => 5,
+            KeyCode::KeyI => 6,
+            KeyCode::KeyK => 7,
+            KeyCode::KeyL => 8,
+            KeyCode::KeyJ => 9,
+            KeyCode::KeyO => 10,
+            KeyCode::KeyU => 11,
+            _ => return false,
+        };
+        self.keys_pressed[index] = pressed;
+        true
+    }
+
+    // Mouse movement in pixels, only used while the cursor is grabbed
+    pub fn process_mouse_motion(&mut self, dx: f32, dy: f32) {
+        if self.cursor_grabbed {
+            self.mouse_delta.0 += dx;
+            self.mouse_delta.1 += dy;
+        }
+    }
+
+    // Scrolling up makes the camera faster
+    pub fn process_scroll(&mut self, lines: f32) {
+        self.speed = (self.speed * SPEED_STEP.powf(lines)).clamp(MIN_MOVE_SPEED, MAX_MOVE_SPEED);
+        println!("Camera speed: {:.1}", self.speed);
+    }
+
+    pub fn toggle_up_mode(&mut self, camera: &mut Camera) {
+        self.up_mode = match self.up_mode {
+            UpMode::World => UpMode::Free,
+            UpMode::Free => {
+                camera.level();
+                UpMode::World
+            }
+        };
+        println!("Camera up mode: {:?}", self.up_mode);
+    }
+
+    // Move the camera by the input since the last update, dt is in seconds.
+    // Returns whether the camera changed.
+    pub fn update(&mut self, camera: &mut Camera, dt: f32) -> bool {
+        let axis = |positive: usize, negative: usize| {
+            self.keys_pressed[positive] as i32 as f32 - self.keys_pressed[negative] as i32 as f32
+        };
+
+        // Local movement, -z is forward
+        let movement = Vector3::new(axis(2, 3), axis(4, 5), -axis(0, 1)) * self.speed * dt;
+
+        // Rotations in degrees
+        let mouse_delta = std::mem::take(&mut self.mouse_delta);
+        let pitch = axis(6, 7) * ROTATION_SPEED * dt - mouse_delta.1 * MOUSE_SENSITIVITY;
+        let yaw = axis(8, 9) * ROTATION_SPEED * dt - mouse_delta.0 * MOUSE_SENSITIVITY;
+        let roll = match self.up_mode {
+            UpMode::World => 0.0,
+            UpMode::Free => axis(10, 11) * ROTATION_SPEED * dt,
+        };
+
+        if movement == Vector3::new(0.0, 0.0, 0.0) && pitch == 0.0 && yaw == 0.0 && roll == 0.0 {
+            return false;
+        }
+
+        match self.up_mode {
+            UpMode::World => {
+                // Up and down follow the world, the rest follows the view
+                let horizontal = camera.orientation.rotate_vector(Vector3::new(movement.x, 0.0, movement.z));
+                camera.position += horizontal + Vector3::unit_y() * movement.y;
+
+                let pitch = (camera.pitch() + pitch).clamp(-MAX_PITCH, MAX_PITCH) - camera.pitch();
+                camera.orientation = Quaternion::from_angle_y(Deg(yaw))
+                    * camera.orientation
+                    * Quaternion::from_angle_x(Deg(pitch));
+            }
+            UpMode::Free => {
+                camera.position += camera.orientation.rotate_vector(movement);
+                camera.orientation = camera.orientation
+                    * Quaternion::from_angle_y(Deg(yaw))
+                    * Quaternion::from_angle_x(Deg(pitch))
+                    * Quaternion::from_angle_z(Deg(roll));
+            }
+        }
+        camera.orientation = camera.orientation.normalize();
+
+        true
+    }
+}
+
+// Turntable camera: left drag orbits around the pivot, right drag pans the pivot and
+// scrolling moves towards or away from it. The camera never rolls.
+pub struct OrbitController {
+    pub pivot: Vector3<f32>,
+    distance: f32,
+    yaw: f32,   // Degrees
+    pitch: f32, // Degrees
+    orbiting: bool,
+    panning: bool,
+    mouse_delta: (f32, f32),
+    scroll: f32,
+}
+
+impl OrbitController {
+    pub fn new(pivot: Vector3<f32>) -> Self {
+        OrbitController {
+            pivot,
+            distance: 1.0,
+            yaw: 0.0,
+            pitch: 0.0,
+            orbiting: false,
+            panning: false,
+            mouse_delta: (0.0, 0.0),
+            scroll: 0.0,
+        }
+    }
+
+    // Continue from the current camera position, turning it towards the pivot
+    pub fn attach(&mut self, camera: &mut Camera) {
+        let offset = self.pivot - camera.position;
+        self.distance = offset.magnitude().max(MIN_ORBIT_DISTANCE);
+        let direction = offset / self.distance;
+        self.yaw = Deg::from(Rad((-direction.x).atan2(-direction.z))).0;
+        self.pitch = Deg::from(Rad(direction.y.clamp(-1.0, 1.0).asin())).0.clamp(-MAX_PITCH, MAX_PITCH);
+        self.apply(camera);
+    }
+
+    // Center the box and move back until all of it is in view, keeping the view direction.
+    // fov is the horizontal field of view in degrees.
+    pub fn frame(&mut self, camera: &mut Camera, bounds: ([f32; 3], [f32; 3]), fov: f32, aspect_ratio: f32) {
+        let (min, max) = (Vector3::from(bounds.0), Vector3::from(bounds.1));
+        let radius = ((max - min).magnitude() * 0.5).max(MIN_ORBIT_DISTANCE);
+
+        // The bounding sphere has to fit in the narrower of the two directions
+        let half_width = (fov.to_radians() * 0.5).tan();
+        let half_fov = half_width.min(half_width / aspect_ratio).atan();
+
+        self.pivot = (min + max) * 0.5;
+        self.yaw = camera.yaw();
+        self.pitch = camera.pitch().clamp(-MAX_PITCH, MAX_PITCH);
+        self.distance = radius / half_fov.sin();
+        self.apply(camera);
+    }
+
+    pub fn set_orbiting(&mut self, orbiting: bool) {
+        self.orbiting = orbiting;
+    }
+
+    pub fn set_panning(&mut self, panning: bool) {
+        self.panning = panning;
+    }
+
+    // Mouse movement in pixels, only used while a button is held
+    pub fn process_mouse_motion(&mut self, dx: f32, dy: f32) {
+        if self.orbiting || self.panning {
+            self.mouse_delta.0 += dx;
+            self.mouse_delta.1 += dy;
+        }
+    }
+
+    pub fn process_scroll(&mut self, lines: f32) {
+        self.scroll += lines;
+    }
+
+    // Apply the input since the last update. Returns whether the camera changed.
+    pub fn update(&mut self, camera: &mut Camera) -> bool {
+        let (dx, dy) = std::mem::take(&mut self.mouse_delta);
+        let scroll = std::mem::take(&mut self.scroll);
+        if (dx, dy) == (0.0, 0.0) && scroll == 0.0 {
+            return false;
+        }
+
+        if self.orbiting {
+            self.yaw -= dx * ORBIT_SENSITIVITY;
+            self.pitch = (self.pitch - dy * ORBIT_SENSITIVITY).clamp(-MAX_PITCH, MAX_PITCH);
+        } else if self.panning {
+            // Drag the scene along with the cursor
+            let scale = self.distance * PAN_SENSITIVITY;
+            self.pivot += (camera.up() * dy - camera.right() * dx) * scale;
+        }
+        self.distance = (self.distance * DOLLY_STEP.powf(scroll)).max(MIN_ORBIT_DISTANCE);
+
+        self.apply(camera);
+        true
+    }
+
+    fn apply(&self, camera: &mut Camera) {
+        camera.orientation = yaw_pitch_orientation(self.yaw, self.pitch);
+        camera.position = self.pivot - camera.forward() * self.distance;
+    }
+}
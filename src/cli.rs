@@ -0,0 +1,210 @@
+use crate::display::{Display, ToneMapping};
+use crate::render_settings::{DebugMode, RenderSettings};
+use crate::renderer_backend::adapter::{self, AdapterOptions};
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+Usage: ray-tracing [options]
+
+Without --output the scene is shown in a window.
+
+Options:
+  --scene <file>          Load the scene from a scene file (see scenes/) instead of the teapot
+  --output <file>         Render headlessly and save to a .png, .pfm or .exr file
+  --spp <n>               Samples per pixel for headless renders (default 1024)
+  --noise-threshold <f>   Sample adaptively, stopping once every pixel's relative error is below this
+  --time-limit <s>        Stop headless renders after this many seconds
+  --width <n>             Width of the image, or of the window in logical pixels (default 1200)
+  --height <n>            Height of the image, or of the window in logical pixels (default 600)
+  --render-scale <f>      Trace the window at this fraction of its resolution (default 1)
+  --renderer <name>       gpu or cpu, the CPU reference renderer only works headlessly (default gpu)
+  --exposure <ev>         Exposure in stops (default 0)
+  --tone-mapping <name>   none, reinhard, aces or agx (default aces)
+  --debug-mode <name>     Show shading-normals, geometric-normals, depth, albedo, material-id,
+                          triangle-id, barycentrics, bvh-cost or the error heat map (default off)
+  --denoise               Filter the image with the edge-aware denoiser
+  --aovs                  Also save the AOVs: light paths, albedo, normal, depth and object ID.
+                          .exr gets them as layers, .pfm as extra <name>_<aov>.pfm files
+  --regression            Compare the canonical scenes against the golden images in tests/golden
+  --bless                 With --regression, overwrite the golden images instead
+  --adapter <name>        Use the GPU whose name contains this (env WGPU_ADAPTER_NAME)
+  --backend <names>       Comma separated backends: vulkan, metal, dx12, gl or webgpu (env WGPU_BACKEND)
+  --power-preference <p>  low, high or none, used without --adapter (env WGPU_POWER_PREF, default high)
+  --list-adapters         Print the available GPUs and exit
+  --help                  Print this message";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RendererKind {
+    Gpu,
+    Cpu,
+}
+
+pub struct Options {
+    pub scene: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub renderer: RendererKind,
+    pub spp: u32,
+    pub noise_threshold: Option<f32>,
+    pub time_limit: Option<f32>,
+    pub width: u32,
+    pub height: u32,
+    pub render_scale: f32,
+    pub exposure: Option<f32>,
+    pub tone_mapping: Option<ToneMapping>,
+    pub debug_mode: DebugMode,
+    pub denoise: bool,
+    pub aovs: bool,
+    pub regression: bool,
+    pub bless: bool,
+    pub adapter: AdapterOptions,
+}
+
+impl Options {
+    // Parse the process arguments, printing the usage and exiting on bad input
+    pub fn from_args() -> Self {
+        match Options::parse(std::env::args().skip(1)) {
+            Ok(options) => options,
+            Err(message) => {
+                eprintln!("{}\n\n{}", message, USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    // Override the default display settings with the ones given on the command line
+    pub fn apply_display(&self, display: &mut Display) {
+        if let Some(exposure) = self.exposure {
+            display.exposure = exposure;
+        }
+        if let Some(tone_mapping) = self.tone_mapping {
+            display.tone_mapping = tone_mapping;
+        }
+    }
+
+    // Override the default render settings with the ones given on the command line
+    pub fn apply_settings(&self, settings: &mut RenderSettings) {
+        settings.debug_mode = self.debug_mode;
+        settings.denoise = self.denoise;
+        settings.aovs = self.aovs;
+        if let Some(noise_threshold) = self.noise_threshold {
+            settings.adaptive_sampling = true;
+            settings.noise_threshold = noise_threshold;
+        }
+    }
+
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            scene: None,
+            output: None,
+            renderer: RendererKind::Gpu,
+            spp: 1024,
+            noise_threshold: None,
+            time_limit: None,
+            width: 1200,
+            height: 600,
+            render_scale: 1.0,
+            exposure: None,
+            tone_mapping: None,
+            debug_mode: DebugMode::Off,
+            denoise: false,
+            aovs: false,
+            regression: false,
+            bless: false,
+            adapter: AdapterOptions::from_env(),
+        };
+        let mut list_adapters = false;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--scene" => options.scene = Some(PathBuf::from(value()?)),
+                "--output" => options.output = Some(PathBuf::from(value()?)),
+                "--spp" => options.spp = parse_number(&arg, &value()?)?,
+                "--noise-threshold" => options.noise_threshold = Some(parse_number(&arg, &value()?)?),
+                "--time-limit" => options.time_limit = Some(parse_number(&arg, &value()?)?),
+                "--width" => options.width = parse_number(&arg, &value()?)?,
+                "--height" => options.height = parse_number(&arg, &value()?)?,
+                "--render-scale" => options.render_scale = parse_number(&arg, &value()?)?,
+                "--renderer" => {
+                    options.renderer = match value()?.as_str() {
+                        "gpu" => RendererKind::Gpu,
+                        "cpu" => RendererKind::Cpu,
+                        name => return Err(format!("Unknown renderer '{}'", name)),
+                    }
+                }
+                "--exposure" => options.exposure = Some(parse_number(&arg, &value()?)?),
+                "--tone-mapping" => {
+                    let name = value()?;
+                    let tone_mapping = ToneMapping::from_name(&name)
+                        .ok_or(format!("Unknown tone mapping operator '{}'", name))?;
+                    options.tone_mapping = Some(tone_mapping);
+                }
+                "--debug-mode" => {
+                    let name = value()?;
+                    options.debug_mode =
+                        DebugMode::from_name(&name).ok_or(format!("Unknown debug mode '{}'", name))?;
+                }
+                "--denoise" => options.denoise = true,
+                "--aovs" => options.aovs = true,
+                "--regression" => options.regression = true,
+                "--bless" => options.bless = true,
+                "--adapter" => options.adapter.name = Some(value()?),
+                "--backend" => {
+                    let names = value()?;
+                    options.adapter.backends =
+                        adapter::parse_backends(&names).ok_or(format!("Unknown backend '{}'", names))?;
+                }
+                "--power-preference" => {
+                    let name = value()?;
+                    options.adapter.power_preference = adapter::parse_power_preference(&name)
+                        .ok_or(format!("Unknown power preference '{}'", name))?;
+                }
+                "--list-adapters" => list_adapters = true,
+                "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                _ => return Err(format!("Unknown argument '{}'", arg)),
+            }
+        }
+
+        if list_adapters {
+            adapter::list_adapters(&options.adapter);
+            std::process::exit(0);
+        }
+        if options.bless && !options.regression {
+            return Err("--bless only works with --regression".to_string());
+        }
+        if options.renderer == RendererKind::Cpu && options.output.is_none() && !options.regression {
+            return Err("The CPU renderer needs --output".to_string());
+        }
+        if options.aovs
+            && !options
+                .output
+                .as_ref()
+                .and_then(|output| output.extension())
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("exr") || extension.eq_ignore_ascii_case("pfm"))
+        {
+            return Err("--aovs needs an --output ending in .exr or .pfm".to_string());
+        }
+        if options.width == 0 || options.height == 0 || options.spp == 0 {
+            return Err("--width, --height and --spp must be greater than 0".to_string());
+        }
+        if options.noise_threshold.is_some_and(|threshold| threshold <= 0.0 || threshold.is_nan()) {
+            return Err("--noise-threshold must be greater than 0".to_string());
+        }
+        if options.time_limit.is_some_and(|time_limit| time_limit <= 0.0 || time_limit.is_nan()) {
+            return Err("--time-limit must be greater than 0".to_string());
+        }
+        if !(options.render_scale > 0.0 && options.render_scale <= 4.0) {
+            return Err("--render-scale must be greater than 0 and at most 4".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value '{}' for {}", value, arg))
+}
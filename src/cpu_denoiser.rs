@@ -0,0 +1,84 @@
+// CPU version of denoise.wgsl, for the CPU renderer. It follows the shader step by step so
+// denoised renders of both match.
+use crate::cpu_renderer::FramePixel;
+use crate::renderer_backend::denoiser::DENOISE_PASSES;
+use cgmath::{InnerSpace, Vector3, Vector4};
+
+// --- Edge stopping --- Must match denoise.wgsl
+const SIGMA_COLOR: f32 = 0.5;
+const NORMAL_POWER: f32 = 64.0;
+const SIGMA_DEPTH: f32 = 0.05;
+const SIGMA_ALBEDO: f32 = 0.1;
+const KERNEL: [f32; 3] = [0.375, 0.25, 0.0625]; // B3 spline, from the center outwards
+
+// Run every pass over the frame data and return the filtered radiance, row by row from the top
+pub fn denoise(width: u32, height: u32, frame_data: &[FramePixel]) -> Vec<[f32; 3]> {
+    let mut colors: Vec<Vector4<f32>> = frame_data.iter().map(|pixel| pixel.color).collect();
+    for pass in 0..DENOISE_PASSES {
+        colors = (0..height as i32)
+            .flat_map(|y| (0..width as i32).map(move |x| (x, y)))
+            .map(|(x, y)| filter_pixel(x, y, 1 << pass, width, height, frame_data, &colors))
+            .collect();
+    }
+    colors.iter().map(|color| [color.x, color.y, color.z]).collect()
+}
+
+// fs_main
+fn filter_pixel(
+    x: i32,
+    y: i32,
+    step_size: i32,
+    width: u32,
+    height: u32,
+    frame_data: &[FramePixel],
+    colors: &[Vector4<f32>],
+) -> Vector4<f32> {
+    let index = (y as u32 * width + x as u32) as usize;
+    let center = frame_data[index];
+    let center_color = colors[index];
+
+    // The noise left in the average shrinks with the square root of the frames, and every
+    // pass has less of it to remove
+    let color_tolerance = SIGMA_COLOR / (center_color.w.max(1.0).sqrt() * step_size as f32);
+
+    let mut sum = Vector3::new(0.0, 0.0, 0.0);
+    let mut total_weight = 0.0;
+    for offset_y in -2..=2 {
+        for offset_x in -2..=2 {
+            let (neighbor_x, neighbor_y) = (x + offset_x * step_size, y + offset_y * step_size);
+            if neighbor_x < 0 || neighbor_y < 0 || neighbor_x >= width as i32 || neighbor_y >= height as i32 {
+                continue;
+            }
+            let neighbor_index = (neighbor_y as u32 * width + neighbor_x as u32) as usize;
+            let neighbor_color = colors[neighbor_index].truncate();
+            let color_difference = center_color.truncate() - neighbor_color;
+            let distance_in_pixels = ((offset_x * offset_x + offset_y * offset_y) as f32).sqrt() * step_size as f32;
+            let weight = KERNEL[offset_x.unsigned_abs() as usize]
+                * KERNEL[offset_y.unsigned_abs() as usize]
+                * feature_weight(center, frame_data[neighbor_index], distance_in_pixels)
+                * (-color_difference.dot(color_difference) / (color_tolerance * color_tolerance)).exp();
+            sum += neighbor_color * weight;
+            total_weight += weight;
+        }
+    }
+
+    (sum / total_weight).extend(center_color.w)
+}
+
+fn feature_weight(center: FramePixel, neighbor: FramePixel, distance_in_pixels: f32) -> f32 {
+    // The sky only blends with the sky
+    let center_is_sky = center.geometry.w < 0.0;
+    if center_is_sky != (neighbor.geometry.w < 0.0) {
+        return 0.0;
+    }
+
+    let mut weight = 1.0;
+    if !center_is_sky {
+        weight *= center.geometry.truncate().dot(neighbor.geometry.truncate()).max(0.0).powf(NORMAL_POWER);
+        let depth_tolerance = SIGMA_DEPTH * center.geometry.w * distance_in_pixels + 0.0001;
+        weight *= (-(center.geometry.w - neighbor.geometry.w).abs() / depth_tolerance).exp();
+    }
+    let albedo_difference = center.albedo.truncate() - neighbor.albedo.truncate();
+    weight *= (-albedo_difference.dot(albedo_difference) / (SIGMA_ALBEDO * SIGMA_ALBEDO)).exp();
+    weight
+}
@@ -0,0 +1,1609 @@
+// Multithreaded CPU version of shader.wgsl. It follows the shader function by function
+// (including its random number generation) so both render the same images, which makes
+// it usable as a reference and for rendering on machines without a GPU.
+use crate::aov::AOV_COUNT;
+use crate::camera::Camera;
+use crate::cpu_denoiser;
+use crate::render_settings::{DebugMode, RenderSettings};
+use crate::primitive::{PrimitiveData, FLOATS_PER_PRIMITIVE, FLOATS_PER_SHAPE};
+use crate::scene::{Material, Scene, FLOATS_PER_VERTEX};
+use crate::procedural;
+use crate::texture::{self, Mapping, Pattern, Texture, TextureSource, TEXTURE_SIZE};
+use cgmath::{ElementWise, InnerSpace, Vector2, Vector3, Vector4};
+
+// --- Rendering Parameters --- Must match shader.wgsl
+#[allow(clippy::approx_constant)]
+const PI: f32 = 3.14159; // The shader's value of pi, the same rounding keeps the rays identical
+const BVH_MAX_DEPTH: usize = 16;
+const FLOAT_EPSILON: f32 = f32::EPSILON / 2.0; // Largest relative rounding error of a float operation
+const PRIMITIVE_ERROR_ROUNDINGS: f32 = 64.0; // Roundings assumed in the hit point of shapes besides spheres
+const BVH_COST_SCALE: f32 = 150.0; // Cost shown as white in the heat map
+const ADAPTIVE_MIN_FRAMES: f32 = 4.0; // Frames traced with the full budget before the variance is trusted
+const ADAPTIVE_MIN_LUMINANCE: f32 = 0.05; // Keeps the relative error of dark pixels finite
+// Indices of the light AOVs
+const AOV_DIRECT_DIFFUSE: usize = 0;
+const AOV_INDIRECT_DIFFUSE: usize = 1;
+const AOV_SPECULAR: usize = 2;
+const AOV_EMISSION: usize = 3;
+const AOV_ENVIRONMENT: usize = 4;
+
+
+#[derive(Clone, Copy)]
+struct Ray {
+    origin: Vector3<f32>,
+    dir: Vector3<f32>,
+}
+
+#[derive(Clone, Copy)]
+struct HitInfo {
+    did_hit: bool,
+    distance: f32,
+    position: Vector3<f32>,
+    normal: Vector3<f32>,           // Shading normal
+    geometric_normal: Vector3<f32>, // Normal of the surface itself
+    material: Material,
+    front_face: bool, // false for the back of a two-sided surface, where a refracted ray leaves the inside
+    uv: Vector2<f32>,
+    uv_density: f32,       // Square root of the texture area per surface area, for the mip level
+    tangent: Vector4<f32>, // Direction of increasing u, w is 1 or -1 for the direction of increasing v
+    material_id: u32,            // 0 for the mesh, 1 + index in the primitive data for the spheres and other primitives
+    triangle_id: Option<usize>,  // None for the primitives
+    barycentrics: Vector3<f32>,
+    position_error: Vector3<f32>, // Bound of the rounding error of position on every axis
+}
+
+// Work done by ray_bvh, for the BVH cost view. The shader counts in private globals.
+#[derive(Default)]
+struct TraversalCost {
+    nodes_visited: u32,
+    items_tested: u32,
+}
+
+impl HitInfo {
+    fn miss() -> Self {
+        HitInfo {
+            did_hit: false,
+            distance: 1000000.0,
+            position: Vector3::new(0.0, 0.0, 0.0),
+            normal: Vector3::new(0.0, 0.0, 0.0),
+            geometric_normal: Vector3::new(0.0, 0.0, 0.0),
+            material: Material::new([0.0, 0.0, 0.0], 0.0),
+            front_face: false,
+            uv: Vector2::new(0.0, 0.0),
+            uv_density: 0.0,
+            tangent: Vector4::new(0.0, 0.0, 0.0, 0.0),
+            material_id: 0,
+            triangle_id: None,
+            barycentrics: Vector3::new(0.0, 0.0, 0.0),
+            position_error: Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+// Accumulated data of a pixel, like FramePixel in shader.wgsl
+#[derive(Clone, Copy)]
+pub struct FramePixel {
+    pub color: Vector4<f32>,    // Average radiance, and in w the number of frames averaged
+    pub geometry: Vector4<f32>, // Normal and distance of the first hit, the distance is -1 for the sky
+    pub albedo: Vector4<f32>,   // Color and material ID of the first hit, black and -1 for the sky
+    pub moments: Vector4<f32>,  // Average squared luminance of the samples and the number of samples, zw are unused
+}
+
+impl FramePixel {
+    fn empty() -> Self {
+        FramePixel {
+            color: Vector4::new(0.0, 0.0, 0.0, 0.0),
+            geometry: Vector4::new(0.0, 0.0, 0.0, 0.0),
+            albedo: Vector4::new(0.0, 0.0, 0.0, 0.0),
+            moments: Vector4::new(0.0, 0.0, 0.0, 0.0),
+        }
+    }
+}
+
+pub struct CpuRenderer {
+    pub width: u32,
+    pub height: u32,
+    pub frame_count: u32,
+    pub camera: Camera,
+    pub settings: RenderSettings,
+    scene: Scene,
+    shapes: Vec<([f32; FLOATS_PER_SHAPE], Material)>, // The spheres and other primitives, like the shader's primitive data
+    bounded_count: usize, // Shapes in the BVH, the planes follow them
+    bvh: Vec<f32>, // The mesh's nodes, then those of the shapes from bvh_root
+    bvh_root: usize,
+    textures: Vec<Option<Texture>>, // Images of Scene::textures, None for procedural textures
+    frame_data: Vec<FramePixel>,
+    aov_data: Vec<[Vector3<f32>; AOV_COUNT]>, // Average light of every AOV, with settings.aovs
+}
+
+impl CpuRenderer {
+    pub fn new(width: u32, height: u32, scene: &Scene) -> Self {
+        let textures = texture::load_textures(&scene.textures).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        });
+        let primitive_data = PrimitiveData::new(scene);
+        CpuRenderer {
+            width,
+            height,
+            frame_count: 0,
+            camera: Camera::new(scene.camera_position, scene.camera_rotation),
+            settings: RenderSettings::new(),
+            scene: scene.clone(),
+            shapes: primitive_data
+                .data
+                .chunks(FLOATS_PER_PRIMITIVE)
+                .zip(primitive_data.materials)
+                .map(|(data, material)| (data[..FLOATS_PER_SHAPE].try_into().unwrap(), material))
+                .collect(),
+            bounded_count: primitive_data.bounded_count as usize,
+            bvh: primitive_data.bvh,
+            bvh_root: primitive_data.bvh_root as usize,
+            textures,
+            frame_data: vec![FramePixel::empty(); (width * height) as usize],
+            aov_data: vec![[Vector3::new(0.0, 0.0, 0.0); AOV_COUNT]; (width * height) as usize],
+        }
+    }
+
+    // Trace one frame on all cores and add it to the accumulated frame data
+    pub fn render(&mut self) {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let rows_per_thread = (self.height as usize).div_ceil(threads);
+        let width = self.width as usize;
+
+        let mut frame_data = std::mem::take(&mut self.frame_data);
+        let mut aov_data = std::mem::take(&mut self.aov_data);
+        let frame = &*self;
+        std::thread::scope(|scope| {
+            let chunk_size = rows_per_thread * width;
+            let chunks = frame_data.chunks_mut(chunk_size).zip(aov_data.chunks_mut(chunk_size));
+            for (chunk_index, (rows, aov_rows)) in chunks.enumerate() {
+                scope.spawn(move || {
+                    for (i, (pixel, aovs)) in rows.iter_mut().zip(aov_rows).enumerate() {
+                        let index = chunk_index * chunk_size + i;
+                        let (x, y) = ((index % width) as u32, (index / width) as u32);
+                        *pixel = frame.render_pixel(x, y, *pixel, aovs);
+                    }
+                });
+            }
+        });
+        self.frame_data = frame_data;
+        self.aov_data = aov_data;
+
+        self.frame_count += 1;
+    }
+
+    // Accumulated linear radiance, row by row from the top
+    pub fn read_frame_data(&self) -> Vec<[f32; 3]> {
+        self.frame_data.iter().map(|pixel| pixel.color.truncate().into()).collect()
+    }
+
+    // Everything accumulated for every pixel, row by row from the top
+    pub fn read_frame_pixels(&self) -> Vec<FramePixel> {
+        self.frame_data.clone()
+    }
+
+    // The accumulated radiance filtered by the denoiser
+    pub fn read_denoised_data(&self) -> Vec<[f32; 3]> {
+        cpu_denoiser::denoise(self.width, self.height, &self.frame_data)
+    }
+
+    // Average light of every AOV, row by row from the top. Only accumulated with settings.aovs.
+    pub fn read_aovs(&self) -> Vec<[[f32; 3]; AOV_COUNT]> {
+        self.aov_data.iter().map(|aovs| aovs.map(|light| light.into())).collect()
+    }
+
+    // Width of the ray cone of a pixel per distance travelled, picks the mip level of the textures
+    fn pixel_spread(&self) -> f32 {
+        (self.settings.fov.to_radians() * 0.5).tan() * 2.0 / self.width as f32
+    }
+
+    // The ray through the center of a pixel, and the index that seeds its random numbers
+    fn primary_ray(&self, x: u32, y: u32) -> (Ray, u32) {
+        let screen_size = (self.width as f32, self.height as f32);
+        let screen_width = (self.settings.fov.to_radians() * 0.5).tan() * 2.0;
+        let screen_height = screen_width / (screen_size.0 / screen_size.1);
+
+        // Fragment positions are at the pixel centers
+        let pos_x = x as f32 + 0.5;
+        let pos_y = y as f32 + 0.5;
+
+        // Map pixel coordinates to screen plane coordinates
+        let u = (2.0 * pos_x / screen_size.0 - 1.0) * screen_width / 2.0;
+        let v = (1.0 - 2.0 * pos_y / screen_size.1) * screen_height / 2.0;
+        let pixel_index = (pos_x + pos_y * screen_size.0) as u32;
+
+        // Create ray
+        let ray_direction = Vector3::new(u, v, -1.0).normalize();
+        let ray = Ray {
+            origin: self.camera.position,
+            dir: rotate_vector(ray_direction, self.camera.orientation_data()),
+        };
+        (ray, pixel_index)
+    }
+
+    // fs_main
+    fn render_pixel(&self, x: u32, y: u32, accumulated: FramePixel, aovs: &mut [Vector3<f32>; AOV_COUNT]) -> FramePixel {
+        let (ray, pixel_index) = self.primary_ray(x, y);
+
+        // The first hit guides the denoiser
+        let first_hit = self.calculate_ray_collision(ray, &mut TraversalCost::default());
+        let first_hit = self.apply_textures(first_hit, ray, first_hit.distance);
+        let (geometry, albedo) = if first_hit.did_hit {
+            (
+                first_hit.normal.extend(first_hit.distance),
+                Vector3::from(first_hit.material.color).extend(first_hit.material_id as f32),
+            )
+        } else {
+            (Vector4::new(0.0, 0.0, 0.0, -1.0), Vector4::new(0.0, 0.0, 0.0, -1.0))
+        };
+
+        // Only used headlessly where the camera stays put, so unlike the shader there's no
+        // reprojection
+        let history = if self.frame_count > 0 {
+            accumulated
+        } else {
+            FramePixel::empty()
+        };
+
+        // Calculate pixel color, the debug views only look at the first hit so one ray is enough
+        let debug_view = !matches!(self.settings.debug_mode, DebugMode::Off | DebugMode::Error);
+        let mut aov_light = [Vector3::new(0.0, 0.0, 0.0); AOV_COUNT];
+        let (pixel_color, luminance_squared, ray_count) = if debug_view {
+            let pixel_color = self.debug_color(ray);
+            (pixel_color, luminance(pixel_color) * luminance(pixel_color), 1)
+        } else {
+            let ray_count = sample_count(&self.settings, history);
+            let mut pixel_color = Vector3::new(0.0, 0.0, 0.0);
+            let mut luminance_squared = 0.0;
+            for i in 0..ray_count {
+                let sample_color = self.trace(ray, pixel_index.wrapping_add(i.wrapping_mul(248135)), &mut aov_light);
+                pixel_color += sample_color;
+                luminance_squared += luminance(sample_color) * luminance(sample_color);
+            }
+            let samples = (ray_count as f32).max(1.0);
+            (pixel_color / samples, luminance_squared / samples, ray_count)
+        };
+
+        // Add the frame to the history, weighted by its number of samples. Converged pixels trace
+        // no rays and keep their history as it is.
+        let mut pixel = FramePixel {
+            geometry,
+            albedo,
+            ..history
+        };
+        if ray_count > 0 {
+            let samples = history.moments.y + ray_count as f32;
+            let weight = ray_count as f32 / samples;
+            pixel.color = (history.color.truncate() * (1.0 - weight) + pixel_color * weight).extend(history.color.w + 1.0);
+            pixel.moments = Vector4::new(
+                history.moments.x * (1.0 - weight) + luminance_squared * weight,
+                samples,
+                0.0,
+                0.0,
+            );
+
+            if self.settings.aovs && !debug_view {
+                for (average, light) in aovs.iter_mut().zip(aov_light) {
+                    *average = lerp(*average, light / ray_count as f32, weight);
+                }
+            }
+        }
+        pixel
+    }
+
+    fn trace(&self, ray_in: Ray, seed: u32, aov_light: &mut [Vector3<f32>; AOV_COUNT]) -> Vector3<f32> {
+        let mut ray = ray_in;
+
+        let mut incoming_light = Vector3::new(0.0, 0.0, 0.0);
+        let mut ray_color = Vector3::new(1.0, 1.0, 1.0);
+        let mut first_smoothness = 0.0;
+        let mut path_length = 0.0; // The ray cone keeps growing after a bounce
+
+        for i in 0..=self.settings.max_bounce_count {
+            let hit_info = self.calculate_ray_collision(ray, &mut TraversalCost::default());
+            if hit_info.did_hit {
+                path_length += hit_info.distance;
+                let hit_info = self.apply_textures(hit_info, ray, path_length);
+                let material = hit_info.material;
+                if i == 0 {
+                    first_smoothness = material.smoothness;
+                }
+                let random_seed = seed
+                    .wrapping_add(i.wrapping_mul(12345))
+                    .wrapping_add(self.frame_count.wrapping_mul(393939123));
+                let diffuse_dir = (hit_info.normal + random_direction(random_seed)).normalize();
+                let specular_dir = reflect(ray.dir, hit_info.normal);
+                let incoming_dir = ray.dir.normalize();
+                ray.dir = lerp(diffuse_dir, specular_dir, material.smoothness);
+
+                // Refractive materials let the light through unless the Fresnel term reflects it.
+                // The ray leaves the inside through a back face, where the indices swap places.
+                let mut transmitted = false;
+                if material.transmission > 0.0 {
+                    let eta = if hit_info.front_face { 1.0 / material.ior } else { material.ior };
+                    let refracted = refract(incoming_dir, hit_info.normal, eta);
+                    let reflectance = fresnel(incoming_dir, refracted, hit_info.normal, eta);
+                    if random_value(hash(random_seed)) < material.transmission * (1.0 - reflectance) {
+                        transmitted = true;
+                        let diffuse_through = (-hit_info.normal + random_direction(random_seed)).normalize();
+                        ray.dir = lerp(diffuse_through, refracted, material.smoothness);
+                    }
+                }
+
+                // Interpolated normals can bend the new direction to the wrong side of the
+                // surface, where it would pass through the mesh. Mirror it back above the surface,
+                // or below it for transmitted light.
+                let side = if transmitted { -1.0 } else { 1.0 };
+                let below = ray.dir.dot(hit_info.geometric_normal) * side;
+                if below < 0.0 {
+                    ray.dir -= hit_info.geometric_normal * (2.0 * below * side);
+                }
+                ray.origin = offset_ray_origin(hit_info.position, hit_info.position_error, hit_info.geometric_normal, ray.dir);
+
+                let emitted_light = Vector3::from(material.emission_color) * material.emission_strength;
+                incoming_light += emitted_light.mul_element_wise(ray_color);
+                self.record_aov(aov_light, i, emitted_light.mul_element_wise(ray_color), first_smoothness, AOV_EMISSION);
+
+                // Metals tint their reflections, other materials only the diffuse light. Transmitted
+                // light takes on the color.
+                let color = Vector3::from(material.color);
+                let specular_color = lerp(Vector3::new(1.0, 1.0, 1.0), color, material.metallic);
+                if transmitted {
+                    ray_color.mul_assign_element_wise(color);
+                } else {
+                    ray_color.mul_assign_element_wise(color + (specular_color - color) * material.smoothness);
+                }
+            } else {
+                if self.settings.use_environment_lighting {
+                    let environment_light = get_environment_light(ray, &self.settings).mul_element_wise(ray_color);
+                    incoming_light += environment_light;
+                    self.record_aov(aov_light, i, environment_light, first_smoothness, AOV_ENVIRONMENT);
+                }
+                break;
+            }
+        }
+
+        incoming_light
+    }
+
+    fn record_aov(
+        &self,
+        aov_light: &mut [Vector3<f32>; AOV_COUNT],
+        bounce: u32,
+        light: Vector3<f32>,
+        first_smoothness: f32,
+        direct_aov: usize,
+    ) {
+        if !self.settings.aovs {
+            return;
+        }
+        if bounce == 0 {
+            aov_light[direct_aov] += light;
+            return;
+        }
+        let diffuse = light * (1.0 - first_smoothness);
+        if bounce == 1 {
+            aov_light[AOV_DIRECT_DIFFUSE] += diffuse;
+        } else {
+            aov_light[AOV_INDIRECT_DIFFUSE] += diffuse;
+        }
+        aov_light[AOV_SPECULAR] += light * first_smoothness;
+    }
+
+    // Information about the first hit instead of the incoming light
+    fn debug_color(&self, ray: Ray) -> Vector3<f32> {
+        let mut cost = TraversalCost::default();
+        let hit_info = self.calculate_ray_collision(ray, &mut cost);
+        let hit_info = self.apply_textures(hit_info, ray, hit_info.distance);
+
+        if self.settings.debug_mode == DebugMode::BvhCost {
+            return heat_map((cost.nodes_visited + cost.items_tested) as f32 / BVH_COST_SCALE);
+        }
+        if !hit_info.did_hit {
+            return Vector3::new(0.0, 0.0, 0.0);
+        }
+
+        let half = Vector3::new(0.5, 0.5, 0.5);
+        match self.settings.debug_mode {
+            DebugMode::ShadingNormals => hit_info.normal * 0.5 + half,
+            DebugMode::GeometricNormals => hit_info.geometric_normal * 0.5 + half,
+            DebugMode::Depth => {
+                let depth = 1.0 - (hit_info.distance / self.settings.depth_range).clamp(0.0, 1.0);
+                Vector3::new(depth, depth, depth)
+            }
+            DebugMode::Albedo => hit_info.material.color.into(),
+            DebugMode::MaterialId => id_color(hit_info.material_id),
+            DebugMode::TriangleId => match hit_info.triangle_id {
+                Some(triangle_id) => id_color(triangle_id as u32),
+                None => Vector3::new(0.0, 0.0, 0.0),
+            },
+            _ => hit_info.barycentrics,
+        }
+    }
+
+    fn calculate_ray_collision(&self, ray: Ray, cost: &mut TraversalCost) -> HitInfo {
+        // Check for intersections with the spheres and the other primitives using their BVH
+        let mut closest_hit = self.ray_bvh(ray, self.bvh_root, true, cost);
+
+        // Planes have no bounds, so they're tested one by one
+        for (i, (shape, material)) in self.shapes.iter().enumerate().skip(self.bounded_count) {
+            let hit_info = ray_primitive(ray, shape, *material);
+            if hit_info.did_hit && hit_info.distance < closest_hit.distance {
+                closest_hit = hit_info;
+                closest_hit.material_id = i as u32 + 1;
+            }
+        }
+
+        // Check for triangle intersections using BVH
+        let hit_info = self.ray_bvh(ray, 0, false, cost);
+        if hit_info.did_hit && hit_info.distance < closest_hit.distance {
+            closest_hit = hit_info;
+        }
+
+        // Two-sided surfaces are also hit from behind, their normals are turned to face the ray
+        closest_hit.front_face = ray.dir.dot(closest_hit.geometric_normal) <= 0.0;
+        if closest_hit.did_hit && !closest_hit.front_face {
+            closest_hit.normal = -closest_hit.normal;
+            closest_hit.geometric_normal = -closest_hit.geometric_normal;
+            closest_hit.tangent.w = -closest_hit.tangent.w;
+        }
+
+        closest_hit
+    }
+
+    fn get_node(&self, index: usize) -> &[f32] {
+        &self.bvh[index * 9..index * 9 + 9]
+    }
+
+    fn get_vertex(&self, index: usize, corner: usize) -> &[f32] {
+        let mesh = &self.scene.mesh;
+        let start = mesh.indices[index * 3 + corner] as usize * FLOATS_PER_VERTEX;
+        &mesh.vertices[start..start + FLOATS_PER_VERTEX]
+    }
+
+    // The vertices of a triangle and their normals
+    fn get_triangle(&self, index: usize) -> ([Vector3<f32>; 3], [Vector3<f32>; 3]) {
+        let vertex = |corner: usize| self.get_vertex(index, corner);
+        let position = |corner: usize| Vector3::new(vertex(corner)[0], vertex(corner)[1], vertex(corner)[2]);
+        let normal = |corner: usize| Vector3::new(vertex(corner)[3], vertex(corner)[4], vertex(corner)[5]);
+        ([position(0), position(1), position(2)], [normal(0), normal(1), normal(2)])
+    }
+
+    fn get_triangle_uvs(&self, index: usize) -> [Vector2<f32>; 3] {
+        [0, 1, 2].map(|corner| Vector2::new(self.get_vertex(index, corner)[6], self.get_vertex(index, corner)[7]))
+    }
+
+    // Closest hit in the BVH starting at root, over the shapes or the triangles of the mesh
+    fn ray_bvh(&self, ray: Ray, root: usize, primitives: bool, cost: &mut TraversalCost) -> HitInfo {
+        let mut node_stack = [0usize; BVH_MAX_DEPTH + 1];
+        node_stack[0] = root;
+        let mut stack_index = 1;
+
+        let mut result = HitInfo::miss();
+
+        while stack_index > 0 {
+            stack_index -= 1;
+            let node = self.get_node(node_stack[stack_index]);
+            cost.nodes_visited += 1;
+
+            if node[8] == 0.0 && primitives {
+                // Leaf node (no children, so test shapes)
+                for i in node[6] as usize..(node[6] + node[7]) as usize {
+                    let (shape, material) = &self.shapes[i];
+                    let primitive_hit_info = ray_primitive(ray, shape, *material);
+                    cost.items_tested += 1;
+                    if primitive_hit_info.did_hit && primitive_hit_info.distance < result.distance {
+                        result = primitive_hit_info;
+                        result.material_id = i as u32 + 1;
+                    }
+                }
+            } else if node[8] == 0.0 {
+                // Leaf node (no children, so test triangles)
+                for i in node[6] as usize..(node[6] + node[7]) as usize {
+                    let (triangle, vertex_normals) = self.get_triangle(i);
+                    let triangle_hit_info = ray_triangle(ray, triangle, vertex_normals, self.scene.mesh.material);
+                    cost.items_tested += 1;
+                    if triangle_hit_info.did_hit && triangle_hit_info.distance < result.distance {
+                        result = triangle_hit_info;
+                        result.triangle_id = Some(i);
+                    }
+                }
+            } else {
+                let child_index_a = node[8] as usize;
+                let child_index_b = node[8] as usize + 1;
+                let dst_a = ray_box(ray, self.get_node(child_index_a));
+                let dst_b = ray_box(ray, self.get_node(child_index_b));
+
+                let (dst_near, dst_far, child_index_near, child_index_far) = if dst_a < dst_b {
+                    (dst_a, dst_b, child_index_a, child_index_b)
+                } else {
+                    (dst_b, dst_a, child_index_b, child_index_a)
+                };
+
+                if dst_far < result.distance {
+                    node_stack[stack_index] = child_index_far;
+                    stack_index += 1;
+                }
+                if dst_near < result.distance {
+                    node_stack[stack_index] = child_index_near;
+                    stack_index += 1;
+                }
+            }
+        }
+
+        if result.did_hit && !primitives {
+            result = self.triangle_surface(result);
+        }
+        result
+    }
+
+    // Texture coordinates, their density and the tangent of the closest triangle hit
+    fn triangle_surface(&self, hit_info: HitInfo) -> HitInfo {
+        let mut result = hit_info;
+        let index = hit_info.triangle_id.unwrap();
+        let (triangle, _) = self.get_triangle(index);
+        let [uv_a, uv_b, uv_c] = self.get_triangle_uvs(index);
+        let barycentrics = hit_info.barycentrics;
+        result.uv = uv_a * barycentrics.x + uv_b * barycentrics.y + uv_c * barycentrics.z;
+
+        let edge_ab = triangle[1] - triangle[0];
+        let edge_ac = triangle[2] - triangle[0];
+        let uv_ab = uv_b - uv_a;
+        let uv_ac = uv_c - uv_a;
+        let uv_determinant = uv_ab.x * uv_ac.y - uv_ac.x * uv_ab.y; // Twice the texture area
+        let area = edge_ab.cross(edge_ac).magnitude(); // Twice the surface area
+        if uv_determinant.abs() < 1e-12 || area == 0.0 {
+            // No texture coordinates, any tangent works
+            result.uv_density = 0.0;
+            result.tangent = perpendicular(hit_info.geometric_normal).extend(1.0);
+            return result;
+        }
+        result.uv_density = (uv_determinant.abs() / area).sqrt();
+        let tangent = (edge_ab * uv_ac.y - edge_ac * uv_ab.y) / uv_determinant;
+        let bitangent = (edge_ac * uv_ab.x - edge_ab * uv_ac.x) / uv_determinant;
+        let handedness = if hit_info.geometric_normal.cross(tangent).dot(bitangent) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+        result.tangent = tangent.extend(handedness);
+        result
+    }
+
+    // The textures of the material at a hit, cone_distance is the distance the ray cone
+    // travelled from the camera to the hit. Every texture multiplies the value it replaces.
+    fn apply_textures(&self, hit_info: HitInfo, ray: Ray, cone_distance: f32) -> HitInfo {
+        let mut result = hit_info;
+        let textures = hit_info.material.textures;
+        if !hit_info.did_hit
+            || (textures.albedo.is_none()
+                && textures.roughness_metallic.is_none()
+                && textures.emission.is_none()
+                && textures.normal.is_none())
+        {
+            return result;
+        }
+
+        // Width of the ray cone where it hits the surface, which grows at grazing angles
+        let cone_width = self.pixel_spread() * cone_distance;
+        let cos_angle = hit_info.normal.dot(ray.dir).abs().max(0.1);
+        let footprint = cone_width / cos_angle;
+        let sample = |index: u32| self.texture_value(index as usize, &hit_info, footprint);
+
+        let material = &mut result.material;
+        if let Some(index) = textures.albedo {
+            material.color = (Vector3::from(material.color).mul_element_wise(sample(index).truncate())).into();
+        }
+        if let Some(index) = textures.roughness_metallic {
+            // Roughness in green and metallic in blue
+            let texel = sample(index);
+            material.smoothness = 1.0 - (1.0 - hit_info.material.smoothness) * texel.y;
+            material.metallic *= texel.z;
+        }
+        if let Some(index) = textures.emission {
+            material.emission_color =
+                (Vector3::from(material.emission_color).mul_element_wise(sample(index).truncate())).into();
+        }
+        if let Some(index) = textures.normal {
+            // Tangent space, in the frame of the shading normal
+            let texel = sample(index).truncate() * 2.0 - Vector3::new(1.0, 1.0, 1.0);
+            let hit_tangent = hit_info.tangent.truncate();
+            let mut tangent = hit_tangent - hit_info.normal * hit_info.normal.dot(hit_tangent);
+            if tangent.dot(tangent) < 1e-12 {
+                tangent = perpendicular(hit_info.normal);
+            }
+            let tangent = tangent.normalize();
+            let bitangent = hit_info.normal.cross(tangent) * hit_info.tangent.w;
+            let normal = tangent * texel.x + bitangent * texel.y + hit_info.normal * texel.z;
+            if normal.dot(normal) > 0.0 {
+                result.normal = normal.normalize();
+            }
+        }
+        result
+    }
+
+    // Value of a texture at a hit, footprint is the width of the pixel on the surface
+    fn texture_value(&self, index: usize, hit_info: &HitInfo, footprint: f32) -> Vector4<f32> {
+        let source = &self.scene.textures[index];
+        let position = hit_info.position * source.scale;
+        let footprint = footprint * source.scale;
+
+        // Solid textures don't need texture coordinates
+        match source.pattern {
+            Pattern::Noise { colors, octaves } => return blend(colors, procedural::noise(position, footprint, octaves)),
+            Pattern::Marble { colors, turbulence } => {
+                return blend(colors, procedural::marble(position, footprint, turbulence))
+            }
+            Pattern::Wood { colors, turbulence } => return blend(colors, procedural::wood(position, footprint, turbulence)),
+            _ => {}
+        }
+
+        match source.mapping {
+            Mapping::Uv => self.planar_texture(
+                index,
+                source,
+                hit_info.uv * source.scale,
+                footprint * hit_info.uv_density,
+            ),
+            Mapping::Triplanar => {
+                // Sharpened weights keep the blend between the projections narrow
+                let squared = hit_info.normal.mul_element_wise(hit_info.normal);
+                let weights = squared.mul_element_wise(squared);
+                let weights = weights / (weights.x + weights.y + weights.z);
+                self.planar_texture(index, source, Vector2::new(position.y, position.z), footprint) * weights.x
+                    + self.planar_texture(index, source, Vector2::new(position.x, position.z), footprint) * weights.y
+                    + self.planar_texture(index, source, Vector2::new(position.x, position.y), footprint) * weights.z
+            }
+        }
+    }
+
+    // Value of an image or 2D pattern, footprint is the width of the pixel in the coordinates
+    fn planar_texture(&self, index: usize, source: &TextureSource, p: Vector2<f32>, footprint: f32) -> Vector4<f32> {
+        match source.pattern {
+            Pattern::Checker { colors } => blend(colors, procedural::checker(p, footprint)),
+            Pattern::Grid { colors, line_width } => blend(colors, procedural::grid(p, footprint, line_width)),
+            _ => {
+                // Mip level from the size of the footprint in texels
+                let lod = (footprint * TEXTURE_SIZE as f32).max(1e-8).log2();
+                let image = self.textures[index].as_ref().unwrap();
+                texture::sample(image, p.into(), lod)
+            }
+        }
+    }
+}
+
+fn ray_box(ray: Ray, bounding_box: &[f32]) -> f32 {
+    let min_bound = Vector3::new(bounding_box[0], bounding_box[1], bounding_box[2]);
+    let max_bound = Vector3::new(bounding_box[3], bounding_box[4], bounding_box[5]);
+
+    let t_min = (min_bound - ray.origin).div_element_wise(ray.dir);
+    let t_max = (max_bound - ray.origin).div_element_wise(ray.dir);
+
+    let t1 = t_min.zip(t_max, f32::min);
+    let t2 = t_min.zip(t_max, f32::max);
+
+    let t_near = t1.x.max(t1.y).max(t1.z);
+    let t_far = t2.x.min(t2.y).min(t2.z);
+
+    let did_hit = t_near <= t_far && t_far >= 0.0;
+    if did_hit {
+        t_near
+    } else {
+        999999999.0
+    }
+}
+
+// Watertight test of Woop, Benthin and Wald, see ray_triangle in shader.wgsl
+fn ray_triangle(
+    ray: Ray,
+    triangle: [Vector3<f32>; 3],
+    vertex_normals: [Vector3<f32>; 3],
+    material: Material,
+) -> HitInfo {
+    let mut hit_info = HitInfo::miss();
+
+    // The largest direction component becomes z, swapping x and y keeps the winding if it's negative
+    let kz = max_dimension(ray.dir.map(f32::abs));
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    if ray.dir[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+    let shear = Vector3::new(-ray.dir[kx], -ray.dir[ky], 1.0) / ray.dir[kz];
+
+    // Vertices relative to the ray origin in the sheared space
+    let [a, b, c] = triangle.map(|vertex| vertex - ray.origin);
+    let (ax, ay) = (a[kx] + shear.x * a[kz], a[ky] + shear.y * a[kz]);
+    let (bx, by) = (b[kx] + shear.x * b[kz], b[ky] + shear.y * b[kz]);
+    let (cx, cy) = (c[kx] + shear.x * c[kz], c[ky] + shear.y * c[kz]);
+
+    // Edge functions, the weight of the vertex opposite to each edge. A front face has them all
+    // positive and a back face all negative, with a zero on the edge itself.
+    let edge_a = cx * by - cy * bx;
+    let edge_b = ax * cy - ay * cx;
+    let edge_c = bx * ay - by * ax;
+    let determinant = edge_a + edge_b + edge_c;
+    let front = edge_a >= 0.0 && edge_b >= 0.0 && edge_c >= 0.0;
+    let back = edge_a <= 0.0 && edge_b <= 0.0 && edge_c <= 0.0;
+    if !(front || (back && material.is_two_sided())) || determinant == 0.0 {
+        return hit_info;
+    }
+
+    // Distance along the ray, scaled by the determinant
+    let scaled_distance = (edge_a * a[kz] + edge_b * b[kz] + edge_c * c[kz]) * shear.z;
+    if scaled_distance * determinant < 0.0 {
+        return hit_info;
+    }
+
+    let inv_det = 1.0 / determinant;
+    let (w, u, v) = (edge_a * inv_det, edge_b * inv_det, edge_c * inv_det);
+    let normal_vector = (triangle[1] - triangle[0]).cross(triangle[2] - triangle[0]);
+
+    hit_info.did_hit = true;
+    hit_info.distance = scaled_distance * inv_det;
+
+    // The barycentric sum of the vertices is closer to the surface than the point along the ray
+    hit_info.position = triangle[0] * w + triangle[1] * u + triangle[2] * v;
+    hit_info.position_error = ((triangle[0] * w).map(f32::abs) + (triangle[1] * u).map(f32::abs)
+        + (triangle[2] * v).map(f32::abs))
+        * gamma(7.0);
+    hit_info.normal = (vertex_normals[0] * w + vertex_normals[1] * u + vertex_normals[2] * v).normalize();
+    hit_info.geometric_normal = normal_vector.normalize();
+    hit_info.material = material;
+    hit_info.barycentrics = Vector3::new(w, u, v);
+    hit_info
+}
+
+// Index of the largest component
+fn max_dimension(v: Vector3<f32>) -> usize {
+    if v.x > v.y {
+        if v.x > v.z { 0 } else { 2 }
+    } else if v.y > v.z {
+        1
+    } else {
+        2
+    }
+}
+
+// Intersection with a sphere or another primitive, see Primitive::to_data()
+fn ray_primitive(ray: Ray, shape: &[f32; FLOATS_PER_SHAPE], material: Material) -> HitInfo {
+    let a = Vector3::new(shape[1], shape[2], shape[3]);
+    let b = Vector3::new(shape[4], shape[5], shape[6]);
+    let c = Vector3::new(shape[7], shape[8], shape[9]);
+    let d = Vector3::new(shape[10], shape[11], shape[12]);
+
+    let two_sided = material.is_two_sided();
+
+    let mut hit_info = match shape[0] as u32 {
+        0 => ray_sphere(ray, a, shape[4], two_sided),
+        1 => ray_plane(ray, a, b, two_sided),
+        2 => ray_oriented_box(ray, a, [b, c, d], two_sided),
+        3 => ray_quad(ray, a, b, c, two_sided),
+        4 => ray_disk(ray, a, b, shape[7], two_sided),
+        5 => ray_cylinder(ray, a, b, shape[7], two_sided),
+        6 => ray_cone(ray, a, b, shape[7], two_sided),
+        _ => ray_torus(ray, a, b, shape[7], shape[8], two_sided),
+    };
+    // Spheres bound their own error. The other shapes find the distance first, so their error is
+    // assumed to be a generous number of roundings of origin + dir * distance.
+    if hit_info.did_hit && shape[0] as u32 != 0 {
+        hit_info.position_error =
+            (ray.origin.map(f32::abs) + (ray.dir * hit_info.distance).map(f32::abs)) * gamma(PRIMITIVE_ERROR_ROUNDINGS);
+    }
+
+    if hit_info.did_hit {
+        hit_info.material = material;
+    }
+    hit_info
+}
+
+// Hit where the ray enters the sphere, or where it leaves if it starts inside a two-sided one
+fn ray_sphere(ray: Ray, sphere_center: Vector3<f32>, sphere_radius: f32, two_sided: bool) -> HitInfo {
+    let mut hit_info = HitInfo::miss();
+
+    let offset_ray_origin = ray.origin - sphere_center;
+    let a = ray.dir.dot(ray.dir);
+    let b = 2.0 * offset_ray_origin.dot(ray.dir);
+    let c = offset_ray_origin.dot(offset_ray_origin) - sphere_radius * sphere_radius;
+    let discriminant = b * b - 4.0 * a * c;
+
+    // No solution when d < 0 (ray misses sphere)
+    if discriminant >= 0.0 {
+        // Distance to nearest interesction point
+        let mut distance = (-b - discriminant.sqrt()) / (2.0 * a);
+        if distance < 0.0 && two_sided {
+            distance = (-b + discriminant.sqrt()) / (2.0 * a);
+        }
+
+        // Ignore intersections that occur behind the ray
+        if distance >= 0.0 {
+            hit_info.did_hit = true;
+            hit_info.distance = distance;
+
+            // Projecting the point back onto the sphere leaves only a few roundings of error
+            let mut local = ray.origin + ray.dir * distance - sphere_center;
+            local *= sphere_radius / local.magnitude();
+            hit_info.position = sphere_center + local;
+            hit_info.position_error = local.map(f32::abs) * gamma(5.0) + hit_info.position.map(f32::abs) * gamma(1.0);
+            hit_info.normal = local.normalize();
+            hit_info.geometric_normal = hit_info.normal;
+
+            // Longitude and latitude, v goes up. u increases around the y axis, towards the tangent.
+            let n = hit_info.normal;
+            hit_info.uv = Vector2::new(
+                0.5 + n.x.atan2(n.z) / (2.0 * PI),
+                0.5 + n.y.clamp(-1.0, 1.0).asin() / PI,
+            );
+            hit_info.uv_density = 1.0 / (PI * sphere_radius * 2.0f32.sqrt());
+            let mut tangent = Vector3::new(n.z, 0.0, -n.x);
+            if tangent.dot(tangent) < 1e-12 {
+                tangent = perpendicular(n); // At the poles
+            }
+            hit_info.tangent = tangent.extend(1.0);
+        }
+    }
+
+    hit_info
+}
+
+// Infinite plane through the point, only hit from the side the unit normal points to unless it's
+// two-sided
+fn ray_plane(ray: Ray, point: Vector3<f32>, normal: Vector3<f32>, two_sided: bool) -> HitInfo {
+    let mut hit_info = HitInfo::miss();
+
+    let facing = ray.dir.dot(normal);
+    let distance = (point - ray.origin).dot(normal) / facing;
+    if (facing < 0.0 || (two_sided && facing > 0.0)) && distance >= 0.0 {
+        hit_info.did_hit = true;
+        hit_info.distance = distance;
+        hit_info.position = ray.origin + ray.dir * distance;
+        hit_info.normal = normal;
+        hit_info.geometric_normal = normal;
+
+        let tangent = perpendicular(normal);
+        let offset = hit_info.position - point;
+        hit_info.uv = Vector2::new(offset.dot(tangent), offset.dot(normal.cross(tangent)));
+        hit_info.uv_density = 1.0;
+        hit_info.tangent = tangent.extend(1.0);
+    }
+
+    hit_info
+}
+
+// Parallelogram spanned by the edges from the corner, only hit from the front unless it's two-sided
+fn ray_quad(ray: Ray, corner: Vector3<f32>, edge_u: Vector3<f32>, edge_v: Vector3<f32>, two_sided: bool) -> HitInfo {
+    let normal = edge_u.cross(edge_v);
+    let mut hit_info = ray_plane(ray, corner, normal.normalize(), two_sided);
+
+    // Coordinates of the hit along the edges
+    let offset = hit_info.position - corner;
+    let scaled_normal = normal / normal.dot(normal);
+    let uv = Vector2::new(scaled_normal.dot(offset.cross(edge_v)), scaled_normal.dot(edge_u.cross(offset)));
+
+    if hit_info.did_hit && (0.0..=1.0).contains(&uv.x) && (0.0..=1.0).contains(&uv.y) {
+        hit_info.uv = uv;
+        hit_info.uv_density = 1.0 / normal.magnitude().sqrt();
+        hit_info.tangent = edge_u.normalize().extend(1.0);
+    } else {
+        hit_info.did_hit = false;
+    }
+    hit_info
+}
+
+// Disk facing the unit normal, only hit from the front unless it's two-sided
+fn ray_disk(ray: Ray, center: Vector3<f32>, normal: Vector3<f32>, radius: f32, two_sided: bool) -> HitInfo {
+    let mut hit_info = ray_plane(ray, center, normal, two_sided);
+
+    let offset = hit_info.position - center;
+    if hit_info.did_hit && offset.dot(offset) <= radius * radius {
+        hit_info.uv = hit_info.uv / (2.0 * radius) + Vector2::new(0.5, 0.5);
+        hit_info.uv_density = 1.0 / (2.0 * radius);
+    } else {
+        hit_info.did_hit = false;
+    }
+    hit_info
+}
+
+// Box around the center with the given half size axes, hit where the ray enters it, or where it
+// leaves if it starts inside a two-sided one
+fn ray_oriented_box(ray: Ray, center: Vector3<f32>, axes: [Vector3<f32>; 3], two_sided: bool) -> HitInfo {
+    let mut hit_info = HitInfo::miss();
+
+    // The box goes from -1 to 1 in the coordinates of the axes
+    let to_box = axes.map(|axis| axis / axis.dot(axis));
+    let offset = ray.origin - center;
+    let origin = Vector3::new(to_box[0].dot(offset), to_box[1].dot(offset), to_box[2].dot(offset));
+    let dir = Vector3::new(to_box[0].dot(ray.dir), to_box[1].dot(ray.dir), to_box[2].dot(ray.dir));
+
+    let t_min = (Vector3::new(-1.0, -1.0, -1.0) - origin).div_element_wise(dir);
+    let t_max = (Vector3::new(1.0, 1.0, 1.0) - origin).div_element_wise(dir);
+    let t1 = t_min.zip(t_max, f32::min);
+    let t2 = t_min.zip(t_max, f32::max);
+    let t_near = t1.x.max(t1.y).max(t1.z);
+    let t_far = t2.x.min(t2.y).min(t2.z);
+
+    let leaving = t_near < 0.0 && two_sided;
+    let distance = if leaving { t_far } else { t_near };
+    if t_near <= t_far && distance >= 0.0 {
+        // The face the ray enters or leaves through, and the two axes along it
+        let t_faces = if leaving { t2 } else { t1 };
+        let face = if distance == t_faces.x {
+            0
+        } else if distance == t_faces.y {
+            1
+        } else {
+            2
+        };
+        let u_face = (face + 1) % 3;
+        let v_face = (face + 2) % 3;
+        let normal = axes[face].normalize() * dir[face].signum() * if leaving { 1.0 } else { -1.0 };
+        let local = origin + dir * distance;
+
+        hit_info.did_hit = true;
+        hit_info.distance = distance;
+        hit_info.position = ray.origin + ray.dir * distance;
+        hit_info.normal = normal;
+        hit_info.geometric_normal = normal;
+        hit_info.uv = Vector2::new(0.5 + 0.5 * local[u_face], 0.5 + 0.5 * local[v_face]);
+        hit_info.uv_density = 1.0 / (2.0 * (axes[u_face].magnitude() * axes[v_face].magnitude()).sqrt());
+        let handedness = if normal.cross(axes[u_face]).dot(axes[v_face]) < 0.0 { -1.0 } else { 1.0 };
+        hit_info.tangent = axes[u_face].normalize().extend(handedness);
+    }
+
+    hit_info
+}
+
+// Cylinder from the base to base + axis, closed by disks at both ends and hit where the ray
+// enters it, or where it leaves if it starts inside a two-sided one
+fn ray_cylinder(ray: Ray, base: Vector3<f32>, axis: Vector3<f32>, radius: f32, two_sided: bool) -> HitInfo {
+    let height = axis.magnitude();
+    let w = axis / height;
+    let mut hit_info = ray_disk(ray, base, -w, radius, two_sided);
+    let top_hit_info = ray_disk(ray, base + axis, w, radius, two_sided);
+    if top_hit_info.did_hit && (!hit_info.did_hit || top_hit_info.distance < hit_info.distance) {
+        hit_info = top_hit_info;
+    }
+
+    // The side, with the parts of the ray perpendicular to the axis
+    let offset = ray.origin - base;
+    let dir_across = ray.dir - w * ray.dir.dot(w);
+    let offset_across = offset - w * offset.dot(w);
+    let a = dir_across.dot(dir_across);
+    let b = 2.0 * offset_across.dot(dir_across);
+    let c = offset_across.dot(offset_across) - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+
+    if a > 0.0 && discriminant >= 0.0 {
+        // The nearer solution is where the ray enters
+        let mut distance = (-b - discriminant.sqrt()) / (2.0 * a);
+        if distance < 0.0 && two_sided {
+            distance = (-b + discriminant.sqrt()) / (2.0 * a);
+        }
+        let along = (offset + ray.dir * distance).dot(w);
+        if distance >= 0.0
+            && (0.0..=height).contains(&along)
+            && (!hit_info.did_hit || distance < hit_info.distance)
+        {
+            let normal = (offset_across + dir_across * distance).normalize();
+            hit_info.did_hit = true;
+            hit_info.distance = distance;
+            hit_info.position = ray.origin + ray.dir * distance;
+            hit_info.normal = normal;
+            hit_info.geometric_normal = normal;
+            hit_info.uv = Vector2::new(angle_around(normal, w), along / height);
+            hit_info.uv_density = 1.0 / (2.0 * PI * radius * height).sqrt();
+            hit_info.tangent = w.cross(normal).extend(1.0);
+        }
+    }
+
+    hit_info
+}
+
+// Cone with a base disk of the radius and its apex at base + axis, closed at the base and hit
+// where the ray enters it, or where it leaves if it starts inside a two-sided one
+fn ray_cone(ray: Ray, base: Vector3<f32>, axis: Vector3<f32>, radius: f32, two_sided: bool) -> HitInfo {
+    let height = axis.magnitude();
+    let w = axis / height;
+    let mut hit_info = ray_disk(ray, base, -w, radius, two_sided);
+
+    // The side, where the distance to the axis is the slope times the distance from the apex
+    // along the axis
+    let slope = radius / height;
+    let k = 1.0 + slope * slope;
+    let apex = base + axis;
+    let offset = ray.origin - apex;
+    let dir_along = -ray.dir.dot(w);
+    let offset_along = -offset.dot(w);
+    let a = ray.dir.dot(ray.dir) - k * dir_along * dir_along;
+    let b = 2.0 * (offset.dot(ray.dir) - k * offset_along * dir_along);
+    let c = offset.dot(offset) - k * offset_along * offset_along;
+    let discriminant = b * b - 4.0 * a * c;
+
+    if discriminant >= 0.0 {
+        // a < 0 swaps the solutions, and either can be on the mirrored cone beyond the apex or
+        // where the ray leaves, so both are checked
+        let root = discriminant.sqrt();
+        for root in [-root, root] {
+            let distance = (-b + root) / (2.0 * a);
+            let position = ray.origin + ray.dir * distance;
+            let along = -(position - apex).dot(w);
+            let radial = (position - apex + w * along).normalize();
+            let normal = (radial + w * slope).normalize();
+            if distance >= 0.0
+                && (0.0..=height).contains(&along)
+                && (normal.dot(ray.dir) < 0.0 || two_sided)
+                && (!hit_info.did_hit || distance < hit_info.distance)
+            {
+                hit_info.did_hit = true;
+                hit_info.distance = distance;
+                hit_info.position = position;
+                hit_info.normal = normal;
+                hit_info.geometric_normal = normal;
+                hit_info.uv = Vector2::new(angle_around(radial, w), 1.0 - along / height);
+                hit_info.uv_density = 1.0 / (PI * radius * (radius * radius + height * height).sqrt()).sqrt();
+                hit_info.tangent = w.cross(radial).extend(1.0);
+            }
+        }
+    }
+
+    hit_info
+}
+
+// Torus around the unit axis through the center, hit where the ray enters it, or where it leaves
+// if it starts inside a two-sided one. The quartic is solved in coordinates where the major
+// radius is 1 and the ray starts on the bounding sphere.
+fn ray_torus(
+    ray: Ray,
+    center: Vector3<f32>,
+    axis: Vector3<f32>,
+    major_radius: f32,
+    minor_radius: f32,
+    two_sided: bool,
+) -> HitInfo {
+    let mut hit_info = HitInfo::miss();
+
+    // Coordinates with the axis as z
+    let x_axis = perpendicular(axis);
+    let y_axis = axis.cross(x_axis);
+    let dir_length = ray.dir.magnitude();
+    let offset = (ray.origin - center) / major_radius;
+    let mut origin = Vector3::new(offset.dot(x_axis), offset.dot(y_axis), offset.dot(axis));
+    let dir = Vector3::new(ray.dir.dot(x_axis), ray.dir.dot(y_axis), ray.dir.dot(axis)) / dir_length;
+    let tube = minor_radius / major_radius;
+
+    // Skip to a tube radius outside the bounding sphere. On the bounding sphere itself, rays in
+    // the plane of the ring would start on the torus, where the quartic has a root at 0.
+    let n = origin.dot(dir);
+    let bounding = n * n - origin.dot(origin) + (1.0 + tube) * (1.0 + tube);
+    if bounding < 0.0 || -n + bounding.sqrt() < 0.0 {
+        return hit_info;
+    }
+    let margin = n * n - origin.dot(origin) + (1.0 + 2.0 * tube) * (1.0 + 2.0 * tube);
+    let start = (-n - margin.sqrt()).max(0.0);
+    origin += dir * start;
+
+    // The nearest solution where the ray enters, the others are where it leaves or behind it. A
+    // two-sided torus is also hit where the ray leaves.
+    let roots = torus_roots(origin, dir, tube);
+    let mut closest: f32 = -1.0;
+    for root in roots {
+        // Newton steps on the implicit surface refine the solution
+        let mut t = root;
+        for _ in 0..2 {
+            let p = origin + dir * t;
+            let value = torus_value(p, tube);
+            let slope = 4.0 * torus_gradient(p, tube).dot(dir);
+            if slope.abs() > 1e-6 {
+                t -= value / slope;
+            }
+        }
+        let entering = torus_gradient(origin + dir * t, tube).dot(dir) < 0.0;
+        if root >= 0.0 && t >= 0.0 && (entering || two_sided) && (closest < 0.0 || t < closest) {
+            closest = t;
+        }
+    }
+    if closest < 0.0 {
+        return hit_info;
+    }
+
+    let p = origin + dir * closest;
+    let local_normal = torus_gradient(p, tube).normalize();
+    let ring = Vector2::new(p.x, p.y).magnitude();
+    hit_info.did_hit = true;
+    hit_info.distance = (start + closest) * major_radius / dir_length;
+    hit_info.position = ray.origin + ray.dir * hit_info.distance;
+    hit_info.normal = (x_axis * local_normal.x + y_axis * local_normal.y + axis * local_normal.z).normalize();
+    hit_info.geometric_normal = hit_info.normal;
+    hit_info.uv = Vector2::new(
+        0.5 + p.y.atan2(p.x) / (2.0 * PI),
+        0.5 + p.z.atan2(ring - 1.0) / (2.0 * PI),
+    );
+    hit_info.uv_density = 1.0 / (2.0 * PI * (major_radius * minor_radius).sqrt());
+    hit_info.tangent = (y_axis * p.x - x_axis * p.y).normalize().extend(1.0);
+    hit_info
+}
+
+// Implicit surface of the torus with major radius 1 around z, 0 on the surface
+fn torus_value(p: Vector3<f32>, tube: f32) -> f32 {
+    let k = p.dot(p) + 1.0 - tube * tube;
+    k * k - 4.0 * (p.x * p.x + p.y * p.y)
+}
+
+// A quarter of the gradient of torus_value, it points out of the torus
+fn torus_gradient(p: Vector3<f32>, tube: f32) -> Vector3<f32> {
+    let k = p.dot(p) - tube * tube;
+    Vector3::new(p.x * (k - 1.0), p.y * (k - 1.0), p.z * (k + 1.0))
+}
+
+// Distances along the unit direction to the torus with major radius 1 around z, -1 where there
+// are fewer than four. The quartic is solved like in Inigo Quilez's torus intersection.
+fn torus_roots(origin: Vector3<f32>, dir: Vector3<f32>, tube: f32) -> [f32; 4] {
+    let mut roots = [-1.0; 4];
+    let m = origin.dot(origin);
+    let n = origin.dot(dir);
+
+    // t^4 + 4 k3 t^3 + 6 k2 t^2 + 4 k1 t + k0 = 0, solved for 1 / t when c1 is close to 0
+    let k = (m - tube * tube - 1.0) / 2.0;
+    let mut k3 = n;
+    let mut k2 = n * n + dir.z * dir.z + k;
+    let mut k1 = k * n + origin.z * dir.z;
+    let mut k0 = k * k + origin.z * origin.z - tube * tube;
+    let inverted = (k3 * (k3 * k3 - k2) + k1).abs() < 0.01;
+    if inverted {
+        std::mem::swap(&mut k1, &mut k3);
+        k0 = 1.0 / k0;
+        k1 *= k0;
+        k2 *= k0;
+        k3 *= k0;
+    }
+
+    // Resolvent cubic
+    let c2 = (2.0 * k2 - 3.0 * k3 * k3) / 3.0;
+    let c1 = (k3 * (k3 * k3 - k2) + k1) * 2.0;
+    let c0 = (k3 * (k3 * (-3.0 * k3 * k3 + 4.0 * k2) - 8.0 * k1) + 4.0 * k0) / 3.0;
+    let q = c2 * c2 + c0;
+    let r = 3.0 * c0 * c2 - c2 * c2 * c2 - c1 * c1;
+    let h = r * r - q * q * q;
+    let z = if h < 0.0 {
+        let sq = q.sqrt();
+        2.0 * sq * ((r / (sq * q)).clamp(-1.0, 1.0).acos() / 3.0).cos()
+    } else {
+        let sq = (h.sqrt() + r.abs()).powf(1.0 / 3.0);
+        sign(r) * (sq + q / sq).abs()
+    };
+    let z = c2 - z;
+
+    // Two quadratics
+    let mut d1 = z - 3.0 * c2;
+    let mut d2 = z * z - 3.0 * c0;
+    if d1.abs() < 1.0e-4 {
+        if d2 < 0.0 {
+            return roots;
+        }
+        d2 = d2.sqrt();
+    } else {
+        if d1 < 0.0 {
+            return roots;
+        }
+        d1 = (d1 / 2.0).sqrt();
+        d2 = c1 / d1;
+    }
+    let h1 = d1 * d1 - z + d2;
+    if h1 > 0.0 {
+        roots[0] = -d1 - h1.sqrt() - k3;
+        roots[1] = -d1 + h1.sqrt() - k3;
+    }
+    let h2 = d1 * d1 - z - d2;
+    if h2 > 0.0 {
+        roots[2] = d1 - h2.sqrt() - k3;
+        roots[3] = d1 + h2.sqrt() - k3;
+    }
+    if inverted {
+        roots = roots.map(|root| 2.0 / root);
+    }
+    roots
+}
+
+// Angle of a direction perpendicular to the unit axis around it, from 0 to 1
+fn angle_around(direction: Vector3<f32>, axis: Vector3<f32>) -> f32 {
+    let x_axis = perpendicular(axis);
+    let y_axis = axis.cross(x_axis);
+    0.5 + direction.dot(y_axis).atan2(direction.dot(x_axis)) / (2.0 * PI)
+}
+
+// Like sign() in WGSL, 0 for 0
+fn sign(x: f32) -> f32 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+// Color of a pattern, from the first color at 0 to the second one at 1
+fn blend(colors: [[f32; 3]; 2], t: f32) -> Vector4<f32> {
+    let [a, b] = colors.map(Vector3::from);
+    (a + (b - a) * t).extend(1.0)
+}
+
+// Bound of the relative error after n roundings
+fn gamma(n: f32) -> f32 {
+    n * FLOAT_EPSILON / (1.0 - n * FLOAT_EPSILON)
+}
+
+// Start of a ray leaving the surface, see offset_ray_origin in shader.wgsl
+fn offset_ray_origin(
+    position: Vector3<f32>,
+    position_error: Vector3<f32>,
+    normal: Vector3<f32>,
+    dir: Vector3<f32>,
+) -> Vector3<f32> {
+    let mut offset = normal * normal.map(f32::abs).dot(position_error);
+    if dir.dot(normal) < 0.0 {
+        offset = -offset;
+    }
+    let mut origin = position + offset;
+    for i in 0..3 {
+        if offset[i] > 0.0 {
+            origin[i] = next_float_up(origin[i]);
+        } else if offset[i] < 0.0 {
+            origin[i] = -next_float_up(-origin[i]);
+        }
+    }
+    origin
+}
+
+// Smallest float above x, like the shader's bit manipulation
+fn next_float_up(x: f32) -> f32 {
+    if x == 0.0 {
+        return f32::from_bits(1);
+    }
+    let bits = x.to_bits();
+    f32::from_bits(if x > 0.0 { bits + 1 } else { bits - 1 })
+}
+
+// Some unit vector perpendicular to a unit vector
+fn perpendicular(v: Vector3<f32>) -> Vector3<f32> {
+    let other = if v.x.abs() > 0.9 {
+        Vector3::new(0.0, 1.0, 0.0)
+    } else {
+        Vector3::new(1.0, 0.0, 0.0)
+    };
+    v.cross(other).normalize()
+}
+
+// Rotate by the quaternion [x, y, z, w]
+fn rotate_vector(v: Vector3<f32>, q: [f32; 4]) -> Vector3<f32> {
+    let axis = Vector3::new(q[0], q[1], q[2]);
+    let t = axis.cross(v) * 2.0;
+    v + t * q[3] + axis.cross(t)
+}
+
+// Function to generate a random number between 0 and 1
+fn random_value(seed: u32) -> f32 {
+    let mut state = seed;
+    state ^= state << 13;
+    state ^= state >> 17;
+    state ^= state << 5;
+    state as f32 / 4294967296.0 // Normalize to [0, 1)
+}
+
+// Random value in normal distribution (mean = 0, std_dev = 1)
+fn random_normal(seed: u32) -> f32 {
+    let u1 = random_value(seed);
+    let u2 = random_value(seed.wrapping_mul(7462));
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+// Random direction vector
+fn random_direction(seed: u32) -> Vector3<f32> {
+    let x = random_normal(seed);
+    let y = random_normal(seed.wrapping_mul(379));
+    let z = random_normal(seed.wrapping_mul(123));
+    Vector3::new(x, y, z).normalize()
+}
+
+// Rays to trace for a pixel this frame. With adaptive sampling the budget goes to the pixels
+// with the highest error, up to twice the rays per pixel, and converged pixels get none.
+pub fn sample_count(settings: &RenderSettings, history: FramePixel) -> u32 {
+    if !settings.adaptive_sampling || history.color.w < ADAPTIVE_MIN_FRAMES {
+        return settings.rays_per_pixel;
+    }
+    let error = relative_error(history);
+    if error <= settings.noise_threshold {
+        return 0;
+    }
+    let rays_per_pixel = settings.rays_per_pixel as f32;
+    (rays_per_pixel * error / (2.0 * settings.noise_threshold))
+        .ceil()
+        .clamp(1.0, 2.0 * rays_per_pixel) as u32
+}
+
+// Standard error of the average luminance relative to the luminance, from the variance of the samples
+pub fn relative_error(pixel: FramePixel) -> f32 {
+    if pixel.moments.y == 0.0 {
+        return 0.0;
+    }
+    let mean = luminance(pixel.color.truncate());
+    let variance = (pixel.moments.x - mean * mean).max(0.0);
+    (variance / pixel.moments.y).sqrt() / (mean + ADAPTIVE_MIN_LUMINANCE)
+}
+
+// Heat map of the relative error, red at the noise threshold and white at three times it
+pub fn error_color(pixel: FramePixel, settings: &RenderSettings) -> [f32; 3] {
+    heat_map(relative_error(pixel) / (3.0 * settings.noise_threshold)).into()
+}
+
+fn luminance(color: Vector3<f32>) -> f32 {
+    color.dot(Vector3::new(0.2126, 0.7152, 0.0722))
+}
+
+// Black-red-yellow-white, like the heat maps of the regression tests
+fn heat_map(value: f32) -> Vector3<f32> {
+    let t = value.clamp(0.0, 1.0) * 3.0;
+    Vector3::new(t, t - 1.0, t - 2.0).map(|c| c.clamp(0.0, 1.0))
+}
+
+// A distinct color for every ID
+fn id_color(id: u32) -> Vector3<f32> {
+    let h = hash(id);
+    Vector3::new((h & 255) as f32, ((h >> 8) & 255) as f32, ((h >> 16) & 255) as f32) / 255.0
+}
+
+// PCG hash, unlike random_value() it spreads consecutive numbers well
+pub fn hash(value: u32) -> u32 {
+    let state = value.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+fn reflect(direction: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+    direction - normal * (2.0 * normal.dot(direction))
+}
+
+// Like the shader's refract(), zero under total internal reflection
+fn refract(incoming: Vector3<f32>, normal: Vector3<f32>, eta: f32) -> Vector3<f32> {
+    let cosine = normal.dot(incoming);
+    let k = 1.0 - eta * eta * (1.0 - cosine * cosine);
+    if k < 0.0 {
+        return Vector3::new(0.0, 0.0, 0.0);
+    }
+    incoming * eta - normal * (eta * cosine + k.sqrt())
+}
+
+// Fraction of the light a refractive surface reflects, see fresnel in shader.wgsl
+fn fresnel(incoming: Vector3<f32>, refracted: Vector3<f32>, normal: Vector3<f32>, eta: f32) -> f32 {
+    if refracted == Vector3::new(0.0, 0.0, 0.0) {
+        return 1.0;
+    }
+    let cosine = if eta > 1.0 { -refracted.dot(normal) } else { -incoming.dot(normal) };
+    let r = (1.0 - eta) / (1.0 + eta);
+    r * r + (1.0 - r * r) * (1.0 - cosine).max(0.0).powi(5)
+}
+
+fn lerp(a: Vector3<f32>, b: Vector3<f32>, t: f32) -> Vector3<f32> {
+    a * (1.0 - t) + b * t
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Background environment lighting
+fn get_environment_light(ray: Ray, settings: &RenderSettings) -> Vector3<f32> {
+    let sky_gradient_t = smoothstep(0.0, 0.4, ray.dir.y).powf(0.35);
+    let sky_gradient = lerp(
+        Vector3::from(settings.sky_color_horizon),
+        Vector3::from(settings.sky_color_zenith),
+        sky_gradient_t,
+    );
+    let sun_light_direction = Vector3::from(settings.sun_light_direction);
+    let sun = ray.dir.dot(-sun_light_direction.normalize()).max(0.0).powf(settings.sun_focus) * settings.sun_intensity;
+
+    // Combine ground, sky, and sun
+    let ground_to_sky_t = smoothstep(-0.01, 0.0, ray.dir.y);
+    let sun_mask = if ground_to_sky_t >= 1.0 { 1.0 } else { 0.0 };
+    lerp(Vector3::from(settings.ground_color), sky_gradient, ground_to_sky_t).add_element_wise(sun * sun_mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitive::Shape;
+
+    const TOLERANCE: f32 = 1e-3;
+
+    fn ray(origin: [f32; 3], dir: [f32; 3]) -> Ray {
+        Ray {
+            origin: origin.into(),
+            dir: Vector3::from(dir).normalize(),
+        }
+    }
+
+    fn hit(shape: Shape, origin: [f32; 3], dir: [f32; 3], two_sided: bool) -> HitInfo {
+        let material = Material {
+            two_sided,
+            ..Material::new([1.0, 1.0, 1.0], 0.0)
+        };
+        ray_primitive(ray(origin, dir), &shape.to_data(), material)
+    }
+
+    // The shapes return their outward normal, calculate_ray_collision turns it towards the ray
+    #[track_caller]
+    fn assert_hit(hit_info: HitInfo, distance: f32, normal: [f32; 3]) {
+        assert!(hit_info.did_hit, "expected a hit at {}", distance);
+        assert!(
+            (hit_info.distance - distance).abs() < TOLERANCE,
+            "distance {} instead of {}",
+            hit_info.distance,
+            distance
+        );
+        let normal = Vector3::from(normal).normalize();
+        assert!(
+            (hit_info.geometric_normal - normal).magnitude() < TOLERANCE,
+            "normal {:?} instead of {:?}",
+            hit_info.geometric_normal,
+            normal
+        );
+    }
+
+    #[track_caller]
+    fn assert_miss(hit_info: HitInfo) {
+        assert!(!hit_info.did_hit, "unexpected hit at {}", hit_info.distance);
+    }
+
+    #[test]
+    fn sphere() {
+        let sphere = |origin: [f32; 3], dir: [f32; 3], two_sided: bool| {
+            ray_sphere(ray(origin, dir), Vector3::new(0.0, 0.0, 0.0), 1.0, two_sided)
+        };
+        assert_hit(sphere([0.0, 0.0, 5.0], [0.0, 0.0, -1.0], false), 4.0, [0.0, 0.0, 1.0]);
+        assert_miss(sphere([0.0, 0.0, 5.0], [0.0, 0.0, 1.0], false));
+
+        // Grazing rays just inside and outside the silhouette
+        let grazing = sphere([0.999, 0.0, 5.0], [0.0, 0.0, -1.0], false);
+        assert!(grazing.did_hit && grazing.geometric_normal.x > 0.99);
+        assert_miss(sphere([1.001, 0.0, 5.0], [0.0, 0.0, -1.0], false));
+
+        // From inside only a two-sided sphere is hit, where the ray leaves
+        assert_miss(sphere([0.0, 0.0, 0.0], [0.0, 0.0, -1.0], false));
+        assert_hit(sphere([0.0, 0.0, 0.0], [0.0, 0.0, -1.0], true), 1.0, [0.0, 0.0, -1.0]);
+    }
+
+    #[test]
+    fn plane() {
+        let plane = Shape::Plane { point: [0.0, -1.0, 0.0], normal: [0.0, 2.0, 0.0] };
+        assert_hit(hit(plane, [3.0, 1.0, 0.0], [0.0, -1.0, 0.0], false), 2.0, [0.0, 1.0, 0.0]);
+        assert_hit(hit(plane, [0.0, 1.0, 0.0], [1.0, -1.0, 0.0], false), 2.0 * 2.0f32.sqrt(), [0.0, 1.0, 0.0]);
+        assert_miss(hit(plane, [0.0, 1.0, 0.0], [1.0, 0.0, 0.0], false));
+        assert_miss(hit(plane, [0.0, 1.0, 0.0], [0.0, 1.0, 0.0], false));
+
+        // From behind
+        assert_miss(hit(plane, [0.0, -3.0, 0.0], [0.0, 1.0, 0.0], false));
+        assert_hit(hit(plane, [0.0, -3.0, 0.0], [0.0, 1.0, 0.0], true), 2.0, [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn quad() {
+        // Faces edge_u x edge_v = +y
+        let quad = Shape::Quad { corner: [-1.0, 0.0, -1.0], edge_u: [0.0, 0.0, 2.0], edge_v: [2.0, 0.0, 0.0] };
+        assert_hit(hit(quad, [0.5, 1.0, 0.5], [0.0, -1.0, 0.0], false), 1.0, [0.0, 1.0, 0.0]);
+        assert_hit(hit(quad, [0.999, 1.0, -0.999], [0.0, -1.0, 0.0], false), 1.0, [0.0, 1.0, 0.0]);
+        assert_miss(hit(quad, [1.001, 1.0, 0.0], [0.0, -1.0, 0.0], false));
+        assert_miss(hit(quad, [0.5, -1.0, 0.5], [0.0, 1.0, 0.0], false));
+        assert_hit(hit(quad, [0.5, -1.0, 0.5], [0.0, 1.0, 0.0], true), 1.0, [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn disk() {
+        let disk = Shape::Disk { center: [0.0, 0.0, 0.0], normal: [0.0, 0.0, 1.0], radius: 1.0 };
+        assert_hit(hit(disk, [0.0, 0.0, 3.0], [0.0, 0.0, -1.0], false), 3.0, [0.0, 0.0, 1.0]);
+        assert_hit(hit(disk, [0.0, 0.999, 3.0], [0.0, 0.0, -1.0], false), 3.0, [0.0, 0.0, 1.0]);
+        assert_miss(hit(disk, [0.0, 1.001, 3.0], [0.0, 0.0, -1.0], false));
+        assert_miss(hit(disk, [0.0, 0.0, -3.0], [0.0, 0.0, 1.0], false));
+    }
+
+    #[test]
+    fn oriented_box() {
+        let cube = Shape::Box { center: [0.0, 0.0, 0.0], size: [2.0, 2.0, 2.0], rotation: [0.0, 0.0, 0.0] };
+        assert_hit(hit(cube, [0.0, 0.0, 5.0], [0.0, 0.0, -1.0], false), 4.0, [0.0, 0.0, 1.0]);
+        assert_hit(hit(cube, [-5.0, 0.5, 0.5], [1.0, 0.0, 0.0], false), 4.0, [-1.0, 0.0, 0.0]);
+        assert_hit(hit(cube, [0.0, 0.999, 5.0], [0.0, 0.0, -1.0], false), 4.0, [0.0, 0.0, 1.0]);
+        assert_miss(hit(cube, [0.0, 1.001, 5.0], [0.0, 0.0, -1.0], false));
+        assert_miss(hit(cube, [0.0, 0.0, 5.0], [0.0, 0.0, 1.0], false));
+
+        // Long along x before turning it by 90 degrees around y, so long along z after
+        let turned = Shape::Box { center: [0.0, 0.0, 0.0], size: [4.0, 2.0, 2.0], rotation: [0.0, 90.0, 0.0] };
+        assert_hit(hit(turned, [0.0, 0.0, 5.0], [0.0, 0.0, -1.0], false), 3.0, [0.0, 0.0, 1.0]);
+        assert_hit(hit(turned, [5.0, 0.0, 0.0], [-1.0, 0.0, 0.0], false), 4.0, [1.0, 0.0, 0.0]);
+
+        // From inside only a two-sided box is hit, where the ray leaves
+        assert_miss(hit(cube, [0.0, 0.0, 0.0], [1.0, 0.0, 0.0], false));
+        assert_hit(hit(cube, [0.0, 0.0, 0.0], [1.0, 0.0, 0.0], true), 1.0, [1.0, 0.0, 0.0]);
+        assert_hit(hit(cube, [0.0, 0.5, 0.0], [0.0, -1.0, 0.0], true), 1.5, [0.0, -1.0, 0.0]);
+    }
+
+    #[test]
+    fn cylinder() {
+        let cylinder = Shape::Cylinder { base: [0.0, -1.0, 0.0], top: [0.0, 1.0, 0.0], radius: 1.0 };
+        assert_hit(hit(cylinder, [5.0, 0.0, 0.0], [-1.0, 0.0, 0.0], false), 4.0, [1.0, 0.0, 0.0]);
+        assert_hit(hit(cylinder, [0.0, 5.0, 0.0], [0.0, -1.0, 0.0], false), 4.0, [0.0, 1.0, 0.0]);
+        assert_hit(hit(cylinder, [0.5, -5.0, 0.0], [0.0, 1.0, 0.0], false), 4.0, [0.0, -1.0, 0.0]);
+        assert_hit(hit(cylinder, [5.0, 0.0, 0.8], [-1.0, 0.0, 0.0], false), 4.4, [0.6, 0.0, 0.8]);
+
+        // Grazing the side, and passing just above the top
+        let grazing = hit(cylinder, [5.0, 0.0, 0.999], [-1.0, 0.0, 0.0], false);
+        assert!(grazing.did_hit && grazing.geometric_normal.z > 0.99);
+        assert_miss(hit(cylinder, [5.0, 0.0, 1.001], [-1.0, 0.0, 0.0], false));
+        assert_miss(hit(cylinder, [5.0, 1.001, 0.0], [-1.0, 0.0, 0.0], false));
+
+        // From inside only a two-sided cylinder is hit, on the side or a cap
+        assert_miss(hit(cylinder, [0.0, 0.0, 0.0], [1.0, 0.0, 0.0], false));
+        assert_hit(hit(cylinder, [0.0, 0.0, 0.0], [1.0, 0.0, 0.0], true), 1.0, [1.0, 0.0, 0.0]);
+        assert_hit(hit(cylinder, [0.0, 0.5, 0.0], [0.0, 1.0, 0.0], true), 0.5, [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn cone() {
+        // The radius shrinks by 1 over a height of 2, so the side's normal is (2, 1) / sqrt(5)
+        let cone = Shape::Cone { base: [0.0, -1.0, 0.0], apex: [0.0, 1.0, 0.0], radius: 1.0 };
+        assert_hit(hit(cone, [5.0, 0.0, 0.0], [-1.0, 0.0, 0.0], false), 4.5, [2.0, 1.0, 0.0]);
+        assert_hit(hit(cone, [0.0, -5.0, 0.0], [0.0, 1.0, 0.0], false), 4.0, [0.0, -1.0, 0.0]);
+        assert_hit(hit(cone, [0.2, 5.0, 0.0], [0.0, -1.0, 0.0], false), 4.4, [2.0, 1.0, 0.0]);
+        assert_miss(hit(cone, [5.0, 1.001, 0.0], [-1.0, 0.0, 0.0], false));
+        assert_miss(hit(cone, [0.0, 0.0, 0.0], [1.0, 0.0, 0.0], false));
+        assert_hit(hit(cone, [0.0, 0.0, 0.0], [1.0, 0.0, 0.0], true), 0.5, [2.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn torus() {
+        // Around the y axis, the tube goes from 1.5 to 2.5 from the center
+        let torus = Shape::Torus { center: [0.0, 0.0, 0.0], axis: [0.0, 1.0, 0.0], major_radius: 2.0, minor_radius: 0.5 };
+
+        // The first of the four roots is where the ray enters the near side of the tube
+        assert_hit(hit(torus, [-5.0, 0.0, 0.0], [1.0, 0.0, 0.0], false), 2.5, [-1.0, 0.0, 0.0]);
+        assert_hit(hit(torus, [0.0, 3.0, 2.0], [0.0, -1.0, 0.0], false), 2.5, [0.0, 1.0, 0.0]);
+
+        // From the hole, the ray enters the far side of the tube from the inside of the ring
+        assert_hit(hit(torus, [0.0, 0.0, 0.0], [1.0, 0.0, 0.0], false), 1.5, [-1.0, 0.0, 0.0]);
+
+        // From inside the tube, the next entering root is across the hole, unless it's two-sided
+        assert_hit(hit(torus, [-2.0, 0.0, 0.0], [1.0, 0.0, 0.0], false), 3.5, [-1.0, 0.0, 0.0]);
+        assert_hit(hit(torus, [-2.0, 0.0, 0.0], [1.0, 0.0, 0.0], true), 0.5, [1.0, 0.0, 0.0]);
+
+        // Through the hole along the axis, and grazing the outside of the tube
+        assert_miss(hit(torus, [0.0, 5.0, 0.0], [0.0, -1.0, 0.0], false));
+        let grazing = hit(torus, [-5.0, 0.499, 0.0], [1.0, 0.0, 0.0], false);
+        assert!(grazing.did_hit && grazing.geometric_normal.y > 0.9);
+        assert_miss(hit(torus, [-5.0, 0.501, 0.0], [1.0, 0.0, 0.0], false));
+    }
+
+    #[test]
+    fn triangle() {
+        // Counterclockwise seen from +z, so it faces +z
+        let a = Vector3::new(0.0, 0.0, 0.0);
+        let b = Vector3::new(1.0, 0.0, 0.0);
+        let c = Vector3::new(0.0, 1.0, 0.0);
+        let normals = [Vector3::new(0.0, 0.0, 1.0); 3];
+        let one_sided = Material::new([1.0, 1.0, 1.0], 0.0);
+        let two_sided = Material {
+            two_sided: true,
+            ..one_sided
+        };
+        let triangle = |origin: [f32; 3], dir: [f32; 3], material: Material| {
+            ray_triangle(ray(origin, dir), [a, b, c], normals, material)
+        };
+        assert_hit(triangle([0.2, 0.2, 1.0], [0.0, 0.0, -1.0], one_sided), 1.0, [0.0, 0.0, 1.0]);
+        assert_miss(triangle([0.6, 0.6, 1.0], [0.0, 0.0, -1.0], one_sided));
+        assert_miss(triangle([0.2, 0.2, -1.0], [0.0, 0.0, 1.0], one_sided));
+        assert_hit(triangle([0.2, 0.2, -1.0], [0.0, 0.0, 1.0], two_sided), 1.0, [0.0, 0.0, 1.0]);
+
+        // A ray through the edge shared with a neighbour hits at least one of them
+        let neighbour = ray_triangle(ray([0.5, 0.5, 1.0], [0.0, 0.0, -1.0]), [b, Vector3::new(1.0, 1.0, 0.0), c], normals, one_sided);
+        assert!(triangle([0.5, 0.5, 1.0], [0.0, 0.0, -1.0], one_sided).did_hit || neighbour.did_hit);
+    }
+}
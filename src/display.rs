@@ -0,0 +1,299 @@
+use cgmath::{ElementWise, Matrix3, SquareMatrix, Vector3};
+
+const EXPOSURE_STEP: f32 = 0.5;
+const TEMPERATURE_STEP: f32 = 250.0;
+const TINT_STEP: f32 = 0.1;
+pub const MIN_TEMPERATURE: f32 = 2000.0;
+pub const MAX_TEMPERATURE: f32 = 15000.0;
+const REFERENCE_TEMPERATURE: f32 = 6504.0; // D65, the white point of the sRGB display
+
+// Tone mapping operator applied after exposure and white balance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapping {
+    None,
+    Reinhard,
+    AcesFitted,
+    Agx,
+}
+
+impl ToneMapping {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(ToneMapping::None),
+            "reinhard" => Some(ToneMapping::Reinhard),
+            "aces" => Some(ToneMapping::AcesFitted),
+            "agx" => Some(ToneMapping::Agx),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            ToneMapping::None => ToneMapping::Reinhard,
+            ToneMapping::Reinhard => ToneMapping::AcesFitted,
+            ToneMapping::AcesFitted => ToneMapping::Agx,
+            ToneMapping::Agx => ToneMapping::None,
+        }
+    }
+
+    // Must match the switch in display_transform() in shader.wgsl
+    pub fn shader_id(self) -> u32 {
+        match self {
+            ToneMapping::None => 0,
+            ToneMapping::Reinhard => 1,
+            ToneMapping::AcesFitted => 2,
+            ToneMapping::Agx => 3,
+        }
+    }
+}
+
+// User facing display parameters. The accumulation buffer always stays linear,
+// these only change how it is presented.
+#[derive(Debug, Clone, Copy)]
+pub struct Display {
+    pub exposure: f32,    // In stops (EV), 0 leaves the radiance unchanged
+    pub tone_mapping: ToneMapping,
+    pub temperature: f32, // Color temperature of the light to neutralize, in Kelvin
+    pub tint: f32,        // Green (-1) to magenta (+1) shift
+    pub encode_srgb: bool, // Set when the target isn't an sRGB format and the shader has to encode itself
+    pub raw: bool, // Show the values without exposure, white balance and tone mapping, for the debug views
+}
+
+impl Display {
+    pub fn new(encode_srgb: bool) -> Self {
+        Display {
+            exposure: 0.0,
+            tone_mapping: ToneMapping::AcesFitted,
+            temperature: REFERENCE_TEMPERATURE,
+            tint: 0.0,
+            encode_srgb,
+            raw: false,
+        }
+    }
+
+    pub fn increase_exposure(&mut self) {
+        self.exposure += EXPOSURE_STEP;
+    }
+
+    pub fn decrease_exposure(&mut self) {
+        self.exposure -= EXPOSURE_STEP;
+    }
+
+    pub fn increase_temperature(&mut self) {
+        self.temperature = (self.temperature + TEMPERATURE_STEP).min(MAX_TEMPERATURE);
+    }
+
+    pub fn decrease_temperature(&mut self) {
+        self.temperature = (self.temperature - TEMPERATURE_STEP).max(MIN_TEMPERATURE);
+    }
+
+    pub fn increase_tint(&mut self) {
+        self.tint = (self.tint + TINT_STEP).min(1.0);
+    }
+
+    pub fn decrease_tint(&mut self) {
+        self.tint = (self.tint - TINT_STEP).max(-1.0);
+    }
+
+    pub fn cycle_tone_mapping(&mut self) {
+        self.tone_mapping = self.tone_mapping.next();
+    }
+
+    pub fn reset(&mut self) {
+        *self = Display::new(self.encode_srgb);
+    }
+
+    // Data in the layout of the DisplaySettings struct in shader.wgsl
+    pub fn to_gpu(self) -> DisplaySettings {
+        let m = self.white_balance_matrix();
+        DisplaySettings {
+            white_balance: [
+                [m.x.x, m.x.y, m.x.z, 0.0],
+                [m.y.x, m.y.y, m.y.z, 0.0],
+                [m.z.x, m.z.y, m.z.z, 0.0],
+            ],
+            exposure: self.exposure,
+            tone_mapping: self.tone_mapping.shader_id(),
+            encode_srgb: self.encode_srgb as u32,
+            raw: self.raw as u32,
+        }
+    }
+
+    // CPU version of display_transform() in shader.wgsl, used for images saved from the accumulation buffer
+    pub fn transform(self, radiance: [f32; 3]) -> [f32; 3] {
+        let radiance = Vector3::new(radiance[0], radiance[1], radiance[2]);
+        let color = if self.raw {
+            radiance
+        } else {
+            let color = self.white_balance_matrix() * radiance * self.exposure.exp2();
+            let color = color.map(|c| c.max(0.0));
+
+            match self.tone_mapping {
+                ToneMapping::None => color,
+                ToneMapping::Reinhard => tone_map_reinhard(color),
+                ToneMapping::AcesFitted => tone_map_aces_fitted(color),
+                ToneMapping::Agx => tone_map_agx(color),
+            }
+        };
+        let mut color = color.map(|c| c.clamp(0.0, 1.0));
+
+        if self.encode_srgb {
+            color = color.map(linear_to_srgb);
+        }
+        [color.x, color.y, color.z]
+    }
+
+    // Linear sRGB -> linear sRGB matrix that maps the white of the chosen
+    // temperature/tint onto D65 using a Bradford chromatic adaptation
+    pub fn white_balance_matrix(&self) -> Matrix3<f32> {
+        let source = white_point_xyz(self.temperature, self.tint);
+        let target = white_point_xyz(REFERENCE_TEMPERATURE, 0.0);
+
+        let bradford = Matrix3::new(
+            0.8951, -0.7502, 0.0389, // Column 1
+            0.2664, 1.7135, -0.0685, // Column 2
+            -0.1614, 0.0367, 1.0296, // Column 3
+        );
+        let bradford_inverse = bradford.invert().unwrap();
+
+        let source_lms = bradford * source;
+        let target_lms = bradford * target;
+        let scale = Matrix3::from_diagonal(Vector3::new(
+            target_lms.x / source_lms.x,
+            target_lms.y / source_lms.y,
+            target_lms.z / source_lms.z,
+        ));
+
+        let srgb_to_xyz = Matrix3::new(
+            0.4124564, 0.2126729, 0.0193339, // Column 1
+            0.3575761, 0.7151522, 0.119192, // Column 2
+            0.1804375, 0.0721750, 0.9503041, // Column 3
+        );
+        let xyz_to_srgb = srgb_to_xyz.invert().unwrap();
+
+        xyz_to_srgb * bradford_inverse * scale * bradford * srgb_to_xyz
+    }
+}
+
+// GPU copy of the display parameters, bound as `display_settings` in the shader
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DisplaySettings {
+    pub white_balance: [[f32; 4]; 3], // mat3x3<f32>, every column padded to 16 bytes
+    pub exposure: f32,
+    pub tone_mapping: u32,
+    pub encode_srgb: u32,
+    pub raw: u32,
+}
+
+// XYZ (Y = 1) of a white with the given color temperature, using the CIE daylight
+// locus above 4000K and the Planckian locus (Kang et al. 2002) below it. The tint
+// moves the white perpendicular to the locus in CIE 1960 uv.
+fn white_point_xyz(temperature: f32, tint: f32) -> Vector3<f32> {
+    let (u, v) = xy_to_uv(locus_xy(temperature));
+    let (u_next, v_next) = xy_to_uv(locus_xy(temperature + 1.0));
+
+    // Unit normal of the locus, pointing towards magenta
+    let (du, dv) = (u_next - u, v_next - v);
+    let length = (du * du + dv * dv).sqrt();
+    let (normal_u, normal_v) = (-dv / length, du / length);
+
+    let duv = -tint * 0.01;
+    let (x, y) = uv_to_xy((u + normal_u * duv, v + normal_v * duv));
+    Vector3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+fn locus_xy(temperature: f32) -> (f32, f32) {
+    let t = temperature as f64;
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    if t >= 4000.0 {
+        let x = if t <= 7000.0 {
+            -4.6070e9 / t3 + 2.9678e6 / t2 + 0.09911e3 / t + 0.244063
+        } else {
+            -2.0064e9 / t3 + 1.9018e6 / t2 + 0.24748e3 / t + 0.237040
+        };
+        let y = -3.0 * x * x + 2.870 * x - 0.275;
+        (x as f32, y as f32)
+    } else {
+        let x = -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910;
+        let x2 = x * x;
+        let x3 = x2 * x;
+        let y = if t <= 2222.0 {
+            -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+        } else {
+            -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+        };
+        (x as f32, y as f32)
+    }
+}
+
+fn xy_to_uv((x, y): (f32, f32)) -> (f32, f32) {
+    let denominator = -2.0 * x + 12.0 * y + 3.0;
+    (4.0 * x / denominator, 6.0 * y / denominator)
+}
+
+fn uv_to_xy((u, v): (f32, f32)) -> (f32, f32) {
+    let denominator = 2.0 * u - 8.0 * v + 4.0;
+    (3.0 * u / denominator, 2.0 * v / denominator)
+}
+
+fn luminance(color: Vector3<f32>) -> f32 {
+    color.x * 0.2126 + color.y * 0.7152 + color.z * 0.0722
+}
+
+fn tone_map_reinhard(color: Vector3<f32>) -> Vector3<f32> {
+    color / (1.0 + luminance(color))
+}
+
+fn tone_map_aces_fitted(color: Vector3<f32>) -> Vector3<f32> {
+    let aces_input = Matrix3::new(
+        0.59719, 0.07600, 0.02840, // Column 1
+        0.35458, 0.90834, 0.13383, // Column 2
+        0.04823, 0.01566, 0.83777, // Column 3
+    );
+    let aces_output = Matrix3::new(
+        1.60475, -0.10208, -0.00327, // Column 1
+        -0.53108, 1.10813, -0.07276, // Column 2
+        -0.07367, -0.00605, 1.07602, // Column 3
+    );
+
+    let v = aces_input * color;
+    let a = v.map(|v| v * (v + 0.0245786) - 0.000090537);
+    let b = v.map(|v| v * (0.983729 * v + 0.432951) + 0.238081);
+    aces_output * a.div_element_wise(b)
+}
+
+#[allow(clippy::excessive_precision)] // Keep the published AgX constants as they are
+fn tone_map_agx(color: Vector3<f32>) -> Vector3<f32> {
+    let agx_inset = Matrix3::new(
+        0.842479062253094, 0.0423282422610123, 0.0423756549057051, // Column 1
+        0.0784335999999992, 0.878468636469772, 0.0784336, // Column 2
+        0.0792237451477643, 0.0791661274605434, 0.879142973793104, // Column 3
+    );
+    let agx_outset = Matrix3::new(
+        1.19687900512017, -0.0528968517574562, -0.0529716355144438, // Column 1
+        -0.0980208811401368, 1.15190312990417, -0.0980434501171241, // Column 2
+        -0.0990297440797205, -0.0989611768448433, 1.15107367264116, // Column 3
+    );
+    let min_ev: f32 = -12.47393;
+    let max_ev: f32 = 4.026069;
+
+    let v = (agx_inset * color).map(|v| {
+        let v = (v.max(1e-10).log2().clamp(min_ev, max_ev) - min_ev) / (max_ev - min_ev);
+        let v2 = v * v;
+        let v4 = v2 * v2;
+        15.5 * v4 * v2 - 40.14 * v4 * v + 31.96 * v4 - 6.868 * v2 * v + 0.4298 * v2 + 0.1191 * v - 0.00232
+    });
+
+    (agx_outset * v).map(|v| v.max(0.0).powf(2.2))
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
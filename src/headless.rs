@@ -0,0 +1,225 @@
+use crate::aov::{self, Layer};
+use crate::cli::{Options, RendererKind};
+use crate::cpu_renderer::{self, CpuRenderer, FramePixel};
+use crate::display::Display;
+use crate::image_io::{save_image, save_layers};
+use crate::renderer_backend::adapter::{self, AdapterOptions};
+use crate::render_settings::{DebugMode, RenderSettings};
+use crate::renderer_backend::renderer::Renderer;
+use crate::scene::Scene;
+
+const OFFSCREEN_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+const CONVERGENCE_CHECK_INTERVAL: u32 = 10; // Frames between looking for pixels above the noise threshold
+
+// Result of render_image
+pub struct RenderedImage {
+    pub pixels: Vec<[f32; 3]>, // Linear radiance, row by row from the top
+    pub layers: Vec<Layer>,    // The AOVs, empty without settings.aovs
+}
+
+// Render a fixed number of samples without a window and save the result
+pub async fn run(options: &Options, scene: &Scene) {
+    let output = options.output.as_ref().unwrap();
+    let mut settings = RenderSettings::new();
+    options.apply_settings(&mut settings);
+
+    let image = render_image(
+        options.renderer,
+        &options.adapter,
+        scene,
+        settings,
+        options.width,
+        options.height,
+        options.spp,
+        options.time_limit.map(std::time::Duration::from_secs_f32),
+    )
+    .await;
+
+    let mut display = Display::new(false);
+    options.apply_display(&mut display);
+    display.raw = settings.debug_mode != DebugMode::Off;
+    let result = if settings.aovs {
+        save_layers(output, options.width, options.height, &image.pixels, &image.layers)
+    } else {
+        save_image(output, options.width, options.height, &image.pixels, display)
+    };
+    match result {
+        Ok(()) => println!("Saved {}", output.display()),
+        Err(error) => {
+            eprintln!("Failed to save {}: {}", output.display(), error);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Accumulate `spp` samples per pixel and return the linear radiance, row by row from the top.
+// With the denoiser enabled in the settings the radiance is denoised. With adaptive sampling
+// the render stops early once every pixel converged, and it always stops at the time limit.
+// With AOVs enabled in the settings the AOV layers are returned as well.
+#[allow(clippy::too_many_arguments)]
+pub async fn render_image(
+    renderer_kind: RendererKind,
+    adapter_options: &AdapterOptions,
+    scene: &Scene,
+    settings: RenderSettings,
+    width: u32,
+    height: u32,
+    spp: u32,
+    time_limit: Option<std::time::Duration>,
+) -> RenderedImage {
+    // Every frame traces rays_per_pixel rays and the last one only the rest, so the render has
+    // exactly spp samples. The frames are weighted by their number of samples.
+    let rays_per_pixel = settings.rays_per_pixel.min(spp);
+    let frame_total = spp.div_ceil(rays_per_pixel);
+    let frame_rays = |frame: u32| rays_per_pixel.min(spp - frame * rays_per_pixel);
+    println!("Rendering {}x{} at {} samples per pixel ({} frames)", width, height, spp, frame_total);
+
+    let start_time = std::time::Instant::now();
+    let print_progress = |frame: u32| {
+        if (frame + 1).is_multiple_of(10) || frame + 1 == frame_total {
+            println!("Frame {}/{} ({:?})", frame + 1, frame_total, start_time.elapsed());
+        }
+    };
+
+    match renderer_kind {
+        RendererKind::Gpu => {
+            let mut renderer = create_gpu_renderer(adapter_options, scene, width, height).await;
+            renderer.settings = settings;
+            let target_view = create_offscreen_target(&renderer);
+            for frame in 0..frame_total {
+                renderer.settings.rays_per_pixel = frame_rays(frame);
+                renderer.render(&target_view);
+                renderer.device.poll(wgpu::Maintain::Wait);
+                print_progress(frame);
+                if should_stop(&settings, frame, start_time, time_limit, || renderer.read_frame_pixels()) {
+                    break;
+                }
+            }
+            report_samples(&renderer.read_frame_pixels());
+            let pixels = if settings.debug_mode == DebugMode::Error {
+                error_image(&settings, &renderer.read_frame_pixels())
+            } else if settings.use_denoiser() {
+                renderer.read_denoised_data()
+            } else {
+                renderer.read_frame_data()
+            };
+            let layers = if settings.aovs {
+                aov::layers(&renderer.read_frame_pixels(), &renderer.read_aovs())
+            } else {
+                Vec::new()
+            };
+            RenderedImage { pixels, layers }
+        }
+        RendererKind::Cpu => {
+            let mut renderer = CpuRenderer::new(width, height, scene);
+            renderer.settings = settings;
+            for frame in 0..frame_total {
+                renderer.settings.rays_per_pixel = frame_rays(frame);
+                renderer.render();
+                print_progress(frame);
+                if should_stop(&settings, frame, start_time, time_limit, || renderer.read_frame_pixels()) {
+                    break;
+                }
+            }
+            report_samples(&renderer.read_frame_pixels());
+            let pixels = if settings.debug_mode == DebugMode::Error {
+                error_image(&settings, &renderer.read_frame_pixels())
+            } else if settings.use_denoiser() {
+                renderer.read_denoised_data()
+            } else {
+                renderer.read_frame_data()
+            };
+            let layers = if settings.aovs {
+                aov::layers(&renderer.read_frame_pixels(), &renderer.read_aovs())
+            } else {
+                Vec::new()
+            };
+            RenderedImage { pixels, layers }
+        }
+    }
+}
+
+// Whether to stop before all frames are rendered, because the time is up or because adaptive
+// sampling wouldn't trace any more rays
+fn should_stop(
+    settings: &RenderSettings,
+    frame: u32,
+    start_time: std::time::Instant,
+    time_limit: Option<std::time::Duration>,
+    read_frame_pixels: impl FnOnce() -> Vec<FramePixel>,
+) -> bool {
+    if time_limit.is_some_and(|time_limit| start_time.elapsed() >= time_limit) {
+        println!("Stopped after {} frames, the time limit is reached", frame + 1);
+        return true;
+    }
+    if !settings.adaptive_sampling || !(frame + 1).is_multiple_of(CONVERGENCE_CHECK_INTERVAL) {
+        return false;
+    }
+
+    let pixels = read_frame_pixels();
+    let remaining = pixels
+        .iter()
+        .filter(|&&pixel| cpu_renderer::sample_count(settings, pixel) > 0)
+        .count();
+    if remaining == 0 {
+        println!("Converged after {} frames", frame + 1);
+        return true;
+    }
+    println!("{} of {} pixels above the noise threshold", remaining, pixels.len());
+    false
+}
+
+// Adaptive sampling and the time limit change the number of samples, so report what the pixels
+// actually got
+fn report_samples(pixels: &[FramePixel]) {
+    let samples = pixels.iter().map(|pixel| pixel.moments.y);
+    let min = samples.clone().fold(f32::INFINITY, f32::min);
+    let max = samples.clone().fold(0.0, f32::max);
+    if min == max {
+        eprintln!("Rendered {} samples per pixel", max);
+    } else {
+        let average = samples.sum::<f32>() / pixels.len() as f32;
+        eprintln!("Rendered {} to {} samples per pixel, {:.1} on average", min, max, average);
+    }
+}
+
+// The error view is a heat map of the accumulated data, not something the tracer outputs
+fn error_image(settings: &RenderSettings, pixels: &[FramePixel]) -> Vec<[f32; 3]> {
+    pixels
+        .iter()
+        .map(|&pixel| cpu_renderer::error_color(pixel, settings))
+        .collect()
+}
+
+pub async fn create_gpu_renderer(adapter_options: &AdapterOptions, scene: &Scene, width: u32, height: u32) -> Renderer {
+    // No surface to be compatible with, so any adapter works
+    let instance = adapter::create_instance(adapter_options);
+    let adapter = adapter::request_adapter(&instance, adapter_options, None).await;
+    let (device, queue) = adapter::request_device(&adapter, scene).await;
+
+    let renderer = Renderer::new(device, queue, OFFSCREEN_FORMAT, width, height, scene);
+    if (renderer.width, renderer.height) != (width, height) {
+        eprintln!("{}x{} is too large for this GPU", width, height);
+        std::process::exit(1);
+    }
+    renderer
+}
+
+// Offscreen target, only written because the tracer runs in a fragment shader
+pub fn create_offscreen_target(renderer: &Renderer) -> wgpu::TextureView {
+    let target = renderer.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Offscreen Target"),
+        size: wgpu::Extent3d {
+            width: renderer.width,
+            height: renderer.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: OFFSCREEN_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    });
+    target.create_view(&wgpu::TextureViewDescriptor::default())
+}
@@ -0,0 +1,215 @@
+use crate::aov::Layer;
+use crate::display::Display;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+// Save linear radiance (row by row from the top) in the format given by the file
+// extension. PNG is tone mapped with the display settings, PFM and EXR stay linear.
+pub fn save_image(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[[f32; 3]],
+    display: Display,
+) -> Result<(), Box<dyn Error>> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("png") => write_png(path, width, height, pixels, display),
+        Some("pfm") => write_pfm(path, width, height, pixels),
+        Some("exr") => write_exr(path, width, height, pixels),
+        _ => Err(format!("Unsupported image format: {}", path.display()).into()),
+    }
+}
+
+// Save linear radiance together with the AOV layers of the same render. EXR keeps them all in one
+// file, with the radiance as the default layer. PFM writes the radiance to the path and every
+// layer next to it as <stem>_<layer>.pfm.
+pub fn save_layers(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[[f32; 3]],
+    layers: &[Layer],
+) -> Result<(), Box<dyn Error>> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("pfm") => {
+            write_pfm(path, width, height, pixels)?;
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+            for layer in layers {
+                let layer_path = path.with_file_name(format!("{}_{}.pfm", stem, layer.name));
+                write_pfm_channels(&layer_path, width, height, layer.channels.len(), &layer.data)?;
+            }
+            Ok(())
+        }
+        Some("exr") => write_exr_layers(path, width, height, pixels, layers),
+        _ => Err(format!("AOVs can only be saved as .pfm or .exr: {}", path.display()).into()),
+    }
+}
+
+// 8-bit sRGB
+pub fn write_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[[f32; 3]],
+    display: Display,
+) -> Result<(), Box<dyn Error>> {
+    let display = Display {
+        encode_srgb: true,
+        ..display
+    };
+    let image = image::RgbImage::from_fn(width, height, |x, y| {
+        let color = display.transform(pixels[(y * width + x) as usize]);
+        image::Rgb(color.map(|c| (c * 255.0).round() as u8))
+    });
+    image.save(path)?;
+    Ok(())
+}
+
+// Portable float map, 32-bit linear RGB. Rows are stored from the bottom up.
+pub fn write_pfm(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[[f32; 3]],
+) -> Result<(), Box<dyn Error>> {
+    write_pfm_channels(path, width, height, 3, pixels.as_flattened())
+}
+
+// PFM with 1 (grayscale) or 3 channels, `values` holds every channel of a pixel after another
+fn write_pfm_channels(
+    path: &Path,
+    width: u32,
+    height: u32,
+    channels: usize,
+    values: &[f32],
+) -> Result<(), Box<dyn Error>> {
+    let magic = match channels {
+        1 => "Pf",
+        3 => "PF",
+        _ => return Err(format!("PFM can't store {} channels", channels).into()),
+    };
+    let mut data: Vec<u8> = format!("{}\n{} {}\n-1.0\n", magic, width, height).into_bytes();
+    let row_length = width as usize * channels;
+    for row in values.chunks_exact(row_length).rev() {
+        data.extend(row.iter().flat_map(|c| c.to_le_bytes()));
+    }
+
+    std::fs::File::create(path)?.write_all(&data)?;
+    Ok(())
+}
+
+// OpenEXR, 32-bit linear RGB
+pub fn write_exr(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[[f32; 3]],
+) -> Result<(), Box<dyn Error>> {
+    exr::prelude::write_rgb_file(path, width as usize, height as usize, |x, y| {
+        let pixel = pixels[y * width as usize + x];
+        (pixel[0], pixel[1], pixel[2])
+    })?;
+    Ok(())
+}
+
+// OpenEXR, 32-bit linear. The radiance is in the R, G and B channels, the layers in channels
+// named <layer>.<channel>, which compositing tools show as separate layers.
+fn write_exr_layers(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[[f32; 3]],
+    layers: &[Layer],
+) -> Result<(), Box<dyn Error>> {
+    use exr::prelude::*;
+
+    let mut channels: SmallVec<[AnyChannel<FlatSamples>; 4]> = SmallVec::new();
+    for (i, name) in ["R", "G", "B"].iter().enumerate() {
+        let samples = pixels.iter().map(|pixel| pixel[i]).collect();
+        channels.push(AnyChannel::new(*name, FlatSamples::F32(samples)));
+    }
+    for layer in layers {
+        for (i, channel) in layer.channels.iter().enumerate() {
+            let samples = layer.data.iter().skip(i).step_by(layer.channels.len()).copied().collect();
+            let name = format!("{}.{}", layer.name, channel);
+            channels.push(AnyChannel::new(name.as_str(), FlatSamples::F32(samples)));
+        }
+    }
+
+    let image = Image::from_channels((width as usize, height as usize), AnyChannels::sort(channels));
+    image.write().to_file(path)?;
+    Ok(())
+}
+
+// Width, height and the pixels row by row from the top
+type PfmImage = (u32, u32, Vec<[f32; 3]>);
+
+// Read a PFM written by write_pfm
+pub fn read_pfm(path: &Path) -> Result<PfmImage, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+
+    // Header: "PF", the size and the scale, each followed by a single whitespace character
+    let mut fields = Vec::new();
+    let mut start = 0;
+    for (i, &byte) in data.iter().enumerate() {
+        if byte.is_ascii_whitespace() {
+            if i > start {
+                fields.push(std::str::from_utf8(&data[start..i])?.to_string());
+            }
+            start = i + 1;
+            if fields.len() == 4 {
+                break;
+            }
+        }
+    }
+    if fields.len() != 4 || fields[0] != "PF" {
+        return Err(format!("{} isn't an RGB PFM file", path.display()).into());
+    }
+    let width: u32 = fields[1].parse()?;
+    let height: u32 = fields[2].parse()?;
+    let little_endian = fields[3].parse::<f32>()? < 0.0;
+
+    let floats: Vec<f32> = data[start..]
+        .chunks_exact(4)
+        .map(|d| {
+            let bytes = [d[0], d[1], d[2], d[3]];
+            if little_endian {
+                f32::from_le_bytes(bytes)
+            } else {
+                f32::from_be_bytes(bytes)
+            }
+        })
+        .collect();
+    if floats.len() != (width * height * 3) as usize {
+        return Err(format!("{} is truncated", path.display()).into());
+    }
+
+    let mut pixels = vec![[0.0; 3]; (width * height) as usize];
+    for (i, rgb) in floats.chunks_exact(3).enumerate() {
+        let (x, y) = (i as u32 % width, height - 1 - i as u32 / width);
+        pixels[(y * width + x) as usize] = [rgb[0], rgb[1], rgb[2]];
+    }
+    Ok((width, height, pixels))
+}
+
+// Visualize values in [0, 1] (row by row from the top) with a black-red-yellow-white heat map
+pub fn write_heat_map(path: &Path, width: u32, height: u32, values: &[f32]) -> Result<(), Box<dyn Error>> {
+    let image = image::RgbImage::from_fn(width, height, |x, y| {
+        let t = values[(y * width + x) as usize].clamp(0.0, 1.0) * 3.0;
+        let color = [t, t - 1.0, t - 2.0].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+        image::Rgb(color)
+    });
+    image.save(path)?;
+    Ok(())
+}
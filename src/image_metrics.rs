@@ -0,0 +1,239 @@
+// Error metrics between a rendered image and a reference, both linear radiance row by row from the top
+use crate::display::Display;
+
+const EPSILON: f32 = 0.01; // Keeps the relative error of black pixels finite
+
+// Root mean squared error over all channels
+pub fn rmse(image: &[[f32; 3]], reference: &[[f32; 3]]) -> f32 {
+    let sum: f32 = image
+        .iter()
+        .zip(reference)
+        .flat_map(|(a, b)| (0..3).map(move |c| (a[c] - b[c]).powi(2)))
+        .sum();
+    (sum / (image.len() * 3) as f32).sqrt()
+}
+
+// Mean squared error relative to the reference value, so bright and dark areas count equally
+pub fn relative_mse(image: &[[f32; 3]], reference: &[[f32; 3]]) -> f32 {
+    let sum: f32 = image
+        .iter()
+        .zip(reference)
+        .flat_map(|(a, b)| (0..3).map(move |c| (a[c] - b[c]).powi(2) / (b[c] * b[c] + EPSILON)))
+        .sum();
+    sum / (image.len() * 3) as f32
+}
+
+// --- FLIP ---
+// A simplified version of NVIDIA's LDR FLIP (Andersson et al. 2020). Both images are tone mapped
+// for display, then compared by a color pipeline (contrast sensitivity filtering and HyAB
+// distance in L*a*b*) and a feature pipeline (edges and points in the luminance). Returns the
+// per pixel error in [0, 1], where 0 means the images look identical.
+const PIXELS_PER_DEGREE: f32 = 67.0; // 0.7m from a 24" 4K monitor, the default of FLIP
+const COLOR_EXPONENT: f32 = 0.7;
+const FEATURE_EXPONENT: f32 = 0.5;
+const ERROR_CUTOFF: f32 = 0.4;
+const ERROR_CUTOFF_VALUE: f32 = 0.95;
+const FEATURE_WIDTH: f32 = 0.082; // In degrees
+
+pub fn flip_error_map(image: &[[f32; 3]], reference: &[[f32; 3]], width: u32, height: u32) -> Vec<f32> {
+    let (width, height) = (width as usize, height as usize);
+
+    // FLIP works on display referred images
+    let display = Display::new(false);
+    let image: Vec<[f32; 3]> = image.iter().map(|&p| display.transform(p)).collect();
+    let reference: Vec<[f32; 3]> = reference.iter().map(|&p| display.transform(p)).collect();
+
+    // Color pipeline
+    let image_lab = filtered_lab(&image, width, height);
+    let reference_lab = filtered_lab(&reference, width, height);
+    let max_color_error = hyab(hunt(linear_rgb_to_lab([0.0, 1.0, 0.0])), hunt(linear_rgb_to_lab([0.0, 0.0, 1.0])))
+        .powf(COLOR_EXPONENT);
+    let color_error = image_lab.iter().zip(&reference_lab).map(|(&a, &b)| {
+        let error = hyab(hunt(a), hunt(b)).powf(COLOR_EXPONENT);
+        let cutoff = ERROR_CUTOFF * max_color_error;
+        if error < cutoff {
+            ERROR_CUTOFF_VALUE / cutoff * error
+        } else {
+            ERROR_CUTOFF_VALUE + (error - cutoff) / (max_color_error - cutoff) * (1.0 - ERROR_CUTOFF_VALUE)
+        }
+    });
+
+    // Feature pipeline
+    let image_features = features(&image, width, height);
+    let reference_features = features(&reference, width, height);
+    let feature_error = image_features.iter().zip(&reference_features).map(|(a, b)| {
+        let difference = (a.0 - b.0).abs().max((a.1 - b.1).abs());
+        (difference / std::f32::consts::SQRT_2).powf(FEATURE_EXPONENT)
+    });
+
+    color_error
+        .zip(feature_error)
+        .map(|(color, feature)| color.powf(1.0 - feature))
+        .collect()
+}
+
+// Approximations of the contrast sensitivity of the three opponent channels, applied in
+// YCxCz and returned as L*a*b*
+fn filtered_lab(image: &[[f32; 3]], width: usize, height: usize) -> Vec<[f32; 3]> {
+    let ycxcz: Vec<[f32; 3]> = image.iter().map(|&p| xyz_to_ycxcz(linear_rgb_to_xyz(p))).collect();
+
+    // (a1, b1, a2, b2) of the two Gaussians in the spatial domain, per channel
+    let parameters = [
+        (1.0, 0.0047, 0.0, 1e-5),  // Achromatic
+        (1.0, 0.0053, 0.0, 1e-5),  // Red-green
+        (34.1, 0.04, 13.5, 0.025), // Blue-yellow
+    ];
+    let radius = (3.0 * (0.04_f32 / (2.0 * std::f32::consts::PI.powi(2))).sqrt() * PIXELS_PER_DEGREE).ceil() as i32;
+
+    let mut filtered = vec![[0.0; 3]; image.len()];
+    for (channel, &(a1, b1, a2, b2)) in parameters.iter().enumerate() {
+        let kernel = kernel(radius, |x, y| {
+            let r2 = (x * x + y * y) / (PIXELS_PER_DEGREE * PIXELS_PER_DEGREE);
+            gaussian_csf(a1, b1, r2) + gaussian_csf(a2, b2, r2)
+        });
+        let kernel_sum: f32 = kernel.iter().sum();
+        let result = convolve(&ycxcz, width, height, radius, &kernel, channel);
+        for (pixel, value) in filtered.iter_mut().zip(result) {
+            pixel[channel] = value / kernel_sum;
+        }
+    }
+
+    filtered
+        .into_iter()
+        .map(|p| {
+            let rgb = xyz_to_linear_rgb(ycxcz_to_xyz(p)).map(|c| c.clamp(0.0, 1.0));
+            linear_rgb_to_lab(rgb)
+        })
+        .collect()
+}
+
+fn gaussian_csf(a: f32, b: f32, r2: f32) -> f32 {
+    let pi2 = std::f32::consts::PI * std::f32::consts::PI;
+    a * (std::f32::consts::PI / b).sqrt() * (-pi2 * r2 / b).exp()
+}
+
+// Edge and point strength of the normalized luminance
+fn features(image: &[[f32; 3]], width: usize, height: usize) -> Vec<(f32, f32)> {
+    let luminance: Vec<[f32; 3]> = image
+        .iter()
+        .map(|&p| {
+            let y = (xyz_to_ycxcz(linear_rgb_to_xyz(p))[0] + 16.0) / 116.0;
+            [y, 0.0, 0.0]
+        })
+        .collect();
+
+    let sigma = 0.5 * FEATURE_WIDTH * PIXELS_PER_DEGREE;
+    let radius = (3.0 * sigma).ceil() as i32;
+    let gaussian = |x: f32, y: f32| (-(x * x + y * y) / (2.0 * sigma * sigma)).exp();
+
+    let edge_x = normalized_derivative_kernel(kernel(radius, |x, y| -x * gaussian(x, y)));
+    let edge_y = normalized_derivative_kernel(kernel(radius, |x, y| -y * gaussian(x, y)));
+    let point_x = normalized_derivative_kernel(kernel(radius, |x, y| (x * x / (sigma * sigma) - 1.0) * gaussian(x, y)));
+    let point_y = normalized_derivative_kernel(kernel(radius, |x, y| (y * y / (sigma * sigma) - 1.0) * gaussian(x, y)));
+
+    let edge_x = convolve(&luminance, width, height, radius, &edge_x, 0);
+    let edge_y = convolve(&luminance, width, height, radius, &edge_y, 0);
+    let point_x = convolve(&luminance, width, height, radius, &point_x, 0);
+    let point_y = convolve(&luminance, width, height, radius, &point_y, 0);
+
+    (0..luminance.len())
+        .map(|i| {
+            (
+                (edge_x[i] * edge_x[i] + edge_y[i] * edge_y[i]).sqrt(),
+                (point_x[i] * point_x[i] + point_y[i] * point_y[i]).sqrt(),
+            )
+        })
+        .collect()
+}
+
+// The positive weights sum to 1 and the negative weights to -1
+fn normalized_derivative_kernel(kernel: Vec<f32>) -> Vec<f32> {
+    let positive: f32 = kernel.iter().filter(|&&w| w > 0.0).sum();
+    let negative: f32 = -kernel.iter().filter(|&&w| w < 0.0).sum::<f32>();
+    kernel
+        .into_iter()
+        .map(|w| if w > 0.0 { w / positive } else { w / negative })
+        .collect()
+}
+
+fn kernel(radius: i32, weight: impl Fn(f32, f32) -> f32) -> Vec<f32> {
+    let mut kernel = Vec::new();
+    for y in -radius..=radius {
+        for x in -radius..=radius {
+            kernel.push(weight(x as f32, y as f32));
+        }
+    }
+    kernel
+}
+
+// Convolve one channel, clamping at the image borders
+fn convolve(image: &[[f32; 3]], width: usize, height: usize, radius: i32, kernel: &[f32], channel: usize) -> Vec<f32> {
+    let size = 2 * radius + 1;
+    let mut result = vec![0.0; image.len()];
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            let mut sum = 0.0;
+            for ky in -radius..=radius {
+                let sy = (y + ky).clamp(0, height as i32 - 1) as usize;
+                for kx in -radius..=radius {
+                    let sx = (x + kx).clamp(0, width as i32 - 1) as usize;
+                    sum += image[sy * width + sx][channel] * kernel[((ky + radius) * size + kx + radius) as usize];
+                }
+            }
+            result[y as usize * width + x as usize] = sum;
+        }
+    }
+    result
+}
+
+// --- Color spaces --- (D65 white)
+const WHITE: [f32; 3] = [0.950489, 1.0, 1.08884];
+
+fn linear_rgb_to_xyz([r, g, b]: [f32; 3]) -> [f32; 3] {
+    [
+        0.4124564 * r + 0.3575761 * g + 0.1804375 * b,
+        0.2126729 * r + 0.7151522 * g + 0.0721750 * b,
+        0.0193339 * r + 0.119192 * g + 0.9503041 * b,
+    ]
+}
+
+fn xyz_to_linear_rgb([x, y, z]: [f32; 3]) -> [f32; 3] {
+    [
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.969266 * x + 1.8760108 * y + 0.041556 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    ]
+}
+
+fn xyz_to_ycxcz([x, y, z]: [f32; 3]) -> [f32; 3] {
+    let (x, y, z) = (x / WHITE[0], y / WHITE[1], z / WHITE[2]);
+    [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+}
+
+fn ycxcz_to_xyz([luminance, cx, cz]: [f32; 3]) -> [f32; 3] {
+    let y = (luminance + 16.0) / 116.0;
+    [(y + cx / 500.0) * WHITE[0], y * WHITE[1], (y - cz / 200.0) * WHITE[2]]
+}
+
+fn linear_rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    let xyz = linear_rgb_to_xyz(rgb);
+    let f = |t: f32| {
+        let delta: f32 = 6.0 / 29.0;
+        if t > delta.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * delta * delta) + 4.0 / 29.0
+        }
+    };
+    let (fx, fy, fz) = (f(xyz[0] / WHITE[0]), f(xyz[1] / WHITE[1]), f(xyz[2] / WHITE[2]));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+// Hunt effect, colors appear less saturated when darker
+fn hunt([l, a, b]: [f32; 3]) -> [f32; 3] {
+    [l, 0.01 * l * a, 0.01 * l * b]
+}
+
+fn hyab(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (a[0] - b[0]).abs() + ((a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
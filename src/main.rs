@@ -1,181 +1,175 @@
-use renderer_backend::pipeline_builder::PipelineBuilder;
+use renderer_backend::adapter::{self, AdapterOptions};
+use renderer_backend::renderer::Renderer;
 mod renderer_backend;
-use wgpu::{
-    core::device::global, util::{BufferInitDescriptor, DeviceExt}, BufferUsages
-};
+mod aov;
+mod camera;
+mod cli;
+mod cpu_denoiser;
+mod cpu_renderer;
+mod display;
+mod headless;
+mod image_io;
+mod image_metrics;
+mod primitive;
+mod procedural;
+mod regression;
+mod render_settings;
+mod scene;
+mod scene_file;
+mod texture;
+mod ui;
+use camera::{CameraMode, FlyController, OrbitController};
+use cgmath::Vector3;
+use cli::Options;
+use scene::Scene;
+use ui::Ui;
 use winit::{
-    dpi::PhysicalSize,
+    dpi::{LogicalSize, PhysicalSize},
     event::*,
     event_loop::EventLoopBuilder,
     keyboard::{KeyCode, PhysicalKey},
-    window::{Window, WindowBuilder},
+    window::{CursorGrabMode, Window, WindowBuilder},
 };
-use cgmath::prelude::*;
 
-const SCREEN_SIZE: (u32, u32) = (1200, 600);
 const TIME_BETWEEN_FRAMES: u64 = 10;
-const CAMERA_SPEED: f32 = 1.0;
-const CAMERA_ROT_SPEED: f32 = 1.0;
+const MAX_CAMERA_TIME_STEP: f32 = 0.1; // Seconds, so a stalled frame doesn't teleport the camera
+const PREVIEW_DURATION: std::time::Duration = std::time::Duration::from_millis(200); // After the last change
 
 struct State<'a> {
     surface: wgpu::Surface<'a>,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     size: PhysicalSize<u32>,
     window: &'a Window,
-    render_pipeline: wgpu::RenderPipeline,
-    bind_group: wgpu::BindGroup,
-    frame_count: u32,
-    frame_count_buffer: wgpu::Buffer,
-    frame_data_buffer: wgpu::Buffer,
-    sphere_buffer: wgpu::Buffer,
-    triangle_buffer: wgpu::Buffer,
-    bvh_buffer: wgpu::Buffer,
-    camera_position: [f32; 3],
-    camera_rotation: [f32; 3],
-    camera_position_buffer: wgpu::Buffer,
-    camera_rotation_buffer: wgpu::Buffer,
-    keys_pressed: [bool; 12], // [W, S, D, A, Space, Shift, I, K, L, J, O, U]
+    renderer: Renderer,
+    ui: Ui,
+    camera_mode: CameraMode,
+    controller: FlyController,
+    orbit_controller: OrbitController,
+    focus_bounds: ([f32; 3], [f32; 3]), // What the orbit camera frames
+    last_update: std::time::Instant,
+    use_preview: bool,
+    use_reprojection: bool,
+    last_change: std::time::Instant, // When the accumulation was last restarted by a change
+    leaving_preview: bool,
     tick: u32,
 }
 
 impl<'a> State<'a> {
-    // Update the camera position based on the keys pressed
+    // Move the camera by the input since the last update
     fn update_camera(&mut self) {
-        // Reset frame count if any key is pressed
-        if self.keys_pressed.iter().any(|&k| k) {
-            self.frame_count = 0;
+        let now = std::time::Instant::now();
+        let dt = (now - self.last_update).as_secs_f32().min(MAX_CAMERA_TIME_STEP);
+        self.last_update = now;
+
+        let camera = &mut self.renderer.camera;
+        let changed = match self.camera_mode {
+            CameraMode::Fly => self.controller.update(camera, dt),
+            CameraMode::Orbit => self.orbit_controller.update(camera),
+        };
+        if changed {
+            camera_moved(&mut self.renderer, self.use_reprojection);
         }
+    }
 
-        let mut local_movement = [0.0, 0.0, 0.0];
-        let mut local_rotation = [0.0, 0.0, 0.0];
-
-        if self.keys_pressed[0] {
-            // W
-            local_movement[2] -= CAMERA_SPEED;
-        }
-        if self.keys_pressed[1] {
-            // S
-            local_movement[2] += CAMERA_SPEED;
-        }
-        if self.keys_pressed[2] {
-            // D
-            local_movement[0] += CAMERA_SPEED;
-        }
-        if self.keys_pressed[3] {
-            // A
-            local_movement[0] -= CAMERA_SPEED;
-        }
-        if self.keys_pressed[4] {
-            // Space
-            local_movement[1] += CAMERA_SPEED;
-        }
-        if self.keys_pressed[5] {
-            // Shift
-            local_movement[1] -= CAMERA_SPEED;
+    fn process_mouse_motion(&mut self, dx: f32, dy: f32) {
+        match self.camera_mode {
+            CameraMode::Fly => self.controller.process_mouse_motion(dx, dy),
+            CameraMode::Orbit => self.orbit_controller.process_mouse_motion(dx, dy),
         }
+    }
 
-        if self.keys_pressed[6] {
-            // I
-            local_rotation[0] += CAMERA_ROT_SPEED;
-        }
-        if self.keys_pressed[7] {
-            // K
-            local_rotation[0] -= CAMERA_ROT_SPEED;
-        }
-        if self.keys_pressed[8] {
-            // L
-            local_rotation[1] += CAMERA_ROT_SPEED;
-        }
-        if self.keys_pressed[9] {
-            // J
-            local_rotation[1] -= CAMERA_ROT_SPEED;
+    fn process_mouse_button(&mut self, button: MouseButton, pressed: bool) {
+        match (self.camera_mode, button) {
+            (CameraMode::Fly, MouseButton::Left) if pressed && !self.controller.cursor_grabbed => {
+                self.set_cursor_grab(true)
+            }
+            (CameraMode::Orbit, MouseButton::Left) => self.orbit_controller.set_orbiting(pressed),
+            (CameraMode::Orbit, MouseButton::Right) => self.orbit_controller.set_panning(pressed),
+            _ => {}
         }
-        if self.keys_pressed[10] {
-            // O
-            local_rotation[2] += CAMERA_ROT_SPEED;
+    }
+
+    fn process_scroll(&mut self, lines: f32) {
+        match self.camera_mode {
+            CameraMode::Fly => self.controller.process_scroll(lines),
+            CameraMode::Orbit => self.orbit_controller.process_scroll(lines),
         }
-        if self.keys_pressed[11] {
-            // U
-            local_rotation[2] -= CAMERA_ROT_SPEED;
+    }
+
+    fn toggle_camera_mode(&mut self) {
+        self.camera_mode = match self.camera_mode {
+            CameraMode::Fly => {
+                self.set_cursor_grab(false);
+                self.orbit_controller.attach(&mut self.renderer.camera);
+                CameraMode::Orbit
+            }
+            CameraMode::Orbit => CameraMode::Fly,
+        };
+        self.renderer.frame_count = 0;
+        println!("Camera mode: {:?}", self.camera_mode);
+    }
+
+    // Fit the focus bounds in the view, in either camera mode
+    fn frame_scene(&mut self) {
+        let aspect_ratio = self.renderer.width as f32 / self.renderer.height as f32;
+        self.orbit_controller
+            .frame(&mut self.renderer.camera, self.focus_bounds, self.renderer.settings.fov, aspect_ratio);
+        self.renderer.frame_count = 0;
+    }
+
+    // Show the low resolution preview while the accumulation keeps restarting, and go back to full
+    // quality accumulation once it has been still for a moment. Camera motion with reprojection
+    // doesn't restart it, see camera_moved.
+    fn update_preview(&mut self) {
+        let now = std::time::Instant::now();
+
+        // Everything that changes the image restarts the accumulation, but so does leaving the preview
+        if self.renderer.frame_count == 0 && !self.leaving_preview {
+            self.last_change = now;
         }
+        self.leaving_preview = false;
 
-        // Convert local to global movement
-        let global_movement = self.rotate_vector(local_movement, self.camera_rotation);
-        self.camera_position[0] += global_movement[0];
-        self.camera_position[1] += global_movement[1];
-        self.camera_position[2] += global_movement[2];
-
-        // Convert local to global rotation
-        let global_rotation = self.rotate_vector(local_rotation, self.camera_rotation);
-        self.camera_rotation[0] += global_rotation[0];
-        self.camera_rotation[1] += global_rotation[1];
-        self.camera_rotation[2] += global_rotation[2];
+        let preview = self.use_preview && now - self.last_change < PREVIEW_DURATION;
+        if preview != self.renderer.preview() {
+            self.renderer.set_preview(preview);
+            self.leaving_preview = !preview;
+        }
     }
 
-    fn rotate_vector(&mut self, vector: [f32; 3], rotation: [f32; 3]) -> [f32; 3] {
-        let x = vector[0];
-        let y = vector[1];
-        let z = vector[2];
-
-        let a = rotation[0].to_radians();
-        let b = rotation[1].to_radians();
-        let c = rotation[2].to_radians();
-
-        let cos_a = a.cos();
-        let sin_a = a.sin();
-        let cos_b = b.cos();
-        let sin_b = b.sin();
-        let cos_c = c.cos();
-        let sin_c = c.sin();
-
-        let x_rot = x * cos_c * cos_b
-            + y * (cos_c * sin_b * sin_a - sin_c * cos_a)
-            + z * (cos_c * sin_b * cos_a + sin_c * sin_a);
-        let y_rot = x * sin_c * cos_b
-            + y * (sin_c * sin_b * sin_a + cos_c * cos_a)
-            + z * (sin_c * sin_b * cos_a - cos_c * sin_a);
-        let z_rot = -x * sin_b + y * cos_b * sin_a + z * cos_b * cos_a;
-
-        [x_rot, y_rot, z_rot]
+    // Hide the cursor and use mouse movement to look around
+    fn set_cursor_grab(&mut self, grab: bool) {
+        if grab {
+            // Not every platform supports locking the cursor in place
+            let result = self
+                .window
+                .set_cursor_grab(CursorGrabMode::Locked)
+                .or_else(|_| self.window.set_cursor_grab(CursorGrabMode::Confined));
+            if let Err(e) = result {
+                eprintln!("Can't grab the cursor: {}", e);
+                return;
+            }
+        } else {
+            self.window.set_cursor_grab(CursorGrabMode::None).ok();
+        }
+        self.window.set_cursor_visible(!grab);
+        self.controller.cursor_grabbed = grab;
     }
 
-    async fn new(window: &'a Window) -> Self {
+    async fn new(window: &'a Window, scene: &Scene, adapter_options: &AdapterOptions) -> Self {
         let size = window.inner_size();
 
-        let instance_descriptor = wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::all(),
-            ..Default::default()
-        };
-        let instance = wgpu::Instance::new(instance_descriptor);
+        let instance = adapter::create_instance(adapter_options);
         let surface = instance.create_surface(window).unwrap();
 
-        // Pick the second adapter (NVIDIA 3060 RTX)
-        let adapter = instance
-            .enumerate_adapters(wgpu::Backends::all())
-            .into_iter()
-            .nth(1)
-            .unwrap();
-        println!("{:?}", adapter.get_info());
-
-        let device_descriptor = wgpu::DeviceDescriptor {
-            required_features: wgpu::Features::empty(),
-            required_limits: wgpu::Limits::default(),
-            label: Some("Device"),
-        };
-        let (device, queue) = adapter
-            .request_device(&device_descriptor, None)
-            .await
-            .unwrap();
+        let adapter = adapter::request_adapter(&instance, adapter_options, Some(&surface)).await;
+        let (device, queue) = adapter::request_device(&adapter, scene).await;
 
         let surface_capabilities = surface.get_capabilities(&adapter);
         let surface_format = surface_capabilities
             .formats
             .iter()
             .copied()
-            .filter(|f| f.is_srgb())
-            .next()
+            .find(|f| f.is_srgb())
             .unwrap_or(surface_capabilities.formats[0]);
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -189,245 +183,29 @@ impl<'a> State<'a> {
         };
         surface.configure(&device, &config);
 
-        // Create bind group layout
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 4,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 5,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 6,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
-            label: Some("Sphere Bind Group Layout"),
-        });
-
-        // Pass bind group layout to pipeline builder
-        let mut pipeline_builder = PipelineBuilder::new();
-        pipeline_builder.set_shader_module("shaders/shader.wgsl", "vs_main", "fs_main");
-        pipeline_builder.set_pixel_format(config.format);
-        pipeline_builder.set_bind_group_layout(bind_group_layout);
-        let render_pipeline = pipeline_builder.build_pipeline(&device);
-
-        // Create a temporary bind group
-        let temp_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Temporary Bind Group"),
-            layout: &device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[],
-                label: Some("Temporary Bind Group Layout"),
-            }),
-            entries: &[],
-        });
-
-        // Create sphere data - Format: [x, y, z, radius, r, g, b, er, eg, eb, emission_strength, smoothness]
-        let mut sphere_data: Vec<Vec<f32>> = Vec::new();
-        // sphere_data.push(vec![
-        //     -40.0, 0.0, 0.0, 10.0, 0.25, 0.25, 1.0, 0.0, 0.0, 0.0, 0.0, 0.9,
-        // ]);
-        // sphere_data.push(vec![
-        //     40.0, 0.0, 0.0, 10.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
-        // ]);
-        // sphere_data.push(vec![
-        //     0.0, -20.0, 0.0, 10.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-        // ]);
-        // sphere_data.push(vec![
-        //     0.0, -5030.0, 0.0, 5000.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-        // ]);
-        // sphere_data.push(vec![
-        //     40.0, 40.0, -20.0, 10.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 10.0, 0.0,
-        // ]); // Light source
-        sphere_data.push(vec![
-            999999.0, 999999.0, 9999999.0, 10.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 10.0, 0.0,
-        ]); // Placeholder data for sphere that isn't visible in scene
-
-        let sphere_data_u8: Vec<u8> = sphere_data
-            .iter()
-            .flat_map(|s| s.iter().map(|f| f.to_ne_bytes().to_vec()).flatten())
-            .collect();
-
-        // Buffer for sphere data
-        let sphere_buffer = device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("Sphere Buffer Data"),
-            contents: bytemuck::cast_slice(&sphere_data_u8),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
-
-        // Write sphere data to buffer
-        queue.write_buffer(&sphere_buffer, 0, bytemuck::cast_slice(&sphere_data_u8));
-
-        // Load triangle data
-        let triangle_data = include_bytes!("../objects/teapot.bin");
-        let triangle_data = triangle_data.to_vec();
-        let triangle_data = triangle_data.chunks(4).collect::<Vec<_>>();
-        let triangle_data = triangle_data
-            .iter()
-            .map(|d| f32::from_ne_bytes([d[0], d[1], d[2], d[3]]))
-            .collect::<Vec<_>>();
-
-        // Buffer for triangle data
-        let triangle_buffer = device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("Triangle Buffer Data"),
-            contents: bytemuck::cast_slice(&triangle_data),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
-
-        // Write triangle data to buffer
-        queue.write_buffer(&triangle_buffer, 0, bytemuck::cast_slice(&triangle_data));
-
-        // Create a bounding box for the triangles
-        let bvh_data = include_bytes!("../objects/teapot_bvh.bin");
-        let bvh_data = bvh_data.to_vec();
-        let bvh_data = bvh_data.chunks(4).collect::<Vec<_>>();
-        let bvh_data = bvh_data
-            .iter()
-            .map(|d| f32::from_ne_bytes([d[0], d[1], d[2], d[3]]))
-            .collect::<Vec<_>>();
-
-        // Buffer for the bounding box
-        let bvh_buffer = device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("Bounding Box Buffer Data"),
-            contents: bytemuck::cast_slice(&bvh_data),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
-
-        // Buffer for the frame count
-        let frame_count_buffer = device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("Frame Count Buffer"),
-            contents: bytemuck::cast_slice(&[0]),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
-
-        // Frame data that starts off completely black
-        let frame_data =
-            vec![vec![vec![0.0; 3]; size.width as usize]; ((size.height as f32) * 1.5) as usize];
-        let frame_data_flat: Vec<f32> = frame_data.iter().flatten().flatten().copied().collect();
-        // let frame_data_u8: Vec<u8> = frame_data_flat
-        //     .iter()
-        //     .map(|f| f.to_ne_bytes().to_vec())
-        //     .flatten()
-        //     .collect();
-
-        // Buffer for the frame data
-        let frame_data_buffer = device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("Frame Data Buffer"),
-            contents: bytemuck::cast_slice(&frame_data_flat),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
-
-        // Camera data
-        let camera_position = [200.0, 0.0, 200.0];
-        let camera_rotation = [0.0, 90.0, 0.0];
-
-        // Buffer for the camera position
-        let camera_position_buffer = device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("Camera Position Buffer"),
-            contents: bytemuck::cast_slice(&camera_position),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
-
-        // Buffer for the camera rotation
-        let camera_rotation_buffer = device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("Camera Rotation Buffer"),
-            contents: bytemuck::cast_slice(&camera_rotation),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
-
-        // Write data to camera buffers
-        queue.write_buffer(
-            &camera_position_buffer,
-            0,
-            bytemuck::cast_slice(&camera_position),
-        );
-        queue.write_buffer(
-            &camera_rotation_buffer,
-            0,
-            bytemuck::cast_slice(&camera_rotation),
-        );
+        let renderer = Renderer::new(device, queue, config.format, size.width, size.height, scene);
+        let ui = Ui::new(window, &renderer.device, config.format);
+
+        // The orbit camera turns around the center of the mesh
+        let focus_bounds = scene.focus_bounds();
+        let pivot = (Vector3::from(focus_bounds.0) + Vector3::from(focus_bounds.1)) * 0.5;
 
         Self {
             window,
             surface,
-            device,
-            queue,
             config,
             size,
-            render_pipeline,
-            bind_group: temp_bind_group,
-            frame_count: 0,
-            frame_count_buffer,
-            frame_data_buffer,
-            sphere_buffer,
-            triangle_buffer,
-            bvh_buffer,
-            camera_position,
-            camera_rotation,
-            camera_position_buffer,
-            camera_rotation_buffer,
-            keys_pressed: [false; 12],
+            renderer,
+            ui,
+            camera_mode: CameraMode::Fly,
+            controller: FlyController::new(),
+            orbit_controller: OrbitController::new(pivot),
+            focus_bounds,
+            last_update: std::time::Instant::now(),
+            use_preview: true,
+            use_reprojection: true,
+            last_change: std::time::Instant::now(),
+            leaving_preview: false,
             tick: 0,
         }
     }
@@ -437,79 +215,27 @@ impl<'a> State<'a> {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+            self.surface.configure(&self.renderer.device, &self.config);
+
+            // The accumulated frames don't fit the new size anymore
+            self.renderer.resize(new_size.width, new_size.height);
         }
     }
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         let start_time = std::time::Instant::now();
 
-        // Update the frame count buffer before rendering
-        self.queue.write_buffer(
-            &self.frame_count_buffer,
-            0,
-            bytemuck::cast_slice(&[self.frame_count]),
-        );
-
-        // Update camera position and rotation buffers before rendering
-        self.queue.write_buffer(
-            &self.camera_position_buffer,
-            0,
-            bytemuck::cast_slice(&self.camera_position),
-        );
-        self.queue.write_buffer(
-            &self.camera_rotation_buffer,
-            0,
-            bytemuck::cast_slice(&self.camera_rotation),
-        );
-
         let drawable = self.surface.get_current_texture()?;
         let image_view_descriptor = wgpu::TextureViewDescriptor::default();
         let image_view = drawable.texture.create_view(&image_view_descriptor);
 
-        let command_encoder_descriptor = wgpu::CommandEncoderDescriptor {
-            label: Some("Render Encoder"),
-        };
-        let mut command_encoder = self
-            .device
-            .create_command_encoder(&command_encoder_descriptor);
-        let color_attachment = wgpu::RenderPassColorAttachment {
-            view: &image_view,
-            resolve_target: None,
-            ops: wgpu::Operations {
-                load: wgpu::LoadOp::Clear(wgpu::Color {
-                    r: 0.75,
-                    g: 0.5,
-                    b: 0.25,
-                    a: 1.0,
-                }),
-                store: wgpu::StoreOp::Store,
-            },
-        };
-
-        let render_pass_descriptor = wgpu::RenderPassDescriptor {
-            label: Some("Render Pass"),
-            color_attachments: &[Some(color_attachment)],
-            depth_stencil_attachment: None,
-            occlusion_query_set: None,
-            timestamp_writes: None,
-        };
-
-        {
-            let mut render_pass = command_encoder.begin_render_pass(&render_pass_descriptor);
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, &self.bind_group, &[]); // Access using self
-            render_pass.draw(0..3, 0..1); // Draw the first triangle
-            render_pass.draw(3..6, 0..1); // Draw the second triangle
-        }
-
-        self.queue.submit(std::iter::once(command_encoder.finish()));
+        self.update_preview();
+        self.renderer.render(&image_view);
+        self.ui.draw(self.window, &mut self.renderer, &image_view);
 
         drawable.present();
 
-        self.frame_count += 1;
-
-        if self.tick % 10 == 0 {
+        if self.tick.is_multiple_of(10) {
             let elapsed_time = start_time.elapsed();
             println!(
                 "fps: {}",
@@ -528,14 +254,13 @@ enum CustomEvent {
     Timer,
 }
 
-async fn run() {
-    env_logger::init();
+async fn run(options: &Options, scene: &Scene) {
 
     let event_loop = EventLoopBuilder::<CustomEvent>::with_user_event()
         .build()
         .unwrap();
     let window = WindowBuilder::new()
-        .with_inner_size(PhysicalSize::new(SCREEN_SIZE.0, SCREEN_SIZE.1))
+        .with_inner_size(LogicalSize::new(options.width, options.height))
         .build(&event_loop)
         .unwrap();
     let event_loop_proxy = event_loop.create_proxy();
@@ -545,144 +270,51 @@ async fn run() {
         event_loop_proxy.send_event(CustomEvent::Timer).ok();
     });
 
-    let mut state = State::new(&window).await;
-
-    // Create bind group layout
-    let bind_group_layout =
-        state
-            .device
-            .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: false },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 3,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 4,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 5,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 6,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                ],
-                label: Some("Sphere Bind Group Layout"),
-            });
-    state.bind_group = state.device.create_bind_group(&wgpu::BindGroupDescriptor {
-        label: Some("Sphere Bind Group"),
-        layout: &bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: state.sphere_buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: state.frame_count_buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 2,
-                resource: state.frame_data_buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 3,
-                resource: state.camera_position_buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 4,
-                resource: state.camera_rotation_buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 5,
-                resource: state.triangle_buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 6,
-                resource: state.bvh_buffer.as_entire_binding(),
-            },
-        ],
-    });
-
-    // Pass bind group layout to pipeline builder
-    let mut pipeline_builder = PipelineBuilder::new();
-    pipeline_builder.set_shader_module("shaders/shader.wgsl", "vs_main", "fs_main");
-    pipeline_builder.set_pixel_format(state.config.format);
-    pipeline_builder.set_bind_group_layout(bind_group_layout);
-    state.render_pipeline = pipeline_builder.build_pipeline(&state.device);
+    let mut state = State::new(&window, scene, &options.adapter).await;
+    options.apply_display(&mut state.renderer.display);
+    options.apply_settings(&mut state.renderer.settings);
+    if options.render_scale != 1.0 {
+        state.renderer.set_render_scale(options.render_scale);
+    }
 
     event_loop
         .run(move |event, elwt| match event {
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta },
+                ..
+            } => state.process_mouse_motion(delta.0 as f32, delta.1 as f32),
+
             Event::UserEvent(..) => {
                 state.update_camera();
                 state.window.request_redraw();
             }
 
+            // The settings panel gets the window events first
+            Event::WindowEvent {
+                window_id,
+                ref event,
+            } if window_id == state.window.id() && state.ui.on_window_event(state.window, event) => {
+                state.window.request_redraw()
+            }
+
             Event::WindowEvent {
                 window_id,
                 ref event,
             } if window_id == state.window.id() => match event {
                 WindowEvent::Resized(physical_size) => state.resize(*physical_size),
 
-                WindowEvent::CloseRequested
-                | WindowEvent::KeyboardInput {
+                // A Resized event with the new physical size follows
+                WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                    println!("Scale factor: {}", scale_factor)
+                }
+
+                WindowEvent::CloseRequested => {
+                    println!("Closing window");
+                    elwt.exit();
+                }
+
+                // Escape releases the cursor first, and closes the window if it's free
+                WindowEvent::KeyboardInput {
                     event:
                         KeyEvent {
                             physical_key: PhysicalKey::Code(KeyCode::Escape),
@@ -692,33 +324,121 @@ async fn run() {
                         },
                     ..
                 } => {
-                    println!("Closing window");
-                    elwt.exit();
+                    if state.controller.cursor_grabbed {
+                        state.set_cursor_grab(false);
+                    } else {
+                        println!("Closing window");
+                        elwt.exit();
+                    }
+                }
+
+                WindowEvent::MouseInput {
+                    state: element_state,
+                    button,
+                    ..
+                } => state.process_mouse_button(*button, *element_state == ElementState::Pressed),
+
+                WindowEvent::Focused(false) if state.controller.cursor_grabbed => {
+                    state.set_cursor_grab(false)
                 }
 
+                WindowEvent::MouseWheel { delta, .. } => match delta {
+                    MouseScrollDelta::LineDelta(_, lines) => state.process_scroll(*lines),
+                    MouseScrollDelta::PixelDelta(position) => state.process_scroll(position.y as f32 / 50.0),
+                },
+
                 WindowEvent::KeyboardInput {
                     event:
                         KeyEvent {
-                            physical_key,
+                            physical_key: PhysicalKey::Code(code),
                             state: element_state,
                             ..
                         },
                     ..
                 } => {
                     let pressed = *element_state == ElementState::Pressed;
-                    match physical_key {
-                        PhysicalKey::Code(KeyCode::KeyW) => state.keys_pressed[0] = pressed,
-                        PhysicalKey::Code(KeyCode::KeyS) => state.keys_pressed[1] = pressed,
-                        PhysicalKey::Code(KeyCode::KeyD) => state.keys_pressed[2] = pressed,
-                        PhysicalKey::Code(KeyCode::KeyA) => state.keys_pressed[3] = pressed,
-                        PhysicalKey::Code(KeyCode::Space) => state.keys_pressed[4] = pressed,
-                        PhysicalKey::Code(KeyCode::ShiftLeft) => state.keys_pressed[5] = pressed,
-                        PhysicalKey::Code(KeyCode::KeyI) => state.keys_pressed[6] = pressed,
-                        PhysicalKey::Code(KeyCode::KeyK) => state.keys_pressed[7] = pressed,
-                        PhysicalKey::Code(KeyCode::KeyL) => state.keys_pressed[8] = pressed,
-                        PhysicalKey::Code(KeyCode::KeyJ) => state.keys_pressed[9] = pressed,
-                        PhysicalKey::Code(KeyCode::KeyO) => state.keys_pressed[10] = pressed,
-                        PhysicalKey::Code(KeyCode::KeyU) => state.keys_pressed[11] = pressed,
+                    // The fly controller keeps track of its keys in every mode, so none get stuck
+                    if state.controller.process_key(*code, pressed) {
+                        return;
+                    }
+                    match code {
+                        KeyCode::F1 if pressed => state.ui.visible = !state.ui.visible,
+
+                        KeyCode::KeyP if pressed => {
+                            state.use_preview = !state.use_preview;
+                            println!("Preview while moving: {}", if state.use_preview { "on" } else { "off" });
+                        }
+
+                        KeyCode::KeyR if pressed => {
+                            state.use_reprojection = !state.use_reprojection;
+                            println!("Reprojection: {}", if state.use_reprojection { "on" } else { "off" });
+                        }
+
+                        // The denoiser filters the accumulated image, so the frames are kept
+                        KeyCode::KeyN if pressed => {
+                            state.renderer.settings.toggle_denoise();
+                            println!("Denoiser: {}", if state.renderer.settings.denoise { "on" } else { "off" });
+                        }
+
+                        KeyCode::KeyM if pressed => {
+                            state.renderer.settings.cycle_debug_mode();
+                            println!("Debug mode: {:?}", state.renderer.settings.debug_mode);
+                            state.renderer.frame_count = 0;
+                        }
+                        KeyCode::KeyC if pressed => state.toggle_camera_mode(),
+                        KeyCode::KeyF if pressed => state.frame_scene(),
+
+                        KeyCode::KeyV if pressed && state.camera_mode == CameraMode::Fly => {
+                            state.controller.toggle_up_mode(&mut state.renderer.camera);
+                            state.renderer.frame_count = 0;
+                        }
+
+                        // Render settings change the traced image, so the accumulation starts over
+                        KeyCode::Digit1
+                        | KeyCode::Digit2
+                        | KeyCode::Digit3
+                        | KeyCode::Digit4
+                        | KeyCode::Digit5
+                        | KeyCode::Digit6
+                        | KeyCode::Digit0
+                            if pressed =>
+                        {
+                            let settings = &mut state.renderer.settings;
+                            match code {
+                                KeyCode::Digit1 => settings.decrease_bounces(),
+                                KeyCode::Digit2 => settings.increase_bounces(),
+                                KeyCode::Digit3 => settings.decrease_rays_per_pixel(),
+                                KeyCode::Digit4 => settings.increase_rays_per_pixel(),
+                                KeyCode::Digit5 => settings.decrease_fov(),
+                                KeyCode::Digit6 => settings.increase_fov(),
+                                _ => settings.reset(),
+                            }
+                            println!(
+                                "Bounces: {}, rays per pixel: {}, field of view: {}°",
+                                settings.max_bounce_count, settings.rays_per_pixel, settings.fov
+                            );
+                            state.renderer.frame_count = 0;
+                        }
+
+                        // Display controls only change the presentation, so the accumulation is kept
+                        code if pressed => {
+                            let display = &mut state.renderer.display;
+                            match code {
+                                KeyCode::Equal => display.increase_exposure(),
+                                KeyCode::Minus => display.decrease_exposure(),
+                                KeyCode::KeyT => display.cycle_tone_mapping(),
+                                KeyCode::BracketRight => display.increase_temperature(),
+                                KeyCode::BracketLeft => display.decrease_temperature(),
+                                KeyCode::Quote => display.increase_tint(),
+                                KeyCode::Semicolon => display.decrease_tint(),
+                                KeyCode::Backspace => display.reset(),
+                                _ => return,
+                            }
+                            println!(
+                                "Exposure: {:+.1} EV, tone mapping: {:?}, temperature: {}K, tint: {:+.1}",
+                                display.exposure, display.tone_mapping, display.temperature, display.tint
+                            );
+                        }
 
                         _ => {}
                     }
@@ -739,6 +459,65 @@ async fn run() {
         .expect("Error!");
 }
 
+// Without reprojection a camera move restarts the accumulation, which shows the preview. With it
+// the renderer keeps what it can of the accumulation itself, and the camera stays out of the
+// preview: switching to the preview resolution would throw the accumulation away.
+fn camera_moved(renderer: &mut Renderer, use_reprojection: bool) {
+    if !use_reprojection {
+        renderer.frame_count = 0;
+    }
+}
+
 fn main() {
-    pollster::block_on(run());
+    env_logger::init();
+
+    let options = Options::from_args();
+    if options.regression {
+        let passed = pollster::block_on(regression::run(&options));
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    let scene = match &options.scene {
+        Some(path) => scene_file::load_scene(path).unwrap_or_else(|message| {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }),
+        None => Scene::teapot(),
+    };
+    if options.output.is_some() {
+        pollster::block_on(headless::run(&options, &scene));
+    } else {
+        pollster::block_on(run(&options, &scene));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A small orbit step keeps the accumulated samples of nearly every pixel
+    #[test]
+    fn reprojection_keeps_frame_data_through_a_camera_move() {
+        let (_, scene, _) = regression::canonical_scenes()
+            .into_iter()
+            .find(|(name, _, _)| *name == "spheres")
+            .unwrap();
+        let adapter_options = AdapterOptions::from_env();
+        let mut renderer = pollster::block_on(headless::create_gpu_renderer(&adapter_options, &scene, 80, 40));
+        let target = headless::create_offscreen_target(&renderer);
+        for _ in 0..4 {
+            renderer.render(&target);
+        }
+
+        renderer.camera.position += renderer.camera.right() * 0.05;
+        camera_moved(&mut renderer, true);
+        assert_eq!(renderer.frame_count, 4, "The move restarted the accumulation and would show the preview");
+        renderer.render(&target);
+        renderer.device.poll(wgpu::Maintain::Wait);
+
+        let rays_per_pixel = renderer.settings.rays_per_pixel as f32;
+        let pixels = renderer.read_frame_pixels();
+        let kept = pixels.iter().filter(|pixel| pixel.moments.y > rays_per_pixel).count();
+        assert!(kept * 10 >= pixels.len() * 9, "Only {} of {} pixels kept their samples", kept, pixels.len());
+    }
 }
@@ -0,0 +1,270 @@
+// Analytic surfaces besides spheres, intersected by their own routines in shader.wgsl and
+// cpu_renderer.rs. Planes, quads and disks are one-sided like the triangles of the mesh: they're
+// only visible from the side their normal points to. Boxes, cylinders, cones and tori are closed
+// solids, hit where the ray enters them.
+use crate::scene::{Material, Scene, Sphere, FLOATS_PER_MATERIAL};
+use bvh_generator::bvh::{self, Bounded, FLOATS_PER_NODE};
+use bvh_generator::vector::Vector;
+use cgmath::{Deg, InnerSpace, Quaternion, Rotation, Rotation3, Vector3};
+
+// Floats per shape in the primitive data: the kind and the parameters
+pub const FLOATS_PER_SHAPE: usize = 13;
+
+// Floats per primitive in the data of the spheres and primitives: the shape, then the material
+pub const FLOATS_PER_PRIMITIVE: usize = FLOATS_PER_SHAPE + FLOATS_PER_MATERIAL;
+
+// Max depth of the BVH over the primitives, must match shader.wgsl
+const BVH_MAX_DEPTH: i32 = 16;
+
+// Kinds of the shape data, must match shader.wgsl
+pub const SPHERE_KIND: f32 = 0.0;
+const PLANE_KIND: f32 = 1.0;
+const BOX_KIND: f32 = 2.0;
+const QUAD_KIND: f32 = 3.0;
+const DISK_KIND: f32 = 4.0;
+const CYLINDER_KIND: f32 = 5.0;
+const CONE_KIND: f32 = 6.0;
+const TORUS_KIND: f32 = 7.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    // Infinite, facing the normal
+    Plane { point: [f32; 3], normal: [f32; 3] },
+    // Rotated by degrees around x, then y, then z like the camera, axis aligned without rotation
+    Box { center: [f32; 3], size: [f32; 3], rotation: [f32; 3] },
+    // Parallelogram facing edge_u x edge_v, which is counterclockwise seen from the front
+    Quad { corner: [f32; 3], edge_u: [f32; 3], edge_v: [f32; 3] },
+    Disk { center: [f32; 3], normal: [f32; 3], radius: f32 },
+    // Closed by a disk at both ends
+    Cylinder { base: [f32; 3], top: [f32; 3], radius: f32 },
+    // Closed by a disk at the base
+    Cone { base: [f32; 3], apex: [f32; 3], radius: f32 },
+    // The tube of minor_radius goes around the axis at major_radius from the center
+    Torus { center: [f32; 3], axis: [f32; 3], major_radius: f32, minor_radius: f32 },
+}
+
+impl Shape {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shape::Plane { .. } => "Plane",
+            Shape::Box { .. } => "Box",
+            Shape::Quad { .. } => "Quad",
+            Shape::Disk { .. } => "Disk",
+            Shape::Cylinder { .. } => "Cylinder",
+            Shape::Cone { .. } => "Cone",
+            Shape::Torus { .. } => "Torus",
+        }
+    }
+
+    // Format: [kind, parameters...], unused parameters are 0.
+    // Plane: point, unit normal. Box: center, then the three half size axes. Quad: corner, edge_u,
+    // edge_v. Disk: center, unit normal, radius. Cylinder: base, base to top, radius. Cone: base,
+    // base to apex, radius. Torus: center, unit axis, major radius, minor radius.
+    pub fn to_data(self) -> [f32; FLOATS_PER_SHAPE] {
+        let (kind, parameters): (f32, Vec<f32>) = match self {
+            Shape::Plane { point, normal } => (PLANE_KIND, [point, unit(normal)].concat()),
+            Shape::Box { center, size, rotation } => {
+                let (center, axes) = box_axes(center, size, rotation);
+                (BOX_KIND, [center, axes[0], axes[1], axes[2]].map(Into::<[f32; 3]>::into).concat())
+            }
+            Shape::Quad { corner, edge_u, edge_v } => (QUAD_KIND, [corner, edge_u, edge_v].concat()),
+            Shape::Disk { center, normal, radius } => (DISK_KIND, [&center[..], &unit(normal), &[radius]].concat()),
+            Shape::Cylinder { base, top, radius } => {
+                let axis: [f32; 3] = (Vector3::from(top) - Vector3::from(base)).into();
+                (CYLINDER_KIND, [&base[..], &axis, &[radius]].concat())
+            }
+            Shape::Cone { base, apex, radius } => {
+                let axis: [f32; 3] = (Vector3::from(apex) - Vector3::from(base)).into();
+                (CONE_KIND, [&base[..], &axis, &[radius]].concat())
+            }
+            Shape::Torus { center, axis, major_radius, minor_radius } => (
+                TORUS_KIND,
+                [&center[..], &unit(axis), &[major_radius, minor_radius]].concat(),
+            ),
+        };
+        let mut data = [0.0; FLOATS_PER_SHAPE];
+        data[0] = kind;
+        data[1..1 + parameters.len()].copy_from_slice(&parameters);
+        data
+    }
+
+    // Box around the shape, None for planes
+    pub fn bounds(&self) -> Option<([f32; 3], [f32; 3])> {
+        // Box around a circle, it reaches radius * sin(angle to the axis) along every axis
+        let circle = |center: Vector3<f32>, normal: Vector3<f32>, radius: f32| {
+            let normal = normal.normalize();
+            let extent = normal.map(|n| radius * (1.0 - n * n).max(0.0).sqrt());
+            (center - extent, center + extent)
+        };
+        let union = |a: (Vector3<f32>, Vector3<f32>), b: (Vector3<f32>, Vector3<f32>)| {
+            (a.0.zip(b.0, f32::min), a.1.zip(b.1, f32::max))
+        };
+
+        let (min, max) = match *self {
+            Shape::Plane { .. } => return None,
+            Shape::Box { center, size, rotation } => {
+                let (center, axes) = box_axes(center, size, rotation);
+                let extent = axes.iter().fold(Vector3::new(0.0, 0.0, 0.0), |sum, a| sum + a.map(f32::abs));
+                (center - extent, center + extent)
+            }
+            Shape::Quad { corner, edge_u, edge_v } => {
+                let (corner, u, v) = (Vector3::from(corner), Vector3::from(edge_u), Vector3::from(edge_v));
+                union((corner, corner + u + v), union((corner + u, corner + u), (corner + v, corner + v)))
+            }
+            Shape::Disk { center, normal, radius } => circle(center.into(), normal.into(), radius),
+            Shape::Cylinder { base, top, radius } => {
+                let axis = Vector3::from(top) - Vector3::from(base);
+                union(circle(base.into(), axis, radius), circle(top.into(), axis, radius))
+            }
+            Shape::Cone { base, apex, radius } => {
+                let axis = Vector3::from(apex) - Vector3::from(base);
+                union(circle(base.into(), axis, radius), (apex.into(), apex.into()))
+            }
+            Shape::Torus { center, axis, major_radius, minor_radius } => {
+                let (min, max) = circle(center.into(), axis.into(), major_radius);
+                let tube = Vector3::new(minor_radius, minor_radius, minor_radius);
+                (min - tube, max + tube)
+            }
+        };
+        Some((min.into(), max.into()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Primitive {
+    pub shape: Shape,
+    pub material: Material,
+}
+
+impl Primitive {
+    // Format: the shape, then the material
+    pub fn to_data(self) -> [f32; FLOATS_PER_PRIMITIVE] {
+        let mut data = [0.0; FLOATS_PER_PRIMITIVE];
+        data[..FLOATS_PER_SHAPE].copy_from_slice(&self.shape.to_data());
+        data[FLOATS_PER_SHAPE..].copy_from_slice(&self.material.to_data());
+        data
+    }
+}
+
+// The spheres and primitives of a scene for the renderers, in the order of their BVH. The ones with
+// bounds come first and are sorted into the BVH, the unbounded planes follow them and are tested
+// one by one.
+pub struct PrimitiveData {
+    pub data: Vec<f32>, // FLOATS_PER_PRIMITIVE floats per primitive
+    pub materials: Vec<Material>, // Material of every primitive in data, for the CPU renderer
+    pub bounded_count: u32,
+    pub bvh: Vec<f32>, // The mesh's BVH nodes followed by those of the primitives, like Mesh::bvh
+    pub bvh_root: u32, // Node where the primitives' BVH starts
+    pub slots: Vec<usize>, // Index in data of every sphere, then every other primitive of the scene
+}
+
+// A sphere or primitive for the BVH builder, index is its position in the scene
+struct BvhItem {
+    index: usize,
+    min: Vector,
+    max: Vector,
+}
+
+impl Bounded for BvhItem {
+    fn center(&self) -> Vector {
+        (self.min + self.max) / 2.0
+    }
+
+    fn min(&self) -> Vector {
+        self.min
+    }
+
+    fn max(&self) -> Vector {
+        self.max
+    }
+}
+
+impl PrimitiveData {
+    pub fn new(scene: &Scene) -> Self {
+        // The shader needs at least one primitive, so an empty scene gets a placeholder sphere
+        // that isn't visible in the scene
+        let placeholder = Sphere {
+            center: [999999.0, 999999.0, 9999999.0],
+            radius: 10.0,
+            material: Material::light([1.0, 0.0, 0.0], 10.0),
+        };
+        let spheres = if scene.spheres.is_empty() && scene.primitives.is_empty() {
+            std::slice::from_ref(&placeholder)
+        } else {
+            &scene.spheres[..]
+        };
+
+        // Every primitive's data and bounds, in the order of the scene
+        let sphere_items = spheres.iter().map(|sphere| {
+            let min = [0, 1, 2].map(|i| sphere.center[i] - sphere.radius);
+            let max = [0, 1, 2].map(|i| sphere.center[i] + sphere.radius);
+            (sphere.to_data(), Some((min, max)), sphere.material)
+        });
+        let primitive_items = scene
+            .primitives
+            .iter()
+            .map(|primitive| (primitive.to_data(), primitive.shape.bounds(), primitive.material));
+        let items: Vec<_> = sphere_items.chain(primitive_items).collect();
+
+        let mut bvh_items: Vec<BvhItem> = items
+            .iter()
+            .enumerate()
+            .filter_map(|(index, (_, bounds, _))| {
+                let (min, max) = (*bounds)?;
+                Some(BvhItem {
+                    index,
+                    min: Vector::new(min[0], min[1], min[2]),
+                    max: Vector::new(max[0], max[1], max[2]),
+                })
+            })
+            .collect();
+        let nodes = bvh::build_bvh(&mut bvh_items, BVH_MAX_DEPTH);
+
+        // The bounded primitives in the order of the BVH, then the planes
+        let bounded_count = bvh_items.len() as u32;
+        let unbounded = (0..items.len()).filter(|&index| items[index].1.is_none());
+        let order: Vec<usize> = bvh_items.iter().map(|item| item.index).chain(unbounded).collect();
+        let mut slots = vec![0; items.len()];
+        for (slot, &index) in order.iter().enumerate() {
+            slots[index] = slot;
+        }
+
+        // The primitives' nodes follow the mesh's, so their child indices move by its node count
+        let mesh_node_count = scene.mesh.bvh.len() / FLOATS_PER_NODE;
+        let mut bvh = scene.mesh.bvh.clone();
+        for mut node in bvh::node_data(&nodes).chunks(FLOATS_PER_NODE).map(<[f32]>::to_vec) {
+            if node[8] != 0.0 {
+                node[8] += mesh_node_count as f32;
+            }
+            bvh.extend(node);
+        }
+
+        PrimitiveData {
+            data: order.iter().flat_map(|&index| items[index].0).collect(),
+            materials: order.iter().map(|&index| items[index].2).collect(),
+            bounded_count,
+            bvh,
+            bvh_root: mesh_node_count as u32,
+            slots,
+        }
+    }
+
+    // Format: [bvh_root, bounded_count, unused, unused]
+    pub fn layout_data(&self) -> [u32; 4] {
+        [self.bvh_root, self.bounded_count, 0, 0]
+    }
+}
+
+fn unit(v: [f32; 3]) -> [f32; 3] {
+    Vector3::from(v).normalize().into()
+}
+
+// Center and half size axes of a box
+fn box_axes(center: [f32; 3], size: [f32; 3], rotation: [f32; 3]) -> (Vector3<f32>, [Vector3<f32>; 3]) {
+    let orientation = Quaternion::from_angle_z(Deg(rotation[2]))
+        * Quaternion::from_angle_y(Deg(rotation[1]))
+        * Quaternion::from_angle_x(Deg(rotation[0]));
+    let axes = [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()];
+    let axes = [0, 1, 2].map(|i| orientation.rotate_vector(axes[i]) * (0.5 * size[i]));
+    (center.into(), axes)
+}
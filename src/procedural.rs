@@ -0,0 +1,101 @@
+// Procedural patterns of texture.rs for the CPU renderer, like their versions in shader.wgsl.
+// Every pattern returns how far it is from its first color (0) to its second one (1), and takes
+// the width of the pixel's footprint in its coordinates to leave out detail smaller than a pixel.
+use crate::cpu_renderer::hash;
+use cgmath::{InnerSpace, Vector2, Vector3};
+
+// --- Pattern Parameters --- Must match shader.wgsl
+#[allow(clippy::approx_constant)]
+const PI: f32 = 3.14159;
+const SOLID_OCTAVES: u32 = 5; // Octaves of the noise that bends marble veins and wood rings
+
+// Box filtered checker board of unit squares
+pub fn checker(p: Vector2<f32>, footprint: f32) -> f32 {
+    let w = footprint.max(1e-4);
+    let triangle = |x: f32| {
+        (fract((x - 0.5 * w) * 0.5) - 0.5).abs() - (fract((x + 0.5 * w) * 0.5) - 0.5).abs()
+    };
+    let i = Vector2::new(triangle(p.x), triangle(p.y)) * 2.0 / w;
+    0.5 - 0.5 * i.x * i.y
+}
+
+// Box filtered grid of unit cells, 0 on the lines
+pub fn grid(p: Vector2<f32>, footprint: f32, line_width: f32) -> f32 {
+    let w = footprint.max(1e-4);
+    let n = 1.0 / line_width.max(1e-4);
+    let coverage = |x: f32| {
+        let (a, b) = (x + 0.5 * w, x - 0.5 * w);
+        (a.floor() + (fract(a) * n).min(1.0) - b.floor() - (fract(b) * n).min(1.0)) / (n * w)
+    };
+    (1.0 - coverage(p.x)) * (1.0 - coverage(p.y))
+}
+
+pub fn noise(p: Vector3<f32>, footprint: f32, octaves: u32) -> f32 {
+    (0.5 + fbm(p, footprint, octaves)).clamp(0.0, 1.0)
+}
+
+// Veins where a sine along x crosses zero, bent by the noise
+pub fn marble(p: Vector3<f32>, footprint: f32, turbulence: f32) -> f32 {
+    ((p.x + turbulence * fbm(p, footprint, SOLID_OCTAVES)) * PI).sin().abs().sqrt()
+}
+
+// A ring every unit away from the y axis, bent by the noise
+pub fn wood(p: Vector3<f32>, footprint: f32, turbulence: f32) -> f32 {
+    let r = Vector2::new(p.x, p.z).magnitude() + turbulence * fbm(p, footprint, SOLID_OCTAVES);
+    fract(r)
+}
+
+// Fractal Brownian motion: octaves of Perlin noise, each twice as fine and half as strong.
+// Octaves finer than the footprint would only add aliasing and average out to 0.
+fn fbm(p: Vector3<f32>, footprint: f32, octaves: u32) -> f32 {
+    let mut sum = 0.0;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.0;
+    for _ in 0..octaves {
+        if frequency * footprint > 1.0 {
+            break;
+        }
+        sum += amplitude * perlin(p * frequency);
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum
+}
+
+// Improved Perlin noise, with the gradients picked by hashing the corners of the lattice cell
+fn perlin(p: Vector3<f32>) -> f32 {
+    let cell = p.map(f32::floor);
+    let f = p - cell;
+    let u = f.map(|t| t * t * t * (t * (t * 6.0 - 15.0) + 10.0));
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        let h = hash(x.wrapping_add(dx) as u32 ^ hash(y.wrapping_add(dy) as u32 ^ hash(z.wrapping_add(dz) as u32)));
+        gradient(h, f - Vector3::new(dx as f32, dy as f32, dz as f32))
+    };
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let x00 = lerp(corner(0, 0, 0), corner(1, 0, 0), u.x);
+    let x10 = lerp(corner(0, 1, 0), corner(1, 1, 0), u.x);
+    let x01 = lerp(corner(0, 0, 1), corner(1, 0, 1), u.x);
+    let x11 = lerp(corner(0, 1, 1), corner(1, 1, 1), u.x);
+    lerp(lerp(x00, x10, u.y), lerp(x01, x11, u.y), u.z)
+}
+
+// Dot product with one of the 12 edge directions of a cube, Perlin's bit trick
+fn gradient(corner_hash: u32, p: Vector3<f32>) -> f32 {
+    let h = corner_hash & 15;
+    let u = if h < 8 { p.x } else { p.y };
+    let v = if h < 4 {
+        p.y
+    } else if h == 12 || h == 14 {
+        p.x
+    } else {
+        p.z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+// Like fract() in WGSL, which rounds down instead of towards zero
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
@@ -0,0 +1,476 @@
+// Golden image regression tests. The canonical scenes are rendered at a small, fixed size and
+// sample count and compared against the references in tests/golden. The random numbers only
+// depend on the pixel and the frame, so an unchanged renderer reproduces the references up to
+// floating point differences between machines.
+use crate::cli::Options;
+use crate::display::Display;
+use crate::headless::render_image;
+use crate::image_io::{read_pfm, save_image, write_heat_map, write_pfm};
+use crate::image_metrics::{flip_error_map, relative_mse, rmse};
+use crate::render_settings::RenderSettings;
+use crate::primitive::{Primitive, Shape};
+use crate::scene::{Material, MaterialTextures, Mesh, Scene, Sphere};
+use crate::texture::{Mapping, Pattern, TextureSource};
+use std::path::{Path, PathBuf};
+
+const REFERENCE_DIR: &str = "tests/golden";
+const OUTPUT_DIR: &str = "target/regression"; // Diff images of failed scenes
+const WIDTH: u32 = 80;
+const HEIGHT: u32 = 40;
+const SPP: u32 = 40; // Two frames, so the accumulation is covered as well
+
+// Largest accepted difference to the reference
+const MAX_RMSE: f32 = 0.01;
+const MAX_RELATIVE_MSE: f32 = 0.001;
+const MAX_FLIP: f32 = 0.01;
+
+// The scenes and whether their render is denoised
+pub fn canonical_scenes() -> Vec<(&'static str, Scene, bool)> {
+    let floor = Sphere {
+        center: [0.0, -5010.0, 0.0],
+        radius: 5000.0,
+        material: Material::new([0.8, 0.8, 0.8], 0.0),
+    };
+
+    let spheres = Scene {
+        spheres: vec![
+            floor,
+            Sphere {
+                center: [-25.0, 0.0, 0.0],
+                radius: 10.0,
+                material: Material::new([1.0, 0.2, 0.2], 0.0),
+            },
+            Sphere {
+                center: [0.0, 0.0, 0.0],
+                radius: 10.0,
+                material: Material::new([1.0, 1.0, 1.0], 1.0),
+            },
+            Sphere {
+                center: [25.0, 0.0, 0.0],
+                radius: 10.0,
+                material: Material::new([0.2, 0.2, 1.0], 0.6),
+            },
+        ],
+        primitives: vec![],
+        mesh: Mesh::empty(),
+        textures: vec![],
+        camera_position: [0.0, 10.0, 70.0],
+        camera_rotation: [-8.0, 0.0, 0.0],
+    };
+
+    vec![
+        (
+            "teapot",
+            Scene {
+                spheres: vec![],
+                primitives: vec![],
+                mesh: Mesh::teapot(),
+                textures: vec![],
+                camera_position: [200.0, 40.0, 200.0],
+                camera_rotation: [-10.0, 45.0, 0.0],
+            },
+            false,
+        ),
+        ("spheres", spheres.clone(), false),
+        ("spheres_denoised", spheres, true),
+        (
+            "emissive_light",
+            Scene {
+                spheres: vec![
+                    floor,
+                    Sphere {
+                        center: [0.0, 0.0, 0.0],
+                        radius: 10.0,
+                        material: Material::new([0.9, 0.9, 0.9], 0.0),
+                    },
+                    Sphere {
+                        center: [18.0, 12.0, 10.0],
+                        radius: 5.0,
+                        material: Material::light([1.0, 0.6, 0.2], 10.0),
+                    },
+                ],
+                primitives: vec![],
+                mesh: Mesh::empty(),
+                textures: vec![],
+                camera_position: [0.0, 10.0, 60.0],
+                camera_rotation: [-8.0, 0.0, 0.0],
+            },
+            false,
+        ),
+        (
+            "textured",
+            Scene {
+                spheres: vec![
+                    floor,
+                    Sphere {
+                        center: [-25.0, 0.0, 0.0],
+                        radius: 10.0,
+                        material: Material {
+                            textures: MaterialTextures {
+                                albedo: Some(0),
+                                ..MaterialTextures::default()
+                            },
+                            ..Material::new([1.0, 1.0, 1.0], 0.0)
+                        },
+                    },
+                    Sphere {
+                        center: [0.0, 0.0, 0.0],
+                        radius: 10.0,
+                        material: Material {
+                            textures: MaterialTextures {
+                                roughness_metallic: Some(1),
+                                normal: Some(2),
+                                ..MaterialTextures::default()
+                            },
+                            ..Material::new([1.0, 0.8, 0.4], 0.9)
+                        },
+                    },
+                    Sphere {
+                        center: [25.0, 0.0, 0.0],
+                        radius: 10.0,
+                        material: Material {
+                            metallic: 0.0,
+                            textures: MaterialTextures {
+                                albedo: Some(0),
+                                emission: Some(0),
+                                ..MaterialTextures::default()
+                            },
+                            ..Material {
+                                emission_color: [1.0, 1.0, 1.0],
+                                emission_strength: 0.5,
+                                ..Material::new([0.3, 0.3, 1.0], 0.5)
+                            }
+                        },
+                    },
+                ],
+                primitives: vec![],
+                mesh: Mesh::empty(),
+                textures: vec![
+                    TextureSource::color("objects/textures/checker.png"),
+                    TextureSource::data("objects/textures/tiles_roughness_metallic.png"),
+                    TextureSource::data("objects/textures/tiles_normal.png"),
+                ],
+                camera_position: [0.0, 10.0, 70.0],
+                camera_rotation: [-8.0, 0.0, 0.0],
+            },
+            false,
+        ),
+        ("procedural", procedural_scene(), false),
+        ("primitives", primitives_scene(), false),
+        ("sphere_grid", sphere_grid_scene(floor), false),
+        ("two_sided", two_sided_scene(), false),
+    ]
+}
+
+// Enough spheres that they're only fast to trace through their BVH
+fn sphere_grid_scene(floor: Sphere) -> Scene {
+    const COUNT: i32 = 24; // Spheres per row
+    let mut spheres = vec![floor];
+    for x in 0..COUNT {
+        for z in 0..COUNT {
+            let t = [x, z].map(|i| i as f32 / (COUNT - 1) as f32);
+            spheres.push(Sphere {
+                center: [(t[0] - 0.5) * 120.0, -7.5, (t[1] - 0.5) * 120.0],
+                radius: 2.5,
+                material: Material::new([t[0], 0.4, t[1]], ((x + z) % 3) as f32 / 2.0),
+            });
+        }
+    }
+
+    Scene {
+        spheres,
+        primitives: vec![],
+        mesh: Mesh::empty(),
+        textures: vec![],
+        camera_position: [0.0, 50.0, 100.0],
+        camera_rotation: [-30.0, 0.0, 0.0],
+    }
+}
+
+// A sphere for every pattern on a floor with a triplanar grid
+fn procedural_scene() -> Scene {
+    let textured = |center: [f32; 3], color: [f32; 3], smoothness: f32, textures: MaterialTextures| Sphere {
+        center,
+        radius: 10.0,
+        material: Material {
+            textures,
+            ..Material::new(color, smoothness)
+        },
+    };
+    let albedo = |index: u32| MaterialTextures {
+        albedo: Some(index),
+        ..MaterialTextures::default()
+    };
+
+    Scene {
+        spheres: vec![
+            textured([0.0, -5010.0, 0.0], [1.0, 1.0, 1.0], 0.0, albedo(0)),
+            textured([-36.0, 0.0, 0.0], [1.0, 1.0, 1.0], 0.0, albedo(1)),
+            textured([-12.0, 0.0, 0.0], [1.0, 1.0, 1.0], 0.3, albedo(2)),
+            textured(
+                [12.0, 0.0, 0.0],
+                [1.0, 0.8, 0.4],
+                1.0,
+                MaterialTextures {
+                    roughness_metallic: Some(3),
+                    ..MaterialTextures::default()
+                },
+            ),
+            textured([36.0, 0.0, 0.0], [1.0, 1.0, 1.0], 0.0, albedo(4)),
+        ],
+        primitives: vec![],
+        mesh: Mesh::empty(),
+        textures: vec![
+            TextureSource {
+                mapping: Mapping::Triplanar,
+                scale: 0.1,
+                ..TextureSource::procedural(Pattern::Grid {
+                    colors: [[0.2, 0.2, 0.2], [0.8, 0.8, 0.8]],
+                    line_width: 0.05,
+                })
+            },
+            TextureSource {
+                scale: 0.1,
+                ..TextureSource::procedural(Pattern::Marble {
+                    colors: [[0.3, 0.3, 0.35], [0.9, 0.9, 0.85]],
+                    turbulence: 3.0,
+                })
+            },
+            TextureSource {
+                scale: 0.3,
+                ..TextureSource::procedural(Pattern::Wood {
+                    colors: [[0.35, 0.2, 0.08], [0.6, 0.4, 0.2]],
+                    turbulence: 0.5,
+                })
+            },
+            TextureSource {
+                scale: 0.2,
+                ..TextureSource::procedural(Pattern::Noise {
+                    colors: [[0.0, 0.0, 1.0], [0.0, 1.0, 0.0]],
+                    octaves: 5,
+                })
+            },
+            TextureSource {
+                scale: 8.0,
+                ..TextureSource::procedural(Pattern::Checker {
+                    colors: [[0.9, 0.9, 0.9], [0.1, 0.3, 0.8]],
+                })
+            },
+        ],
+        camera_position: [0.0, 12.0, 80.0],
+        camera_rotation: [-8.0, 0.0, 0.0],
+    }
+}
+
+// Every kind of primitive on an infinite plane, lit by a quad
+fn primitives_scene() -> Scene {
+    let primitive = |shape: Shape, color: [f32; 3], smoothness: f32| Primitive {
+        shape,
+        material: Material::new(color, smoothness),
+    };
+    let albedo = |index: u32| MaterialTextures {
+        albedo: Some(index),
+        ..MaterialTextures::default()
+    };
+
+    Scene {
+        spheres: vec![],
+        primitives: vec![
+            Primitive {
+                material: Material {
+                    textures: albedo(0),
+                    ..Material::new([1.0, 1.0, 1.0], 0.0)
+                },
+                ..primitive(Shape::Plane { point: [0.0, -10.0, 0.0], normal: [0.0, 1.0, 0.0] }, [1.0; 3], 0.0)
+            },
+            primitive(
+                Shape::Box { center: [-44.0, -3.0, 0.0], size: [12.0, 14.0, 12.0], rotation: [0.0, 30.0, 0.0] },
+                [1.0, 0.3, 0.2],
+                0.0,
+            ),
+            Primitive {
+                material: Material {
+                    textures: albedo(1),
+                    ..Material::new([1.0, 0.9, 0.6], 0.0)
+                },
+                ..primitive(
+                    Shape::Cylinder { base: [-22.0, -10.0, 0.0], top: [-22.0, 6.0, 0.0], radius: 7.0 },
+                    [1.0; 3],
+                    0.0,
+                )
+            },
+            primitive(
+                Shape::Cone { base: [0.0, -10.0, 0.0], apex: [0.0, 10.0, 0.0], radius: 8.0 },
+                [1.0, 0.8, 0.4],
+                0.8,
+            ),
+            primitive(
+                Shape::Torus { center: [22.0, -2.0, 0.0], axis: [0.0, 1.0, 1.0], major_radius: 7.0, minor_radius: 2.5 },
+                [0.3, 0.9, 0.3],
+                0.3,
+            ),
+            primitive(
+                Shape::Disk { center: [44.0, -1.0, 0.0], normal: [0.0, 0.3, 1.0], radius: 8.0 },
+                [0.2, 0.3, 1.0],
+                0.0,
+            ),
+            Primitive {
+                material: Material::light([1.0, 0.9, 0.8], 4.0),
+                ..primitive(
+                    Shape::Quad { corner: [-20.0, 40.0, -20.0], edge_u: [40.0, 0.0, 0.0], edge_v: [0.0, 0.0, 40.0] },
+                    [1.0; 3],
+                    0.0,
+                )
+            },
+        ],
+        mesh: Mesh::empty(),
+        textures: vec![
+            TextureSource {
+                scale: 0.05,
+                ..TextureSource::procedural(Pattern::Grid {
+                    colors: [[0.2, 0.2, 0.2], [0.8, 0.8, 0.8]],
+                    line_width: 0.05,
+                })
+            },
+            TextureSource {
+                scale: 4.0,
+                ..TextureSource::procedural(Pattern::Checker {
+                    colors: [[0.9, 0.9, 0.9], [0.3, 0.3, 0.3]],
+                })
+            },
+        ],
+        camera_position: [0.0, 20.0, 100.0],
+        camera_rotation: [-12.0, 0.0, 0.0],
+    }
+}
+
+// Glass that refracts through its back faces, and a quad seen from behind that's only visible
+// because it's two-sided
+fn two_sided_scene() -> Scene {
+    let primitive = |shape: Shape, material: Material| Primitive { shape, material };
+
+    Scene {
+        spheres: vec![
+            Sphere {
+                center: [-20.0, 0.0, 0.0],
+                radius: 10.0,
+                material: Material::glass([1.0, 1.0, 1.0], 1.5),
+            },
+            Sphere {
+                center: [-20.0, -6.0, -25.0],
+                radius: 4.0,
+                material: Material::new([1.0, 0.3, 0.2], 0.0),
+            },
+        ],
+        primitives: vec![
+            primitive(
+                Shape::Plane { point: [0.0, -10.0, 0.0], normal: [0.0, 1.0, 0.0] },
+                Material {
+                    textures: MaterialTextures {
+                        albedo: Some(0),
+                        ..MaterialTextures::default()
+                    },
+                    ..Material::new([1.0, 1.0, 1.0], 0.0)
+                },
+            ),
+            primitive(
+                Shape::Box { center: [5.0, -2.0, 0.0], size: [12.0, 16.0, 12.0], rotation: [0.0, 35.0, 0.0] },
+                Material::glass([0.6, 0.9, 1.0], 1.3),
+            ),
+            // Faces away from the camera
+            primitive(
+                Shape::Quad { corner: [20.0, -10.0, -5.0], edge_u: [0.0, 20.0, 0.0], edge_v: [20.0, 0.0, 0.0] },
+                Material {
+                    two_sided: true,
+                    ..Material::new([0.3, 0.9, 0.3], 0.0)
+                },
+            ),
+            primitive(
+                Shape::Quad { corner: [-20.0, 40.0, -20.0], edge_u: [40.0, 0.0, 0.0], edge_v: [0.0, 0.0, 40.0] },
+                Material::light([1.0, 0.9, 0.8], 4.0),
+            ),
+        ],
+        mesh: Mesh::empty(),
+        textures: vec![TextureSource {
+            scale: 0.05,
+            ..TextureSource::procedural(Pattern::Grid {
+                colors: [[0.2, 0.2, 0.2], [0.8, 0.8, 0.8]],
+                line_width: 0.05,
+            })
+        }],
+        camera_position: [0.0, 20.0, 90.0],
+        camera_rotation: [-12.0, 0.0, 0.0],
+    }
+}
+
+// Compare every canonical scene against its reference, or overwrite the references when
+// blessing. Returns whether all scenes passed.
+pub async fn run(options: &Options) -> bool {
+    let mut all_passed = true;
+
+    for (name, scene, denoise) in canonical_scenes() {
+        let settings = RenderSettings {
+            denoise,
+            ..RenderSettings::new()
+        };
+        let pixels = render_image(options.renderer, &options.adapter, &scene, settings, WIDTH, HEIGHT, SPP, None)
+            .await
+            .pixels;
+        let reference_path = Path::new(REFERENCE_DIR).join(format!("{}.pfm", name));
+
+        if options.bless {
+            std::fs::create_dir_all(REFERENCE_DIR).unwrap();
+            write_pfm(&reference_path, WIDTH, HEIGHT, &pixels).unwrap();
+            println!("{}: wrote {}", name, reference_path.display());
+            continue;
+        }
+
+        let reference = match read_pfm(&reference_path) {
+            Ok((WIDTH, HEIGHT, reference)) => reference,
+            Ok((width, height, _)) => {
+                println!("{}: FAILED, the reference is {}x{} instead of {}x{}", name, width, height, WIDTH, HEIGHT);
+                all_passed = false;
+                continue;
+            }
+            Err(error) => {
+                println!("{}: FAILED, can't read the reference: {}", name, error);
+                all_passed = false;
+                continue;
+            }
+        };
+
+        let flip_map = flip_error_map(&pixels, &reference, WIDTH, HEIGHT);
+        let rmse = rmse(&pixels, &reference);
+        let relative_mse = relative_mse(&pixels, &reference);
+        let flip = flip_map.iter().sum::<f32>() / flip_map.len() as f32;
+        let passed = rmse <= MAX_RMSE && relative_mse <= MAX_RELATIVE_MSE && flip <= MAX_FLIP;
+
+        println!(
+            "{}: {} (RMSE {:.6}, relative MSE {:.6}, FLIP {:.6})",
+            name,
+            if passed { "passed" } else { "FAILED" },
+            rmse,
+            relative_mse,
+            flip
+        );
+
+        if !passed {
+            all_passed = false;
+            write_failure_images(name, &pixels, &reference, &flip_map);
+        }
+    }
+
+    all_passed
+}
+
+fn write_failure_images(name: &str, pixels: &[[f32; 3]], reference: &[[f32; 3]], flip_map: &[f32]) {
+    std::fs::create_dir_all(OUTPUT_DIR).unwrap();
+    let path = |suffix: &str| -> PathBuf { Path::new(OUTPUT_DIR).join(format!("{}_{}", name, suffix)) };
+    let display = Display::new(false);
+
+    write_pfm(&path("actual.pfm"), WIDTH, HEIGHT, pixels).unwrap();
+    save_image(&path("actual.png"), WIDTH, HEIGHT, pixels, display).unwrap();
+    save_image(&path("reference.png"), WIDTH, HEIGHT, reference, display).unwrap();
+    write_heat_map(&path("diff.png"), WIDTH, HEIGHT, flip_map).unwrap();
+    println!("  Diff images written to {}", path("*").display());
+}
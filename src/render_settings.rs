@@ -0,0 +1,206 @@
+// Settings of the path tracer that can change while it runs: the ray budget, the field of view
+// and the environment lighting
+
+pub const MAX_BOUNCE_COUNT: u32 = 50;
+pub const MAX_RAYS_PER_PIXEL: u32 = 1000;
+const FOV_STEP: f32 = 5.0;
+pub const MIN_FOV: f32 = 5.0;
+pub const MAX_FOV: f32 = 150.0;
+// Ray budget of the preview shown while the camera moves
+const PREVIEW_BOUNCE_COUNT: u32 = 2;
+const PREVIEW_RAYS_PER_PIXEL: u32 = 1;
+
+// What the tracer shows. Everything but Off replaces the path traced radiance with information
+// about the first hit, to find out why a mesh renders wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugMode {
+    Off,
+    ShadingNormals,
+    GeometricNormals,
+    Depth,
+    Albedo,
+    MaterialId,
+    TriangleId,
+    Barycentrics,
+    BvhCost, // Nodes visited plus triangles tested, as a heat map
+    Error,   // Relative error of the accumulated radiance, as a heat map. Keeps tracing the lighting.
+}
+
+impl DebugMode {
+    pub const ALL: [DebugMode; 10] = [
+        DebugMode::Off,
+        DebugMode::ShadingNormals,
+        DebugMode::GeometricNormals,
+        DebugMode::Depth,
+        DebugMode::Albedo,
+        DebugMode::MaterialId,
+        DebugMode::TriangleId,
+        DebugMode::Barycentrics,
+        DebugMode::BvhCost,
+        DebugMode::Error,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(DebugMode::Off),
+            "shading-normals" => Some(DebugMode::ShadingNormals),
+            "geometric-normals" => Some(DebugMode::GeometricNormals),
+            "depth" => Some(DebugMode::Depth),
+            "albedo" => Some(DebugMode::Albedo),
+            "material-id" => Some(DebugMode::MaterialId),
+            "triangle-id" => Some(DebugMode::TriangleId),
+            "barycentrics" => Some(DebugMode::Barycentrics),
+            "bvh-cost" => Some(DebugMode::BvhCost),
+            "error" => Some(DebugMode::Error),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        let index = DebugMode::ALL.iter().position(|&mode| mode == self).unwrap();
+        DebugMode::ALL[(index + 1) % DebugMode::ALL.len()]
+    }
+
+    // Must match the switch in debug_color() and error_debug_mode in shader.wgsl
+    pub fn shader_id(self) -> u32 {
+        DebugMode::ALL.iter().position(|&mode| mode == self).unwrap() as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderSettings {
+    pub max_bounce_count: u32, // Max bounces per ray
+    pub rays_per_pixel: u32,   // Rays per pixel every frame
+    pub fov: f32,              // Horizontal field of view in degrees
+    pub use_environment_lighting: bool,
+    pub sky_color_horizon: [f32; 3],
+    pub sky_color_zenith: [f32; 3],
+    pub ground_color: [f32; 3],
+    pub sun_light_direction: [f32; 3], // Direction the light travels in, not normalized
+    pub sun_intensity: f32,
+    pub sun_focus: f32, // Higher values make the sun smaller
+    pub debug_mode: DebugMode,
+    pub depth_range: f32, // Distance shown as black in the depth view
+    pub denoise: bool,    // Filter the accumulated image, see denoise.wgsl
+    pub adaptive_sampling: bool, // Trace more rays where the error is high and none where it's low
+    pub noise_threshold: f32,    // Relative error of a converged pixel
+    pub aovs: bool,              // Accumulate the light AOVs as well, for headless renders
+}
+
+impl RenderSettings {
+    pub fn new() -> Self {
+        RenderSettings {
+            max_bounce_count: 10,
+            rays_per_pixel: 20,
+            fov: 60.0,
+            use_environment_lighting: true,
+            sky_color_horizon: [0.5, 0.7, 1.0],
+            sky_color_zenith: [0.1, 0.25, 1.0],
+            ground_color: [0.2, 0.2, 0.2],
+            sun_light_direction: [0.0, -0.4, 0.5],
+            sun_intensity: 3.0,
+            sun_focus: 200.0,
+            debug_mode: DebugMode::Off,
+            depth_range: 1000.0,
+            denoise: false,
+            adaptive_sampling: false,
+            noise_threshold: 0.02,
+            aovs: false,
+        }
+    }
+
+    pub fn increase_bounces(&mut self) {
+        self.max_bounce_count = (self.max_bounce_count + 1).min(MAX_BOUNCE_COUNT);
+    }
+
+    pub fn decrease_bounces(&mut self) {
+        self.max_bounce_count = self.max_bounce_count.saturating_sub(1);
+    }
+
+    // Doubles and halves, the useful range spans a few orders of magnitude
+    pub fn increase_rays_per_pixel(&mut self) {
+        self.rays_per_pixel = (self.rays_per_pixel * 2).min(MAX_RAYS_PER_PIXEL);
+    }
+
+    pub fn decrease_rays_per_pixel(&mut self) {
+        self.rays_per_pixel = (self.rays_per_pixel / 2).max(1);
+    }
+
+    pub fn increase_fov(&mut self) {
+        self.fov = (self.fov + FOV_STEP).min(MAX_FOV);
+    }
+
+    pub fn decrease_fov(&mut self) {
+        self.fov = (self.fov - FOV_STEP).max(MIN_FOV);
+    }
+
+    pub fn cycle_debug_mode(&mut self) {
+        self.debug_mode = self.debug_mode.next();
+    }
+
+    pub fn toggle_denoise(&mut self) {
+        self.denoise = !self.denoise;
+    }
+
+    // The debug views are shown as they are
+    pub fn use_denoiser(self) -> bool {
+        self.denoise && self.debug_mode == DebugMode::Off
+    }
+
+    pub fn reset(&mut self) {
+        *self = RenderSettings::new();
+    }
+
+    // Cheaper settings for the preview, which only has to be good enough to navigate
+    pub fn preview(self) -> Self {
+        RenderSettings {
+            max_bounce_count: self.max_bounce_count.min(PREVIEW_BOUNCE_COUNT),
+            rays_per_pixel: PREVIEW_RAYS_PER_PIXEL,
+            ..self
+        }
+    }
+
+    // Data in the layout of the RenderSettings struct in shader.wgsl
+    pub fn to_gpu(self) -> RenderSettingsGpu {
+        RenderSettingsGpu {
+            sky_color_horizon: self.sky_color_horizon,
+            max_bounce_count: self.max_bounce_count,
+            sky_color_zenith: self.sky_color_zenith,
+            rays_per_pixel: self.rays_per_pixel,
+            ground_color: self.ground_color,
+            fov: self.fov.to_radians(),
+            sun_light_direction: self.sun_light_direction,
+            sun_intensity: self.sun_intensity,
+            sun_focus: self.sun_focus,
+            use_environment_lighting: self.use_environment_lighting as u32,
+            debug_mode: self.debug_mode.shader_id(),
+            depth_range: self.depth_range,
+            denoise: self.use_denoiser() as u32,
+            adaptive_sampling: self.adaptive_sampling as u32,
+            noise_threshold: self.noise_threshold,
+            aovs: self.aovs as u32,
+        }
+    }
+}
+
+// Layout of the RenderSettings struct in shader.wgsl, the scalars fill up the vec3s to 16 bytes
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct RenderSettingsGpu {
+    sky_color_horizon: [f32; 3],
+    max_bounce_count: u32,
+    sky_color_zenith: [f32; 3],
+    rays_per_pixel: u32,
+    ground_color: [f32; 3],
+    fov: f32, // In radians
+    sun_light_direction: [f32; 3],
+    sun_intensity: f32,
+    sun_focus: f32,
+    use_environment_lighting: u32,
+    debug_mode: u32,
+    depth_range: f32,
+    denoise: u32,
+    adaptive_sampling: u32,
+    noise_threshold: f32,
+    aovs: u32,
+}
@@ -0,0 +1,184 @@
+// Choosing the GPU adapter and creating the device, for both the window and headless rendering.
+// The settings come from the command line, or from the environment variables wgpu uses
+// (WGPU_ADAPTER_NAME, WGPU_BACKEND and WGPU_POWER_PREF).
+use crate::primitive::PrimitiveData;
+use crate::scene::Scene;
+use crate::texture::Pattern;
+
+const STORAGE_BUFFERS_PER_STAGE: u32 = 8; // Used by the fragment shader in shader.wgsl
+
+#[derive(Debug, Clone)]
+pub struct AdapterOptions {
+    pub name: Option<String>, // Case insensitive part of the adapter name
+    pub backends: wgpu::Backends,
+    pub power_preference: wgpu::PowerPreference,
+}
+
+impl AdapterOptions {
+    pub fn from_env() -> Self {
+        AdapterOptions {
+            name: std::env::var("WGPU_ADAPTER_NAME").ok(),
+            backends: wgpu::util::backend_bits_from_env().unwrap_or(wgpu::Backends::all()),
+            power_preference: wgpu::util::power_preference_from_env()
+                .unwrap_or(wgpu::PowerPreference::HighPerformance),
+        }
+    }
+}
+
+// Comma separated list like "vulkan,gl"
+pub fn parse_backends(names: &str) -> Option<wgpu::Backends> {
+    let backends = wgpu::util::parse_backends_from_comma_list(&names.to_lowercase());
+    (!backends.is_empty()).then_some(backends)
+}
+
+pub fn parse_power_preference(name: &str) -> Option<wgpu::PowerPreference> {
+    match name {
+        "low" => Some(wgpu::PowerPreference::LowPower),
+        "high" => Some(wgpu::PowerPreference::HighPerformance),
+        "none" => Some(wgpu::PowerPreference::None),
+        _ => None,
+    }
+}
+
+pub fn create_instance(options: &AdapterOptions) -> wgpu::Instance {
+    wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: options.backends,
+        ..Default::default()
+    })
+}
+
+// Print every adapter of the selected backends
+pub fn list_adapters(options: &AdapterOptions) {
+    let adapters = create_instance(options).enumerate_adapters(options.backends);
+    if adapters.is_empty() {
+        println!("No adapters found");
+    }
+    for (i, adapter) in adapters.iter().enumerate() {
+        let info = adapter.get_info();
+        let driver = format!("{} {}", info.driver, info.driver_info);
+        println!(
+            "{}: {} ({:?}, {:?}) {}",
+            i,
+            info.name,
+            info.backend,
+            info.device_type,
+            driver.trim()
+        );
+    }
+}
+
+// The adapter with the given name if there is one, otherwise the one wgpu prefers for the
+// power preference, and the software fallback adapter if there's no GPU at all.
+// The surface is None for headless rendering.
+pub async fn request_adapter(
+    instance: &wgpu::Instance,
+    options: &AdapterOptions,
+    surface: Option<&wgpu::Surface<'_>>,
+) -> wgpu::Adapter {
+    if let Some(name) = &options.name {
+        let adapter = instance
+            .enumerate_adapters(options.backends)
+            .into_iter()
+            .filter(|a| surface.is_none_or(|s| a.is_surface_supported(s)))
+            .find(|a| a.get_info().name.to_lowercase().contains(&name.to_lowercase()));
+        match adapter {
+            Some(adapter) => return adapter,
+            None => eprintln!("No adapter matches '{}', using the default one (see --list-adapters)", name),
+        }
+    }
+
+    let mut adapter_options = wgpu::RequestAdapterOptions {
+        power_preference: options.power_preference,
+        force_fallback_adapter: false,
+        compatible_surface: surface,
+    };
+    if let Some(adapter) = instance.request_adapter(&adapter_options).await {
+        return adapter;
+    }
+
+    adapter_options.force_fallback_adapter = true;
+    match instance.request_adapter(&adapter_options).await {
+        Some(adapter) => {
+            println!("No GPU available, using the fallback adapter");
+            adapter
+        }
+        None => {
+            eprintln!("No GPU or fallback adapter available");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Create the device with the limits the scene needs, exiting with a message if the adapter
+// can't provide them
+pub async fn request_device(adapter: &wgpu::Adapter, scene: &Scene) -> (wgpu::Device, wgpu::Queue) {
+    println!("{:?}", adapter.get_info());
+    let supported = adapter.limits();
+
+    // The mesh has the largest buffers of the scene
+    let float_size = std::mem::size_of::<f32>() as u64;
+    let primitive_data = PrimitiveData::new(scene);
+    let largest_scene_buffer = [
+        scene.mesh.vertices.len(),
+        scene.mesh.indices.len(),
+        primitive_data.bvh.len(),
+        primitive_data.data.len(),
+    ]
+    .into_iter()
+    .max()
+    .unwrap() as u64
+        * float_size;
+
+    // Every image texture is a layer of the texture array
+    let image_count = scene
+        .textures
+        .iter()
+        .filter(|texture| matches!(texture.pattern, Pattern::Image { .. }))
+        .count() as u32;
+
+    // Start from limits that every adapter supports and raise what the renderer needs. The frame
+    // data grows with the window, so buffers and textures may be as large as the adapter allows.
+    let required_limits = wgpu::Limits {
+        max_storage_buffers_per_shader_stage: STORAGE_BUFFERS_PER_STAGE,
+        max_storage_buffer_binding_size: supported.max_storage_buffer_binding_size,
+        max_buffer_size: supported.max_buffer_size,
+        max_texture_dimension_2d: supported.max_texture_dimension_2d,
+        max_texture_array_layers: supported.max_texture_array_layers,
+        ..wgpu::Limits::downlevel_defaults()
+    };
+
+    let failure = if required_limits.max_storage_buffers_per_shader_stage
+        > supported.max_storage_buffers_per_shader_stage
+    {
+        Some(format!(
+            "needs {} storage buffers per shader stage, but supports {}",
+            STORAGE_BUFFERS_PER_STAGE, supported.max_storage_buffers_per_shader_stage
+        ))
+    } else if largest_scene_buffer > supported.max_storage_buffer_binding_size as u64 {
+        Some(format!(
+            "needs storage buffers of {} bytes, but supports {}",
+            largest_scene_buffer, supported.max_storage_buffer_binding_size
+        ))
+    } else if image_count > supported.max_texture_array_layers {
+        Some(format!(
+            "needs {} texture array layers, but supports {}",
+            image_count, supported.max_texture_array_layers
+        ))
+    } else {
+        None
+    };
+    if let Some(failure) = failure {
+        eprintln!("{} can't render this scene: {}", adapter.get_info().name, failure);
+        std::process::exit(1);
+    }
+
+    let device_descriptor = wgpu::DeviceDescriptor {
+        required_features: wgpu::Features::empty(),
+        required_limits,
+        label: Some("Device"),
+    };
+    adapter
+        .request_device(&device_descriptor, None)
+        .await
+        .unwrap()
+}
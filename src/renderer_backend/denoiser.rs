@@ -0,0 +1,148 @@
+use super::pipeline_builder::PipelineBuilder;
+use super::renderer::{create_bind_group, storage_buffer_entry, uniform_buffer_entry};
+use wgpu::{
+    util::{BufferInitDescriptor, DeviceExt},
+    BufferUsages,
+};
+
+// Passes of the à-trous filter, the taps of the last one are 2^(PASSES - 1) pixels apart
+pub const DENOISE_PASSES: u32 = 5;
+
+// Layout of the DenoiseParameters struct in denoise.wgsl
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct DenoiseParameters {
+    size: [u32; 2],
+    step_size: i32,
+    first_pass: u32,
+}
+
+// Runs denoise.wgsl over the accumulated frame data. The passes ping-pong between two scratch
+// buffers, which only the bind groups hold on to, and the last one writes the output buffer
+// that the present pass shows.
+pub struct Denoiser {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    pub output_buffer: wgpu::Buffer,
+    bind_groups: [Vec<wgpu::BindGroup>; 2], // One per pass, for either frame data buffer
+}
+
+impl Denoiser {
+    pub fn new(
+        device: &wgpu::Device,
+        target_format: wgpu::TextureFormat,
+        frame_data_buffers: &[wgpu::Buffer; 2],
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                storage_buffer_entry(0, true),  // Frame data
+                storage_buffer_entry(1, true),  // Input
+                storage_buffer_entry(2, false), // Output
+                uniform_buffer_entry(3, wgpu::ShaderStages::FRAGMENT), // Parameters
+            ],
+            label: Some("Denoise Bind Group Layout"),
+        });
+
+        let mut pipeline_builder = PipelineBuilder::new();
+        pipeline_builder.set_shader_module("shaders/denoise.wgsl", "vs_main", "fs_main");
+        pipeline_builder.set_pixel_format(target_format);
+        pipeline_builder.set_bind_group_layout(&bind_group_layout);
+        let pipeline = pipeline_builder.build_pipeline(device);
+
+        let (output_buffer, bind_groups) = create_buffers(device, &bind_group_layout, frame_data_buffers, width, height);
+        Denoiser {
+            pipeline,
+            bind_group_layout,
+            output_buffer,
+            bind_groups,
+        }
+    }
+
+    // Follow the frame data to a new size
+    pub fn resize(&mut self, device: &wgpu::Device, frame_data_buffers: &[wgpu::Buffer; 2], width: u32, height: u32) {
+        (self.output_buffer, self.bind_groups) =
+            create_buffers(device, &self.bind_group_layout, frame_data_buffers, width, height);
+    }
+
+    // Filter the frame data buffer with the given index into the output buffer. Like the trace
+    // pass, the passes draw to a render target only because they run in a fragment shader.
+    pub fn encode(&self, command_encoder: &mut wgpu::CommandEncoder, frame_data_index: usize, target: &wgpu::TextureView) {
+        for bind_group in &self.bind_groups[frame_data_index] {
+            let mut render_pass = command_encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Denoise Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Discard,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.draw(0..6, 0..1);
+        }
+    }
+}
+
+// Output buffer of the size of the frame data, and the bind groups of every pass
+fn create_buffers(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    frame_data_buffers: &[wgpu::Buffer; 2],
+    width: u32,
+    height: u32,
+) -> (wgpu::Buffer, [Vec<wgpu::BindGroup>; 2]) {
+    let create_color_buffer = |label| {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (width as usize * height as usize * std::mem::size_of::<[f32; 4]>()) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    };
+    let scratch_buffers = [create_color_buffer("Denoise Scratch Buffer"), create_color_buffer("Denoise Scratch Buffer")];
+    let output_buffer = create_color_buffer("Denoised Data Buffer");
+
+    // Pass i reads what pass i - 1 wrote, the first pass reads the frame data instead
+    let parameter_buffers: Vec<wgpu::Buffer> = (0..DENOISE_PASSES)
+        .map(|pass| {
+            device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("Denoise Parameters Buffer"),
+                contents: bytemuck::cast_slice(&[DenoiseParameters {
+                    size: [width, height],
+                    step_size: 1 << pass,
+                    first_pass: (pass == 0) as u32,
+                }]),
+                usage: BufferUsages::UNIFORM,
+            })
+        })
+        .collect();
+    let bind_groups = [0, 1].map(|frame_data_index| {
+        (0..DENOISE_PASSES as usize)
+            .map(|pass| {
+                // The first pass doesn't read its input, any buffer but its output will do
+                let input = &scratch_buffers[(pass + 1) % 2];
+                let output = if pass + 1 == DENOISE_PASSES as usize {
+                    &output_buffer
+                } else {
+                    &scratch_buffers[pass % 2]
+                };
+                create_bind_group(
+                    device,
+                    layout,
+                    &[&frame_data_buffers[frame_data_index], input, output, &parameter_buffers[pass]],
+                    &[],
+                )
+            })
+            .collect()
+    });
+
+    (output_buffer, bind_groups)
+}
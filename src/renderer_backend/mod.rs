@@ -1 +1,4 @@
-pub mod pipeline_builder;
\ No newline at end of file
+pub mod adapter;
+pub mod denoiser;
+pub mod pipeline_builder;
+pub mod renderer;
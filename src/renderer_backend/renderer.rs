@@ -0,0 +1,785 @@
+use super::denoiser::Denoiser;
+use super::pipeline_builder::PipelineBuilder;
+use crate::aov::AOV_COUNT;
+use crate::camera::Camera;
+use crate::cpu_renderer::FramePixel;
+use crate::display::Display;
+use crate::render_settings::{DebugMode, RenderSettings};
+use crate::primitive::{Primitive, PrimitiveData, FLOATS_PER_PRIMITIVE};
+use crate::scene::{Material, Scene, Sphere};
+use crate::texture::{self, Texture, MIP_LEVELS, TEXTURE_SIZE};
+use cgmath::Vector4;
+use wgpu::{
+    util::{BufferInitDescriptor, DeviceExt},
+    BufferUsages,
+};
+
+// Fraction of the render scale the preview is traced at
+const PREVIEW_SCALE: f32 = 0.25;
+
+// Floats per pixel of the frame data, the color, geometry, albedo and moments of FramePixel in shader.wgsl
+const FRAME_PIXEL_FLOATS: usize = 16;
+
+// Floats per pixel of the AOV buffer, five vec4s of light AOVs
+const AOV_PIXEL_FLOATS: usize = 20;
+
+// fs_main has to write somewhere, but only the frame data it accumulates is used. The denoise
+// passes draw to the same target.
+const TRACE_TARGET_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+// Everything needed to path trace the scene into a render target, independent of
+// whether the target is a window surface or an offscreen texture
+pub struct Renderer {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    pub width: u32,  // Size of the traced image
+    pub height: u32,
+    target_width: u32, // Size of the window or image it's presented on
+    target_height: u32,
+    render_scale: f32,
+    preview: bool, // Trace at a lower resolution and with fewer rays, see set_preview()
+    trace_pipeline: wgpu::RenderPipeline,
+    present_pipeline: wgpu::RenderPipeline,
+    trace_target: wgpu::TextureView,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_groups: [wgpu::BindGroup; 2], // Write to one frame data buffer and reproject from the other
+    current: usize, // Index of the bind group and frame data buffer the next frame writes
+    pub frame_count: u32,
+    primitive_buffer: wgpu::Buffer,
+    primitive_slots: Vec<usize>, // Index in the primitive buffer of every sphere, then every primitive
+    primitive_layout_buffer: wgpu::Buffer,
+    frame_count_buffer: wgpu::Buffer,
+    frame_data_buffers: [wgpu::Buffer; 2],
+    denoiser: Denoiser,
+    aov_buffer: wgpu::Buffer, // Only as large as the frame data while settings.aovs is set
+    aovs_allocated: bool,
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    bvh_buffer: wgpu::Buffer,
+    mesh_material_buffer: wgpu::Buffer,
+    texture_table_buffer: wgpu::Buffer,
+    texture_view: wgpu::TextureView, // Every image of the scene, one per layer
+    screen_size_buffer: wgpu::Buffer,
+    pub camera: Camera,
+    previous_camera: Camera, // Pose of the last rendered frame
+    camera_buffer: wgpu::Buffer,
+    pub display: Display,
+    display_buffer: wgpu::Buffer,
+    pub settings: RenderSettings,
+    settings_buffer: wgpu::Buffer,
+    pub spheres: Vec<Sphere>, // Kept to edit materials while rendering
+    pub primitives: Vec<Primitive>,
+    pub mesh_material: Material,
+}
+
+impl Renderer {
+    pub fn new(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        target_format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        scene: &Scene,
+    ) -> Self {
+        // Primitive data - Format: the spheres and primitives in the order of their BVH, see PrimitiveData
+        let primitive_data = PrimitiveData::new(scene);
+
+        // Buffer for primitive data
+        let primitive_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Primitive Buffer Data"),
+            contents: bytemuck::cast_slice(&primitive_data.data),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        });
+
+        // Buffer for where the primitives' BVH starts and how many primitives it holds
+        let primitive_layout_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Primitive Layout Buffer"),
+            contents: bytemuck::cast_slice(&primitive_data.layout_data()),
+            usage: BufferUsages::UNIFORM,
+        });
+
+        // Buffers for the vertices of the mesh and the three vertex indices of every triangle
+        let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Vertex Buffer Data"),
+            contents: bytemuck::cast_slice(&scene.mesh.vertices),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        });
+        let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Index Buffer Data"),
+            contents: bytemuck::cast_slice(&scene.mesh.indices),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        });
+
+        // Buffer for the bounding volume hierarchies of the triangles and of the primitives
+        let bvh_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Bounding Box Buffer Data"),
+            contents: bytemuck::cast_slice(&primitive_data.bvh),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        });
+
+        // Buffer for the material of the triangle mesh - Format: Material::to_data(), padded to whole vec4s
+        let mesh_material_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Mesh Material Buffer"),
+            contents: bytemuck::cast_slice(&mesh_material_data(scene.mesh.material)),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        // The textures of the materials, the table describes them and the array holds the images
+        let textures = texture::load_textures(&scene.textures).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        });
+        let texture_table_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Texture Table Buffer"),
+            contents: bytemuck::cast_slice(&texture::texture_data(&scene.textures)),
+            usage: BufferUsages::UNIFORM,
+        });
+        let images: Vec<&Texture> = textures.iter().flatten().collect();
+        let texture_view = create_texture_array(&device, &queue, &images);
+
+        // Buffer for the frame count
+        let frame_count_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Frame Count Buffer"),
+            contents: bytemuck::cast_slice(&[0]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        // The traced image starts at the size of the target, resize() changes both
+        let (target_width, target_height) = (width, height);
+        let (width, height) = render_size(&device, target_width, target_height, 1.0, false);
+        let frame_data_buffers = [0, 1].map(|_| create_frame_data_buffer(&device, width, height));
+        let trace_target = create_trace_target(&device, width, height);
+        let denoiser = Denoiser::new(&device, TRACE_TARGET_FORMAT, &frame_data_buffers, width, height);
+        let aov_buffer = create_aov_buffer(&device, 1, 1);
+
+        // Buffer for the size of the traced image and of the target
+        let screen_size_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Screen Size Buffer"),
+            contents: bytemuck::cast_slice(&[
+                width as f32,
+                height as f32,
+                target_width as f32,
+                target_height as f32,
+            ]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        // Camera data
+        let camera = Camera::new(scene.camera_position, scene.camera_rotation);
+
+        // Buffer for the camera pose of this frame and the previous one
+        let camera_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Camera Buffer"),
+            contents: bytemuck::cast_slice(&[camera.to_gpu(camera)]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        // Display settings, the shader encodes sRGB itself if the target can't
+        let display = Display::new(!target_format.is_srgb());
+        let display_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Display Settings Buffer"),
+            contents: bytemuck::cast_slice(&[display.to_gpu()]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        // Render settings that can change without recompiling the shader
+        let settings = RenderSettings::new();
+        let settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Render Settings Buffer"),
+            contents: bytemuck::cast_slice(&[settings.to_gpu()]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        // Create bind group layout
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                // The default limit is 8 storage buffers per stage, small values go in uniforms
+                storage_buffer_entry(0, true),  // Spheres and primitives
+                uniform_buffer_entry(1, wgpu::ShaderStages::FRAGMENT), // Frame count
+                storage_buffer_entry(2, false), // Frame data
+                uniform_buffer_entry(3, wgpu::ShaderStages::FRAGMENT), // Camera
+                storage_buffer_entry(4, true),  // Previous frame data
+                storage_buffer_entry(5, true),  // Vertices
+                storage_buffer_entry(6, true),  // BVH
+                uniform_buffer_entry(7, wgpu::ShaderStages::FRAGMENT), // Display settings
+                uniform_buffer_entry(8, wgpu::ShaderStages::VERTEX_FRAGMENT), // Screen size
+                uniform_buffer_entry(9, wgpu::ShaderStages::FRAGMENT), // Mesh material
+                uniform_buffer_entry(10, wgpu::ShaderStages::VERTEX_FRAGMENT), // Render settings
+                storage_buffer_entry(11, true), // Denoised data
+                storage_buffer_entry(12, false), // AOVs
+                storage_buffer_entry(13, true), // Triangle indices
+                uniform_buffer_entry(14, wgpu::ShaderStages::FRAGMENT), // Texture table
+                uniform_buffer_entry(15, wgpu::ShaderStages::FRAGMENT), // Primitive layout
+                texture_array_entry(16), // Images
+            ],
+            label: Some("Sphere Bind Group Layout"),
+        });
+        let bind_groups = [0, 1].map(|i| {
+            create_bind_group(
+                &device,
+                &bind_group_layout,
+                &[
+                    &primitive_buffer,
+                    &frame_count_buffer,
+                    &frame_data_buffers[i],
+                    &camera_buffer,
+                    &frame_data_buffers[1 - i],
+                    &vertex_buffer,
+                    &bvh_buffer,
+                    &display_buffer,
+                    &screen_size_buffer,
+                    &mesh_material_buffer,
+                    &settings_buffer,
+                    &denoiser.output_buffer,
+                    &aov_buffer,
+                    &index_buffer,
+                    &texture_table_buffer,
+                    &primitive_layout_buffer,
+                ],
+                &[&texture_view],
+            )
+        });
+
+        // Pass bind group layout to pipeline builder. The trace pipeline accumulates the frame
+        // data at the render resolution, the present pipeline shows it on the target.
+        let mut pipeline_builder = PipelineBuilder::new();
+        pipeline_builder.set_shader_module("shaders/shader.wgsl", "vs_main", "fs_main");
+        pipeline_builder.set_pixel_format(TRACE_TARGET_FORMAT);
+        pipeline_builder.set_bind_group_layout(&bind_group_layout);
+        let trace_pipeline = pipeline_builder.build_pipeline(&device);
+
+        pipeline_builder.set_shader_module("shaders/shader.wgsl", "vs_main", "fs_present");
+        pipeline_builder.set_pixel_format(target_format);
+        let present_pipeline = pipeline_builder.build_pipeline(&device);
+
+        Self {
+            device,
+            queue,
+            width,
+            height,
+            target_width,
+            target_height,
+            render_scale: 1.0,
+            preview: false,
+            trace_pipeline,
+            present_pipeline,
+            trace_target,
+            bind_group_layout,
+            bind_groups,
+            current: 0,
+            frame_count: 0,
+            primitive_buffer,
+            primitive_slots: primitive_data.slots,
+            primitive_layout_buffer,
+            frame_count_buffer,
+            frame_data_buffers,
+            denoiser,
+            aov_buffer,
+            aovs_allocated: false,
+            vertex_buffer,
+            index_buffer,
+            bvh_buffer,
+            mesh_material_buffer,
+            texture_table_buffer,
+            texture_view,
+            screen_size_buffer,
+            camera,
+            previous_camera: camera,
+            camera_buffer,
+            display,
+            display_buffer,
+            settings,
+            settings_buffer,
+            spheres: scene.spheres.clone(),
+            primitives: scene.primitives.clone(),
+            mesh_material: scene.mesh.material,
+        }
+    }
+
+    // The target changed size, the traced image follows it scaled by the render scale
+    pub fn resize(&mut self, target_width: u32, target_height: u32) {
+        self.target_width = target_width;
+        self.target_height = target_height;
+        self.reallocate();
+    }
+
+    pub fn render_scale(&self) -> f32 {
+        self.render_scale
+    }
+
+    // Trace at a fraction (or a multiple) of the target resolution
+    pub fn set_render_scale(&mut self, render_scale: f32) {
+        self.render_scale = render_scale;
+        self.reallocate();
+    }
+
+    pub fn preview(&self) -> bool {
+        self.preview
+    }
+
+    // The preview keeps navigation smooth: it traces fewer pixels, bounces and rays while the
+    // camera or scene is changing, so every frame is cheap and shows the latest view
+    pub fn set_preview(&mut self, preview: bool) {
+        self.preview = preview;
+        self.reallocate();
+    }
+
+    // Upload a single sphere after changing it
+    pub fn write_sphere(&mut self, index: usize) {
+        self.write_primitive_data(self.primitive_slots[index], &self.spheres[index].to_data());
+    }
+
+    // Upload a single primitive after changing it, its slot follows those of the spheres
+    pub fn write_primitive(&mut self, index: usize) {
+        let slot = self.primitive_slots[self.spheres.len() + index];
+        self.write_primitive_data(slot, &self.primitives[index].to_data());
+    }
+
+    // Only the material may change, the BVH is built for the shapes of the scene
+    fn write_primitive_data(&mut self, slot: usize, data: &[f32; FLOATS_PER_PRIMITIVE]) {
+        let primitive_size = std::mem::size_of_val(data) as u64;
+        self.queue.write_buffer(
+            &self.primitive_buffer,
+            slot as u64 * primitive_size,
+            bytemuck::cast_slice(data),
+        );
+        self.frame_count = 0;
+    }
+
+    pub fn write_mesh_material(&mut self) {
+        self.queue.write_buffer(
+            &self.mesh_material_buffer,
+            0,
+            bytemuck::cast_slice(&mesh_material_data(self.mesh_material)),
+        );
+        self.frame_count = 0;
+    }
+
+    // Resize the frame data and start accumulating again
+    fn reallocate(&mut self) {
+        let render_scale = if self.preview {
+            self.render_scale * PREVIEW_SCALE
+        } else {
+            self.render_scale
+        };
+        let aovs = self.settings.aovs;
+        (self.width, self.height) =
+            render_size(&self.device, self.target_width, self.target_height, render_scale, aovs);
+
+        self.frame_data_buffers = [0, 1].map(|_| create_frame_data_buffer(&self.device, self.width, self.height));
+        self.trace_target = create_trace_target(&self.device, self.width, self.height);
+        self.denoiser
+            .resize(&self.device, &self.frame_data_buffers, self.width, self.height);
+        self.aov_buffer = if aovs {
+            create_aov_buffer(&self.device, self.width, self.height)
+        } else {
+            create_aov_buffer(&self.device, 1, 1)
+        };
+        self.aovs_allocated = aovs;
+        self.bind_groups = [0, 1].map(|i| {
+            create_bind_group(
+                &self.device,
+                &self.bind_group_layout,
+                &[
+                    &self.primitive_buffer,
+                    &self.frame_count_buffer,
+                    &self.frame_data_buffers[i],
+                    &self.camera_buffer,
+                    &self.frame_data_buffers[1 - i],
+                    &self.vertex_buffer,
+                    &self.bvh_buffer,
+                    &self.display_buffer,
+                    &self.screen_size_buffer,
+                    &self.mesh_material_buffer,
+                    &self.settings_buffer,
+                    &self.denoiser.output_buffer,
+                    &self.aov_buffer,
+                    &self.index_buffer,
+                    &self.texture_table_buffer,
+                    &self.primitive_layout_buffer,
+                ],
+                &[&self.texture_view],
+            )
+        });
+        self.queue.write_buffer(
+            &self.screen_size_buffer,
+            0,
+            bytemuck::cast_slice(&[
+                self.width as f32,
+                self.height as f32,
+                self.target_width as f32,
+                self.target_height as f32,
+            ]),
+        );
+        self.frame_count = 0;
+    }
+
+    // Trace one frame, add it to the accumulated frame data and show the result on the target
+    pub fn render(&mut self, target: &wgpu::TextureView) {
+        if self.settings.aovs != self.aovs_allocated {
+            self.reallocate();
+        }
+
+        // Update the frame count buffer before rendering
+        self.queue.write_buffer(
+            &self.frame_count_buffer,
+            0,
+            bytemuck::cast_slice(&[self.frame_count]),
+        );
+
+        // Update the camera buffer before rendering, the previous pose is used for reprojection
+        self.queue.write_buffer(
+            &self.camera_buffer,
+            0,
+            bytemuck::cast_slice(&[self.camera.to_gpu(self.previous_camera)]),
+        );
+        // The debug views are shown as they are
+        let display = Display {
+            raw: self.settings.debug_mode != DebugMode::Off,
+            ..self.display
+        };
+        self.queue.write_buffer(
+            &self.display_buffer,
+            0,
+            bytemuck::cast_slice(&[display.to_gpu()]),
+        );
+        let settings = if self.preview {
+            self.settings.preview()
+        } else {
+            self.settings
+        };
+        self.queue.write_buffer(
+            &self.settings_buffer,
+            0,
+            bytemuck::cast_slice(&[settings.to_gpu()]),
+        );
+
+        let command_encoder_descriptor = wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        };
+        let mut command_encoder = self
+            .device
+            .create_command_encoder(&command_encoder_descriptor);
+
+        // Trace into the frame data at the render resolution
+        {
+            let mut render_pass = command_encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Trace Pass"),
+                color_attachments: &[Some(color_attachment(&self.trace_target))],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            render_pass.set_pipeline(&self.trace_pipeline);
+            render_pass.set_bind_group(0, &self.bind_groups[self.current], &[]); // Access using self
+            render_pass.draw(0..3, 0..1); // Draw the first triangle
+            render_pass.draw(3..6, 0..1); // Draw the second triangle
+        }
+
+        // Filter the frame data that was just written, the present pass shows the result
+        if self.settings.use_denoiser() {
+            self.denoiser
+                .encode(&mut command_encoder, self.current, &self.trace_target);
+        }
+
+        // Tone map and scale the frame data to the target
+        {
+            let mut render_pass = command_encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Present Pass"),
+                color_attachments: &[Some(color_attachment(target))],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            render_pass.set_pipeline(&self.present_pipeline);
+            render_pass.set_bind_group(0, &self.bind_groups[self.current], &[]);
+            render_pass.draw(0..6, 0..1);
+        }
+
+        self.queue.submit(std::iter::once(command_encoder.finish()));
+
+        // This frame is the history of the next one
+        self.current = 1 - self.current;
+        self.previous_camera = self.camera;
+        self.frame_count += 1;
+    }
+
+    // Copy the accumulated linear radiance back to the CPU, row by row from the top
+    pub fn read_frame_data(&self) -> Vec<[f32; 3]> {
+        let frame_data_buffer = &self.frame_data_buffers[1 - self.current]; // Written by the last frame
+        let data = self.read_buffer(frame_data_buffer);
+        data.chunks(FRAME_PIXEL_FLOATS).map(|p| [p[0], p[1], p[2]]).collect()
+    }
+
+    // Everything accumulated for every pixel, row by row from the top
+    pub fn read_frame_pixels(&self) -> Vec<FramePixel> {
+        let data = self.read_buffer(&self.frame_data_buffers[1 - self.current]);
+        data.chunks(FRAME_PIXEL_FLOATS)
+            .map(|p| FramePixel {
+                color: Vector4::new(p[0], p[1], p[2], p[3]),
+                geometry: Vector4::new(p[4], p[5], p[6], p[7]),
+                albedo: Vector4::new(p[8], p[9], p[10], p[11]),
+                moments: Vector4::new(p[12], p[13], p[14], p[15]),
+            })
+            .collect()
+    }
+
+    // Average light of every AOV in aov.rs, row by row from the top. Only accumulated while
+    // settings.aovs is set.
+    pub fn read_aovs(&self) -> Vec<[[f32; 3]; AOV_COUNT]> {
+        let data = self.read_buffer(&self.aov_buffer);
+        data.chunks(AOV_PIXEL_FLOATS)
+            .map(|p| std::array::from_fn(|i| [p[i * 4], p[i * 4 + 1], p[i * 4 + 2]]))
+            .collect()
+    }
+
+    // The output of the denoiser for the last frame, which only runs if the settings enable it
+    pub fn read_denoised_data(&self) -> Vec<[f32; 3]> {
+        let data = self.read_buffer(&self.denoiser.output_buffer);
+        data.chunks(4).map(|p| [p[0], p[1], p[2]]).collect()
+    }
+
+    fn read_buffer(&self, buffer: &wgpu::Buffer) -> Vec<f32> {
+        let size = buffer.size();
+        let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Readback Buffer"),
+            size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut command_encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Readback Encoder"),
+            });
+        command_encoder.copy_buffer_to_buffer(buffer, 0, &staging_buffer, 0, size);
+        self.queue.submit(std::iter::once(command_encoder.finish()));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let buffer_slice = staging_buffer.slice(..);
+        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+            sender.send(result).ok();
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .unwrap()
+            .expect("Failed to map the buffer for reading.");
+
+        let data = bytemuck::cast_slice(&buffer_slice.get_mapped_range()).to_vec();
+        staging_buffer.unmap();
+        data
+    }
+}
+
+fn color_attachment(view: &wgpu::TextureView) -> wgpu::RenderPassColorAttachment<'_> {
+    wgpu::RenderPassColorAttachment {
+        view,
+        resolve_target: None,
+        ops: wgpu::Operations {
+            load: wgpu::LoadOp::Clear(wgpu::Color {
+                r: 0.75,
+                g: 0.5,
+                b: 0.25,
+                a: 1.0,
+            }),
+            store: wgpu::StoreOp::Store,
+        },
+    }
+}
+
+// Size of the traced image for a target size, within the largest frame data (and AOV) buffer
+// the device allows
+fn render_size(
+    device: &wgpu::Device,
+    target_width: u32,
+    target_height: u32,
+    render_scale: f32,
+    aovs: bool,
+) -> (u32, u32) {
+    let pixel_floats = if aovs { AOV_PIXEL_FLOATS } else { FRAME_PIXEL_FLOATS };
+    let pixel_size = (pixel_floats * std::mem::size_of::<f32>()) as f32;
+    let max_pixels = device.limits().max_storage_buffer_binding_size as f32 / pixel_size;
+    let target_pixels = target_width as f32 * target_height as f32;
+    let scale = render_scale.min((max_pixels / target_pixels).sqrt());
+
+    // Rounding up could go over the limit
+    let scaled = |size: u32| {
+        let size = size as f32 * scale;
+        let size = if scale < render_scale { size.floor() } else { size.round() };
+        (size as u32).max(1)
+    };
+    (scaled(target_width), scaled(target_height))
+}
+
+// Frame data that starts off completely black with no frames accumulated
+fn create_frame_data_buffer(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Frame Data Buffer"),
+        size: (width as usize * height as usize * FRAME_PIXEL_FLOATS * std::mem::size_of::<f32>()) as u64,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+        mapped_at_creation: false, // wgpu zero initializes buffers
+    })
+}
+
+fn create_aov_buffer(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("AOV Buffer"),
+        size: (width as usize * height as usize * AOV_PIXEL_FLOATS * std::mem::size_of::<f32>()) as u64,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_trace_target(device: &wgpu::Device, width: u32, height: u32) -> wgpu::TextureView {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Trace Target"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: TRACE_TARGET_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    });
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+// The uniform holds whole vec4s
+fn mesh_material_data(material: Material) -> [f32; 16] {
+    let mut data = [0.0; 16];
+    let material_data = material.to_data();
+    data[..material_data.len()].copy_from_slice(&material_data);
+    data
+}
+
+// All images in the layers of one texture array, with their mip levels. Without images there's
+// a single texel, since the shader needs something to bind. The GL backend makes textures with a
+// single layer plain 2D textures, so there are always at least two layers.
+fn create_texture_array(device: &wgpu::Device, queue: &wgpu::Queue, textures: &[&Texture]) -> wgpu::TextureView {
+    let (size, mip_level_count) = if textures.is_empty() {
+        (1, 1)
+    } else {
+        (TEXTURE_SIZE, MIP_LEVELS)
+    };
+    let texture_array = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Texture Array"),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: textures.len().max(2) as u32,
+        },
+        mip_level_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float, // Not filterable, the shader filters itself
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+
+    for (layer, texture) in textures.iter().enumerate() {
+        for (level, mip) in texture.mips.iter().enumerate() {
+            let mip_size = TEXTURE_SIZE >> level;
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture_array,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                bytemuck::cast_slice(mip),
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(mip_size * std::mem::size_of::<[f32; 4]>() as u32),
+                    rows_per_image: Some(mip_size),
+                },
+                wgpu::Extent3d {
+                    width: mip_size,
+                    height: mip_size,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+    }
+
+    // A single layer would be viewed as a plain 2D texture
+    texture_array.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    })
+}
+
+// Bind every buffer as a whole, then every texture. The binding index is the position in the
+// slices, with the textures following the buffers.
+pub(super) fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    buffers: &[&wgpu::Buffer],
+    textures: &[&wgpu::TextureView],
+) -> wgpu::BindGroup {
+    let buffer_resources = buffers.iter().map(|buffer| buffer.as_entire_binding());
+    let texture_resources = textures
+        .iter()
+        .map(|texture| wgpu::BindingResource::TextureView(texture));
+    let entries: Vec<wgpu::BindGroupEntry> = buffer_resources
+        .chain(texture_resources)
+        .enumerate()
+        .map(|(binding, resource)| wgpu::BindGroupEntry {
+            binding: binding as u32,
+            resource,
+        })
+        .collect();
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Bind Group"),
+        layout,
+        entries: &entries,
+    })
+}
+
+pub(super) fn storage_buffer_entry(binding: u32, read_only: bool) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn texture_array_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            view_dimension: wgpu::TextureViewDimension::D2Array,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+pub(super) fn uniform_buffer_entry(binding: u32, visibility: wgpu::ShaderStages) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
@@ -0,0 +1,262 @@
+// Scene description shared by the GPU renderer and the CPU reference renderer
+use crate::primitive::{Primitive, FLOATS_PER_PRIMITIVE, FLOATS_PER_SHAPE, SPHERE_KIND};
+use crate::texture::TextureSource;
+
+// Floats per material in the primitive and mesh material data
+pub const FLOATS_PER_MATERIAL: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    pub color: [f32; 3],
+    pub emission_color: [f32; 3],
+    pub emission_strength: f32,
+    pub smoothness: f32,
+    pub metallic: f32, // How much reflections take on the color, 1 tints them fully
+    pub textures: MaterialTextures,
+    pub two_sided: bool, // Also hit from behind, or from inside a closed shape
+    pub transmission: f32, // How much light refracts through the surface instead of bouncing off it
+    pub ior: f32, // Index of refraction of the inside, for the transmitted light
+}
+
+// Indices into Scene::textures. Every texture multiplies the value it replaces, except the normal
+// map, which bends the shading normal.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MaterialTextures {
+    pub albedo: Option<u32>,
+    pub roughness_metallic: Option<u32>, // Roughness in green and metallic in blue, like glTF
+    pub emission: Option<u32>,
+    pub normal: Option<u32>, // Tangent space
+}
+
+impl Material {
+    pub fn new(color: [f32; 3], smoothness: f32) -> Self {
+        Material {
+            color,
+            emission_color: [0.0, 0.0, 0.0],
+            emission_strength: 0.0,
+            smoothness,
+            metallic: 1.0,
+            textures: MaterialTextures::default(),
+            two_sided: false,
+            transmission: 0.0,
+            ior: 1.5,
+        }
+    }
+
+    // Clear like glass, seen from both sides and tinted by the color
+    pub fn glass(color: [f32; 3], ior: f32) -> Self {
+        Material {
+            metallic: 0.0,
+            transmission: 1.0,
+            ior,
+            ..Material::new(color, 1.0)
+        }
+    }
+
+    pub fn light(emission_color: [f32; 3], emission_strength: f32) -> Self {
+        Material {
+            color: [0.0, 0.0, 0.0],
+            emission_color,
+            emission_strength,
+            smoothness: 0.0,
+            metallic: 1.0,
+            textures: MaterialTextures::default(),
+            two_sided: false,
+            transmission: 0.0,
+            ior: 1.5,
+        }
+    }
+
+    // Refracted light passes through back faces, so refractive materials are always two-sided
+    pub fn is_two_sided(&self) -> bool {
+        self.two_sided || self.transmission > 0.0
+    }
+
+    // Format: [r, g, b, er, eg, eb, emission_strength, smoothness, metallic, albedo_texture,
+    // roughness_metallic_texture, emission_texture, normal_texture, two_sided, transmission, ior],
+    // -1 for no texture and two_sided 1 or 0
+    pub fn to_data(self) -> [f32; FLOATS_PER_MATERIAL] {
+        let [r, g, b] = self.color;
+        let [er, eg, eb] = self.emission_color;
+        let texture = |index: Option<u32>| index.map_or(-1.0, |index| index as f32);
+        let textures = self.textures;
+        [
+            r,
+            g,
+            b,
+            er,
+            eg,
+            eb,
+            self.emission_strength,
+            self.smoothness,
+            self.metallic,
+            texture(textures.albedo),
+            texture(textures.roughness_metallic),
+            texture(textures.emission),
+            texture(textures.normal),
+            if self.is_two_sided() { 1.0 } else { 0.0 },
+            self.transmission,
+            self.ior,
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sphere {
+    pub center: [f32; 3],
+    pub radius: f32,
+    pub material: Material,
+}
+
+impl Sphere {
+    // Format: like Primitive::to_data(), the shape is [kind, x, y, z, radius]
+    pub fn to_data(self) -> [f32; FLOATS_PER_PRIMITIVE] {
+        let mut data = [0.0; FLOATS_PER_PRIMITIVE];
+        data[0] = SPHERE_KIND;
+        data[1..4].copy_from_slice(&self.center);
+        data[4] = self.radius;
+        data[FLOATS_PER_SHAPE..].copy_from_slice(&self.material.to_data());
+        data
+    }
+}
+
+// Floats per vertex in the mesh data: the position, the normal and the texture coordinates
+pub const FLOATS_PER_VERTEX: usize = 8;
+
+// An indexed triangle mesh in the format written by prepare_data: FLOATS_PER_VERTEX floats per
+// vertex, three vertex indices per triangle, and nine floats per BVH node (min, max,
+// triangle_index, triangle_count, child_index)
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    pub vertices: Vec<f32>,
+    pub indices: Vec<u32>,
+    pub bvh: Vec<f32>,
+    pub material: Material,
+}
+
+impl Mesh {
+    pub fn from_bytes(vertex_data: &[u8], index_data: &[u8], bvh_data: &[u8], material: Material) -> Self {
+        Mesh {
+            vertices: bytes_to_floats(vertex_data),
+            indices: index_data
+                .chunks(4)
+                .map(|d| u32::from_ne_bytes([d[0], d[1], d[2], d[3]]))
+                .collect(),
+            bvh: bytes_to_floats(bvh_data),
+            material,
+        }
+    }
+
+    // A single degenerate triangle and an empty leaf, since the shader needs some data to bind
+    pub fn empty() -> Self {
+        Mesh {
+            vertices: vec![0.0; FLOATS_PER_VERTEX],
+            indices: vec![0; 3],
+            bvh: vec![0.0; 9],
+            material: Material::new([0.0, 0.0, 0.0], 0.0),
+        }
+    }
+
+    // Box of the BVH root node, None if the mesh has no triangles
+    pub fn bounds(&self) -> Option<([f32; 3], [f32; 3])> {
+        let root = &self.bvh[0..9];
+        if root[7] == 0.0 && root[8] == 0.0 {
+            return None;
+        }
+        Some(([root[0], root[1], root[2]], [root[3], root[4], root[5]]))
+    }
+
+    pub fn teapot() -> Self {
+        Mesh::from_bytes(
+            include_bytes!("../objects/teapot_vertices.bin"),
+            include_bytes!("../objects/teapot_indices.bin"),
+            include_bytes!("../objects/teapot_bvh.bin"),
+            Material::new([0.65, 0.65, 1.0], 0.9),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Scene {
+    pub spheres: Vec<Sphere>,
+    pub primitives: Vec<Primitive>, // The other analytic shapes
+    pub mesh: Mesh,
+    pub textures: Vec<TextureSource>, // Indexed by the materials
+    pub camera_position: [f32; 3],
+    pub camera_rotation: [f32; 3],
+}
+
+impl Scene {
+    // The scene shown by default: the blue teapot
+    pub fn teapot() -> Self {
+        Scene {
+            spheres: vec![
+                // Sphere {
+                //     center: [-40.0, 0.0, 0.0],
+                //     radius: 10.0,
+                //     material: Material::new([0.25, 0.25, 1.0], 0.9),
+                // },
+                // Sphere {
+                //     center: [40.0, 0.0, 0.0],
+                //     radius: 10.0,
+                //     material: Material::new([1.0, 1.0, 1.0], 1.0),
+                // },
+                // Sphere {
+                //     center: [0.0, -20.0, 0.0],
+                //     radius: 10.0,
+                //     material: Material::new([0.0, 1.0, 0.0], 0.0),
+                // },
+                // Sphere {
+                //     center: [0.0, -5030.0, 0.0],
+                //     radius: 5000.0,
+                //     material: Material::new([1.0, 1.0, 1.0], 0.0),
+                // },
+                // Sphere {
+                //     center: [40.0, 40.0, -20.0],
+                //     radius: 10.0,
+                //     material: Material::light([1.0, 0.0, 0.0], 10.0),
+                // }, // Light source
+            ],
+            primitives: vec![],
+            mesh: Mesh::teapot(),
+            textures: vec![],
+            camera_position: [200.0, 0.0, 200.0],
+            camera_rotation: [0.0, 90.0, 0.0],
+        }
+    }
+
+    // The part of the scene the orbit camera looks at: the mesh, or the spheres and primitives if
+    // there's no mesh. Infinite planes are left out.
+    pub fn focus_bounds(&self) -> ([f32; 3], [f32; 3]) {
+        if let Some(bounds) = self.mesh.bounds() {
+            return bounds;
+        }
+
+        let sphere_bounds = self.spheres.iter().map(|sphere| {
+            let min = [0, 1, 2].map(|i| sphere.center[i] - sphere.radius);
+            let max = [0, 1, 2].map(|i| sphere.center[i] + sphere.radius);
+            (min, max)
+        });
+        let primitive_bounds = self.primitives.iter().filter_map(|primitive| primitive.shape.bounds());
+        let mut bounds = sphere_bounds.chain(primitive_bounds).peekable();
+        if bounds.peek().is_none() {
+            return ([0.0; 3], [0.0; 3]);
+        }
+
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for (shape_min, shape_max) in bounds {
+            for i in 0..3 {
+                min[i] = min[i].min(shape_min[i]);
+                max[i] = max[i].max(shape_max[i]);
+            }
+        }
+        (min, max)
+    }
+}
+
+fn bytes_to_floats(data: &[u8]) -> Vec<f32> {
+    data.chunks(4)
+        .map(|d| f32::from_ne_bytes([d[0], d[1], d[2], d[3]]))
+        .collect()
+}
@@ -0,0 +1,303 @@
+// Scenes described in a text file, loaded with --scene. Every line is one object: a keyword, its
+// parameters, and for the spheres, primitives and mesh their material. Vectors and colors are
+// three numbers, and # starts a comment. scenes/primitives.scene is an example.
+//
+//   camera <position> <rotation>
+//   texture image|data <path>                   Color (sRGB) or data (linear) image
+//   texture checker <color> <color>
+//   texture grid <color> <color> <line width>
+//   texture noise <color> <color> <octaves>
+//   texture marble|wood <color> <color> <turbulence>
+//   mesh <path>                                 Files written by prepare_data, without _vertices.bin
+//   sphere <center> <radius>
+//   plane <point> <normal>
+//   box <center> <size> <rotation>
+//   quad <corner> <edge u> <edge v>
+//   disk <center> <normal> <radius>
+//   cylinder <base> <top> <radius>
+//   cone <base> <apex> <radius>
+//   torus <center> <axis> <major radius> <minor radius>
+//
+// Textures are numbered from 0 in the order of the file and can be followed by `scale <s>` and
+// `triplanar`. The material properties are `color <color>`, `smoothness <s>`, `metallic <m>`,
+// `emission <color> <strength>`, `transmission <t>`, `ior <n>`, `two-sided`, and the textures
+// `albedo`, `roughness-metallic`, `emission-map` and `normal-map`, each followed by an index.
+// Without a color the material is white, and everything else defaults like Material::new.
+use crate::primitive::{Primitive, Shape};
+use crate::scene::{Material, Mesh, Scene, Sphere};
+use crate::texture::{Mapping, Pattern, TextureSource};
+use std::path::{Path, PathBuf};
+
+pub fn load_scene(path: &Path) -> Result<Scene, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    parse_scene(&text).map_err(|message| format!("{}:{}", path.display(), message))
+}
+
+// Errors start with the line number. Textures can be used before the line that adds them, so the
+// indices are checked at the end.
+fn parse_scene(text: &str) -> Result<Scene, String> {
+    let mut scene = Scene {
+        spheres: vec![],
+        primitives: vec![],
+        mesh: Mesh::empty(),
+        textures: vec![],
+        camera_position: [0.0, 10.0, 70.0],
+        camera_rotation: [0.0, 0.0, 0.0],
+    };
+
+    let mut used_textures = vec![]; // Line number and texture index
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = Words(line.split_whitespace());
+        let Some(keyword) = words.0.next() else {
+            continue;
+        };
+        let mut used = vec![];
+        parse_line(&mut scene, keyword, &mut words, &mut used).map_err(|message| format!("{}: {}", index + 1, message))?;
+        used_textures.extend(used.into_iter().map(|texture| (index + 1, texture)));
+    }
+
+    for (line, texture) in used_textures {
+        if texture as usize >= scene.textures.len() {
+            return Err(format!("{}: No texture {}, there are {}", line, texture, scene.textures.len()));
+        }
+    }
+
+    Ok(scene)
+}
+
+fn parse_line(scene: &mut Scene, keyword: &str, words: &mut Words, used_textures: &mut Vec<u32>) -> Result<(), String> {
+    let shape = match keyword {
+        "camera" => {
+            scene.camera_position = words.vector("camera position")?;
+            scene.camera_rotation = words.vector("camera rotation")?;
+            return words.end();
+        }
+        "texture" => {
+            let texture = parse_texture(words)?;
+            scene.textures.push(texture);
+            return Ok(());
+        }
+        "mesh" => {
+            let path = words.word("mesh path")?;
+            let read = |suffix: &str| {
+                let file = format!("{}_{}.bin", path, suffix);
+                std::fs::read(&file).map_err(|e| format!("Can't read {}: {}", file, e))
+            };
+            let (vertices, indices, bvh) = (read("vertices")?, read("indices")?, read("bvh")?);
+            let material = parse_material(words, used_textures)?;
+            scene.mesh = Mesh::from_bytes(&vertices, &indices, &bvh, material);
+            return Ok(());
+        }
+        "sphere" => {
+            let center = words.vector("sphere center")?;
+            let radius = words.number("sphere radius")?;
+            let material = parse_material(words, used_textures)?;
+            scene.spheres.push(Sphere { center, radius, material });
+            return Ok(());
+        }
+        "plane" => Shape::Plane {
+            point: words.vector("plane point")?,
+            normal: words.vector("plane normal")?,
+        },
+        "box" => Shape::Box {
+            center: words.vector("box center")?,
+            size: words.vector("box size")?,
+            rotation: words.vector("box rotation")?,
+        },
+        "quad" => Shape::Quad {
+            corner: words.vector("quad corner")?,
+            edge_u: words.vector("quad edge u")?,
+            edge_v: words.vector("quad edge v")?,
+        },
+        "disk" => Shape::Disk {
+            center: words.vector("disk center")?,
+            normal: words.vector("disk normal")?,
+            radius: words.number("disk radius")?,
+        },
+        "cylinder" => Shape::Cylinder {
+            base: words.vector("cylinder base")?,
+            top: words.vector("cylinder top")?,
+            radius: words.number("cylinder radius")?,
+        },
+        "cone" => Shape::Cone {
+            base: words.vector("cone base")?,
+            apex: words.vector("cone apex")?,
+            radius: words.number("cone radius")?,
+        },
+        "torus" => Shape::Torus {
+            center: words.vector("torus center")?,
+            axis: words.vector("torus axis")?,
+            major_radius: words.number("torus major radius")?,
+            minor_radius: words.number("torus minor radius")?,
+        },
+        _ => return Err(format!("Unknown object '{}'", keyword)),
+    };
+    let material = parse_material(words, used_textures)?;
+    scene.primitives.push(Primitive { shape, material });
+    Ok(())
+}
+
+fn parse_texture(words: &mut Words) -> Result<TextureSource, String> {
+    let kind = words.word("texture kind")?;
+    let pattern = match kind {
+        "image" | "data" => Pattern::Image {
+            path: PathBuf::from(words.word("texture path")?),
+            srgb: kind == "image",
+        },
+        "checker" => Pattern::Checker {
+            colors: [words.vector("first color")?, words.vector("second color")?],
+        },
+        "grid" => Pattern::Grid {
+            colors: [words.vector("line color")?, words.vector("cell color")?],
+            line_width: words.number("line width")?,
+        },
+        "noise" => Pattern::Noise {
+            colors: [words.vector("first color")?, words.vector("second color")?],
+            octaves: words.number("octaves")?,
+        },
+        "marble" => Pattern::Marble {
+            colors: [words.vector("vein color")?, words.vector("stone color")?],
+            turbulence: words.number("turbulence")?,
+        },
+        "wood" => Pattern::Wood {
+            colors: [words.vector("early wood color")?, words.vector("late wood color")?],
+            turbulence: words.number("turbulence")?,
+        },
+        _ => return Err(format!("Unknown texture '{}'", kind)),
+    };
+
+    let mut texture = TextureSource::procedural(pattern);
+    while let Some(property) = words.0.next() {
+        match property {
+            "scale" => texture.scale = words.number("scale")?,
+            "triplanar" => texture.mapping = Mapping::Triplanar,
+            _ => return Err(format!("Unknown texture property '{}'", property)),
+        }
+    }
+    Ok(texture)
+}
+
+// The rest of the line. The texture indices are added to used_textures.
+fn parse_material(words: &mut Words, used_textures: &mut Vec<u32>) -> Result<Material, String> {
+    let mut material = Material::new([1.0, 1.0, 1.0], 0.0);
+    while let Some(property) = words.0.next() {
+        match property {
+            "color" => material.color = words.vector("color")?,
+            "smoothness" => material.smoothness = words.number("smoothness")?,
+            "metallic" => material.metallic = words.number("metallic")?,
+            "emission" => {
+                material.emission_color = words.vector("emission color")?;
+                material.emission_strength = words.number("emission strength")?;
+            }
+            "transmission" => material.transmission = words.number("transmission")?,
+            "ior" => material.ior = words.number("ior")?,
+            "two-sided" => material.two_sided = true,
+            "albedo" => material.textures.albedo = Some(words.number("albedo texture")?),
+            "roughness-metallic" => material.textures.roughness_metallic = Some(words.number("roughness-metallic texture")?),
+            "emission-map" => material.textures.emission = Some(words.number("emission texture")?),
+            "normal-map" => material.textures.normal = Some(words.number("normal texture")?),
+            _ => return Err(format!("Unknown material property '{}'", property)),
+        }
+    }
+    let textures = &material.textures;
+    used_textures.extend([textures.albedo, textures.roughness_metallic, textures.emission, textures.normal].into_iter().flatten());
+    Ok(material)
+}
+
+struct Words<'a>(std::str::SplitWhitespace<'a>);
+
+impl<'a> Words<'a> {
+    fn word(&mut self, name: &str) -> Result<&'a str, String> {
+        self.0.next().ok_or(format!("Missing {}", name))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, name: &str) -> Result<T, String> {
+        let word = self.word(name)?;
+        word.parse().map_err(|_| format!("Invalid {} '{}'", name, word))
+    }
+
+    fn vector(&mut self, name: &str) -> Result<[f32; 3], String> {
+        Ok([self.number(name)?, self.number(name)?, self.number(name)?])
+    }
+
+    fn end(&mut self) -> Result<(), String> {
+        match self.0.next() {
+            Some(word) => Err(format!("Unexpected '{}'", word)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regression;
+    use crate::scene::MaterialTextures;
+
+    #[test]
+    fn example_matches_regression_scene() {
+        let scene = load_scene(Path::new("scenes/primitives.scene")).unwrap();
+        let (_, expected, _) = regression::canonical_scenes()
+            .into_iter()
+            .find(|(name, _, _)| *name == "primitives")
+            .unwrap();
+        assert_eq!(scene.spheres, expected.spheres);
+        assert_eq!(scene.primitives, expected.primitives);
+        assert_eq!(scene.textures, expected.textures);
+        assert_eq!(scene.camera_position, expected.camera_position);
+        assert_eq!(scene.camera_rotation, expected.camera_rotation);
+    }
+
+    #[test]
+    fn spheres_and_materials() {
+        let scene = parse_scene(
+            "
+            camera 1 2 3  -10 20 0 # comment
+            texture marble 0 0 0  1 1 1  3 triplanar
+            sphere 0 -5 0 2.5  color 1 0.5 0.25 smoothness 1 metallic 0 transmission 1 ior 1.3
+            disk 0 0 0  0 1 0  4  two-sided albedo 0 emission-map 0
+            ",
+        )
+        .unwrap();
+        assert_eq!(scene.camera_position, [1.0, 2.0, 3.0]);
+        assert_eq!(scene.camera_rotation, [-10.0, 20.0, 0.0]);
+        assert_eq!(scene.textures[0].mapping, Mapping::Triplanar);
+        assert_eq!(
+            scene.spheres,
+            vec![Sphere {
+                center: [0.0, -5.0, 0.0],
+                radius: 2.5,
+                material: Material {
+                    transmission: 1.0,
+                    ior: 1.3,
+                    metallic: 0.0,
+                    ..Material::new([1.0, 0.5, 0.25], 1.0)
+                },
+            }]
+        );
+        assert_eq!(
+            scene.primitives[0].material,
+            Material {
+                two_sided: true,
+                textures: MaterialTextures {
+                    albedo: Some(0),
+                    emission: Some(0),
+                    ..MaterialTextures::default()
+                },
+                ..Material::new([1.0, 1.0, 1.0], 0.0)
+            }
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = |text: &str| parse_scene(text).err().unwrap();
+        assert_eq!(error("\n\nteapot"), "3: Unknown object 'teapot'");
+        assert_eq!(error("cone 0 0 0  0 1 0"), "1: Missing cone radius");
+        assert_eq!(error("torus 0 0 0  0 1 0  x 1"), "1: Invalid torus major radius 'x'");
+        assert_eq!(error("plane 0 0 0  0 1 0  shiny"), "1: Unknown material property 'shiny'");
+        assert_eq!(error("camera 0 0 0  0 0 0  0"), "1: Unexpected '0'");
+        assert_eq!(error("texture checker 0 0 0  1 1 1\nsphere 0 0 0 1  albedo 0 normal-map 3"), "2: No texture 3, there are 1");
+    }
+}
@@ -0,0 +1,122 @@
+// Edge-avoiding à-trous wavelet filter over the accumulated frame data. Every pass blurs with a
+// 5x5 B3 spline kernel whose taps are step_size pixels apart, doubling the step each pass, and
+// weights the taps by how alike their first hits are. Noise on a surface is averaged away while
+// silhouettes, creases and material boundaries stay sharp.
+// cpu_denoiser.rs does the same on the CPU, keep both in sync.
+
+struct VertexOutput {
+    @builtin(position) pos: vec4<f32>,
+};
+
+// Must match FramePixel in shader.wgsl
+struct FramePixel {
+    color: vec4<f32>, // Average radiance, and in w the number of frames averaged
+    geometry: vec4<f32>, // Normal and distance of the first hit, the distance is -1 for the sky
+    albedo: vec4<f32>, // Color and material ID of the first hit, black and -1 for the sky
+    moments: vec4<f32>, // Average squared luminance of the samples and the number of samples
+};
+
+struct DenoiseParameters {
+    size: vec2<u32>, // Size of the frame data in pixels
+    step_size: i32, // Distance between the taps in pixels
+    first_pass: u32, // 1 to filter the frame data, 0 to filter the output of the previous pass
+};
+
+// --- Edge stopping --- Must match cpu_denoiser.rs
+const sigma_color: f32 = 0.5; // Color difference that still blends, at one frame and the first pass
+const normal_power: f32 = 64.0; // Higher values keep more of the creases
+const sigma_depth: f32 = 0.05; // Relative difference in distance per pixel of the same surface
+const sigma_albedo: f32 = 0.1;
+
+@group(0) @binding(0) var<storage, read> frame_data: array<FramePixel>;
+@group(0) @binding(1) var<storage, read> input_data: array<vec4<f32>>; // Output of the previous pass
+@group(0) @binding(2) var<storage, read_write> output_data: array<vec4<f32>>;
+@group(0) @binding(3) var<uniform> parameters: DenoiseParameters;
+
+@vertex
+fn vs_main(@builtin(vertex_index) i: u32) -> VertexOutput {
+    var positions = array<vec2<f32>, 6>(
+        vec2<f32>(-1.0, -1.0), // Bottom Left
+        vec2<f32>(1.0, -1.0),  // Bottom Right
+        vec2<f32>(-1.0, 1.0),   // Top Left
+
+        vec2<f32>(1.0, 1.0), // Top Right
+        vec2<f32>(-1.0, 1.0), // Top Left
+        vec2<f32>(1.0, -1.0) // Bottom Right
+    );
+
+    var out: VertexOutput;
+    out.pos = vec4<f32>(positions[i], 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    var kernel = array<f32, 3>(0.375, 0.25, 0.0625); // B3 spline, from the center outwards
+    let pixel = vec2<i32>(in.pos.xy);
+    let index: u32 = pixel_index(pixel);
+    let center: FramePixel = frame_data[index];
+    let center_color: vec4<f32> = color(index);
+
+    // The noise left in the average shrinks with the square root of the frames, and every
+    // pass has less of it to remove
+    let color_tolerance: f32 = sigma_color / (sqrt(max(center_color.w, 1.0)) * f32(parameters.step_size));
+
+    var sum: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
+    var total_weight: f32 = 0.0;
+    for (var y: i32 = -2; y <= 2; y++) {
+        for (var x: i32 = -2; x <= 2; x++) {
+            let offset: vec2<i32> = vec2<i32>(x, y) * parameters.step_size;
+            let neighbor: vec2<i32> = pixel + offset;
+            if (any(neighbor < vec2<i32>(0, 0)) || any(neighbor >= vec2<i32>(parameters.size))) {
+                continue;
+            }
+            let neighbor_index: u32 = pixel_index(neighbor);
+            let neighbor_color: vec3<f32> = color(neighbor_index).xyz;
+            let color_difference: vec3<f32> = center_color.xyz - neighbor_color;
+            let weight: f32 = kernel[abs(x)] * kernel[abs(y)]
+                * feature_weight(center, frame_data[neighbor_index], length(vec2<f32>(offset)))
+                * exp(-dot(color_difference, color_difference) / (color_tolerance * color_tolerance));
+            sum += neighbor_color * weight;
+            total_weight += weight;
+        }
+    }
+
+    // The center always has a weight of at least 0.375 * 0.375, the sum can't be zero
+    let filtered: vec4<f32> = vec4<f32>(sum / total_weight, center_color.w);
+    output_data[index] = filtered;
+
+    // Nothing reads the render target, the output buffer is presented by shader.wgsl
+    return filtered;
+}
+
+fn pixel_index(pixel: vec2<i32>) -> u32 {
+    return u32(pixel.y) * parameters.size.x + u32(pixel.x);
+}
+
+// Color to filter, with the frame count in w
+fn color(index: u32) -> vec4<f32> {
+    if (parameters.first_pass == 1u) {
+        return frame_data[index].color;
+    }
+    return input_data[index];
+}
+
+// How likely two pixels see the same surface with the same material, from 0 to 1
+fn feature_weight(center: FramePixel, neighbor: FramePixel, distance_in_pixels: f32) -> f32 {
+    // The sky only blends with the sky
+    let center_is_sky: bool = center.geometry.w < 0.0;
+    if (center_is_sky != (neighbor.geometry.w < 0.0)) {
+        return 0.0;
+    }
+
+    var weight: f32 = 1.0;
+    if (!center_is_sky) {
+        weight *= pow(max(dot(center.geometry.xyz, neighbor.geometry.xyz), 0.0), normal_power);
+        let depth_tolerance: f32 = sigma_depth * center.geometry.w * distance_in_pixels + 0.0001;
+        weight *= exp(-abs(center.geometry.w - neighbor.geometry.w) / depth_tolerance);
+    }
+    let albedo_difference: vec3<f32> = center.albedo.xyz - neighbor.albedo.xyz;
+    weight *= exp(-dot(albedo_difference, albedo_difference) / (sigma_albedo * sigma_albedo));
+    return weight;
+}
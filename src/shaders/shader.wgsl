@@ -8,11 +8,24 @@ struct HitInfo {
     did_hit: bool,
     distance: f32,
     position: vec3<f32>,
-    normal: vec3<f32>,
+    normal: vec3<f32>, // Shading normal
+    geometric_normal: vec3<f32>, // Normal of the surface itself
     color: vec3<f32>,
     emission_color: vec3<f32>,
     emission_strength: f32,
     smoothness: f32,
+    metallic: f32,
+    transmission: f32,
+    ior: f32,
+    front_face: bool, // false for the back of a two-sided surface, where a refracted ray leaves the inside
+    textures: vec4<i32>, // Albedo, roughness/metallic, emission and normal map, -1 for none
+    uv: vec2<f32>,
+    uv_density: f32, // Square root of the texture area per surface area, for the mip level
+    tangent: vec4<f32>, // Direction of increasing u, w is 1 or -1 for the direction of increasing v
+    material_id: u32, // 0 for the mesh, 1 + index in primitive_data for the spheres and other primitives
+    triangle_id: i32, // -1 for the primitives
+    barycentrics: vec3<f32>,
+    position_error: vec3<f32>, // Bound of the rounding error of position on every axis
 };
 
 struct Ray {
@@ -20,38 +33,132 @@ struct Ray {
     dir: vec3<f32>,
 };
 
-// --- Sphere Information ---
-const sphere_count: u32 = 1; // Number of spheres in the scene
-const nums_per_sphere: u32 = 12; // Number of values stored for every sphere --- Should remain constant as long as there are no significant changes to the sphere data structure ---
+struct DisplaySettings {
+    white_balance: mat3x3<f32>, // Chromatic adaptation in linear sRGB
+    exposure: f32, // In stops
+    tone_mapping: u32, // 0 = none, 1 = Reinhard, 2 = ACES fitted, 3 = AgX
+    encode_srgb: u32, // 1 if the render target isn't sRGB and the shader has to encode the output
+    raw: u32, // 1 to skip exposure, white balance and tone mapping, for the debug views
+};
+
+struct Camera {
+    position: vec3<f32>,
+    moved: u32, // 1 if the pose changed since the previous frame
+    orientation: vec4<f32>, // Quaternion (x, y, z, w)
+    previous_position: vec3<f32>,
+    previous_orientation: vec4<f32>,
+};
+
+// Accumulated data of a pixel
+struct FramePixel {
+    color: vec4<f32>, // Average radiance, and in w the number of frames averaged
+    geometry: vec4<f32>, // Normal and distance of the first hit, the distance is -1 for the sky
+    albedo: vec4<f32>, // Color and material ID of the first hit, black and -1 for the sky
+    moments: vec4<f32>, // Average squared luminance of the samples and the number of samples, zw are unused
+};
+
+struct ScreenSize {
+    render: vec2<f32>, // Size of the traced image and the frame data in pixels
+    output: vec2<f32>, // Size of the window or image it's presented on
+};
+
+// --- Primitive Information --- Must match primitive.rs
+const nums_per_primitive: u32 = 29; // Number of values stored for every sphere and primitive, the shape then the material
+const nums_per_shape: u32 = 13; // The kind, then the parameters
+
+struct PrimitiveLayout {
+    bvh_root: u32, // Node in bvh_data where the primitives' BVH starts
+    bounded_count: u32, // Primitives in the BVH, the planes after them have no bounds
+    unused: vec2<u32>,
+};
+
+// --- Mesh Information ---
+const nums_per_vertex: u32 = 8; // Position, normal, then texture coordinates
+
+// --- Texture Information --- Must match texture.rs
+const texture_size: u32 = 1024; // Every texture is scaled to this size
+const texture_mip_levels: u32 = 11;
+const max_textures: u32 = 64; // Entries of the texture table
+
+// --- Procedural Textures --- Must match procedural.rs
+const solid_octaves: u32 = 5u; // Octaves of the noise that bends marble veins and wood rings
+
+// --- Ray Offsets --- Must match cpu_renderer.rs
+const float_epsilon: f32 = 5.96046448e-8; // Largest relative rounding error of a float operation
+const primitive_error_roundings: f32 = 64.0; // Roundings assumed in the hit point of shapes besides spheres
 
 // --- BVH Information ---
-const triangle_count: u32 = 15704; // Number of triangles in the scene
-const bvh_node_count: u32 = 30661; // Number of nodes in the BVH
-const bvh_max_depth: u32 = 16; // Max depth of the BVH
-
-// --- Rendering Parameters ---
-const max_bounce_count: u32 = 10; // Max bounces per ray
-const rays_per_pixel: u32 = 20; // Number of rays per pixel
-const screen_size: vec2<f32> = vec2<f32>(1200.0, 600.0); // Size of the screen
-const fov: f32 = 60.0 * 3.14159 / 180.0; // Field of view in radians
-const aspect_ratio: f32 = screen_size.x / screen_size.y; // Aspect ratio of the screen
-
-@group(0) @binding(0) var<storage, read> sphere_data : array<array<f32, nums_per_sphere>, sphere_count>;
-@group(0) @binding(1) var<storage, read> frame_count: u32;
-@group(0) @binding(2) var<storage, read_write> frame_data: array<array<vec3<f32>, u32(screen_size.x)>, u32(screen_size.y * 1.5)>;
-@group(0) @binding(3) var<storage, read> camera_position: vec3<f32>;
-@group(0) @binding(4) var<storage, read> camera_rotation: vec3<f32>;
-@group(0) @binding(5) var<storage, read> triangle_data: array<f32, u32(i32(triangle_count) * 3 * 3)>;
-@group(0) @binding(6) var<storage, read> bvh_data: array<f32, u32(9 * bvh_node_count)>;
-
-// Environment lighting
-const sky_color_horizon: vec3<f32> = vec3<f32>(0.5, 0.7, 1.0);
-const sky_color_zenith: vec3<f32> = vec3<f32>(0.1, 0.25, 1.0);
-const ground_color: vec3<f32> = vec3<f32>(0.2, 0.2, 0.2);
-const sun_light_direction: vec3<f32> = vec3<f32>(0, -0.4, 0.5); // Not normalized
-const sun_intensity: f32 = 3;
-const sun_focus: f32 = 200;
-const use_environment_lighting: bool = true;
+const bvh_max_depth: u32 = 16; // Max depth of the BVHs, must match prepare_data and primitive.rs
+
+// --- Reprojection ---
+const max_reprojected_frames: f32 = 32.0; // Limits the blur and ghosting of history resampled while moving
+const reprojection_depth_tolerance: f32 = 0.05; // Relative difference in distance of the same surface
+const reprojection_normal_tolerance: f32 = 0.9; // Smallest cosine between the normals of the same surface
+
+// --- Adaptive Sampling --- Must match cpu_renderer.rs
+const adaptive_min_frames: f32 = 4.0; // Frames traced with the full budget before the variance is trusted
+const adaptive_min_luminance: f32 = 0.05; // Keeps the relative error of dark pixels finite
+const error_debug_mode: u32 = 9u; // Shows the relative error instead of a first hit
+
+// --- Rendering Parameters --- Set at runtime from RenderSettings in render_settings.rs
+struct RenderSettings {
+    sky_color_horizon: vec3<f32>,
+    max_bounce_count: u32, // Max bounces per ray
+    sky_color_zenith: vec3<f32>,
+    rays_per_pixel: u32, // Number of rays per pixel
+    ground_color: vec3<f32>,
+    fov: f32, // Field of view in radians
+    sun_light_direction: vec3<f32>, // Not normalized
+    sun_intensity: f32,
+    sun_focus: f32,
+    use_environment_lighting: u32,
+    debug_mode: u32, // 0 = off, see debug_color() for the others
+    depth_range: f32, // Distance shown as black in the depth view
+    denoise: u32, // 1 to present the output of denoise.wgsl instead of the frame data
+    adaptive_sampling: u32, // 1 to trace more rays where the error is high and none where it's low
+    noise_threshold: f32, // Relative error of a converged pixel
+    aovs: u32, // 1 to accumulate the light AOVs, for headless renders
+};
+
+// --- AOVs --- Indices of the light AOVs in aov_data, must match aov.rs. Together they add up to the
+// radiance. The material mixes a diffuse and a specular direction by its smoothness, so the light
+// that arrives after the first bounce is split between diffuse and specular by the smoothness of
+// the first hit.
+const aov_direct_diffuse: u32 = 0u; // Light that arrives after the first bounce
+const aov_indirect_diffuse: u32 = 1u; // Light that arrives after more bounces
+const aov_specular: u32 = 2u;
+const aov_emission: u32 = 3u; // Emitters seen directly
+const aov_environment: u32 = 4u; // Environment seen directly
+const aov_count: u32 = 5u;
+
+@group(0) @binding(0) var<storage, read> primitive_data : array<array<f32, nums_per_primitive>>; // The spheres and other primitives in the order of their BVH, then the planes
+@group(0) @binding(1) var<uniform> frame_count: u32;
+@group(0) @binding(2) var<storage, read_write> frame_data: array<FramePixel>; // Row by row
+@group(0) @binding(3) var<uniform> camera: Camera;
+@group(0) @binding(4) var<storage, read> previous_frame_data: array<FramePixel>; // frame_data of the previous frame
+@group(0) @binding(5) var<storage, read> vertex_data: array<f32>; // nums_per_vertex values per vertex
+@group(0) @binding(6) var<storage, read> bvh_data: array<f32>; // 9 values per node, the mesh's nodes then the primitives'
+@group(0) @binding(7) var<uniform> display_settings: DisplaySettings;
+@group(0) @binding(8) var<uniform> screen_size: ScreenSize;
+@group(0) @binding(9) var<uniform> mesh_material: array<vec4<f32>, 4>; // Format: [r, g, b, er], [eg, eb, emission_strength, smoothness], [metallic, albedo_texture, roughness_metallic_texture, emission_texture], [normal_texture, two_sided, transmission, ior]
+@group(0) @binding(10) var<uniform> settings: RenderSettings;
+@group(0) @binding(11) var<storage, read> denoised_data: array<vec4<f32>>; // Row by row, written by denoise.wgsl
+@group(0) @binding(12) var<storage, read_write> aov_data: array<array<vec4<f32>, aov_count>>; // Row by row, a single pixel without settings.aovs
+@group(0) @binding(13) var<storage, read> index_data: array<u32>; // Three vertex indices per triangle
+@group(0) @binding(14) var<uniform> texture_table: array<array<vec4<f32>, 3>, max_textures>; // Format: [kind, image layer, scale, mapping], [first color, parameter], [second color, unused], see texture_data() in texture.rs
+@group(0) @binding(15) var<uniform> primitive_layout: PrimitiveLayout;
+@group(0) @binding(16) var textures: texture_2d_array<f32>; // One layer per image, with all mip levels
+
+// Traversal cost of the last ray_bvh calls, for the BVH cost view
+var<private> bvh_nodes_visited: u32;
+var<private> bvh_items_tested: u32;
+const bvh_cost_scale: f32 = 150.0; // Cost shown as white in the heat map
+
+// Light of the rays traced for the pixel, split into the AOVs by trace()
+var<private> aov_light: array<vec3<f32>, aov_count>;
+
+// Width of the ray cone of a pixel per distance travelled, picks the mip level of the textures
+var<private> pixel_spread: f32;
 
 @vertex
 fn vs_main(@builtin(vertex_index) i: u32) -> VertexOutput {
@@ -65,8 +172,8 @@ fn vs_main(@builtin(vertex_index) i: u32) -> VertexOutput {
         vec2<f32>(1.0, -1.0) // Bottom Right
     );
 
-    var screen_width: f32 = tan(fov * 0.5) * 2.0;
-    var screen_height: f32 = screen_width / aspect_ratio;
+    var screen_width: f32 = tan(settings.fov * 0.5) * 2.0;
+    var screen_height: f32 = screen_width / (screen_size.render.x / screen_size.render.y);
 
     var out: VertexOutput;
     out.pos = vec4<f32>(positions[i], 0.0, 1.0);
@@ -78,34 +185,216 @@ fn vs_main(@builtin(vertex_index) i: u32) -> VertexOutput {
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     // Map pixel coordinates to screen plane coordinates
-    let u: f32 = (2.0 * in.pos.x / screen_size.x - 1.0) * in.screen_width / 2.0;
-    let v: f32 = (1.0 - 2.0 * in.pos.y / screen_size.y) * in.screen_height / 2.0;
-    let pixel_index: u32 = u32(in.pos.x + in.pos.y * screen_size.x);
+    let u: f32 = (2.0 * in.pos.x / screen_size.render.x - 1.0) * in.screen_width / 2.0;
+    let v: f32 = (1.0 - 2.0 * in.pos.y / screen_size.render.y) * in.screen_height / 2.0;
+    let pixel_index: u32 = u32(in.pos.x + in.pos.y * screen_size.render.x);
+    pixel_spread = in.screen_width / screen_size.render.x;
 
     // Create ray and ray direction vector
     var ray_direction: vec3<f32> = vec3<f32>(u, v, -1.0);
     ray_direction = normalize(ray_direction);
 
     // Rotate ray direction vector
-    ray_direction = rotate_vector(ray_direction, camera_rotation);
+    ray_direction = rotate_vector(ray_direction, camera.orientation);
 
     // Create ray
     var ray: Ray;
-    ray.origin = camera_position;
+    ray.origin = camera.position;
     ray.dir = ray_direction;
 
-    // Calculate pixel color
+    // The first hit identifies the surface seen through the pixel in the next frame, and
+    // guides the denoiser
+    var first_hit: HitInfo = calculate_ray_collision(ray);
+    first_hit = apply_textures(first_hit, ray, first_hit.distance);
+    var geometry: vec4<f32> = vec4<f32>(0.0, 0.0, 0.0, -1.0);
+    var albedo: vec4<f32> = vec4<f32>(0.0, 0.0, 0.0, -1.0);
+    if (first_hit.did_hit) {
+        geometry = vec4<f32>(first_hit.normal, first_hit.distance);
+        albedo = vec4<f32>(first_hit.color, f32(first_hit.material_id));
+    }
+
+    // The history of the pixel, which has to be found again if the camera moved
+    let frame_index: u32 = u32(in.pos.y) * u32(screen_size.render.x) + u32(in.pos.x);
+    var history: FramePixel;
+    if (frame_count > 0u) {
+        if (camera.moved == 1u) {
+            history = reproject(ray, first_hit);
+        } else {
+            history = previous_frame_data[frame_index];
+        }
+    }
+
+    // Calculate pixel color, the debug views only look at the first hit so one ray is enough
     var pixel_color: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
-    for (var i: u32 = 0u; i < rays_per_pixel; i = i + 1u) {
-        pixel_color += trace(ray, pixel_index + i * 248135);
+    var luminance_squared: f32 = 0.0;
+    var ray_count: u32 = 1u;
+    let debug_view: bool = settings.debug_mode != 0u && settings.debug_mode != error_debug_mode;
+    if (debug_view) {
+        pixel_color = debug_color(ray);
+        luminance_squared = luminance(pixel_color) * luminance(pixel_color);
+    } else {
+        ray_count = sample_count(history);
+        for (var i: u32 = 0u; i < ray_count; i = i + 1u) {
+            let sample_color: vec3<f32> = trace(ray, pixel_index + i * 248135);
+            pixel_color += sample_color;
+            luminance_squared += luminance(sample_color) * luminance(sample_color);
+        }
+        pixel_color /= max(f32(ray_count), 1.0);
+        luminance_squared /= max(f32(ray_count), 1.0);
+    }
+
+    // Add the frame to the history, weighted by its number of samples. Converged pixels trace
+    // no rays and keep their history as it is.
+    var color: vec4<f32> = history.color;
+    var moments: vec4<f32> = history.moments;
+    if (ray_count > 0u) {
+        let samples: f32 = history.moments.y + f32(ray_count);
+        let weight: f32 = f32(ray_count) / samples;
+        color = vec4<f32>(history.color.xyz * (1.0 - weight) + pixel_color * weight, history.color.w + 1.0);
+        moments = vec4<f32>(history.moments.x * (1.0 - weight) + luminance_squared * weight, samples, 0.0, 0.0);
+
+        // The AOVs are for headless renders, where the camera stays put. They start over when it moves.
+        if (settings.aovs == 1u && !debug_view) {
+            let aov_weight: f32 = select(weight, 1.0, camera.moved == 1u);
+            for (var i: u32 = 0u; i < aov_count; i++) {
+                let average: vec3<f32> = mix(aov_data[frame_index][i].xyz, aov_light[i] / f32(ray_count), aov_weight);
+                aov_data[frame_index][i] = vec4<f32>(average, 0.0);
+            }
+        }
+    }
+    frame_data[frame_index] = FramePixel(color, geometry, albedo, moments);
+
+    // Nothing reads the trace target, fs_present shows the frame data
+    return vec4<f32>(color.xyz, 1.0);
+}
+
+// Show the frame data on the target, with bilinear filtering if the render scale isn't 1.
+// The accumulated radiance stays linear, only the returned color is tone mapped.
+@fragment
+fn fs_present(in: VertexOutput) -> @location(0) vec4<f32> {
+    let position: vec2<f32> = in.pos.xy * screen_size.render / screen_size.output - 0.5;
+    let corner: vec2<f32> = floor(position);
+    let t: vec2<f32> = position - corner;
+    let pixel = vec2<i32>(corner);
+
+    let top: vec3<f32> = mix(frame_pixel(pixel), frame_pixel(pixel + vec2<i32>(1, 0)), t.x);
+    let bottom: vec3<f32> = mix(frame_pixel(pixel + vec2<i32>(0, 1)), frame_pixel(pixel + vec2<i32>(1, 1)), t.x);
+    return vec4<f32>(display_transform(mix(top, bottom, t.y)), 1.0);
+}
+
+// Accumulated or denoised radiance of a pixel, clamped to the edges of the frame
+fn frame_pixel(pixel: vec2<i32>) -> vec3<f32> {
+    let p: vec2<i32> = clamp(pixel, vec2<i32>(0, 0), vec2<i32>(screen_size.render) - 1);
+    let index: u32 = u32(p.y) * u32(screen_size.render.x) + u32(p.x);
+    if (settings.debug_mode == error_debug_mode) {
+        return error_color(frame_data[index]);
+    }
+    if (settings.denoise == 1u) {
+        return denoised_data[index].xyz;
+    }
+    return frame_data[index].color.xyz;
+}
+
+// Rays to trace for a pixel this frame. With adaptive sampling the budget goes to the pixels
+// with the highest error, up to twice the rays per pixel, and converged pixels get none.
+fn sample_count(history: FramePixel) -> u32 {
+    if (settings.adaptive_sampling == 0u || history.color.w < adaptive_min_frames) {
+        return settings.rays_per_pixel;
     }
-    pixel_color /= f32(rays_per_pixel);
+    let error: f32 = relative_error(history);
+    if (error <= settings.noise_threshold) {
+        return 0u;
+    }
+    let rays_per_pixel: f32 = f32(settings.rays_per_pixel);
+    return u32(clamp(ceil(rays_per_pixel * error / (2.0 * settings.noise_threshold)), 1.0, 2.0 * rays_per_pixel));
+}
+
+// Standard error of the average luminance relative to the luminance, from the variance of the samples
+fn relative_error(pixel: FramePixel) -> f32 {
+    if (pixel.moments.y == 0.0) {
+        return 0.0;
+    }
+    let mean: f32 = luminance(pixel.color.xyz);
+    let variance: f32 = max(pixel.moments.x - mean * mean, 0.0);
+    return sqrt(variance / pixel.moments.y) / (mean + adaptive_min_luminance);
+}
+
+// Heat map of the relative error, red at the noise threshold and white at three times it
+fn error_color(pixel: FramePixel) -> vec3<f32> {
+    return heat_map(relative_error(pixel) / (3.0 * settings.noise_threshold));
+}
+
+// History of the surface seen through the pixel, looked up where the previous camera saw it.
+// Neighbors that saw a different surface are left out, so only points that were visible
+// before keep their samples. Returns zero frames if there's nothing to reuse.
+fn reproject(ray: Ray, hit_info: HitInfo) -> FramePixel {
+    var history: FramePixel; // Zero initialized
+    // The sky is infinitely far away, only its direction matters
+    var direction: vec3<f32> = ray.dir;
+    var distance: f32 = -1.0;
+    if (hit_info.did_hit) {
+        direction = hit_info.position - camera.previous_position;
+        distance = length(direction);
+    }
+
+    // Into the previous camera space and onto its screen plane, the inverse of fs_main
+    let previous_orientation_inverse = vec4<f32>(-camera.previous_orientation.xyz, camera.previous_orientation.w);
+    let local: vec3<f32> = rotate_vector(direction, previous_orientation_inverse);
+    if (local.z >= 0.0) {
+        return history; // Behind the previous camera
+    }
+    let screen_width: f32 = tan(settings.fov * 0.5) * 2.0;
+    let screen_height: f32 = screen_width / (screen_size.render.x / screen_size.render.y);
+    let u: f32 = local.x / -local.z;
+    let v: f32 = local.y / -local.z;
+    let x: f32 = (u / screen_width * 2.0 + 1.0) * 0.5 * screen_size.render.x;
+    let y: f32 = (1.0 - v / screen_height * 2.0) * 0.5 * screen_size.render.y;
+
+    // Bilinear filtering over the neighbors that saw the same surface
+    let position: vec2<f32> = vec2<f32>(x, y) - 0.5;
+    let corner: vec2<f32> = floor(position);
+    let t: vec2<f32> = position - corner;
+    var total_weight: f32 = 0.0;
+    for (var i: i32 = 0; i < 4; i++) {
+        let offset = vec2<i32>(i & 1, i >> 1u);
+        let pixel: vec2<i32> = vec2<i32>(corner) + offset;
+        if (any(pixel < vec2<i32>(0, 0)) || any(pixel >= vec2<i32>(screen_size.render))) {
+            continue;
+        }
+        let previous: FramePixel = previous_frame_data[u32(pixel.y) * u32(screen_size.render.x) + u32(pixel.x)];
+        if (!same_surface(previous.geometry, hit_info, distance)) {
+            continue;
+        }
+        let weight_x: f32 = select(1.0 - t.x, t.x, offset.x == 1);
+        let weight_y: f32 = select(1.0 - t.y, t.y, offset.y == 1);
+        history.color += previous.color * weight_x * weight_y;
+        history.moments += previous.moments * weight_x * weight_y;
+        total_weight += weight_x * weight_y;
+    }
+
+    if (total_weight < 0.01) {
+        var none: FramePixel;
+        return none;
+    }
+    history.color /= total_weight;
+    history.moments /= total_weight;
 
-    let weight: f32 = 1.0 / f32(frame_count + 1); // Might need to be + 2 since frame_count starts at 0
-    let weighted_average: vec3<f32> = frame_data[i32(in.pos.y)][i32(in.pos.x)] * (1.0 - weight) + pixel_color * weight;
-    frame_data[i32(in.pos.y)][i32(in.pos.x)] = weighted_average;
-    
-    return vec4<f32>(weighted_average, 1.0);
+    // Fewer frames stand for proportionally fewer samples
+    if (history.color.w > max_reprojected_frames) {
+        history.moments.y *= max_reprojected_frames / history.color.w;
+        history.color.w = max_reprojected_frames;
+    }
+    return history;
+}
+
+// Disocclusion test, whether the previous frame saw the same surface in a pixel
+fn same_surface(previous_geometry: vec4<f32>, hit_info: HitInfo, distance: f32) -> bool {
+    if (!hit_info.did_hit) {
+        return previous_geometry.w < 0.0;
+    }
+    return previous_geometry.w >= 0.0
+        && abs(previous_geometry.w - distance) <= reprojection_depth_tolerance * distance
+        && dot(previous_geometry.xyz, hit_info.normal) >= reprojection_normal_tolerance;
 }
 
 fn trace(ray_in: Ray, seed: u32) -> vec3<f32> {
@@ -113,21 +402,64 @@ fn trace(ray_in: Ray, seed: u32) -> vec3<f32> {
 
     var incoming_light: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
     var ray_color: vec3<f32> = vec3<f32>(1.0, 1.0, 1.0);
+    var first_smoothness: f32 = 0.0;
+    var path_length: f32 = 0.0; // The ray cone keeps growing after a bounce
 
-    for(var i: u32 = 0; i <= max_bounce_count; i++){
+    for(var i: u32 = 0; i <= settings.max_bounce_count; i++){
         var hit_info: HitInfo = calculate_ray_collision(ray);
         if(hit_info.did_hit) {
-            ray.origin = hit_info.position;
-            let diffuse_dir: vec3<f32> = normalize(hit_info.normal + random_direction(seed + i * 12345 + frame_count * 393939123));
+            path_length += hit_info.distance;
+            hit_info = apply_textures(hit_info, ray, path_length);
+            if (i == 0u) {
+                first_smoothness = hit_info.smoothness;
+            }
+            let random_seed: u32 = seed + i * 12345 + frame_count * 393939123;
+            let diffuse_dir: vec3<f32> = normalize(hit_info.normal + random_direction(random_seed));
             let specular_dir: vec3<f32> = reflect(ray.dir, hit_info.normal);
+            let incoming_dir: vec3<f32> = normalize(ray.dir);
             ray.dir = lerp(diffuse_dir, specular_dir, hit_info.smoothness);
 
+            // Refractive materials let the light through unless the Fresnel term reflects it. The
+            // ray leaves the inside through a back face, where the indices swap places.
+            var transmitted: bool = false;
+            if (hit_info.transmission > 0.0) {
+                let eta: f32 = select(hit_info.ior, 1.0 / hit_info.ior, hit_info.front_face);
+                let refracted: vec3<f32> = refract(incoming_dir, hit_info.normal, eta);
+                let reflectance: f32 = fresnel(incoming_dir, refracted, hit_info.normal, eta);
+                if (random_value(hash(random_seed)) < hit_info.transmission * (1.0 - reflectance)) {
+                    transmitted = true;
+                    let diffuse_through: vec3<f32> = normalize(-hit_info.normal + random_direction(random_seed));
+                    ray.dir = lerp(diffuse_through, refracted, hit_info.smoothness);
+                }
+            }
+
+            // Interpolated normals can bend the new direction to the wrong side of the surface,
+            // where it would pass through the mesh. Mirror it back above the surface, or below it
+            // for transmitted light.
+            let side: f32 = select(1.0, -1.0, transmitted);
+            let below: f32 = dot(ray.dir, hit_info.geometric_normal) * side;
+            if (below < 0.0) {
+                ray.dir -= 2.0 * below * side * hit_info.geometric_normal;
+            }
+            ray.origin = offset_ray_origin(hit_info.position, hit_info.position_error, hit_info.geometric_normal, ray.dir);
+
             var emitted_light: vec3<f32> = hit_info.emission_color * hit_info.emission_strength;
             incoming_light += emitted_light * ray_color;
-            ray_color *= hit_info.color;
+            record_aov(i, emitted_light * ray_color, first_smoothness, aov_emission);
+
+            // Metals tint their reflections, other materials only the diffuse light. Transmitted
+            // light takes on the color.
+            let specular_color: vec3<f32> = lerp(vec3<f32>(1.0, 1.0, 1.0), hit_info.color, hit_info.metallic);
+            if (transmitted) {
+                ray_color *= hit_info.color;
+            } else {
+                ray_color *= hit_info.color + (specular_color - hit_info.color) * hit_info.smoothness;
+            }
         } else {
-            if(use_environment_lighting){
-                incoming_light += get_environment_light(ray) * ray_color;
+            if(settings.use_environment_lighting == 1u){
+                let environment_light: vec3<f32> = get_environment_light(ray) * ray_color;
+                incoming_light += environment_light;
+                record_aov(i, environment_light, first_smoothness, aov_environment);
             }
             break;
         }
@@ -136,29 +468,118 @@ fn trace(ray_in: Ray, seed: u32) -> vec3<f32> {
     return incoming_light;
 }
 
+// Add light that reached the camera after a number of bounces to its AOV. Light seen directly
+// goes to direct_aov.
+fn record_aov(bounce: u32, light: vec3<f32>, first_smoothness: f32, direct_aov: u32) {
+    if (settings.aovs == 0u) {
+        return;
+    }
+    if (bounce == 0u) {
+        aov_light[direct_aov] += light;
+        return;
+    }
+    let diffuse: vec3<f32> = light * (1.0 - first_smoothness);
+    if (bounce == 1u) {
+        aov_light[aov_direct_diffuse] += diffuse;
+    } else {
+        aov_light[aov_indirect_diffuse] += diffuse;
+    }
+    aov_light[aov_specular] += light * first_smoothness;
+}
+
+// Information about the first hit instead of the incoming light
+fn debug_color(ray: Ray) -> vec3<f32> {
+    bvh_nodes_visited = 0u;
+    bvh_items_tested = 0u;
+    var hit_info: HitInfo = calculate_ray_collision(ray);
+    hit_info = apply_textures(hit_info, ray, hit_info.distance);
+
+    if (settings.debug_mode == 8u) {
+        return heat_map(f32(bvh_nodes_visited + bvh_items_tested) / bvh_cost_scale);
+    }
+    if (!hit_info.did_hit) {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    switch settings.debug_mode {
+        case 1u: {
+            return hit_info.normal * 0.5 + 0.5;
+        }
+        case 2u: {
+            return hit_info.geometric_normal * 0.5 + 0.5;
+        }
+        case 3u: {
+            return vec3<f32>(1.0 - saturate(hit_info.distance / settings.depth_range));
+        }
+        case 4u: {
+            return hit_info.color;
+        }
+        case 5u: {
+            return id_color(hit_info.material_id);
+        }
+        case 6u: {
+            if (hit_info.triangle_id < 0) {
+                return vec3<f32>(0.0, 0.0, 0.0);
+            }
+            return id_color(u32(hit_info.triangle_id));
+        }
+        default: {
+            return hit_info.barycentrics;
+        }
+    }
+}
+
+// Black-red-yellow-white, like the heat maps of the regression tests
+fn heat_map(value: f32) -> vec3<f32> {
+    let t: f32 = saturate(value) * 3.0;
+    return saturate(vec3<f32>(t, t - 1.0, t - 2.0));
+}
+
+// A distinct color for every ID
+fn id_color(id: u32) -> vec3<f32> {
+    let h: u32 = hash(id);
+    return vec3<f32>(f32(h & 255u), f32((h >> 8u) & 255u), f32((h >> 16u) & 255u)) / 255.0;
+}
+
+// PCG hash, unlike random_value() it spreads consecutive numbers well
+fn hash(value: u32) -> u32 {
+    let state: u32 = value * 747796405u + 2891336453u;
+    let word: u32 = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
 fn calculate_ray_collision(ray: Ray) -> HitInfo {
     var closest_hit: HitInfo;
     closest_hit.did_hit = false;
     closest_hit.distance = 1000000.0;
 
-    // Check for sphere intersections
-    for (var i = 0u; i < sphere_count; i = i + 1u) {
-        var sphere_center: vec3<f32> = vec3<f32>(sphere_data[i][0], sphere_data[i][1], sphere_data[i][2]);
-        var sphere_radius: f32 = sphere_data[i][3];
+    // Check for intersections with the spheres and the other primitives using their BVH
+    closest_hit = ray_bvh(ray, i32(primitive_layout.bvh_root), true);
 
-        var hit_info: HitInfo = ray_sphere(ray, sphere_data[i]);
+    // Planes have no bounds, so they're tested one by one
+    for (var i = primitive_layout.bounded_count; i < arrayLength(&primitive_data); i = i + 1u) {
+        var hit_info: HitInfo = ray_primitive(ray, primitive_data[i]);
 
         if hit_info.did_hit && hit_info.distance < closest_hit.distance {
             closest_hit = hit_info;
+            closest_hit.material_id = i + 1u;
         }
     }
 
         // Check for triangle intersections using BVH
-        var hit_info: HitInfo = ray_triangle_bvh(ray);
+        var hit_info: HitInfo = ray_bvh(ray, 0, false);
         if hit_info.did_hit && hit_info.distance < closest_hit.distance {
             closest_hit = hit_info;
         }
 
+    // Two-sided surfaces are also hit from behind, their normals are turned to face the ray
+    closest_hit.front_face = dot(ray.dir, closest_hit.geometric_normal) <= 0.0;
+    if (closest_hit.did_hit && !closest_hit.front_face) {
+        closest_hit.normal = -closest_hit.normal;
+        closest_hit.geometric_normal = -closest_hit.geometric_normal;
+        closest_hit.tangent.w = -closest_hit.tangent.w;
+    }
+
     return closest_hit;
 }
 
@@ -166,10 +587,12 @@ fn get_node(index: i32) -> array<f32, 9> {
     return array<f32, 9>(bvh_data[u32(index * 9)], bvh_data[u32(index * 9 + 1)], bvh_data[u32(index * 9 + 2)], bvh_data[u32(index * 9 + 3)], bvh_data[u32(index * 9 + 4)], bvh_data[u32(index * 9 + 5)], bvh_data[u32(index * 9 + 6)], bvh_data[u32(index * 9 + 7)], bvh_data[u32(index * 9 + 8)]);
 }
 
-fn ray_triangle_bvh(ray: Ray) -> HitInfo {
+// Closest hit in the BVH starting at root, over the primitives in primitive_data or the triangles
+// of the mesh
+fn ray_bvh(ray: Ray, root: i32, primitives: bool) -> HitInfo {
     var node_stack: array<i32, u32(bvh_max_depth + 1)> = array<i32, u32(bvh_max_depth + 1)>();
     var stack_index: i32 = 0;
-    node_stack[stack_index] = 0;
+    node_stack[stack_index] = root;
     stack_index++;
 
     var result: HitInfo;
@@ -180,17 +603,32 @@ fn ray_triangle_bvh(ray: Ray) -> HitInfo {
         stack_index--;
         let node_index: i32 = node_stack[stack_index];
         let node: array<f32, 9> = get_node(node_index);
+        bvh_nodes_visited++;
 
-            if(node[8] == 0) {
+            if(node[8] == 0 && primitives) {
+                // Leaf node (no children, so test primitives)
+                for(var i: u32 = u32(node[6]); i < u32(node[6] + node[7]); i++){
+                    let primitive_hit_info: HitInfo = ray_primitive(ray, primitive_data[i]);
+                    bvh_items_tested++;
+                    if (primitive_hit_info.did_hit && primitive_hit_info.distance < result.distance) {
+                        result = primitive_hit_info;
+                        result.material_id = i + 1u;
+                    }
+                }
+            } else if(node[8] == 0) {
                 // Leaf node (no children, so test triangles)
                 for(var i: u32 = u32(node[6]); i < u32(node[6] + node[7]); i++){
-                    let triangle_hit_info: HitInfo = ray_triangle(ray, array<vec3<f32>, 3>(
-                        vec3<f32>(triangle_data[i * 9], triangle_data[i * 9 + 1], triangle_data[i * 9 + 2]),
-                        vec3<f32>(triangle_data[i * 9 + 3], triangle_data[i * 9 + 4], triangle_data[i * 9 + 5]),
-                        vec3<f32>(triangle_data[i * 9 + 6], triangle_data[i * 9 + 7], triangle_data[i * 9 + 8])
-                    ));
+                    let a: u32 = index_data[i * 3];
+                    let b: u32 = index_data[i * 3 + 1];
+                    let c: u32 = index_data[i * 3 + 2];
+                    let triangle_hit_info: HitInfo = ray_triangle(ray, mesh_material[3].y != 0.0,
+                        array<vec3<f32>, 3>(vertex_position(a), vertex_position(b), vertex_position(c)),
+                        array<vec3<f32>, 3>(vertex_normal(a), vertex_normal(b), vertex_normal(c))
+                    );
+                    bvh_items_tested++;
                     if (triangle_hit_info.did_hit && triangle_hit_info.distance < result.distance) {
                         result = triangle_hit_info;
+                        result.triangle_id = i32(i);
                     }
                 }
             } else {
@@ -231,9 +669,84 @@ fn ray_triangle_bvh(ray: Ray) -> HitInfo {
             }
     }
 
+    if (result.did_hit && !primitives) {
+        result = triangle_surface(result);
+    }
+    return result;
+}
+
+// Texture coordinates, their density and the tangent of the closest triangle hit. Only done once
+// per ray, not for every triangle tested.
+fn triangle_surface(hit_info: HitInfo) -> HitInfo {
+    var result: HitInfo = hit_info;
+    let first: u32 = u32(hit_info.triangle_id) * 3;
+    let a: u32 = index_data[first];
+    let b: u32 = index_data[first + 1];
+    let c: u32 = index_data[first + 2];
+    let uv_a: vec2<f32> = vertex_uv(a);
+    let uv_b: vec2<f32> = vertex_uv(b);
+    let uv_c: vec2<f32> = vertex_uv(c);
+    result.uv = uv_a * hit_info.barycentrics.x + uv_b * hit_info.barycentrics.y + uv_c * hit_info.barycentrics.z;
+
+    let edge_ab: vec3<f32> = vertex_position(b) - vertex_position(a);
+    let edge_ac: vec3<f32> = vertex_position(c) - vertex_position(a);
+    let uv_ab: vec2<f32> = uv_b - uv_a;
+    let uv_ac: vec2<f32> = uv_c - uv_a;
+    let uv_determinant: f32 = uv_ab.x * uv_ac.y - uv_ac.x * uv_ab.y; // Twice the texture area
+    let area: f32 = length(cross(edge_ab, edge_ac)); // Twice the surface area
+    if (abs(uv_determinant) < 1e-12 || area == 0.0) {
+        // No texture coordinates, any tangent works
+        result.uv_density = 0.0;
+        result.tangent = vec4<f32>(perpendicular(hit_info.geometric_normal), 1.0);
+        return result;
+    }
+    result.uv_density = sqrt(abs(uv_determinant) / area);
+    let tangent: vec3<f32> = (edge_ab * uv_ac.y - edge_ac * uv_ab.y) / uv_determinant;
+    let bitangent: vec3<f32> = (edge_ac * uv_ab.x - edge_ab * uv_ac.x) / uv_determinant;
+    let handedness: f32 = select(1.0, -1.0, dot(cross(hit_info.geometric_normal, tangent), bitangent) < 0.0);
+    result.tangent = vec4<f32>(tangent, handedness);
     return result;
 }
 
+// Bound of the relative error after n roundings
+fn gamma(n: f32) -> f32 {
+    return n * float_epsilon / (1.0 - n * float_epsilon);
+}
+
+// Start of a ray leaving the surface at a position that is within position_error of the exact
+// surface. It moves along the geometric normal to the side the ray leaves through, just past the
+// error bound, and then one more float away so rounding can't bring it back.
+fn offset_ray_origin(position: vec3<f32>, position_error: vec3<f32>, normal: vec3<f32>, dir: vec3<f32>) -> vec3<f32> {
+    var offset: vec3<f32> = normal * dot(abs(normal), position_error);
+    if (dot(dir, normal) < 0.0) {
+        offset = -offset;
+    }
+    var origin: vec3<f32> = position + offset;
+    for (var i = 0u; i < 3u; i++) {
+        if (offset[i] > 0.0) {
+            origin[i] = next_float_up(origin[i]);
+        } else if (offset[i] < 0.0) {
+            origin[i] = -next_float_up(-origin[i]);
+        }
+    }
+    return origin;
+}
+
+// Smallest float above x
+fn next_float_up(x: f32) -> f32 {
+    if (x == 0.0) {
+        return bitcast<f32>(1u);
+    }
+    let bits: u32 = bitcast<u32>(x);
+    return bitcast<f32>(select(bits - 1u, bits + 1u, x > 0.0));
+}
+
+// Some unit vector perpendicular to a unit vector
+fn perpendicular(v: vec3<f32>) -> vec3<f32> {
+    let other: vec3<f32> = select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), abs(v.x) > 0.9);
+    return normalize(cross(v, other));
+}
+
 fn ray_box(ray: Ray, bounding_box: array<f32, 6>) -> f32 {
     let min_bound: vec3<f32> = vec3<f32>(bounding_box[0], bounding_box[1], bounding_box[2]);  
     let max_bound: vec3<f32> = vec3<f32>(bounding_box[3], bounding_box[4], bounding_box[5]);
@@ -255,45 +768,165 @@ fn ray_box(ray: Ray, bounding_box: array<f32, 6>) -> f32 {
     }
 }
 
-fn ray_triangle(ray: Ray, triangle: array<vec3<f32>, 3>) -> HitInfo {
+fn vertex_position(index: u32) -> vec3<f32> {
+    let start: u32 = index * nums_per_vertex;
+    return vec3<f32>(vertex_data[start], vertex_data[start + 1], vertex_data[start + 2]);
+}
+
+fn vertex_normal(index: u32) -> vec3<f32> {
+    let start: u32 = index * nums_per_vertex + 3;
+    return vec3<f32>(vertex_data[start], vertex_data[start + 1], vertex_data[start + 2]);
+}
+
+fn vertex_uv(index: u32) -> vec2<f32> {
+    let start: u32 = index * nums_per_vertex + 6;
+    return vec2<f32>(vertex_data[start], vertex_data[start + 1]);
+}
+
+// Watertight test of Woop, Benthin and Wald: the ray is moved to the origin and sheared to point
+// along z, then the 2D edge functions of the triangle decide the hit. Triangles that share an edge
+// compute its edge function from the same numbers, so a ray can't slip through between them. The
+// back is only hit if the triangle is two-sided. The shading normal interpolates the vertex
+// normals, the geometric normal is the triangle's own.
+fn ray_triangle(ray: Ray, two_sided: bool, triangle: array<vec3<f32>, 3>, vertex_normals: array<vec3<f32>, 3>) -> HitInfo {
     var hit_info: HitInfo;
     hit_info.did_hit = false;
 
-    var edge_ab: vec3<f32> = triangle[1] - triangle[0];
-    var edge_ac: vec3<f32> = triangle[2] - triangle[0];
-    var normal_vector: vec3<f32> = cross(edge_ab, edge_ac);
-    var ao: vec3<f32> = ray.origin - triangle[0];
-    var dao: vec3<f32> = cross(ao, ray.dir);
-
-    var determinant: f32 = -dot(ray.dir, normal_vector);
-    var inv_det: f32 = 1.0 / determinant;
-
-    // Calculate distance to triangle and barycentirc coordinates of intersection point
-    var dst: f32 = dot(ao, normal_vector) * inv_det;
-    var u: f32 = dot(edge_ac, dao) * inv_det;
-    var v: f32 = -dot(edge_ab, dao) * inv_det;
-    var w: f32 = 1.0 - u - v;
-
-    // Check if the intersection is within the triangle's bounds
-    if (dst >= 0.0 && u >= 0.0 && v >= 0.0 && w >= 0.0) {
-        hit_info.did_hit = determinant >= 0.0001 && dst >= 0 && u >= 0 && v >= 0 && w >= 0;
-        hit_info.distance = dst;
-        hit_info.position = ray.origin + ray.dir * dst;
-        hit_info.normal = normalize(normal_vector);
-        hit_info.color = vec3<f32>(0.65, 0.65, 1.0);
-        hit_info.emission_color = vec3<f32>(0.0, 0.0, 0.0);
-        hit_info.emission_strength = 0.0;
-        hit_info.smoothness = 0.9;
+    // The largest direction component becomes z, swapping x and y keeps the winding if it's negative
+    let kz: u32 = max_dimension(abs(ray.dir));
+    var kx: u32 = (kz + 1u) % 3u;
+    var ky: u32 = (kx + 1u) % 3u;
+    if (ray.dir[kz] < 0.0) {
+        let swap: u32 = kx;
+        kx = ky;
+        ky = swap;
+    }
+    let shear: vec3<f32> = vec3<f32>(-ray.dir[kx], -ray.dir[ky], 1.0) / ray.dir[kz];
+
+    // Vertices relative to the ray origin in the sheared space
+    let a: vec3<f32> = triangle[0] - ray.origin;
+    let b: vec3<f32> = triangle[1] - ray.origin;
+    let c: vec3<f32> = triangle[2] - ray.origin;
+    let ax: f32 = a[kx] + shear.x * a[kz];
+    let ay: f32 = a[ky] + shear.y * a[kz];
+    let bx: f32 = b[kx] + shear.x * b[kz];
+    let by: f32 = b[ky] + shear.y * b[kz];
+    let cx: f32 = c[kx] + shear.x * c[kz];
+    let cy: f32 = c[ky] + shear.y * c[kz];
+
+    // Edge functions, the weight of the vertex opposite to each edge. A front face has them all
+    // positive and a back face all negative, with a zero on the edge itself.
+    let edge_a: f32 = cx * by - cy * bx;
+    let edge_b: f32 = ax * cy - ay * cx;
+    let edge_c: f32 = bx * ay - by * ax;
+    let determinant: f32 = edge_a + edge_b + edge_c;
+    let front: bool = edge_a >= 0.0 && edge_b >= 0.0 && edge_c >= 0.0;
+    let back: bool = edge_a <= 0.0 && edge_b <= 0.0 && edge_c <= 0.0;
+    if (!(front || (back && two_sided)) || determinant == 0.0) {
+        return hit_info;
+    }
+
+    // Distance along the ray, scaled by the determinant
+    let scaled_distance: f32 = (edge_a * a[kz] + edge_b * b[kz] + edge_c * c[kz]) * shear.z;
+    if (scaled_distance * determinant < 0.0) {
+        return hit_info;
     }
 
+    let inv_det: f32 = 1.0 / determinant;
+    let w: f32 = edge_a * inv_det;
+    let u: f32 = edge_b * inv_det;
+    let v: f32 = edge_c * inv_det;
+    let normal_vector: vec3<f32> = cross(triangle[1] - triangle[0], triangle[2] - triangle[0]);
+
+    hit_info.did_hit = true;
+    hit_info.distance = scaled_distance * inv_det;
+
+    // The barycentric sum of the vertices is closer to the surface than the point along the ray
+    hit_info.position = triangle[0] * w + triangle[1] * u + triangle[2] * v;
+    hit_info.position_error = gamma(7.0) * (abs(triangle[0] * w) + abs(triangle[1] * u) + abs(triangle[2] * v));
+    hit_info.normal = normalize(vertex_normals[0] * w + vertex_normals[1] * u + vertex_normals[2] * v);
+    hit_info.geometric_normal = normalize(normal_vector);
+    hit_info.color = mesh_material[0].xyz;
+    hit_info.emission_color = vec3<f32>(mesh_material[0].w, mesh_material[1].xy);
+    hit_info.emission_strength = mesh_material[1].z;
+    hit_info.smoothness = mesh_material[1].w;
+    hit_info.metallic = mesh_material[2].x;
+    hit_info.transmission = mesh_material[3].z;
+    hit_info.ior = mesh_material[3].w;
+    hit_info.textures = vec4<i32>(vec4<f32>(mesh_material[2].yzw, mesh_material[3].x));
+    hit_info.material_id = 0u;
+    hit_info.barycentrics = vec3<f32>(w, u, v);
     return hit_info;
 }
 
-fn ray_sphere(ray: Ray, sphere: array<f32, nums_per_sphere>) -> HitInfo {
-    var sphere_center: vec3<f32> = vec3<f32>(sphere[0], sphere[1], sphere[2]);
-    var sphere_radius: f32 = sphere[3];
-    var sphere_color: vec3<f32> = vec3<f32>(sphere[4], sphere[5], sphere[6]);
+// Index of the largest component
+fn max_dimension(v: vec3<f32>) -> u32 {
+    if (v.x > v.y) {
+        return select(2u, 0u, v.x > v.z);
+    }
+    return select(2u, 1u, v.y > v.z);
+}
+
+// Intersection with a sphere or another primitive, see Primitive::to_data() in primitive.rs. The
+// shape functions find the surface and the primitive's material is filled in here.
+fn ray_primitive(ray: Ray, primitive: array<f32, nums_per_primitive>) -> HitInfo {
+    let a: vec3<f32> = vec3<f32>(primitive[1], primitive[2], primitive[3]);
+    let b: vec3<f32> = vec3<f32>(primitive[4], primitive[5], primitive[6]);
+    let c: vec3<f32> = vec3<f32>(primitive[7], primitive[8], primitive[9]);
+    let d: vec3<f32> = vec3<f32>(primitive[10], primitive[11], primitive[12]);
+    let m: u32 = nums_per_shape;
+    let two_sided: bool = primitive[m + 13] != 0.0;
 
+    var hit_info: HitInfo;
+    switch (u32(primitive[0])) {
+        case 0u: {
+            hit_info = ray_sphere(ray, a, primitive[4], two_sided);
+        }
+        case 1u: {
+            hit_info = ray_plane(ray, a, b, two_sided);
+        }
+        case 2u: {
+            hit_info = ray_oriented_box(ray, a, array<vec3<f32>, 3>(b, c, d), two_sided);
+        }
+        case 3u: {
+            hit_info = ray_quad(ray, a, b, c, two_sided);
+        }
+        case 4u: {
+            hit_info = ray_disk(ray, a, b, primitive[7], two_sided);
+        }
+        case 5u: {
+            hit_info = ray_cylinder(ray, a, b, primitive[7], two_sided);
+        }
+        case 6u: {
+            hit_info = ray_cone(ray, a, b, primitive[7], two_sided);
+        }
+        default: {
+            hit_info = ray_torus(ray, a, b, primitive[7], primitive[8], two_sided);
+        }
+    }
+
+    // Spheres bound their own error. The other shapes find the distance first, so their error is
+    // assumed to be a generous number of roundings of origin + dir * distance.
+    if (hit_info.did_hit && u32(primitive[0]) != 0u) {
+        hit_info.position_error = gamma(primitive_error_roundings) * (abs(ray.origin) + abs(ray.dir * hit_info.distance));
+    }
+
+    if (hit_info.did_hit) {
+        hit_info.color = vec3<f32>(primitive[m], primitive[m + 1], primitive[m + 2]);
+        hit_info.emission_color = vec3<f32>(primitive[m + 3], primitive[m + 4], primitive[m + 5]);
+        hit_info.emission_strength = primitive[m + 6];
+        hit_info.smoothness = primitive[m + 7];
+        hit_info.metallic = primitive[m + 8];
+        hit_info.transmission = primitive[m + 14];
+        hit_info.ior = primitive[m + 15];
+        hit_info.textures = vec4<i32>(i32(primitive[m + 9]), i32(primitive[m + 10]), i32(primitive[m + 11]), i32(primitive[m + 12]));
+        hit_info.triangle_id = -1;
+    }
+    return hit_info;
+}
+
+// Hit where the ray enters the sphere, or where it leaves if it starts inside a two-sided one
+fn ray_sphere(ray: Ray, sphere_center: vec3<f32>, sphere_radius: f32, two_sided: bool) -> HitInfo {
     var hit_info: HitInfo;
     hit_info.did_hit = false;
 
@@ -306,45 +939,613 @@ fn ray_sphere(ray: Ray, sphere: array<f32, nums_per_sphere>) -> HitInfo {
     // No solution when d < 0 (ray misses sphere)
     if discriminant >= 0.0 {
         // Distance to nearest interesction point
-        let distance: f32 = (-b - sqrt(discriminant)) / (2.0 * a);
+        var distance: f32 = (-b - sqrt(discriminant)) / (2.0 * a);
+        if (distance < 0.0 && two_sided) {
+            distance = (-b + sqrt(discriminant)) / (2.0 * a);
+        }
 
         // Ignore intersections that occur behind the ray
         if distance >= 0.0 {
             hit_info.did_hit = true;
             hit_info.distance = distance;
+
+            // Projecting the point back onto the sphere leaves only a few roundings of error
+            var local: vec3<f32> = ray.origin + ray.dir * distance - sphere_center;
+            local *= sphere_radius / length(local);
+            hit_info.position = sphere_center + local;
+            hit_info.position_error = gamma(5.0) * abs(local) + gamma(1.0) * abs(hit_info.position);
+            hit_info.normal = normalize(local);
+            hit_info.geometric_normal = hit_info.normal;
+
+            // Longitude and latitude, v goes up. u increases around the y axis, towards the tangent.
+            let n: vec3<f32> = hit_info.normal;
+            hit_info.uv = vec2<f32>(0.5 + atan2(n.x, n.z) / (2.0 * 3.14159), 0.5 + asin(clamp(n.y, -1.0, 1.0)) / 3.14159);
+            hit_info.uv_density = 1.0 / (3.14159 * sphere_radius * sqrt(2.0));
+            var tangent: vec3<f32> = vec3<f32>(n.z, 0.0, -n.x);
+            if (dot(tangent, tangent) < 1e-12) {
+                tangent = perpendicular(n); // At the poles
+            }
+            hit_info.tangent = vec4<f32>(tangent, 1.0);
+        }
+    }
+
+    return hit_info;
+}
+
+// Infinite plane through the point, only hit from the side the unit normal points to unless it's
+// two-sided. The texture coordinates are distances along two directions in the plane.
+fn ray_plane(ray: Ray, point: vec3<f32>, normal: vec3<f32>, two_sided: bool) -> HitInfo {
+    var hit_info: HitInfo;
+    hit_info.did_hit = false;
+
+    let facing: f32 = dot(ray.dir, normal);
+    let distance: f32 = dot(point - ray.origin, normal) / facing;
+    if ((facing < 0.0 || (two_sided && facing > 0.0)) && distance >= 0.0) {
+        hit_info.did_hit = true;
+        hit_info.distance = distance;
+        hit_info.position = ray.origin + ray.dir * distance;
+        hit_info.normal = normal;
+        hit_info.geometric_normal = normal;
+
+        let tangent: vec3<f32> = perpendicular(normal);
+        let offset: vec3<f32> = hit_info.position - point;
+        hit_info.uv = vec2<f32>(dot(offset, tangent), dot(offset, cross(normal, tangent)));
+        hit_info.uv_density = 1.0;
+        hit_info.tangent = vec4<f32>(tangent, 1.0);
+    }
+
+    return hit_info;
+}
+
+// Parallelogram spanned by the edges from the corner, only hit from the front unless it's
+// two-sided. The texture coordinates go from 0 to 1 along the edges.
+fn ray_quad(ray: Ray, corner: vec3<f32>, edge_u: vec3<f32>, edge_v: vec3<f32>, two_sided: bool) -> HitInfo {
+    let normal: vec3<f32> = cross(edge_u, edge_v);
+    var hit_info: HitInfo = ray_plane(ray, corner, normalize(normal), two_sided);
+
+    // Coordinates of the hit along the edges
+    let offset: vec3<f32> = hit_info.position - corner;
+    let scaled_normal: vec3<f32> = normal / dot(normal, normal);
+    let uv: vec2<f32> = vec2<f32>(dot(scaled_normal, cross(offset, edge_v)), dot(scaled_normal, cross(edge_u, offset)));
+
+    if (hit_info.did_hit && all(uv >= vec2<f32>(0.0)) && all(uv <= vec2<f32>(1.0))) {
+        hit_info.uv = uv;
+        hit_info.uv_density = 1.0 / sqrt(length(normal));
+        hit_info.tangent = vec4<f32>(normalize(edge_u), 1.0);
+    } else {
+        hit_info.did_hit = false;
+    }
+    return hit_info;
+}
+
+// Disk facing the unit normal, only hit from the front unless it's two-sided. The texture is
+// mapped to the square around it.
+fn ray_disk(ray: Ray, center: vec3<f32>, normal: vec3<f32>, radius: f32, two_sided: bool) -> HitInfo {
+    var hit_info: HitInfo = ray_plane(ray, center, normal, two_sided);
+
+    let offset: vec3<f32> = hit_info.position - center;
+    if (hit_info.did_hit && dot(offset, offset) <= radius * radius) {
+        hit_info.uv = 0.5 + hit_info.uv / (2.0 * radius);
+        hit_info.uv_density = 1.0 / (2.0 * radius);
+    } else {
+        hit_info.did_hit = false;
+    }
+    return hit_info;
+}
+
+// Box around the center with the given half size axes, hit where the ray enters it, or where it
+// leaves if it starts inside a two-sided one. Every face is mapped to the unit square.
+fn ray_oriented_box(ray: Ray, center: vec3<f32>, axes_in: array<vec3<f32>, 3>, two_sided: bool) -> HitInfo {
+    var hit_info: HitInfo;
+    hit_info.did_hit = false;
+    var axes: array<vec3<f32>, 3> = axes_in;
+
+    // The box goes from -1 to 1 in the coordinates of the axes
+    let to_box: mat3x3<f32> = mat3x3<f32>(axes[0] / dot(axes[0], axes[0]), axes[1] / dot(axes[1], axes[1]), axes[2] / dot(axes[2], axes[2]));
+    let origin: vec3<f32> = (ray.origin - center) * to_box;
+    let dir: vec3<f32> = ray.dir * to_box;
+
+    let t_min = (-1.0 - origin) / dir;
+    let t_max = (1.0 - origin) / dir;
+    let t1 = min(t_min, t_max);
+    let t2 = max(t_min, t_max);
+    let t_near = max(max(t1.x, t1.y), t1.z);
+    let t_far = min(min(t2.x, t2.y), t2.z);
+
+    let leaving: bool = t_near < 0.0 && two_sided;
+    let distance: f32 = select(t_near, t_far, leaving);
+    if (t_near <= t_far && distance >= 0.0) {
+        // The face the ray enters or leaves through, and the two axes along it
+        let t_faces: vec3<f32> = select(t1, t2, leaving);
+        var face: u32 = 2u;
+        if (distance == t_faces.x) {
+            face = 0u;
+        } else if (distance == t_faces.y) {
+            face = 1u;
+        }
+        let u_face: u32 = (face + 1u) % 3u;
+        let v_face: u32 = (face + 2u) % 3u;
+        let normal: vec3<f32> = normalize(axes[face]) * sign(dir[face]) * select(-1.0, 1.0, leaving);
+        let local: vec3<f32> = origin + dir * distance;
+
+        hit_info.did_hit = true;
+        hit_info.distance = distance;
+        hit_info.position = ray.origin + ray.dir * distance;
+        hit_info.normal = normal;
+        hit_info.geometric_normal = normal;
+        hit_info.uv = 0.5 + 0.5 * vec2<f32>(local[u_face], local[v_face]);
+        hit_info.uv_density = 1.0 / (2.0 * sqrt(length(axes[u_face]) * length(axes[v_face])));
+        let handedness: f32 = select(1.0, -1.0, dot(cross(normal, axes[u_face]), axes[v_face]) < 0.0);
+        hit_info.tangent = vec4<f32>(normalize(axes[u_face]), handedness);
+    }
+
+    return hit_info;
+}
+
+// Cylinder from the base to base + axis, closed by disks at both ends and hit where the ray
+// enters it, or where it leaves if it starts inside a two-sided one. On the side u goes around
+// the axis and v along it.
+fn ray_cylinder(ray: Ray, base: vec3<f32>, axis: vec3<f32>, radius: f32, two_sided: bool) -> HitInfo {
+    let height: f32 = length(axis);
+    let w: vec3<f32> = axis / height;
+    var hit_info: HitInfo = ray_disk(ray, base, -w, radius, two_sided);
+    let top_hit_info: HitInfo = ray_disk(ray, base + axis, w, radius, two_sided);
+    if (top_hit_info.did_hit && (!hit_info.did_hit || top_hit_info.distance < hit_info.distance)) {
+        hit_info = top_hit_info;
+    }
+
+    // The side, with the parts of the ray perpendicular to the axis
+    let offset: vec3<f32> = ray.origin - base;
+    let dir_across: vec3<f32> = ray.dir - w * dot(ray.dir, w);
+    let offset_across: vec3<f32> = offset - w * dot(offset, w);
+    let a: f32 = dot(dir_across, dir_across);
+    let b: f32 = 2.0 * dot(offset_across, dir_across);
+    let c: f32 = dot(offset_across, offset_across) - radius * radius;
+    let discriminant: f32 = b * b - 4.0 * a * c;
+
+    if (a > 0.0 && discriminant >= 0.0) {
+        // The nearer solution is where the ray enters
+        var distance: f32 = (-b - sqrt(discriminant)) / (2.0 * a);
+        if (distance < 0.0 && two_sided) {
+            distance = (-b + sqrt(discriminant)) / (2.0 * a);
+        }
+        let along: f32 = dot(offset + ray.dir * distance, w);
+        if (distance >= 0.0 && along >= 0.0 && along <= height && (!hit_info.did_hit || distance < hit_info.distance)) {
+            let normal: vec3<f32> = normalize(offset_across + dir_across * distance);
+            hit_info.did_hit = true;
+            hit_info.distance = distance;
             hit_info.position = ray.origin + ray.dir * distance;
-            hit_info.normal = normalize(hit_info.position - sphere_center);
-            hit_info.color = sphere_color;
-            hit_info.emission_color = vec3<f32>(sphere[7], sphere[8], sphere[9]);
-            hit_info.emission_strength = sphere[10];
-            hit_info.smoothness = sphere[11];
+            hit_info.normal = normal;
+            hit_info.geometric_normal = normal;
+            hit_info.uv = vec2<f32>(angle_around(normal, w), along / height);
+            hit_info.uv_density = 1.0 / sqrt(2.0 * 3.14159 * radius * height);
+            hit_info.tangent = vec4<f32>(cross(w, normal), 1.0);
         }
     }
 
     return hit_info;
 }
 
-fn rotate_vector(ray: vec3<f32>, angles: vec3<f32>) -> vec3<f32> {
-    let x = ray.x;
-    let y = ray.y;
-    let z = ray.z;
+// Cone with a base disk of the radius and its apex at base + axis, closed at the base and hit
+// where the ray enters it, or where it leaves if it starts inside a two-sided one. On the side u
+// goes around the axis and v along it.
+fn ray_cone(ray: Ray, base: vec3<f32>, axis: vec3<f32>, radius: f32, two_sided: bool) -> HitInfo {
+    let height: f32 = length(axis);
+    let w: vec3<f32> = axis / height;
+    var hit_info: HitInfo = ray_disk(ray, base, -w, radius, two_sided);
+
+    // The side, where the distance to the axis is the slope times the distance from the apex
+    // along the axis
+    let slope: f32 = radius / height;
+    let k: f32 = 1.0 + slope * slope;
+    let apex: vec3<f32> = base + axis;
+    let offset: vec3<f32> = ray.origin - apex;
+    let dir_along: f32 = -dot(ray.dir, w);
+    let offset_along: f32 = -dot(offset, w);
+    let a: f32 = dot(ray.dir, ray.dir) - k * dir_along * dir_along;
+    let b: f32 = 2.0 * (dot(offset, ray.dir) - k * offset_along * dir_along);
+    let c: f32 = dot(offset, offset) - k * offset_along * offset_along;
+    let discriminant: f32 = b * b - 4.0 * a * c;
+
+    if (discriminant >= 0.0) {
+        // a < 0 swaps the solutions, and either can be on the mirrored cone beyond the apex or
+        // where the ray leaves, so both are checked
+        let root: f32 = sqrt(discriminant);
+        for (var i = 0u; i < 2u; i++) {
+            let distance: f32 = (-b + select(-root, root, i == 1u)) / (2.0 * a);
+            let position: vec3<f32> = ray.origin + ray.dir * distance;
+            let along: f32 = -dot(position - apex, w);
+            let radial: vec3<f32> = normalize(position - apex + w * along);
+            let normal: vec3<f32> = normalize(radial + w * slope);
+            if (distance >= 0.0 && along >= 0.0 && along <= height && (dot(normal, ray.dir) < 0.0 || two_sided)
+                && (!hit_info.did_hit || distance < hit_info.distance)) {
+                hit_info.did_hit = true;
+                hit_info.distance = distance;
+                hit_info.position = position;
+                hit_info.normal = normal;
+                hit_info.geometric_normal = normal;
+                hit_info.uv = vec2<f32>(angle_around(radial, w), 1.0 - along / height);
+                hit_info.uv_density = 1.0 / sqrt(3.14159 * radius * sqrt(radius * radius + height * height));
+                hit_info.tangent = vec4<f32>(cross(w, radial), 1.0);
+            }
+        }
+    }
+
+    return hit_info;
+}
+
+// Torus around the unit axis through the center, hit where the ray enters it, or where it leaves
+// if it starts inside a two-sided one. u goes around the axis and v around the tube. The quartic is solved in coordinates where the major radius is 1
+// and the ray starts on the bounding sphere, which keeps 32-bit floats precise enough.
+fn ray_torus(ray: Ray, center: vec3<f32>, axis: vec3<f32>, major_radius: f32, minor_radius: f32, two_sided: bool) -> HitInfo {
+    var hit_info: HitInfo;
+    hit_info.did_hit = false;
+
+    // Coordinates with the axis as z
+    let x_axis: vec3<f32> = perpendicular(axis);
+    let y_axis: vec3<f32> = cross(axis, x_axis);
+    let dir_length: f32 = length(ray.dir);
+    let offset: vec3<f32> = (ray.origin - center) / major_radius;
+    var origin: vec3<f32> = vec3<f32>(dot(offset, x_axis), dot(offset, y_axis), dot(offset, axis));
+    let dir: vec3<f32> = vec3<f32>(dot(ray.dir, x_axis), dot(ray.dir, y_axis), dot(ray.dir, axis)) / dir_length;
+    let tube: f32 = minor_radius / major_radius;
+
+    // Skip to a tube radius outside the bounding sphere. On the bounding sphere itself, rays in
+    // the plane of the ring would start on the torus, where the quartic has a root at 0.
+    let n: f32 = dot(origin, dir);
+    let bounding: f32 = n * n - dot(origin, origin) + (1.0 + tube) * (1.0 + tube);
+    if (bounding < 0.0 || -n + sqrt(bounding) < 0.0) {
+        return hit_info;
+    }
+    let margin: f32 = n * n - dot(origin, origin) + (1.0 + 2.0 * tube) * (1.0 + 2.0 * tube);
+    let start: f32 = max(-n - sqrt(margin), 0.0);
+    origin += dir * start;
+
+    // The nearest solution where the ray enters, the others are where it leaves or behind it. A
+    // two-sided torus is also hit where the ray leaves.
+    let roots: vec4<f32> = torus_roots(origin, dir, tube);
+    var closest: f32 = -1.0;
+    for (var i = 0u; i < 4u; i++) {
+        // Newton steps on the implicit surface refine the solution
+        var t: f32 = roots[i];
+        for (var j = 0u; j < 2u; j++) {
+            let p: vec3<f32> = origin + dir * t;
+            let value: f32 = torus_value(p, tube);
+            let slope: f32 = 4.0 * dot(torus_gradient(p, tube), dir);
+            if (abs(slope) > 1e-6) {
+                t -= value / slope;
+            }
+        }
+        let entering: bool = dot(torus_gradient(origin + dir * t, tube), dir) < 0.0;
+        if (roots[i] >= 0.0 && t >= 0.0 && (entering || two_sided) && (closest < 0.0 || t < closest)) {
+            closest = t;
+        }
+    }
+    if (closest < 0.0) {
+        return hit_info;
+    }
+
+    let p: vec3<f32> = origin + dir * closest;
+    let local_normal: vec3<f32> = normalize(torus_gradient(p, tube));
+    let ring: f32 = length(p.xy);
+    hit_info.did_hit = true;
+    hit_info.distance = (start + closest) * major_radius / dir_length;
+    hit_info.position = ray.origin + ray.dir * hit_info.distance;
+    hit_info.normal = normalize(x_axis * local_normal.x + y_axis * local_normal.y + axis * local_normal.z);
+    hit_info.geometric_normal = hit_info.normal;
+    hit_info.uv = vec2<f32>(0.5 + atan2(p.y, p.x) / (2.0 * 3.14159), 0.5 + atan2(p.z, ring - 1.0) / (2.0 * 3.14159));
+    hit_info.uv_density = 1.0 / (2.0 * 3.14159 * sqrt(major_radius * minor_radius));
+    hit_info.tangent = vec4<f32>(normalize(y_axis * p.x - x_axis * p.y), 1.0);
+    return hit_info;
+}
+
+// Implicit surface of the torus with major radius 1 around z, 0 on the surface
+fn torus_value(p: vec3<f32>, tube: f32) -> f32 {
+    let k: f32 = dot(p, p) + 1.0 - tube * tube;
+    return k * k - 4.0 * dot(p.xy, p.xy);
+}
+
+// A quarter of the gradient of torus_value, it points out of the torus
+fn torus_gradient(p: vec3<f32>, tube: f32) -> vec3<f32> {
+    return p * (dot(p, p) - tube * tube - vec3<f32>(1.0, 1.0, -1.0));
+}
+
+// Distances along the unit direction to the torus with major radius 1 around z, -1 where there
+// are fewer than four. The quartic is solved like in Inigo Quilez's torus intersection.
+fn torus_roots(origin: vec3<f32>, dir: vec3<f32>, tube: f32) -> vec4<f32> {
+    var roots: vec4<f32> = vec4<f32>(-1.0);
+    let m: f32 = dot(origin, origin);
+    let n: f32 = dot(origin, dir);
+
+    // t^4 + 4 k3 t^3 + 6 k2 t^2 + 4 k1 t + k0 = 0, solved for 1 / t when c1 is close to 0
+    let k: f32 = (m - tube * tube - 1.0) / 2.0;
+    var k3: f32 = n;
+    var k2: f32 = n * n + dir.z * dir.z + k;
+    var k1: f32 = k * n + origin.z * dir.z;
+    var k0: f32 = k * k + origin.z * origin.z - tube * tube;
+    let inverted: bool = abs(k3 * (k3 * k3 - k2) + k1) < 0.01;
+    if (inverted) {
+        let swap: f32 = k1;
+        k1 = k3;
+        k3 = swap;
+        k0 = 1.0 / k0;
+        k1 = k1 * k0;
+        k2 = k2 * k0;
+        k3 = k3 * k0;
+    }
+
+    // Resolvent cubic
+    let c2: f32 = (2.0 * k2 - 3.0 * k3 * k3) / 3.0;
+    let c1: f32 = (k3 * (k3 * k3 - k2) + k1) * 2.0;
+    let c0: f32 = (k3 * (k3 * (-3.0 * k3 * k3 + 4.0 * k2) - 8.0 * k1) + 4.0 * k0) / 3.0;
+    let q: f32 = c2 * c2 + c0;
+    let r: f32 = 3.0 * c0 * c2 - c2 * c2 * c2 - c1 * c1;
+    let h: f32 = r * r - q * q * q;
+    var z: f32;
+    if (h < 0.0) {
+        let sq: f32 = sqrt(q);
+        z = 2.0 * sq * cos(acos(clamp(r / (sq * q), -1.0, 1.0)) / 3.0);
+    } else {
+        let sq: f32 = pow(sqrt(h) + abs(r), 1.0 / 3.0);
+        z = sign(r) * abs(sq + q / sq);
+    }
+    z = c2 - z;
+
+    // Two quadratics
+    var d1: f32 = z - 3.0 * c2;
+    var d2: f32 = z * z - 3.0 * c0;
+    if (abs(d1) < 1.0e-4) {
+        if (d2 < 0.0) {
+            return roots;
+        }
+        d2 = sqrt(d2);
+    } else {
+        if (d1 < 0.0) {
+            return roots;
+        }
+        d1 = sqrt(d1 / 2.0);
+        d2 = c1 / d1;
+    }
+    let h1: f32 = d1 * d1 - z + d2;
+    if (h1 > 0.0) {
+        roots.x = -d1 - sqrt(h1) - k3;
+        roots.y = -d1 + sqrt(h1) - k3;
+    }
+    let h2: f32 = d1 * d1 - z - d2;
+    if (h2 > 0.0) {
+        roots.z = d1 - sqrt(h2) - k3;
+        roots.w = d1 + sqrt(h2) - k3;
+    }
+    if (inverted) {
+        roots = 2.0 / roots;
+    }
+    return roots;
+}
+
+// Angle of a direction perpendicular to the unit axis around it, from 0 to 1
+fn angle_around(direction: vec3<f32>, axis: vec3<f32>) -> f32 {
+    let x_axis: vec3<f32> = perpendicular(axis);
+    let y_axis: vec3<f32> = cross(axis, x_axis);
+    return 0.5 + atan2(dot(direction, y_axis), dot(direction, x_axis)) / (2.0 * 3.14159);
+}
+
+// The textures of the material at a hit, cone_distance is the distance the ray cone travelled
+// from the camera to the hit. Every texture multiplies the value it replaces.
+fn apply_textures(hit_info: HitInfo, ray: Ray, cone_distance: f32) -> HitInfo {
+    var result: HitInfo = hit_info;
+    if (!hit_info.did_hit || all(hit_info.textures < vec4<i32>(0))) {
+        return result;
+    }
+
+    // Width of the ray cone where it hits the surface, which grows at grazing angles
+    let cone_width: f32 = pixel_spread * cone_distance;
+    let cos_angle: f32 = max(abs(dot(hit_info.normal, ray.dir)), 0.1);
+    let footprint: f32 = cone_width / cos_angle;
+
+    if (hit_info.textures.x >= 0) {
+        result.color *= texture_value(hit_info.textures.x, hit_info, footprint).xyz;
+    }
+    if (hit_info.textures.y >= 0) {
+        // Roughness in green and metallic in blue
+        let texel: vec4<f32> = texture_value(hit_info.textures.y, hit_info, footprint);
+        result.smoothness = 1.0 - (1.0 - hit_info.smoothness) * texel.y;
+        result.metallic *= texel.z;
+    }
+    if (hit_info.textures.z >= 0) {
+        result.emission_color *= texture_value(hit_info.textures.z, hit_info, footprint).xyz;
+    }
+    if (hit_info.textures.w >= 0) {
+        // Tangent space, in the frame of the shading normal
+        let texel: vec3<f32> = texture_value(hit_info.textures.w, hit_info, footprint).xyz * 2.0 - 1.0;
+        var tangent: vec3<f32> = hit_info.tangent.xyz - hit_info.normal * dot(hit_info.normal, hit_info.tangent.xyz);
+        if (dot(tangent, tangent) < 1e-12) {
+            tangent = perpendicular(hit_info.normal);
+        }
+        tangent = normalize(tangent);
+        let bitangent: vec3<f32> = cross(hit_info.normal, tangent) * hit_info.tangent.w;
+        let normal: vec3<f32> = tangent * texel.x + bitangent * texel.y + hit_info.normal * texel.z;
+        if (dot(normal, normal) > 0.0) {
+            result.normal = normalize(normal);
+        }
+    }
+    return result;
+}
+
+// Value of a texture at a hit, footprint is the width of the pixel on the surface
+fn texture_value(index: i32, hit_info: HitInfo, footprint_in: f32) -> vec4<f32> {
+    let entry: array<vec4<f32>, 3> = texture_table[index];
+    let kind: u32 = u32(entry[0].x);
+    let scale: f32 = entry[0].z;
+    let position: vec3<f32> = hit_info.position * scale;
+    let footprint: f32 = footprint_in * scale;
+
+    // Solid textures don't need texture coordinates
+    switch kind {
+        case 3u: {
+            return blend(entry, noise(position, footprint, u32(entry[1].w)));
+        }
+        case 4u: {
+            return blend(entry, marble(position, footprint, entry[1].w));
+        }
+        case 5u: {
+            return blend(entry, wood(position, footprint, entry[1].w));
+        }
+        default: {}
+    }
+
+    if (entry[0].w == 0.0) {
+        return planar_texture(entry, hit_info.uv * scale, footprint * hit_info.uv_density);
+    }
+    // Triplanar, sharpened weights keep the blend between the projections narrow
+    let squared: vec3<f32> = hit_info.normal * hit_info.normal;
+    var weights: vec3<f32> = squared * squared;
+    weights /= weights.x + weights.y + weights.z;
+    return planar_texture(entry, position.yz, footprint) * weights.x
+        + planar_texture(entry, position.xz, footprint) * weights.y
+        + planar_texture(entry, position.xy, footprint) * weights.z;
+}
+
+// Value of an image or 2D pattern, footprint is the width of the pixel in the coordinates
+fn planar_texture(entry: array<vec4<f32>, 3>, p: vec2<f32>, footprint: f32) -> vec4<f32> {
+    switch u32(entry[0].x) {
+        case 1u: {
+            return blend(entry, checker(p, footprint));
+        }
+        case 2u: {
+            return blend(entry, grid(p, footprint, entry[1].w));
+        }
+        default: {
+            // Mip level from the size of the footprint in texels
+            let lod: f32 = log2(max(footprint * f32(texture_size), 1e-8));
+            return sample_texture(i32(entry[0].y), p, lod);
+        }
+    }
+}
+
+// Color of a pattern, from the first color at 0 to the second one at 1
+fn blend(entry: array<vec4<f32>, 3>, t: f32) -> vec4<f32> {
+    return vec4<f32>(entry[1].xyz + (entry[2].xyz - entry[1].xyz) * t, 1.0);
+}
+
+// Box filtered checker board of unit squares
+fn checker(p: vec2<f32>, footprint: f32) -> f32 {
+    let w: f32 = max(footprint, 1e-4);
+    let i: vec2<f32> = (abs(fract((p - 0.5 * w) * 0.5) - 0.5) - abs(fract((p + 0.5 * w) * 0.5) - 0.5)) * 2.0 / w;
+    return 0.5 - 0.5 * i.x * i.y;
+}
+
+// Box filtered grid of unit cells, 0 on the lines
+fn grid(p: vec2<f32>, footprint: f32, line_width: f32) -> f32 {
+    let w: f32 = max(footprint, 1e-4);
+    let n: f32 = 1.0 / max(line_width, 1e-4);
+    let a: vec2<f32> = p + 0.5 * w;
+    let b: vec2<f32> = p - 0.5 * w;
+    let coverage: vec2<f32> = (floor(a) + min(fract(a) * n, vec2<f32>(1.0)) - floor(b) - min(fract(b) * n, vec2<f32>(1.0))) / (n * w);
+    return (1.0 - coverage.x) * (1.0 - coverage.y);
+}
+
+fn noise(p: vec3<f32>, footprint: f32, octaves: u32) -> f32 {
+    return saturate(0.5 + fbm(p, footprint, octaves));
+}
+
+// Veins where a sine along x crosses zero, bent by the noise
+fn marble(p: vec3<f32>, footprint: f32, turbulence: f32) -> f32 {
+    return sqrt(abs(sin((p.x + turbulence * fbm(p, footprint, solid_octaves)) * 3.14159)));
+}
+
+// A ring every unit away from the y axis, bent by the noise
+fn wood(p: vec3<f32>, footprint: f32, turbulence: f32) -> f32 {
+    return fract(length(p.xz) + turbulence * fbm(p, footprint, solid_octaves));
+}
 
-    let a = angles.x * 3.14159 / 180.0;
-    let b = angles.y * 3.14159 / 180.0;
-    let c = angles.z * 3.14159 / 180.0;
+// Fractal Brownian motion: octaves of Perlin noise, each twice as fine and half as strong.
+// Octaves finer than the footprint would only add aliasing and average out to 0.
+fn fbm(p: vec3<f32>, footprint: f32, octaves: u32) -> f32 {
+    var sum: f32 = 0.0;
+    var amplitude: f32 = 0.5;
+    var frequency: f32 = 1.0;
+    for (var i: u32 = 0u; i < octaves; i++) {
+        if (frequency * footprint > 1.0) {
+            break;
+        }
+        sum += amplitude * perlin(p * frequency);
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    return sum;
+}
 
-    let cos_a = cos(a);
-    let sin_a = sin(a);
-    let cos_b = cos(b);
-    let sin_b = sin(b);
-    let cos_c = cos(c);
-    let sin_c = sin(c);
+// Improved Perlin noise, with the gradients picked by hashing the corners of the lattice cell
+fn perlin(p: vec3<f32>) -> f32 {
+    let cell: vec3<f32> = floor(p);
+    let f: vec3<f32> = p - cell;
+    let u: vec3<f32> = f * f * f * (f * (f * 6.0 - 15.0) + 10.0);
+    let c: vec3<i32> = vec3<i32>(cell);
+
+    let x00: f32 = mix_scalar(lattice(c, f, vec3<i32>(0, 0, 0)), lattice(c, f, vec3<i32>(1, 0, 0)), u.x);
+    let x10: f32 = mix_scalar(lattice(c, f, vec3<i32>(0, 1, 0)), lattice(c, f, vec3<i32>(1, 1, 0)), u.x);
+    let x01: f32 = mix_scalar(lattice(c, f, vec3<i32>(0, 0, 1)), lattice(c, f, vec3<i32>(1, 0, 1)), u.x);
+    let x11: f32 = mix_scalar(lattice(c, f, vec3<i32>(0, 1, 1)), lattice(c, f, vec3<i32>(1, 1, 1)), u.x);
+    return mix_scalar(mix_scalar(x00, x10, u.y), mix_scalar(x01, x11, u.y), u.z);
+}
 
-    let x_rot = x * cos_c * cos_b + y * (cos_c * sin_b * sin_a - sin_c * cos_a) + z * (cos_c * sin_b * cos_a + sin_c * sin_a);
-    let y_rot = x * sin_c * cos_b + y * (sin_c * sin_b * sin_a + cos_c * cos_a) + z * (sin_c * sin_b * cos_a - cos_c * sin_a);
-    let z_rot = -x * sin_b + y * cos_b * sin_a + z * cos_b * cos_a;
+// Contribution of a corner of the lattice cell
+fn lattice(cell: vec3<i32>, f: vec3<f32>, offset: vec3<i32>) -> f32 {
+    let corner: vec3<u32> = vec3<u32>(cell + offset);
+    let h: u32 = hash(corner.x ^ hash(corner.y ^ hash(corner.z)));
+    return gradient(h, f - vec3<f32>(offset));
+}
 
-    return vec3<f32>(x_rot, y_rot, z_rot);
+// Dot product with one of the 12 edge directions of a cube, Perlin's bit trick
+fn gradient(corner_hash: u32, p: vec3<f32>) -> f32 {
+    let h: u32 = corner_hash & 15u;
+    let u: f32 = select(p.y, p.x, h < 8u);
+    let v: f32 = select(select(p.z, p.x, h == 12u || h == 14u), p.y, h < 4u);
+    return select(-u, u, (h & 1u) == 0u) + select(-v, v, (h & 2u) == 0u);
+}
+
+// Like procedural.rs, which lerps as a + (b - a) * t
+fn mix_scalar(a: f32, b: f32, t: f32) -> f32 {
+    return a + (b - a) * t;
+}
+
+// Bilinear filtering on the two mip levels around lod, blended by its fraction. The texture
+// repeats, and v points up while the rows go down.
+fn sample_texture(index: i32, uv: vec2<f32>, lod: f32) -> vec4<f32> {
+    let level_lod: f32 = clamp(lod, 0.0, f32(texture_mip_levels - 1u));
+    let level: u32 = u32(floor(level_lod));
+    let next: u32 = min(level + 1u, texture_mip_levels - 1u);
+    let a: vec4<f32> = bilinear(index, uv, level);
+    let b: vec4<f32> = bilinear(index, uv, next);
+    return a + (b - a) * (level_lod - f32(level));
+}
+
+fn bilinear(index: i32, uv: vec2<f32>, level: u32) -> vec4<f32> {
+    let size: i32 = i32(texture_size >> level);
+    let x: f32 = uv.x * f32(size) - 0.5;
+    let y: f32 = (1.0 - uv.y) * f32(size) - 0.5;
+    let base: vec2<f32> = floor(vec2<f32>(x, y));
+    let fraction: vec2<f32> = vec2<f32>(x, y) - base;
+
+    let t00: vec4<f32> = texel(index, vec2<i32>(base), size, level);
+    let t10: vec4<f32> = texel(index, vec2<i32>(base) + vec2<i32>(1, 0), size, level);
+    let t01: vec4<f32> = texel(index, vec2<i32>(base) + vec2<i32>(0, 1), size, level);
+    let t11: vec4<f32> = texel(index, vec2<i32>(base) + vec2<i32>(1, 1), size, level);
+    let top: vec4<f32> = t00 + (t10 - t00) * fraction.x;
+    let bottom: vec4<f32> = t01 + (t11 - t01) * fraction.x;
+    return top + (bottom - top) * fraction.y;
+}
+
+// Wraps around the edges
+fn texel(index: i32, position: vec2<i32>, size: i32, level: u32) -> vec4<f32> {
+    let wrapped: vec2<i32> = ((position % size) + size) % size;
+    return textureLoad(textures, wrapped, index, i32(level));
+}
+
+// Rotate by a unit quaternion
+fn rotate_vector(v: vec3<f32>, q: vec4<f32>) -> vec3<f32> {
+    let t = 2.0 * cross(q.xyz, v);
+    return v + q.w * t + cross(q.xyz, t);
 }
 
 // Function to generate a random number between 0 and 1
@@ -381,6 +1582,17 @@ fn random_hemisphere_direction(normal: vec3<f32>, seed: u32) -> vec3<f32>
     return dir * sign(dot(normal, dir));
 }
 
+// Fraction of the light a refractive surface reflects, with Schlick's approximation. It uses the
+// angle on the side of the thinner medium, and refracted is zero under total internal reflection.
+fn fresnel(incoming: vec3<f32>, refracted: vec3<f32>, normal: vec3<f32>, eta: f32) -> f32 {
+    if (all(refracted == vec3<f32>(0.0))) {
+        return 1.0;
+    }
+    let cosine: f32 = select(-dot(incoming, normal), -dot(refracted, normal), eta > 1.0);
+    let r: f32 = (1.0 - eta) / (1.0 + eta);
+    return r * r + (1.0 - r * r) * pow(max(1.0 - cosine, 0.0), 5.0);
+}
+
 // Lerp
 fn lerp(a: vec3<f32>, b: vec3<f32>, t: f32) -> vec3<f32>
 {
@@ -391,8 +1603,8 @@ fn lerp(a: vec3<f32>, b: vec3<f32>, t: f32) -> vec3<f32>
 fn get_environment_light(ray: Ray) -> vec3<f32>
 {
     let sky_gradient_t = pow(smoothstep(0.0, 0.4, ray.dir.y), 0.35);
-    let sky_gradient: vec3<f32> = lerp(sky_color_horizon, sky_color_zenith, sky_gradient_t);
-    let sun: f32 = pow(max(0.0, dot(ray.dir, -normalize(sun_light_direction))), sun_focus) * sun_intensity;
+    let sky_gradient: vec3<f32> = lerp(settings.sky_color_horizon, settings.sky_color_zenith, sky_gradient_t);
+    let sun: f32 = pow(max(0.0, dot(ray.dir, -normalize(settings.sun_light_direction))), settings.sun_focus) * settings.sun_intensity;
 
     // Combine ground, sky, and sun
     let ground_to_sky_t = smoothstep(-0.01, 0.0, ray.dir.y);
@@ -400,5 +1612,96 @@ fn get_environment_light(ray: Ray) -> vec3<f32>
     if (ground_to_sky_t >= 1) {
         sun_mask = 1.0;
     }
-    return lerp(ground_color, sky_gradient, ground_to_sky_t) + sun * sun_mask;
+    return lerp(settings.ground_color, sky_gradient, ground_to_sky_t) + sun * sun_mask;
+}
+
+// --- Display Transform ---
+// Maps linear HDR radiance to the [0, 1] range of the render target
+fn display_transform(radiance: vec3<f32>) -> vec3<f32> {
+    var color: vec3<f32> = radiance;
+    if (display_settings.raw == 0u) {
+        color = display_settings.white_balance * radiance * exp2(display_settings.exposure);
+        color = max(color, vec3<f32>(0.0));
+
+        switch display_settings.tone_mapping {
+            case 1u: {
+                color = tone_map_reinhard(color);
+            }
+            case 2u: {
+                color = tone_map_aces_fitted(color);
+            }
+            case 3u: {
+                color = tone_map_agx(color);
+            }
+            default: {}
+        }
+    }
+    color = saturate(color);
+
+    if (display_settings.encode_srgb == 1u) {
+        color = linear_to_srgb(color);
+    }
+    return color;
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Luminance based Reinhard, keeps the hue of bright colors
+fn tone_map_reinhard(color: vec3<f32>) -> vec3<f32> {
+    return color / (1.0 + luminance(color));
+}
+
+// Stephen Hill's fit of the ACES RRT + ODT
+fn tone_map_aces_fitted(color: vec3<f32>) -> vec3<f32> {
+    let aces_input: mat3x3<f32> = mat3x3<f32>(
+        vec3<f32>(0.59719, 0.07600, 0.02840),
+        vec3<f32>(0.35458, 0.90834, 0.13383),
+        vec3<f32>(0.04823, 0.01566, 0.83777)
+    );
+    let aces_output: mat3x3<f32> = mat3x3<f32>(
+        vec3<f32>(1.60475, -0.10208, -0.00327),
+        vec3<f32>(-0.53108, 1.10813, -0.07276),
+        vec3<f32>(-0.07367, -0.00605, 1.07602)
+    );
+
+    let v: vec3<f32> = aces_input * color;
+    let a: vec3<f32> = v * (v + 0.0245786) - 0.000090537;
+    let b: vec3<f32> = v * (0.983729 * v + 0.4329510) + 0.238081;
+    return aces_output * (a / b);
+}
+
+// AgX base look with the polynomial approximation of the default contrast curve
+fn tone_map_agx(color: vec3<f32>) -> vec3<f32> {
+    let agx_inset: mat3x3<f32> = mat3x3<f32>(
+        vec3<f32>(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+        vec3<f32>(0.0784335999999992, 0.878468636469772, 0.0784336),
+        vec3<f32>(0.0792237451477643, 0.0791661274605434, 0.879142973793104)
+    );
+    let agx_outset: mat3x3<f32> = mat3x3<f32>(
+        vec3<f32>(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+        vec3<f32>(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+        vec3<f32>(-0.0990297440797205, -0.0989611768448433, 1.15107367264116)
+    );
+    let min_ev: f32 = -12.47393;
+    let max_ev: f32 = 4.026069;
+
+    var v: vec3<f32> = agx_inset * color;
+    v = clamp(log2(max(v, vec3<f32>(1e-10))), vec3<f32>(min_ev), vec3<f32>(max_ev));
+    v = (v - min_ev) / (max_ev - min_ev);
+
+    let v2: vec3<f32> = v * v;
+    let v4: vec3<f32> = v2 * v2;
+    v = 15.5 * v4 * v2 - 40.14 * v4 * v + 31.96 * v4 - 6.868 * v2 * v + 0.4298 * v2 + 0.1191 * v - 0.00232;
+
+    // The curve outputs display encoded values, go back to linear
+    v = agx_outset * v;
+    return pow(max(v, vec3<f32>(0.0)), vec3<f32>(2.2));
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let low: vec3<f32> = color * 12.92;
+    let high: vec3<f32> = 1.055 * pow(color, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, color <= vec3<f32>(0.0031308));
 }
\ No newline at end of file
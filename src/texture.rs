@@ -0,0 +1,218 @@
+// Textures of the materials, images or procedural patterns. Every image is scaled to
+// TEXTURE_SIZE x TEXTURE_SIZE and gets a full mip chain, so all of them fit in one texture array
+// on the GPU. The shader filters the texels itself (sample_texture in shader.wgsl), sample() is
+// the same filter for the CPU renderer. The patterns are evaluated by procedural.rs and the shader.
+use cgmath::Vector4;
+use std::path::{Path, PathBuf};
+
+// --- Texture Parameters --- Must match shader.wgsl
+pub const TEXTURE_SIZE: u32 = 1024;
+pub const MIP_LEVELS: u32 = TEXTURE_SIZE.ilog2() + 1;
+pub const MAX_TEXTURES: usize = 64; // Size of the texture table uniform
+pub const FLOATS_PER_TEXTURE: usize = 12; // Three vec4s in the texture table, see texture_data()
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextureSource {
+    pub pattern: Pattern,
+    pub mapping: Mapping,
+    pub scale: f32, // Repetitions per unit of the texture coordinates, or per world unit
+}
+
+// The patterns blend from the first color to the second one. Colors are linear.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Image {
+        path: PathBuf,
+        srgb: bool, // 8-bit colors are sRGB encoded, other data isn't. HDR images are always linear.
+    },
+    Checker {
+        colors: [[f32; 3]; 2],
+    },
+    Grid {
+        colors: [[f32; 3]; 2], // Lines, then cells
+        line_width: f32,       // Fraction of a cell
+    },
+    // The patterns below are solid textures: they fill space, so they're evaluated at the
+    // position and need no texture coordinates
+    Noise {
+        colors: [[f32; 3]; 2],
+        octaves: u32, // Layers of Perlin noise, each one twice as fine and half as strong
+    },
+    Marble {
+        colors: [[f32; 3]; 2], // Veins, then stone
+        turbulence: f32,
+    },
+    Wood {
+        colors: [[f32; 3]; 2], // Early and late wood of the rings around the y axis
+        turbulence: f32,
+    },
+}
+
+// Where the 2D patterns and images get their coordinates from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mapping {
+    Uv,
+    Triplanar, // Projected along the three axes and blended by the normal, for meshes without UVs
+}
+
+impl TextureSource {
+    // Image for albedo and emission textures
+    pub fn color(path: &str) -> Self {
+        TextureSource::procedural(Pattern::Image {
+            path: PathBuf::from(path),
+            srgb: true,
+        })
+    }
+
+    // Image for roughness/metallic and normal maps
+    pub fn data(path: &str) -> Self {
+        TextureSource::procedural(Pattern::Image {
+            path: PathBuf::from(path),
+            srgb: false,
+        })
+    }
+
+    // Any pattern, with the texture coordinates of the surface
+    pub fn procedural(pattern: Pattern) -> Self {
+        TextureSource {
+            pattern,
+            mapping: Mapping::Uv,
+            scale: 1.0,
+        }
+    }
+}
+
+// The texture table for the shader, FLOATS_PER_TEXTURE floats for each of the MAX_TEXTURES:
+// [kind, image layer, scale, mapping], [first color, parameter], [second color, unused], where the
+// kind is 0 for images and 1 + the index of the pattern in Pattern for the others
+pub fn texture_data(sources: &[TextureSource]) -> Vec<f32> {
+    let mut data = vec![0.0; MAX_TEXTURES * FLOATS_PER_TEXTURE];
+    let mut layer = 0;
+    for (source, entry) in sources.iter().zip(data.chunks_mut(FLOATS_PER_TEXTURE)) {
+        let (kind, colors, parameter) = match source.pattern {
+            Pattern::Image { .. } => (0, [[0.0; 3]; 2], 0.0),
+            Pattern::Checker { colors } => (1, colors, 0.0),
+            Pattern::Grid { colors, line_width } => (2, colors, line_width),
+            Pattern::Noise { colors, octaves } => (3, colors, octaves as f32),
+            Pattern::Marble { colors, turbulence } => (4, colors, turbulence),
+            Pattern::Wood { colors, turbulence } => (5, colors, turbulence),
+        };
+        let mapping = match source.mapping {
+            Mapping::Uv => 0.0,
+            Mapping::Triplanar => 1.0,
+        };
+        entry[..4].copy_from_slice(&[kind as f32, layer as f32, source.scale, mapping]);
+        entry[4..7].copy_from_slice(&colors[0]);
+        entry[7] = parameter;
+        entry[8..11].copy_from_slice(&colors[1]);
+        if kind == 0 {
+            layer += 1;
+        }
+    }
+    data
+}
+
+// Linear RGBA texels, mip level i is TEXTURE_SIZE >> i texels wide and stored row by row from
+// the top
+pub struct Texture {
+    pub mips: Vec<Vec<[f32; 4]>>,
+}
+
+// Load the image of every texture of a scene in order, so material texture indices index the
+// result. Procedural textures have no image.
+pub fn load_textures(sources: &[TextureSource]) -> Result<Vec<Option<Texture>>, String> {
+    if sources.len() > MAX_TEXTURES {
+        return Err(format!(
+            "The scene has {} textures, but at most {} are supported",
+            sources.len(),
+            MAX_TEXTURES
+        ));
+    }
+    sources
+        .iter()
+        .map(|source| match &source.pattern {
+            Pattern::Image { path, srgb } => load_texture(path, *srgb).map(Some),
+            _ => Ok(None),
+        })
+        .collect()
+}
+
+// Load a PNG, JPEG or Radiance HDR image
+fn load_texture(path: &Path, srgb: bool) -> Result<Texture, String> {
+    let image = image::open(path)
+        .map_err(|error| format!("Failed to load texture {}: {}", path.display(), error))?;
+    let is_hdr = matches!(
+        image,
+        image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_)
+    );
+
+    // Decode sRGB before scaling, so the filtering happens on linear values
+    let mut texels = image.to_rgba32f();
+    if srgb && !is_hdr {
+        for pixel in texels.pixels_mut() {
+            for channel in &mut pixel.0[..3] {
+                *channel = srgb_to_linear(*channel);
+            }
+        }
+    }
+    let texels = image::imageops::resize(
+        &texels,
+        TEXTURE_SIZE,
+        TEXTURE_SIZE,
+        image::imageops::FilterType::Triangle,
+    );
+
+    // Every level averages 2x2 texels of the one above
+    let mut mips = vec![texels.pixels().map(|pixel| pixel.0).collect::<Vec<[f32; 4]>>()];
+    for level in 1..MIP_LEVELS {
+        let size = (TEXTURE_SIZE >> level) as usize;
+        let above = &mips[level as usize - 1];
+        let texel = |x: usize, y: usize| above[y * size * 2 + x];
+        let mip = (0..size * size)
+            .map(|i| {
+                let (x, y) = (i % size * 2, i / size * 2);
+                let quad = [texel(x, y), texel(x + 1, y), texel(x, y + 1), texel(x + 1, y + 1)];
+                std::array::from_fn(|c| (quad[0][c] + quad[1][c] + quad[2][c] + quad[3][c]) * 0.25)
+            })
+            .collect();
+        mips.push(mip);
+    }
+    Ok(Texture { mips })
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// sample_texture: bilinear filtering on the two mip levels around lod, blended by its fraction.
+// The texture repeats, and v points up while the rows go down.
+pub fn sample(texture: &Texture, uv: [f32; 2], lod: f32) -> Vector4<f32> {
+    let level_lod = lod.clamp(0.0, (MIP_LEVELS - 1) as f32);
+    let level = level_lod.floor() as u32;
+    let next = (level + 1).min(MIP_LEVELS - 1);
+    let a = bilinear(texture, uv, level);
+    let b = bilinear(texture, uv, next);
+    a + (b - a) * (level_lod - level as f32)
+}
+
+fn bilinear(texture: &Texture, uv: [f32; 2], level: u32) -> Vector4<f32> {
+    let size = (TEXTURE_SIZE >> level) as i32;
+    let x = uv[0] * size as f32 - 0.5;
+    let y = (1.0 - uv[1]) * size as f32 - 0.5;
+    let (base_x, base_y) = (x.floor(), y.floor());
+    let (fraction_x, fraction_y) = (x - base_x, y - base_y);
+
+    let mip = &texture.mips[level as usize];
+    let texel = |offset_x: i32, offset_y: i32| {
+        let tx = (base_x as i32 + offset_x).rem_euclid(size);
+        let ty = (base_y as i32 + offset_y).rem_euclid(size);
+        Vector4::from(mip[(ty * size + tx) as usize])
+    };
+    let top = texel(0, 0) + (texel(1, 0) - texel(0, 0)) * fraction_x;
+    let bottom = texel(0, 1) + (texel(1, 1) - texel(0, 1)) * fraction_x;
+    top + (bottom - top) * fraction_y
+}
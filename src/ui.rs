@@ -0,0 +1,297 @@
+// Overlay panel for changing the render settings, camera, environment and materials while the
+// viewer runs. It is drawn on top of the presented frame, and every change that affects the
+// traced image uploads only the buffers it touched and restarts the accumulation.
+use crate::display::{ToneMapping, MAX_TEMPERATURE, MIN_TEMPERATURE};
+use crate::render_settings::{DebugMode, RenderSettings, MAX_BOUNCE_COUNT, MAX_FOV, MAX_RAYS_PER_PIXEL, MIN_FOV};
+use crate::renderer_backend::renderer::Renderer;
+use crate::scene::Material;
+use winit::window::Window;
+
+pub struct Ui {
+    context: egui::Context,
+    state: egui_winit::State,
+    renderer: egui_wgpu::Renderer,
+    pub visible: bool,
+}
+
+impl Ui {
+    pub fn new(window: &Window, device: &wgpu::Device, target_format: wgpu::TextureFormat) -> Self {
+        let context = egui::Context::default();
+        let state = egui_winit::State::new(
+            context.clone(),
+            egui::ViewportId::ROOT,
+            window,
+            Some(window.scale_factor() as f32),
+            Some(device.limits().max_texture_dimension_2d as usize),
+        );
+        let renderer = egui_wgpu::Renderer::new(device, target_format, None, 1);
+
+        Ui {
+            context,
+            state,
+            renderer,
+            visible: false,
+        }
+    }
+
+    // Returns whether the panel used the event, in which case the viewer shouldn't handle it
+    pub fn on_window_event(&mut self, window: &Window, event: &winit::event::WindowEvent) -> bool {
+        if !self.visible {
+            return false;
+        }
+        self.state.on_window_event(window, event).consumed
+    }
+
+    // Show the panel, apply the changes made in it and draw it on the target
+    pub fn draw(&mut self, window: &Window, renderer: &mut Renderer, target: &wgpu::TextureView) {
+        if !self.visible {
+            return;
+        }
+
+        // Edit copies, comparing them afterwards tells which buffers have to be uploaded
+        let mut settings = renderer.settings;
+        let mut render_scale = renderer.render_scale();
+        let mut camera_position = renderer.camera.position;
+        let mut mesh_material = renderer.mesh_material;
+        let mut spheres = renderer.spheres.clone();
+        let mut primitives = renderer.primitives.clone();
+        let display = &mut renderer.display;
+        let frame_count = renderer.frame_count;
+
+        let raw_input = self.state.take_egui_input(window);
+        let output = self.context.run(raw_input, |context| {
+            egui::Window::new("Settings")
+                .default_width(260.0)
+                .show(context, |ui| {
+                    egui::CollapsingHeader::new("Render")
+                        .default_open(true)
+                        .show(ui, |ui| {
+                            ui.add(
+                                egui::Slider::new(&mut settings.max_bounce_count, 0..=MAX_BOUNCE_COUNT)
+                                    .text("Bounces"),
+                            );
+                            ui.add(
+                                egui::Slider::new(&mut settings.rays_per_pixel, 1..=MAX_RAYS_PER_PIXEL)
+                                    .text("Rays per pixel")
+                                    .logarithmic(true),
+                            );
+                            ui.add(
+                                egui::Slider::new(&mut render_scale, 0.1..=2.0)
+                                    .text("Render scale")
+                                    .logarithmic(true),
+                            );
+                            ui.checkbox(&mut settings.adaptive_sampling, "Adaptive sampling");
+                            if settings.adaptive_sampling || settings.debug_mode == DebugMode::Error {
+                                ui.add(
+                                    egui::Slider::new(&mut settings.noise_threshold, 0.001..=0.5)
+                                        .text("Noise threshold")
+                                        .logarithmic(true),
+                                );
+                            }
+                            ui.checkbox(&mut settings.denoise, "Denoise");
+                            egui::ComboBox::from_label("Debug view")
+                                .selected_text(format!("{:?}", settings.debug_mode))
+                                .show_ui(ui, |ui| {
+                                    for debug_mode in DebugMode::ALL {
+                                        ui.selectable_value(
+                                            &mut settings.debug_mode,
+                                            debug_mode,
+                                            format!("{:?}", debug_mode),
+                                        );
+                                    }
+                                });
+                            if settings.debug_mode == DebugMode::Depth {
+                                ui.add(
+                                    egui::Slider::new(&mut settings.depth_range, 1.0..=100000.0)
+                                        .text("Depth range")
+                                        .logarithmic(true),
+                                );
+                            }
+                            ui.label(format!("Frames: {}", frame_count));
+                        });
+
+                    egui::CollapsingHeader::new("Camera").show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("Position");
+                            ui.add(egui::DragValue::new(&mut camera_position.x).speed(1.0));
+                            ui.add(egui::DragValue::new(&mut camera_position.y).speed(1.0));
+                            ui.add(egui::DragValue::new(&mut camera_position.z).speed(1.0));
+                        });
+                        ui.add(egui::Slider::new(&mut settings.fov, MIN_FOV..=MAX_FOV).text("Field of view"));
+                    });
+
+                    egui::CollapsingHeader::new("Environment").show(ui, |ui| {
+                        ui.checkbox(&mut settings.use_environment_lighting, "Environment lighting");
+                        color_row(ui, "Horizon", &mut settings.sky_color_horizon);
+                        color_row(ui, "Zenith", &mut settings.sky_color_zenith);
+                        color_row(ui, "Ground", &mut settings.ground_color);
+                        ui.horizontal(|ui| {
+                            ui.label("Sun direction");
+                            for component in &mut settings.sun_light_direction {
+                                ui.add(egui::DragValue::new(component).speed(0.01));
+                            }
+                        });
+                        ui.add(egui::Slider::new(&mut settings.sun_intensity, 0.0..=20.0).text("Sun intensity"));
+                        ui.add(
+                            egui::Slider::new(&mut settings.sun_focus, 1.0..=2000.0)
+                                .text("Sun focus")
+                                .logarithmic(true),
+                        );
+                    });
+
+                    egui::CollapsingHeader::new("Display").show(ui, |ui| {
+                        ui.add(egui::Slider::new(&mut display.exposure, -10.0..=10.0).text("Exposure"));
+                        egui::ComboBox::from_label("Tone mapping")
+                            .selected_text(format!("{:?}", display.tone_mapping))
+                            .show_ui(ui, |ui| {
+                                for tone_mapping in [
+                                    ToneMapping::None,
+                                    ToneMapping::Reinhard,
+                                    ToneMapping::AcesFitted,
+                                    ToneMapping::Agx,
+                                ] {
+                                    ui.selectable_value(
+                                        &mut display.tone_mapping,
+                                        tone_mapping,
+                                        format!("{:?}", tone_mapping),
+                                    );
+                                }
+                            });
+                        ui.add(
+                            egui::Slider::new(&mut display.temperature, MIN_TEMPERATURE..=MAX_TEMPERATURE)
+                                .text("Temperature"),
+                        );
+                        ui.add(egui::Slider::new(&mut display.tint, -1.0..=1.0).text("Tint"));
+                    });
+
+                    egui::CollapsingHeader::new("Materials").show(ui, |ui| {
+                        egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                            ui.collapsing("Mesh", |ui| material_editor(ui, &mut mesh_material));
+                            for (i, sphere) in spheres.iter_mut().enumerate() {
+                                ui.collapsing(format!("Sphere {}", i), |ui| {
+                                    material_editor(ui, &mut sphere.material)
+                                });
+                            }
+                            for (i, primitive) in primitives.iter_mut().enumerate() {
+                                ui.collapsing(format!("{} {}", primitive.shape.name(), i), |ui| {
+                                    material_editor(ui, &mut primitive.material)
+                                });
+                            }
+                        });
+                    });
+                });
+        });
+
+        // The display and render settings are uploaded every frame. The display, the denoiser and
+        // adaptive sampling keep the accumulation and everything else restarts it.
+        let unchanged = RenderSettings {
+            denoise: settings.denoise,
+            adaptive_sampling: settings.adaptive_sampling,
+            noise_threshold: settings.noise_threshold,
+            ..renderer.settings
+        };
+        if settings != unchanged {
+            renderer.frame_count = 0;
+        }
+        renderer.settings = settings;
+        if render_scale != renderer.render_scale() {
+            renderer.set_render_scale(render_scale);
+        }
+        if camera_position != renderer.camera.position {
+            renderer.camera.position = camera_position;
+            renderer.frame_count = 0;
+        }
+        if mesh_material != renderer.mesh_material {
+            renderer.mesh_material = mesh_material;
+            renderer.write_mesh_material();
+        }
+        for (i, sphere) in spheres.into_iter().enumerate() {
+            if sphere != renderer.spheres[i] {
+                renderer.spheres[i] = sphere;
+                renderer.write_sphere(i);
+            }
+        }
+        for (i, primitive) in primitives.into_iter().enumerate() {
+            if primitive != renderer.primitives[i] {
+                renderer.primitives[i] = primitive;
+                renderer.write_primitive(i);
+            }
+        }
+
+        self.state.handle_platform_output(window, output.platform_output);
+        self.paint(window, renderer, target, output.shapes, output.textures_delta, output.pixels_per_point);
+    }
+
+    fn paint(
+        &mut self,
+        window: &Window,
+        renderer: &Renderer,
+        target: &wgpu::TextureView,
+        shapes: Vec<egui::epaint::ClippedShape>,
+        textures_delta: egui::TexturesDelta,
+        pixels_per_point: f32,
+    ) {
+        let device = &renderer.device;
+        let queue = &renderer.queue;
+        let size = window.inner_size();
+        let screen_descriptor = egui_wgpu::ScreenDescriptor {
+            size_in_pixels: [size.width, size.height],
+            pixels_per_point,
+        };
+        let paint_jobs = self.context.tessellate(shapes, pixels_per_point);
+
+        for (id, image_delta) in &textures_delta.set {
+            self.renderer.update_texture(device, queue, *id, image_delta);
+        }
+
+        let mut command_encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("UI Encoder"),
+        });
+        let command_buffers =
+            self.renderer
+                .update_buffers(device, queue, &mut command_encoder, &paint_jobs, &screen_descriptor);
+
+        // Load instead of clear, the panel goes on top of the frame
+        {
+            let mut render_pass = command_encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("UI Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            self.renderer.render(&mut render_pass, &paint_jobs, &screen_descriptor);
+        }
+
+        queue.submit(command_buffers.into_iter().chain(std::iter::once(command_encoder.finish())));
+
+        for id in &textures_delta.free {
+            self.renderer.free_texture(id);
+        }
+    }
+}
+
+fn color_row(ui: &mut egui::Ui, label: &str, color: &mut [f32; 3]) {
+    ui.horizontal(|ui| {
+        ui.color_edit_button_rgb(color);
+        ui.label(label);
+    });
+}
+
+fn material_editor(ui: &mut egui::Ui, material: &mut Material) {
+    color_row(ui, "Color", &mut material.color);
+    color_row(ui, "Emission color", &mut material.emission_color);
+    ui.add(egui::Slider::new(&mut material.emission_strength, 0.0..=100.0).text("Emission strength"));
+    ui.add(egui::Slider::new(&mut material.smoothness, 0.0..=1.0).text("Smoothness"));
+    ui.add(egui::Slider::new(&mut material.metallic, 0.0..=1.0).text("Metallic"));
+    ui.add(egui::Slider::new(&mut material.transmission, 0.0..=1.0).text("Transmission"));
+    ui.add(egui::Slider::new(&mut material.ior, 1.0..=2.5).text("Index of refraction"));
+    ui.checkbox(&mut material.two_sided, "Two-sided");
+}
@@ -0,0 +1,17 @@
+// Renders the canonical scenes with the CPU reference renderer and compares them against the
+// golden images in tests/golden. After an intended change to the output, bless new references
+// with `cargo run --release -- --regression --bless --renderer cpu`.
+use std::process::Command;
+
+#[test]
+fn golden_images_match() {
+    let status = Command::new(env!("CARGO_BIN_EXE_ray-tracing"))
+        .args(["--regression", "--renderer", "cpu"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .expect("Failed to run the renderer");
+    assert!(
+        status.success(),
+        "The renders don't match the golden images, see target/regression for the differences"
+    );
+}